@@ -0,0 +1,214 @@
+use color_eyre::eyre::{eyre, ContextCompat, Report, WrapErr};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWriteExt};
+use tokio::process::{ChildStdout, Command};
+use tokio::time::Instant;
+
+use crate::compression::Compression;
+use crate::failure::Failure;
+use crate::multipart::{MultipartUpload, ObjectMetadata, Split, Spool};
+use crate::process::{self, before_deadline};
+use crate::s3::S3Access;
+use crate::size::ByteSize;
+use crate::tools::Tools;
+
+/// Where directory archives and other streamed backups go, and how they are compressed on the way.
+pub struct ArchiveUpload<'a> {
+    pub tools: &'a Tools,
+    pub s3_access: &'a S3Access,
+    pub bucket_name: &'a str,
+    pub compression: Compression,
+    pub compression_level: Option<u32>,
+    pub part_size: ByteSize,
+    pub part_retries: u32,
+    pub spool: Option<Spool>,
+    pub split: Option<Split>,
+    pub concurrency: usize,
+    pub deadline: Option<Instant>,
+    /// When the backup started, for the objects' metadata.
+    pub metadata: ObjectMetadata,
+}
+
+/// What feeds the compressor, or the upload when there is none.
+enum Input {
+    Pipe(ChildStdout),
+    File(std::fs::File),
+}
+
+impl ArchiveUpload<'_> {
+    /// Streams a tar of `paths`, relative to `root`, through the compressor into `key` and returns
+    /// the object's size.
+    pub async fn upload(&self, root: &Path, paths: &[PathBuf], key: &str) -> Result<u64, Report> {
+        let mut tar = Command::new(&self.tools.tar);
+        tar.arg("-cf").arg("-").arg("-C").arg(root).arg("--").args(paths);
+        self.stream("tar", tar, None, key).await
+    }
+
+    /// Streams what `producer` writes to stdout through the compressor into `key` and returns the
+    /// object's size. `stdin`, when given, is written to the producer, as with `Runner::run`. As with
+    /// exports, the object only appears once the producer and the compressor have both succeeded;
+    /// `stage` names the producer when it fails.
+    pub async fn stream(&self, stage: &str, mut producer: Command, stdin: Option<Vec<u8>>, key: &str) -> Result<u64, Report> {
+        if stdin.is_some() {
+            producer.stdin(Stdio::piped());
+        }
+        let mut producer = process::spawn(
+            producer
+                .kill_on_drop(true)
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped()),
+        )?;
+        let producer_stdout = producer.stdout.take().wrap_err("failed to pipe")?;
+        let producer_stdin = producer.stdin.take().zip(stdin);
+        let finished = async {
+            if let Some((mut pipe, input)) = producer_stdin {
+                pipe.write_all(&input).await.wrap_err("Unable to write to the producer")?;
+                // Closing stdin lets the producer see the end of its input.
+                drop(pipe);
+            }
+            before_deadline(self.deadline, stage, producer.wait_with_output())
+                .await
+                .and_then(process::succeeded)
+                .map(|_| ())
+        };
+        self.compress_and_upload(stage, Input::Pipe(producer_stdout), finished, key).await
+    }
+
+    /// Compresses the file at `path` into `key` and returns the object's size.
+    pub async fn upload_file(&self, path: &Path, key: &str) -> Result<u64, Report> {
+        let file = std::fs::File::open(path).wrap_err_with(|| format!("Unable to read {}", path.display()))?;
+        self.compress_and_upload("read", Input::File(file), async { Ok(()) }, key).await
+    }
+
+    async fn compress_and_upload(
+        &self,
+        stage: &str,
+        input: Input,
+        producer: impl Future<Output = Result<(), Report>>,
+        key: &str,
+    ) -> Result<u64, Report> {
+        // A file's size is known up front; a producer's output is relayed into the compressor so
+        // it can be counted. Without a compressor, the upload counts it.
+        let file_bytes = match &input {
+            Input::File(file) => Some(file.metadata()?.len()),
+            Input::Pipe(_) => None,
+        };
+        let (compressor, upload_source, relay): (_, Box<dyn AsyncRead + Unpin>, _) = match self.compression.command(self.tools, self.compression_level) {
+            Some(mut compressor) => {
+                let (input, pipe): (Stdio, _) = match input {
+                    Input::Pipe(stdout) => (Stdio::piped(), Some(stdout)),
+                    Input::File(file) => (file.into(), None),
+                };
+                let mut child = process::spawn(
+                    compressor
+                        .kill_on_drop(true)
+                        .stdin(input)
+                        .stdout(Stdio::piped())
+                        .stderr(Stdio::piped()),
+                )?;
+                let stdout = child.stdout.take().wrap_err("failed to pipe")?;
+                let relay = match (pipe, child.stdin.take()) {
+                    (Some(mut producer), Some(mut compressor_stdin)) => Some(tokio::spawn(async move {
+                        let bytes = tokio::io::copy(&mut producer, &mut compressor_stdin).await?;
+                        // Closing stdin lets the compressor see the end of its input.
+                        drop(compressor_stdin);
+                        Ok::<_, std::io::Error>(bytes)
+                    })),
+                    _ => None,
+                };
+                (Some(child), Box::new(stdout), relay)
+            }
+            None => match input {
+                Input::Pipe(stdout) => (None, Box::new(stdout), None),
+                Input::File(file) => (None, Box::new(tokio::fs::File::from_std(file)), None),
+            },
+        };
+        let upload = Arc::new(MultipartUpload {
+            tools: self.tools.clone(),
+            s3_access: self.s3_access.clone(),
+            bucket_name: self.bucket_name.to_string(),
+            key: key.to_string(),
+            part_size: self.part_size.0,
+            part_retries: self.part_retries,
+            concurrency: self.concurrency,
+            deadline: self.deadline,
+            spool: self.spool.clone(),
+            split: self.split.clone(),
+            metadata: Some(self.metadata.clone()),
+        });
+        let (producer_result, relay_result, compressor_result, upload_result) = tokio::join!(
+            producer,
+            async {
+                match relay {
+                    Some(relay) => relay
+                        .await
+                        .map_err(|_| eyre!("The relay task panicked"))?
+                        .map(Some)
+                        .wrap_err("Unable to relay the output to the compressor"),
+                    None => Ok(None),
+                }
+            },
+            async {
+                match compressor {
+                    Some(child) => before_deadline(self.deadline, "compression", child.wait_with_output())
+                        .await
+                        .and_then(process::succeeded)
+                        .map(|_| ()),
+                    None => Ok(()),
+                }
+            },
+            upload.upload(upload_source),
+        );
+        let (uploaded, upload_result) = match upload_result {
+            Ok(uploaded) => (Some(uploaded), Ok(())),
+            Err(err) => (None, Err(err)),
+        };
+        // A spooled upload that broke on its own is kept for `btagger resume`, unless what was
+        // spooled is itself broken.
+        let resumable = producer_result.is_ok() && relay_result.is_ok() && compressor_result.is_ok();
+        let relayed_bytes = relay_result.as_ref().ok().copied().flatten();
+        if let Err(err) = process::first_failure([
+            (stage, producer_result),
+            ("relay", relay_result.map(|_| ())),
+            ("compression", compressor_result),
+            ("upload", upload_result),
+        ]) {
+            match uploaded {
+                Some(uploaded) => upload.abort(uploaded).await,
+                None if !resumable => upload.discard().await,
+                None => {}
+            }
+            return Err(err);
+        }
+        let uploaded = uploaded.wrap_err("Upload finished without parts")?;
+        let uncompressed_bytes = match &self.compression {
+            Compression::None => Some(uploaded.bytes),
+            _ => file_bytes.or(relayed_bytes),
+        };
+        upload.complete(uploaded, uncompressed_bytes).await.wrap_err(Failure::Upload)
+    }
+}
+
+/// Number of files under `directory`, and their total size.
+pub async fn directory_size(directory: &Path) -> Result<(u64, u64), Report> {
+    let (mut files, mut bytes) = (0, 0);
+    let mut pending = vec![directory.to_path_buf()];
+    while let Some(directory) = pending.pop() {
+        let mut entries = tokio::fs::read_dir(&directory)
+            .await
+            .wrap_err_with(|| format!("Unable to read {}", directory.display()))?;
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            if metadata.is_dir() {
+                pending.push(entry.path());
+            } else {
+                files += 1;
+                bytes += metadata.len();
+            }
+        }
+    }
+    Ok((files, bytes))
+}
@@ -0,0 +1,96 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use color_eyre::eyre::{eyre, Report, WrapErr};
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::cron::Cron;
+use crate::tags::{self, Decision, Evaluation, Schedule, Tag};
+
+/// Dates whose backups get an extra tag, e.g. a fiscal year end, read from a TOML file:
+///
+/// ```toml
+/// [[dates]]
+/// date = "2025-06-30"
+/// tag = "fy-close=2025"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Calendar {
+    #[serde(default)]
+    pub dates: Vec<CalendarDate>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CalendarDate {
+    pub date: NaiveDate,
+    /// `key=value`, or a bare `key` meaning `key=1`.
+    pub tag: String,
+}
+
+impl Calendar {
+    pub fn load(path: &Path) -> Result<Calendar, Report> {
+        let text = std::fs::read_to_string(path).wrap_err_with(|| format!("Unable to read calendar file {}", path.display()))?;
+        let calendar: Calendar = toml::from_str(&text).wrap_err_with(|| format!("Unable to parse calendar file {}", path.display()))?;
+        for date in &calendar.dates {
+            date.tag.parse::<Tag>().map_err(|err| eyre!("{} in calendar file {}", err, path.display()))?;
+        }
+        Ok(calendar)
+    }
+}
+
+/// Checks the nightly trigger of every calendar date against `now`, with the same window as the
+/// period rules, so a backup matching it gets the date's tag.
+pub fn evaluate(schedule: &Schedule, calendar: &Calendar, now: DateTime<Utc>) -> Result<Vec<Evaluation>, Report> {
+    let mut evaluations = Vec::new();
+    for date in &calendar.dates {
+        // The nightly trigger, on this day of this month only.
+        let cron: Cron = format!(
+            "{} {} {} {} *",
+            schedule.minutes_offset_from_hour,
+            schedule.every_n_hours + schedule.day_offset_in_hours,
+            date.date.day(),
+            date.date.month()
+        )
+        .parse()
+        .map_err(|err: String| eyre!(err))?;
+        let midnight = date.date.and_hms_opt(0, 0, 0).map(|midnight| midnight.and_utc()).ok_or_else(|| eyre!("{} has no midnight", date.date))?;
+        let searched_from = midnight - Duration::minutes(1);
+        let when = tags::next(&cron, searched_from).wrap_err_with(|| format!("Unable to find the trigger on {}", date.date))?;
+        let diff = when - now;
+        let Decision { matched, reason } = schedule.decide(diff);
+        evaluations.push(Evaluation {
+            tag: date.tag.parse().map_err(|err: String| eyre!(err))?,
+            cron: cron.to_string(),
+            period_start_cron: None,
+            searched_from,
+            period_start: None,
+            when,
+            diff,
+            matched,
+            reason,
+        });
+    }
+    Ok(evaluations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tags::{PeriodAnchor, WindowStrategy};
+
+    #[test]
+    fn calendar_dates_tag_the_backup_of_their_day() {
+        let calendar: Calendar = toml::from_str("[[dates]]\ndate = \"2025-06-30\"\ntag = \"fy-close=2025\"\n\n[[dates]]\ndate = \"2025-09-15\"\ntag = \"audit\"\n").unwrap();
+        let schedule = Schedule { every_n_hours: 4, minutes_offset_from_hour: 30, day_offset_in_hours: 0, lag_window_in_minutes: 20, window_strategy: WindowStrategy::PreShift, window_pre_shift: None, period_anchor: PeriodAnchor::End };
+        let tags = |at: &str| {
+            let evaluations = evaluate(&schedule, &calendar, at.parse().unwrap()).unwrap();
+            tags::matched(&evaluations).into_iter().map(|tag| format!("{}={}", tag.key, tag.value)).collect::<Vec<_>>()
+        };
+        assert_eq!(tags("2025-06-30T04:32:00Z"), ["standard=1", "fy-close=2025"]);
+        assert_eq!(tags("2025-09-15T04:20:00Z"), ["standard=1", "audit=1"]);
+        // The backup of the day before or after is not tagged.
+        assert_eq!(tags("2025-06-29T04:30:00Z"), ["standard=1"]);
+        assert_eq!(tags("2025-07-01T04:30:00Z"), ["standard=1"]);
+    }
+}
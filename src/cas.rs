@@ -0,0 +1,346 @@
+use color_eyre::eyre::{eyre, Report, WrapErr};
+use futures::stream::{FuturesUnordered, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::path::Path;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::time::Instant;
+use tracing::info;
+
+use crate::compression::Compression;
+use crate::process::{self, before_deadline};
+use crate::s3::{self, S3Access};
+use crate::tools::Tools;
+
+/// Where chunks are kept, addressed by the SHA-256 of their uncompressed contents. They carry no
+/// tags; `btagger gc` deletes the ones no index references any more.
+pub const CHUNK_PREFIX: &str = "chunks/";
+
+/// Where a backup stored as chunks marks itself as running, from before it looks for chunks to
+/// reuse until its index is stored. `btagger gc` deletes nothing while a recent marker is there.
+pub const PENDING_PREFIX: &str = "_cas-pending/";
+
+/// Suffix of a backup's index, the object that is tagged and expired in place of the backup.
+pub const INDEX_EXTENSION: &str = ".cas.json";
+
+/// No chunk is cut shorter than this, except the last one.
+const MIN_CHUNK: usize = 512 * 1024;
+/// Chunks are cut here at the latest, whatever their contents.
+const MAX_CHUNK: usize = 8 * 1024 * 1024;
+/// Past the minimum, a chunk ends where the top bits of the rolling hash are all clear, about
+/// once a MiB. The hash only sees the last 64 bytes, so an edit moves the cut points near it
+/// and leaves the rest of the chunks as they were.
+const CUT_BITS: u32 = 20;
+
+/// Pseudo-random values for the rolling hash, one per byte value.
+const GEAR: [u64; 256] = gear();
+
+const fn gear() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut state: u64 = 0x6274_6167_6765_7221;
+    let mut i = 0;
+    while i < 256 {
+        // splitmix64
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut value = state;
+        value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = value ^ (value >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// A backup's chunks, in the order their contents make up the export.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Index {
+    pub chunks: Vec<IndexChunk>,
+    /// Size of the export.
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IndexChunk {
+    pub key: String,
+    /// Uncompressed size.
+    pub bytes: u64,
+}
+
+/// The outcome of storing an export as chunks.
+pub struct Stored {
+    pub index: Index,
+    /// Chunks that were not in the bucket yet, and their compressed size.
+    pub new_chunks: usize,
+    pub new_bytes: u64,
+}
+
+/// Where the chunks of an export go, and how each one is compressed.
+pub struct ChunkStore<'a> {
+    pub tools: &'a Tools,
+    pub s3_access: &'a S3Access,
+    pub bucket_name: &'a str,
+    /// Key the index will be stored under.
+    pub key: &'a str,
+    pub compression: Compression,
+    pub compression_level: Option<u32>,
+    pub concurrency: usize,
+    pub deadline: Option<Instant>,
+}
+
+impl ChunkStore<'_> {
+    /// Cuts what `reader` yields into chunks and uploads those the bucket does not have yet,
+    /// `concurrency` at a time. Nothing refers to the new chunks until [`put_index`] stores the
+    /// index; a run that fails before leaves them, and its pending marker, to `gc`.
+    pub async fn store(&self, reader: impl AsyncRead + Unpin) -> Result<Stored, Report> {
+        before_deadline(self.deadline, "upload", self.store_chunks(reader)).await?
+    }
+
+    async fn store_chunks(&self, mut reader: impl AsyncRead + Unpin) -> Result<Stored, Report> {
+        // Marked before the listing, so a `gc` that might still delete a chunk reused from it
+        // sees the marker first.
+        s3::write_object(self.tools, self.s3_access, self.bucket_name, &pending_key(self.key), Vec::new(), None).await?;
+        let mut known = s3::list_objects(self.tools, self.s3_access, self.bucket_name, CHUNK_PREFIX)
+            .await?
+            .into_iter()
+            .map(|object| object.key)
+            .collect::<HashSet<_>>();
+        let (mut index, mut new_chunks, mut new_bytes) = (Index::default(), 0, 0);
+        let mut pending = FuturesUnordered::new();
+        let mut buffer = Vec::with_capacity(MAX_CHUNK);
+        loop {
+            // Cut points are only looked for in a full buffer, so they do not depend on how the
+            // export happened to arrive.
+            (&mut reader)
+                .take((MAX_CHUNK - buffer.len()) as u64)
+                .read_to_end(&mut buffer)
+                .await
+                .wrap_err("Unable to read the export")?;
+            if buffer.is_empty() {
+                break;
+            }
+            let chunk = buffer.drain(..cut_point(&buffer)).collect::<Vec<_>>();
+            let key = chunk_key(&chunk, self.compression);
+            index.bytes += chunk.len() as u64;
+            index.chunks.push(IndexChunk { key: key.clone(), bytes: chunk.len() as u64 });
+            if known.insert(key.clone()) {
+                new_chunks += 1;
+                pending.push(self.put_chunk(key, chunk));
+                while pending.len() >= self.concurrency.max(1) {
+                    new_bytes += pending.next().await.unwrap_or(Ok(0))?;
+                }
+            }
+        }
+        while let Some(stored) = pending.next().await {
+            new_bytes += stored?;
+        }
+        Ok(Stored { index, new_chunks, new_bytes })
+    }
+
+    /// Compresses `chunk` and stores it as `key`, returning its compressed size.
+    async fn put_chunk(&self, key: String, chunk: Vec<u8>) -> Result<u64, Report> {
+        let contents = match self.compression.command(self.tools, self.compression_level) {
+            Some(compressor) => {
+                process::succeeded(self.tools.runner.run(compressor, Some(chunk)).await)
+                    .wrap_err_with(|| format!("Unable to compress {}", key))?
+                    .stdout
+            }
+            None => chunk,
+        };
+        let bytes = contents.len() as u64;
+        s3::write_object(self.tools, self.s3_access, self.bucket_name, &key, contents, None).await?;
+        info!(target: "cas", key, bytes, "Stored a new chunk");
+        Ok(bytes)
+    }
+}
+
+/// Stores `index` as `key`, with user `metadata` when given, and removes the backup's pending
+/// marker, returning the index size.
+pub async fn put_index(tools: &Tools, s3_access: &S3Access, bucket_name: &str, key: &str, index: &Index, metadata: Option<&str>) -> Result<u64, Report> {
+    let contents = serde_json::to_vec(index)?;
+    let bytes = contents.len() as u64;
+    s3::write_object(tools, s3_access, bucket_name, key, contents, metadata).await?;
+    remove_pending(tools, s3_access, bucket_name, key).await;
+    Ok(bytes)
+}
+
+/// Removes the pending marker of the backup whose index is now stored as `key`. The index holds
+/// on to the chunks from here; a marker left behind only delays `gc` by its grace.
+pub async fn remove_pending(tools: &Tools, s3_access: &S3Access, bucket_name: &str, key: &str) {
+    if let Err(err) = s3::delete_object(tools, s3_access, bucket_name, &pending_key(key)).await {
+        tracing::warn!(target: "cas", key, error = format!("{:#}", err), "Unable to remove the pending marker");
+    }
+}
+
+/// Server-side copies the chunks the index at `key` references from `from_bucket` into
+/// `to_bucket`, skipping those already there, and returns how many were copied. The backup is
+/// marked pending in `to_bucket` first, as a backup storing chunks is, so a `gc` there does not
+/// delete a reused chunk before the index arrives; [`remove_pending`] clears the mark.
+pub async fn copy_chunks(tools: &Tools, s3_access: &S3Access, from_bucket: &str, to_bucket: &str, key: &str) -> Result<usize, Report> {
+    let index = read_index(tools, s3_access, from_bucket, key).await?;
+    s3::write_object(tools, s3_access, to_bucket, &pending_key(key), Vec::new(), None).await?;
+    let mut known = s3::list_objects(tools, s3_access, to_bucket, CHUNK_PREFIX)
+        .await?
+        .into_iter()
+        .map(|object| object.key)
+        .collect::<HashSet<_>>();
+    let mut copied = 0;
+    for chunk in index.chunks {
+        if known.insert(chunk.key.clone()) {
+            s3::copy_object(tools, s3_access, from_bucket, to_bucket, &chunk.key).await?;
+            copied += 1;
+        }
+    }
+    info!(target: "cas", key, to_bucket, copied, "Copied the missing chunks");
+    Ok(copied)
+}
+
+/// The marker of the running backup whose index goes under `key`.
+pub fn pending_key(key: &str) -> String {
+    format!("{}{}", PENDING_PREFIX, key)
+}
+
+/// Where a backup stored as chunks keeps its index; `key` is the rendered key template.
+pub fn index_key(key: &str) -> String {
+    format!("{}{}", key, INDEX_EXTENSION)
+}
+
+/// Whether `key` is the index of a backup stored as chunks.
+pub fn is_index(key: &str) -> bool {
+    key.ends_with(INDEX_EXTENSION)
+}
+
+pub async fn read_index(tools: &Tools, s3_access: &S3Access, bucket_name: &str, key: &str) -> Result<Index, Report> {
+    let contents = s3::read_object(tools, s3_access, bucket_name, key).await?;
+    serde_json::from_slice(&contents).wrap_err_with(|| format!("Unable to parse the index {}", key))
+}
+
+/// Reassembles the export indexed by `key` into `export`, returning its size.
+pub async fn download(tools: &Tools, s3_access: &S3Access, bucket_name: &str, key: &str, export: &Path) -> Result<u64, Report> {
+    let index = read_index(tools, s3_access, bucket_name, key).await?;
+    let mut file = tokio::fs::File::create(export)
+        .await
+        .wrap_err_with(|| format!("Unable to create {}", export.display()))?;
+    for chunk in &index.chunks {
+        let contents = s3::read_object(tools, s3_access, bucket_name, &chunk.key).await?;
+        let contents = match Compression::from_key(&chunk.key).decompress_command(tools) {
+            Some(decompressor) => {
+                process::succeeded(tools.runner.run(decompressor, Some(contents)).await)
+                    .wrap_err_with(|| format!("Unable to decompress {}", chunk.key))?
+                    .stdout
+            }
+            None => contents,
+        };
+        if contents.len() as u64 != chunk.bytes {
+            return Err(eyre!("{} holds {} bytes where {} expects {}", chunk.key, contents.len(), key, chunk.bytes));
+        }
+        file.write_all(&contents).await?;
+    }
+    file.flush().await?;
+    Ok(index.bytes)
+}
+
+/// Where the next chunk of `data` ends. `data` is only shorter than the longest chunk at the end
+/// of the export.
+fn cut_point(data: &[u8]) -> usize {
+    let end = data.len().min(MAX_CHUNK);
+    let mut hash: u64 = 0;
+    for (i, byte) in data[..end].iter().enumerate().skip(MIN_CHUNK.saturating_sub(64)) {
+        hash = (hash << 1).wrapping_add(GEAR[usize::from(*byte)]);
+        if i >= MIN_CHUNK && hash >> (64 - CUT_BITS) == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+fn chunk_key(chunk: &[u8], compression: Compression) -> String {
+    let hash = format!("{:x}", Sha256::digest(chunk));
+    format!("{}{}/{}{}", CHUNK_PREFIX, &hash[..2], hash, compression.extension())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::MockRunner;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn repeated_contents_are_stored_once() {
+        let runner = Arc::new(MockRunner::new(|call| match call.program.as_str() {
+            "zstd" => MockRunner::output(0, "compressed", ""),
+            _ if call.has_args(&["list-objects-v2"]) => MockRunner::output(0, "", ""),
+            _ => MockRunner::output(0, "", ""),
+        }));
+        let tools = Tools::mock(runner.clone());
+        let s3_access = S3Access::default();
+        let store = ChunkStore {
+            tools: &tools,
+            s3_access: &s3_access,
+            bucket_name: "bk",
+            key: "surrealdb/app/main/2025-01-02.04-30.cas.json",
+            compression: Compression::Zstd,
+            compression_level: None,
+            concurrency: 2,
+            deadline: None,
+        };
+        let export = vec![7u8; 2 * MAX_CHUNK + 10];
+        let stored = store.store(&export[..]).await.unwrap();
+
+        assert_eq!(stored.index.bytes, export.len() as u64);
+        assert_eq!(stored.index.chunks.iter().map(|chunk| chunk.bytes).sum::<u64>(), export.len() as u64);
+        // The chunks cut from the same contents share a key, and are uploaded once.
+        assert_eq!(stored.index.chunks[0].key, stored.index.chunks[1].key);
+        let keys = stored.index.chunks.iter().map(|chunk| &chunk.key).collect::<HashSet<_>>();
+        assert_eq!(stored.new_chunks, keys.len());
+        let calls = runner.calls();
+        // The pending marker is written before the chunks are listed.
+        assert!(calls[0].has_args(&["s3://bk/_cas-pending/surrealdb/app/main/2025-01-02.04-30.cas.json"]));
+        assert!(calls[1].has_args(&["list-objects-v2"]));
+        let uploads = calls.iter().filter(|call| call.has_args(&["cp", "-"])).count();
+        assert_eq!(uploads, keys.len() + 1);
+        assert!(stored.index.chunks[0].key.starts_with(CHUNK_PREFIX) && stored.index.chunks[0].key.ends_with(".zst"));
+    }
+
+    #[test]
+    fn cut_points_follow_the_contents_not_their_offset() {
+        let mut state: u64 = 1;
+        let contents = (0..3 * MAX_CHUNK)
+            .map(|_| {
+                // xorshift64
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect::<Vec<_>>();
+        let cut = cut_point(&contents);
+        assert!((MIN_CHUNK..=MAX_CHUNK).contains(&cut));
+        // Whatever precedes them, the same bytes are cut in the same place.
+        let mut shifted = vec![1u8; 1000];
+        shifted.extend_from_slice(&contents[cut - MIN_CHUNK - 100..]);
+        let shifted_cut = cut_point(&shifted[1000..]);
+        assert_eq!(&shifted[1000..1000 + shifted_cut], &contents[cut - MIN_CHUNK - 100..cut]);
+    }
+
+    #[tokio::test]
+    async fn copying_an_index_copies_the_chunks_the_destination_lacks() {
+        let runner = Arc::new(MockRunner::new(|call| {
+            if call.has_args(&["s3://src/k.cas.json", "-"]) {
+                MockRunner::output(0, r#"{"chunks":[{"key":"chunks/aa/aa01.zst","bytes":1},{"key":"chunks/bb/bb02.zst","bytes":1},{"key":"chunks/aa/aa01.zst","bytes":1}],"bytes":3}"#, "")
+            } else if call.has_args(&["list-objects-v2"]) {
+                MockRunner::output(0, r#"{"Contents":[{"Key":"chunks/bb/bb02.zst","Size":1}]}"#, "")
+            } else {
+                MockRunner::output(0, "", "")
+            }
+        }));
+        let tools = Tools::mock(runner.clone());
+        let copied = copy_chunks(&tools, &S3Access::default(), "src", "dst", "k.cas.json").await.unwrap();
+        assert_eq!(copied, 1);
+        let calls = runner.calls();
+        assert!(calls[1].has_args(&["-", "s3://dst/_cas-pending/k.cas.json"]));
+        let copies = calls.iter().filter(|call| call.has_args(&["s3://src/chunks/aa/aa01.zst", "s3://dst/chunks/aa/aa01.zst"])).count();
+        assert_eq!(copies, 1);
+        assert!(!calls.iter().any(|call| call.has_args(&["s3://src/chunks/bb/bb02.zst"])));
+    }
+}
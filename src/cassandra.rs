@@ -0,0 +1,259 @@
+use color_eyre::eyre::{eyre, Report, WrapErr};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+use tracing::info;
+
+use crate::archive::ArchiveUpload;
+use crate::compression::Compression;
+use crate::failure::Failure;
+use crate::multipart::{ObjectMetadata, Split, Spool};
+use crate::process::{self, before_deadline};
+use crate::s3::{self, S3Access};
+use crate::size::ByteSize;
+use crate::summary::{BackupReport, EmptyBackup, Timings};
+use crate::tools::Tools;
+
+/// A Cassandra or ScyllaDB node to snapshot, and where the snapshot goes.
+pub struct CassandraOptions {
+    pub tools: Tools,
+    pub bucket_name: String,
+    pub s3_access: S3Access,
+    pub create_bucket: bool,
+    /// JMX address nodetool connects to, `host` or `host:port`.
+    pub address: String,
+    /// The node's data directory. The snapshot is read from it, so btagger runs on the node.
+    pub data_dir: PathBuf,
+    /// Keyspaces to snapshot; every keyspace when empty.
+    pub keyspaces: Vec<String>,
+    /// Snapshot name, unique to the run.
+    pub snapshot: String,
+    /// Tag set JSON applied to every archive.
+    pub tags: String,
+    /// Prefix the per-keyspace archives are stored under.
+    pub storage_key: String,
+    pub compression: Compression,
+    pub compression_level: Option<u32>,
+    pub part_size: ByteSize,
+    pub part_retries: u32,
+    pub spool: Option<Spool>,
+    pub split: Option<Split>,
+    pub min_expected_bytes: u64,
+    pub allow_empty: bool,
+    pub concurrency: usize,
+    pub deadline: Option<tokio::time::Instant>,
+    pub timings: Timings,
+}
+
+/// Snapshots the node, uploads one archive per keyspace under the storage key and tags them. The
+/// snapshot is cleared afterwards whether or not the backup succeeded, so failed runs do not pile
+/// up hard links in the data directory.
+pub async fn backup(options: &CassandraOptions) -> Result<BackupReport, Report> {
+    let result = snapshot_and_upload(options).await;
+    let cleared = process::succeeded(options.tools.runner.run(nodetool(options, "clearsnapshot"), None).await);
+    info!(
+        target: "nodetool_clearsnapshot_output",
+        snapshot = options.snapshot,
+        success = cleared.is_ok(),
+        error = cleared.err().map(|err| format!("{:#}", err))
+    );
+    result
+}
+
+async fn snapshot_and_upload(options: &CassandraOptions) -> Result<BackupReport, Report> {
+    let metadata = ObjectMetadata::now();
+    let (tools, s3_access, bucket_name, key) = (&options.tools, &options.s3_access, &options.bucket_name, &options.storage_key);
+    let timings = &options.timings;
+    if options.create_bucket {
+        let created = timings.time("bucket_ensure", s3::ensure_bucket(tools, s3_access, bucket_name)).await.wrap_err(Failure::Upload)?;
+        info!(target: "aws_create_bucket_output", bucket = bucket_name, created);
+    }
+
+    let output = timings.time("snapshot", before_deadline(options.deadline, "nodetool snapshot", tools.runner.run(nodetool(options, "snapshot"), None)))
+        .await
+        .and_then(process::succeeded)
+        .wrap_err(Failure::Export { stage: String::from("nodetool snapshot") })?;
+    info!(target: "nodetool_snapshot_output", snapshot = options.snapshot, stdout = process::captured("nodetool-snapshot", &output.stdout));
+
+    let directories = snapshot_directories(&options.data_dir, &options.keyspaces, &options.snapshot)
+        .await
+        .wrap_err_with(|| format!("Unable to find snapshot {} under {}", options.snapshot, options.data_dir.display()))?;
+    if directories.is_empty() && !options.allow_empty {
+        return Err(Report::new(EmptyBackup(format!("Snapshot {}", options.snapshot))));
+    }
+
+    let archive = ArchiveUpload {
+        tools,
+        s3_access,
+        bucket_name,
+        compression: options.compression,
+        compression_level: options.compression_level,
+        part_size: options.part_size,
+        part_retries: options.part_retries,
+        spool: options.spool.clone(),
+        split: options.split.clone(),
+        concurrency: options.concurrency,
+        deadline: options.deadline,
+        metadata,
+    };
+    let mut keys = Vec::new();
+    let mut bytes = 0;
+    let mut failure = None;
+    for (keyspace, paths) in &directories {
+        let archive_key = options.compression.with_extension(format!("{}/{}.tar", key, keyspace));
+        match timings.time("upload", archive.upload(&options.data_dir, paths, &archive_key)).await {
+            Ok(size) => {
+                info!(target: "backup_size", key = archive_key, tables = paths.len(), bytes = size);
+                keys.push(archive_key);
+                bytes += size;
+            }
+            Err(err) => {
+                failure = Some(err.wrap_err(format!("Unable to archive keyspace {}", keyspace)));
+                break;
+            }
+        }
+    }
+    if failure.is_none() && bytes < options.min_expected_bytes {
+        failure = Some(
+            eyre!("Snapshot {} is only {} bytes, below --min-expected-bytes {}", options.snapshot, bytes, options.min_expected_bytes)
+                .wrap_err(Failure::Export { stage: String::from("size check") }),
+        );
+    }
+    if let Some(failure) = failure {
+        // The keyspaces uploaded so far would be an incomplete backup.
+        if !keys.is_empty() {
+            let removed = s3::delete_prefix(tools, s3_access, bucket_name, key).await;
+            info!(target: "aws_remove_partial_backup_output", key, objects = keys.len(), success = removed.is_ok(), error = removed.err().map(|err| format!("{:#}", err)));
+        }
+        return Err(failure);
+    }
+
+    let object_keys = keys.iter().map(String::as_str).collect::<Vec<_>>();
+    timings.time("tagging", before_deadline(options.deadline, "tagging", s3::tag_objects(tools, s3_access, bucket_name, &object_keys, &options.tags, options.concurrency)))
+        .await?
+        .wrap_err(Failure::Tagging)?;
+    Ok(BackupReport {
+        storage_key: key.clone(),
+        bytes,
+        raw_bytes: None,
+        success: true,
+        stderr_tail: None,
+        tikv_stats: None,
+        signal: None,
+    })
+}
+
+/// `nodetool snapshot` or `nodetool clearsnapshot` for the run's snapshot and keyspaces.
+fn nodetool(options: &CassandraOptions, action: &str) -> Command {
+    let (host, port) = options.address.split_once(':').unwrap_or((&options.address, "7199"));
+    let mut command = Command::new(&options.tools.nodetool);
+    command
+        .kill_on_drop(true)
+        .arg("-h").arg(host)
+        .arg("-p").arg(port)
+        .arg(action)
+        .arg("-t").arg(&options.snapshot)
+        .args(&options.keyspaces);
+    command
+}
+
+/// The snapshot's directories, `{keyspace}/{table}/snapshots/{snapshot}` relative to `data_dir`,
+/// by keyspace.
+async fn snapshot_directories(data_dir: &Path, keyspaces: &[String], snapshot: &str) -> Result<BTreeMap<String, Vec<PathBuf>>, Report> {
+    let mut found = BTreeMap::new();
+    for keyspace in subdirectories(data_dir).await? {
+        if !keyspaces.is_empty() && !keyspaces.contains(&keyspace) {
+            continue;
+        }
+        for table in subdirectories(&data_dir.join(&keyspace)).await? {
+            let directory = Path::new(&keyspace).join(table).join("snapshots").join(snapshot);
+            if tokio::fs::try_exists(data_dir.join(&directory)).await? {
+                found.entry(keyspace.clone()).or_insert_with(Vec::new).push(directory);
+            }
+        }
+    }
+    Ok(found)
+}
+
+async fn subdirectories(directory: &Path) -> Result<Vec<String>, Report> {
+    let mut entries = tokio::fs::read_dir(directory)
+        .await
+        .wrap_err_with(|| format!("Unable to read {}", directory.display()))?;
+    let mut names = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_dir() {
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::MockRunner;
+    use std::sync::Arc;
+
+    fn data_dir(name: &str, tables: &[&str]) -> PathBuf {
+        let data_dir = std::env::temp_dir().join(format!("btagger-cassandra-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&data_dir);
+        for table in tables {
+            std::fs::create_dir_all(data_dir.join(table)).unwrap();
+        }
+        data_dir
+    }
+
+    #[tokio::test]
+    async fn snapshot_directories_are_grouped_by_keyspace() {
+        let data_dir = data_dir("grouped", &[
+            "shop/orders-1a/snapshots/run",
+            "shop/users-2b/snapshots/run",
+            "shop/carts-3c/snapshots/other",
+            "system/local-4d/snapshots/run",
+        ]);
+        let found = snapshot_directories(&data_dir, &[], "run").await.unwrap();
+        assert_eq!(found.keys().collect::<Vec<_>>(), ["shop", "system"]);
+        assert_eq!(found["shop"], [PathBuf::from("shop/orders-1a/snapshots/run"), PathBuf::from("shop/users-2b/snapshots/run")]);
+
+        let found = snapshot_directories(&data_dir, &[String::from("shop")], "run").await.unwrap();
+        assert_eq!(found.keys().collect::<Vec<_>>(), ["shop"]);
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn empty_snapshot_is_refused_and_still_cleared() {
+        let runner = Arc::new(MockRunner::new(|_| MockRunner::output(0, "{}", "")));
+        let options = CassandraOptions {
+            tools: Tools::mock(runner.clone()),
+            bucket_name: String::from("bk"),
+            s3_access: S3Access::default(),
+            create_bucket: false,
+            address: String::from("cass-1"),
+            data_dir: data_dir("empty", &["shop/orders-1a/snapshots/older"]),
+            keyspaces: vec![String::from("shop")],
+            snapshot: String::from("btagger-20250101T043000Z"),
+            tags: String::from(r#"{"TagSet":[]}"#),
+            storage_key: String::from("cassandra/2025-01-01.04-30"),
+            compression: Compression::Zstd,
+            compression_level: None,
+            part_size: ByteSize(8 * 1024 * 1024),
+            part_retries: 0,
+            spool: None,
+            split: None,
+            min_expected_bytes: 0,
+            allow_empty: false,
+            concurrency: 4,
+            deadline: None,
+            timings: Timings::default(),
+        };
+        let err = backup(&options).await.unwrap_err();
+        assert_eq!(Failure::of(&err).code(), "empty");
+
+        let calls = runner.calls();
+        assert_eq!(calls.len(), 2);
+        assert!(calls[0].has_args(&["-h", "cass-1", "-p", "7199", "snapshot", "-t", "btagger-20250101T043000Z", "shop"]));
+        assert!(calls[1].has_args(&["clearsnapshot", "-t", "btagger-20250101T043000Z", "shop"]));
+        std::fs::remove_dir_all(&options.data_dir).unwrap();
+    }
+}
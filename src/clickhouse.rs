@@ -0,0 +1,199 @@
+use color_eyre::eyre::{eyre, Report, WrapErr};
+use tokio::process::Command;
+use tracing::info;
+
+use crate::failure::Failure;
+use crate::process::{self, before_deadline};
+use crate::s3::{self, S3Access};
+use crate::secret::Secret;
+use crate::summary::{BackupReport, EmptyBackup, Timings};
+use crate::tools::Tools;
+
+/// A ClickHouse database to back up with `BACKUP ... TO S3`, and where to.
+pub struct ClickhouseOptions {
+    pub tools: Tools,
+    pub bucket_name: String,
+    pub s3_access: S3Access,
+    pub create_bucket: bool,
+    /// Native protocol address of the server, `host` or `host:port`.
+    pub address: String,
+    pub user: String,
+    pub password: Option<Secret>,
+    pub database: String,
+    /// Tag set JSON applied to every object of the backup.
+    pub tags: String,
+    pub storage_key: String,
+    pub min_expected_bytes: u64,
+    pub allow_empty: bool,
+    pub concurrency: usize,
+    pub deadline: Option<tokio::time::Instant>,
+    pub timings: Timings,
+}
+
+/// Has the ClickHouse server write the database straight to the bucket, then tags every object
+/// it wrote. Like a failed tikv-br run, a failed or undersized backup is removed rather than
+/// left untagged.
+pub async fn backup(options: &ClickhouseOptions) -> Result<BackupReport, Report> {
+    let (tools, s3_access, bucket_name, key) = (&options.tools, &options.s3_access, &options.bucket_name, &options.storage_key);
+    let timings = &options.timings;
+    if options.create_bucket {
+        let created = timings.time("bucket_ensure", s3::ensure_bucket(tools, s3_access, bucket_name)).await.wrap_err(Failure::Upload)?;
+        info!(target: "aws_create_bucket_output", bucket = bucket_name, created);
+    }
+
+    let statement = backup_statement(&options.database, &s3_url(s3_access, bucket_name, key), &s3_access.server_credentials().wrap_err(Failure::Config)?);
+    let (host, port) = options.address.split_once(':').unwrap_or((&options.address, "9000"));
+    let mut command = Command::new(&tools.clickhouse_client);
+    command
+        .kill_on_drop(true)
+        .arg("--host").arg(host)
+        .arg("--port").arg(port)
+        .arg("--user").arg(&options.user);
+    if let Some(password) = &options.password {
+        // Read by clickhouse-client, and kept out of process listings.
+        command.env("CLICKHOUSE_PASSWORD", password.expose());
+    }
+    // The statement carries the S3 credentials, so it goes in on stdin.
+    let output = timings.time("export", before_deadline(options.deadline, "clickhouse backup", tools.runner.run(command, Some(statement.into_bytes()))))
+        .await
+        .and_then(process::succeeded);
+    let stdout = output.as_ref().map(|output| String::from_utf8_lossy(&output.stdout).to_string()).unwrap_or_default();
+    info!(target: "clickhouse_backup_output", success = output.is_ok(), stdout = process::captured("clickhouse-backup", stdout.as_bytes()));
+    // `BACKUP` answers with the backup's id and status.
+    let created = output.and_then(|_| match stdout.contains("BACKUP_CREATED") {
+        true => Ok(()),
+        false => Err(eyre!("BACKUP did not report BACKUP_CREATED: {}", stdout.trim())),
+    });
+
+    let objects = timings.time("list", s3::list_objects(tools, s3_access, bucket_name, key)).await?;
+    let object_keys = objects.iter().map(|object| object.key.as_str()).collect::<Vec<_>>();
+    let bytes = objects.iter().map(|object| object.size).sum();
+    info!(target: "aws_list_objects_output", key, objects = objects.len(), bytes);
+    let failure = match created {
+        Err(err) => Some(err.wrap_err(Failure::Export { stage: String::from("clickhouse backup") })),
+        Ok(()) if objects.is_empty() && !options.allow_empty => Some(Report::new(EmptyBackup(format!("ClickHouse backup {}", key)))),
+        Ok(()) if bytes < options.min_expected_bytes => Some(
+            eyre!("ClickHouse backup {} is only {} bytes, below --min-expected-bytes {}", key, bytes, options.min_expected_bytes)
+                .wrap_err(Failure::Export { stage: String::from("size check") }),
+        ),
+        Ok(()) => None,
+    };
+    if let Some(failure) = failure {
+        if !objects.is_empty() {
+            let removed = s3::delete_prefix(tools, s3_access, bucket_name, key).await;
+            info!(target: "aws_remove_partial_backup_output", key, objects = objects.len(), success = removed.is_ok(), error = removed.err().map(|err| format!("{:#}", err)));
+        }
+        return Err(failure);
+    }
+
+    timings.time("tagging", before_deadline(options.deadline, "tagging", s3::tag_objects(tools, s3_access, bucket_name, &object_keys, &options.tags, options.concurrency)))
+        .await?
+        .wrap_err(Failure::Tagging)?;
+    Ok(BackupReport {
+        storage_key: key.clone(),
+        bytes,
+        raw_bytes: None,
+        success: true,
+        stderr_tail: None,
+        tikv_stats: None,
+        signal: None,
+    })
+}
+
+/// The backup's S3 URL as the ClickHouse server sees it: path style under an endpoint override,
+/// virtual-hosted on AWS.
+fn s3_url(s3_access: &S3Access, bucket_name: &str, key: &str) -> String {
+    match (&s3_access.endpoint, &s3_access.region) {
+        (Some(endpoint), _) => format!("{}/{}/{}/", endpoint.trim_end_matches('/'), bucket_name, key),
+        (None, Some(region)) => format!("https://{}.s3.{}.amazonaws.com/{}/", bucket_name, region, key),
+        (None, None) => format!("https://{}.s3.amazonaws.com/{}/", bucket_name, key),
+    }
+}
+
+fn backup_statement(database: &str, url: &str, credentials: &Option<(Secret, Secret)>) -> String {
+    let quote = |value: &str| format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"));
+    let destination = match credentials {
+        Some((id, key)) => format!("S3({}, {}, {})", quote(url), quote(id.expose()), quote(key.expose())),
+        None => format!("S3({})", quote(url)),
+    };
+    format!("BACKUP DATABASE `{}` TO {}", database.replace('`', "\\`"), destination)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::{Call, MockRunner};
+    use std::sync::Arc;
+
+    fn options(runner: Arc<MockRunner>) -> ClickhouseOptions {
+        ClickhouseOptions {
+            tools: Tools::mock(runner),
+            bucket_name: String::from("bk"),
+            s3_access: S3Access::minio(),
+            create_bucket: true,
+            address: String::from("ch:9440"),
+            user: String::from("backup"),
+            password: Some(Secret::new("ch-pw")),
+            database: String::from("events"),
+            tags: String::from(r#"{"TagSet":[{"Key":"standard","Value":"1"}]}"#),
+            storage_key: String::from("clickhouse/events/2025-01-01.04-30"),
+            min_expected_bytes: 0,
+            allow_empty: false,
+            concurrency: 4,
+            deadline: None,
+            timings: Timings::default(),
+        }
+    }
+
+    fn server(status: &'static str) -> impl Fn(&Call) -> std::process::Output {
+        move |call| {
+            if call.program == "clickhouse-client" {
+                MockRunner::output(0, status, "")
+            } else if call.has_args(&["list-objects-v2"]) {
+                MockRunner::output(0, r#"{"Contents":[{"Key":"clickhouse/events/2025-01-01.04-30/.backup","Size":10}]}"#, "")
+            } else {
+                MockRunner::output(0, "{}", "")
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn backup_goes_to_the_bucket_and_every_object_is_tagged() {
+        let runner = Arc::new(MockRunner::new(server("9f2c\tBACKUP_CREATED\n")));
+        let report = backup(&options(runner.clone())).await.unwrap();
+        assert_eq!((report.bytes, report.success), (10, true));
+
+        let calls = runner.calls();
+        let client = calls.iter().find(|call| call.program == "clickhouse-client").unwrap();
+        assert!(client.has_args(&["--host", "ch", "--port", "9440", "--user", "backup"]));
+        assert_eq!(client.env("CLICKHOUSE_PASSWORD"), Some("ch-pw"));
+        assert!(!client.args.iter().any(|arg| arg.contains("pw") || arg.contains("key")));
+        assert_eq!(
+            String::from_utf8(client.stdin.clone().unwrap()).unwrap(),
+            "BACKUP DATABASE `events` TO S3('http://minio:9000/bk/clickhouse/events/2025-01-01.04-30/', 'id', 'key')"
+        );
+        assert!(calls.iter().any(|call| call.has_args(&["put-object-tagging"]) && call.args.last().unwrap().ends_with("/.backup")));
+    }
+
+    #[tokio::test]
+    async fn failed_backup_is_removed_instead_of_tagged() {
+        let runner = Arc::new(MockRunner::new(server("9f2c\tBACKUP_FAILED\n")));
+        let err = backup(&options(runner.clone())).await.unwrap_err();
+        assert_eq!(Failure::of(&err).code(), "export");
+        let calls = runner.calls();
+        assert!(calls.iter().any(|call| call.has_args(&["s3", "rm", "s3://bk/clickhouse/events/2025-01-01.04-30", "--recursive"])));
+        assert!(!calls.iter().any(|call| call.has_args(&["put-object-tagging"])));
+    }
+
+    #[test]
+    fn urls_follow_the_endpoint_or_region() {
+        let access = |endpoint: Option<&str>, region: Option<&str>| S3Access {
+            endpoint: endpoint.map(String::from),
+            region: region.map(String::from),
+            ..S3Access::default()
+        };
+        assert_eq!(s3_url(&access(Some("https://s3.internal/"), None), "bk", "k"), "https://s3.internal/bk/k/");
+        assert_eq!(s3_url(&access(None, Some("eu-west-1")), "bk", "k"), "https://bk.s3.eu-west-1.amazonaws.com/k/");
+        assert_eq!(backup_statement("ev`il", "u", &None), "BACKUP DATABASE `ev\\`il` TO S3('u')");
+    }
+}
@@ -0,0 +1,200 @@
+use color_eyre::eyre::{eyre, Report, WrapErr};
+use tokio::process::Command;
+use tracing::info;
+
+use crate::failure::Failure;
+use crate::process::{self, before_deadline};
+use crate::s3::{self, S3Access};
+use crate::secret::Secret;
+use crate::summary::{BackupReport, EmptyBackup, Timings};
+use crate::tools::Tools;
+
+/// A CockroachDB cluster or database to back up with `BACKUP INTO`, and where to.
+pub struct CockroachOptions {
+    pub tools: Tools,
+    pub bucket_name: String,
+    pub s3_access: S3Access,
+    pub create_bucket: bool,
+    /// Connection URL, handed to cockroach as COCKROACH_URL; its own default when unset.
+    pub url: Option<Secret>,
+    /// Back up this database rather than the whole cluster.
+    pub database: Option<String>,
+    /// Tag set JSON applied to every object of the backup.
+    pub tags: String,
+    pub storage_key: String,
+    pub min_expected_bytes: u64,
+    pub allow_empty: bool,
+    pub concurrency: usize,
+    pub deadline: Option<tokio::time::Instant>,
+    pub timings: Timings,
+}
+
+/// Has the cluster write the backup straight to the bucket, then tags every object under the
+/// backup's key. As with ClickHouse, a failed or undersized backup is removed rather than left
+/// untagged.
+pub async fn backup(options: &CockroachOptions) -> Result<BackupReport, Report> {
+    let (tools, s3_access, bucket_name, key) = (&options.tools, &options.s3_access, &options.bucket_name, &options.storage_key);
+    let timings = &options.timings;
+    if options.create_bucket {
+        let created = timings.time("bucket_ensure", s3::ensure_bucket(tools, s3_access, bucket_name)).await.wrap_err(Failure::Upload)?;
+        info!(target: "aws_create_bucket_output", bucket = bucket_name, created);
+    }
+
+    let uri = collection_uri(s3_access, bucket_name, key).wrap_err(Failure::Config)?;
+    let statement = backup_statement(options.database.as_deref(), &uri);
+    let mut command = Command::new(&tools.cockroach);
+    command.kill_on_drop(true).arg("sql").arg("--format=csv");
+    if let Some(url) = &options.url {
+        // Read by cockroach, and kept out of process listings.
+        command.env("COCKROACH_URL", url.expose());
+    }
+    // The statement carries the S3 credentials, so it goes in on stdin.
+    let output = timings.time("export", before_deadline(options.deadline, "cockroach backup", tools.runner.run(command, Some(statement.into_bytes()))))
+        .await
+        .and_then(process::succeeded);
+    let stdout = output.as_ref().map(|output| String::from_utf8_lossy(&output.stdout).to_string()).unwrap_or_default();
+    info!(target: "cockroach_backup_output", success = output.is_ok(), stdout = process::captured("cockroach-backup", stdout.as_bytes()));
+    let succeeded = output.and_then(|_| match job_status(&stdout).as_deref() {
+        Some("succeeded") => Ok(()),
+        status => Err(eyre!("BACKUP finished as {}", status.unwrap_or("unknown"))),
+    });
+
+    let objects = timings.time("list", s3::list_objects(tools, s3_access, bucket_name, &format!("{}/", key))).await?;
+    let object_keys = objects.iter().map(|object| object.key.as_str()).collect::<Vec<_>>();
+    let bytes = objects.iter().map(|object| object.size).sum();
+    info!(target: "aws_list_objects_output", key, objects = objects.len(), bytes);
+    let failure = match succeeded {
+        Err(err) => Some(err.wrap_err(Failure::Export { stage: String::from("cockroach backup") })),
+        Ok(()) if objects.is_empty() && !options.allow_empty => Some(Report::new(EmptyBackup(format!("CockroachDB backup {}", key)))),
+        Ok(()) if bytes < options.min_expected_bytes => Some(
+            eyre!("CockroachDB backup {} is only {} bytes, below --min-expected-bytes {}", key, bytes, options.min_expected_bytes)
+                .wrap_err(Failure::Export { stage: String::from("size check") }),
+        ),
+        Ok(()) => None,
+    };
+    if let Some(failure) = failure {
+        if !objects.is_empty() {
+            let removed = s3::delete_prefix(tools, s3_access, bucket_name, key).await;
+            info!(target: "aws_remove_partial_backup_output", key, objects = objects.len(), success = removed.is_ok(), error = removed.err().map(|err| format!("{:#}", err)));
+        }
+        return Err(failure);
+    }
+
+    timings.time("tagging", before_deadline(options.deadline, "tagging", s3::tag_objects(tools, s3_access, bucket_name, &object_keys, &options.tags, options.concurrency)))
+        .await?
+        .wrap_err(Failure::Tagging)?;
+    Ok(BackupReport {
+        storage_key: key.clone(),
+        bytes,
+        raw_bytes: None,
+        success: true,
+        stderr_tail: None,
+        tikv_stats: None,
+        signal: None,
+    })
+}
+
+/// The backup collection as the cluster sees it, with the endpoint, region and credentials as
+/// query parameters. Without keys the nodes use their own credentials (`AUTH=implicit`).
+fn collection_uri(s3_access: &S3Access, bucket_name: &str, key: &str) -> Result<String, Report> {
+    let mut parameters = Vec::new();
+    match s3_access.server_credentials()? {
+        Some((id, secret)) => {
+            parameters.push(("AWS_ACCESS_KEY_ID", id.expose().to_string()));
+            parameters.push(("AWS_SECRET_ACCESS_KEY", secret.expose().to_string()));
+        }
+        None => parameters.push(("AUTH", String::from("implicit"))),
+    }
+    if let Some(endpoint) = &s3_access.endpoint {
+        parameters.push(("AWS_ENDPOINT", endpoint.clone()));
+    }
+    if let Some(region) = &s3_access.region {
+        parameters.push(("AWS_REGION", region.clone()));
+    }
+    let query = parameters.iter().map(|(name, value)| format!("{}={}", name, encode(value))).collect::<Vec<_>>().join("&");
+    Ok(format!("s3://{}/{}?{}", bucket_name, key, query))
+}
+
+/// Percent-encodes everything but the characters URLs leave unreserved.
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => char::from(byte).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn backup_statement(database: Option<&str>, uri: &str) -> String {
+    let target = match database {
+        Some(database) => format!("DATABASE \"{}\" ", database.replace('"', "\"\"")),
+        None => String::new(),
+    };
+    // Reading slightly in the past keeps the backup from contending with live transactions.
+    format!("BACKUP {}INTO '{}' AS OF SYSTEM TIME '-10s';", target, uri.replace('\'', "''"))
+}
+
+/// The `status` column of the job row `BACKUP` prints as CSV.
+fn job_status(stdout: &str) -> Option<String> {
+    let mut lines = stdout.lines().filter(|line| !line.trim().is_empty());
+    let column = lines.next()?.split(',').position(|name| name.trim() == "status")?;
+    lines.next()?.split(',').nth(column).map(|status| status.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::MockRunner;
+    use crate::s3::Credentials;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn backup_writes_into_the_key_and_every_object_is_tagged() {
+        let runner = Arc::new(MockRunner::new(|call| {
+            if call.program == "cockroach" {
+                MockRunner::output(0, "job_id,status,fraction_completed,rows,index_entries,bytes\n9001,succeeded,1,12,0,4096\n", "")
+            } else if call.has_args(&["list-objects-v2"]) {
+                MockRunner::output(0, r#"{"Contents":[{"Key":"cockroach/2025-01-01.04-30/2025/01/01-043000.00/BACKUP_MANIFEST","Size":4096}]}"#, "")
+            } else {
+                MockRunner::output(0, "{}", "")
+            }
+        }));
+        let options = CockroachOptions {
+            tools: Tools::mock(runner.clone()),
+            bucket_name: String::from("bk"),
+            s3_access: S3Access { credentials: Credentials::Static(Secret::new("id"), Secret::new("k+y/=")), ..S3Access::minio() },
+            create_bucket: false,
+            url: Some(Secret::new("postgresql://root@crdb:26257?sslmode=disable")),
+            database: Some(String::from("bank")),
+            tags: String::from(r#"{"TagSet":[]}"#),
+            storage_key: String::from("cockroach/2025-01-01.04-30"),
+            min_expected_bytes: 0,
+            allow_empty: false,
+            concurrency: 4,
+            deadline: None,
+            timings: Timings::default(),
+        };
+        let report = backup(&options).await.unwrap();
+        assert_eq!(report.bytes, 4096);
+
+        let calls = runner.calls();
+        assert!(calls[0].has_args(&["sql", "--format=csv"]));
+        assert_eq!(calls[0].env("COCKROACH_URL"), Some("postgresql://root@crdb:26257?sslmode=disable"));
+        assert_eq!(
+            String::from_utf8(calls[0].stdin.clone().unwrap()).unwrap(),
+            "BACKUP DATABASE \"bank\" INTO 's3://bk/cockroach/2025-01-01.04-30?AWS_ACCESS_KEY_ID=id&AWS_SECRET_ACCESS_KEY=k%2By%2F%3D&AWS_ENDPOINT=http%3A%2F%2Fminio%3A9000' AS OF SYSTEM TIME '-10s';"
+        );
+        assert!(calls[1].has_args(&["--prefix", "cockroach/2025-01-01.04-30/"]));
+        assert!(calls[2].has_args(&["put-object-tagging"]));
+    }
+
+    #[test]
+    fn instance_credentials_and_job_status() {
+        let uri = collection_uri(&S3Access { region: Some(String::from("eu-west-1")), ..S3Access::default() }, "bk", "k").unwrap();
+        assert_eq!(uri, "s3://bk/k?AUTH=implicit&AWS_REGION=eu-west-1");
+        assert_eq!(backup_statement(None, "s3://bk/o'k"), "BACKUP INTO 's3://bk/o''k' AS OF SYSTEM TIME '-10s';");
+        assert_eq!(job_status("job_id,status\n1,failed\n").as_deref(), Some("failed"));
+        assert_eq!(job_status(""), None);
+    }
+}
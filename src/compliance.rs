@@ -0,0 +1,215 @@
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::Report;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use tracing::info;
+
+use crate::cas;
+use crate::history;
+use crate::inventory;
+use crate::output::{OutputFormat, Rows};
+use crate::s3::{self, ObjectHead, S3Access};
+use crate::tags;
+use crate::tools::Tools;
+
+/// Which objects to report on and how to print the report.
+pub struct ComplianceOptions {
+    pub tools: Tools,
+    pub bucket_name: String,
+    pub s3_access: S3Access,
+    /// Only objects under this prefix; the whole bucket when empty.
+    pub prefix: String,
+    pub format: OutputFormat,
+    pub concurrency: usize,
+}
+
+/// What the objects of one tier show an auditor.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct TierReport {
+    pub objects: u64,
+    pub bytes: u64,
+    pub oldest: Option<DateTime<Utc>>,
+    pub newest: Option<DateTime<Utc>>,
+    /// Objects carrying both an Object Lock mode and a retain-until date.
+    pub locked: u64,
+    pub legal_hold: u64,
+    /// Lock modes seen, e.g. `COMPLIANCE`.
+    pub lock_modes: BTreeSet<String>,
+    pub encrypted: u64,
+    /// Server-side encryption seen, e.g. `AES256` or `aws:kms`.
+    pub encryption: BTreeSet<String>,
+    /// Objects whose tags name a tier and fit the S3 tagging limits.
+    pub correctly_tagged: u64,
+}
+
+/// The report over every tier, and why objects counted as incorrectly tagged.
+#[derive(Debug, Default)]
+pub struct ComplianceReport {
+    pub tiers: BTreeMap<&'static str, TierReport>,
+    /// Key and problem of each incorrectly tagged object.
+    pub findings: Vec<(String, String)>,
+}
+
+impl ComplianceReport {
+    fn add(&mut self, key: &str, size: u64, modified: Option<DateTime<Utc>>, tier: &'static str, head: &ObjectHead, tagging: Result<(), String>) {
+        let report = self.tiers.entry(tier).or_default();
+        report.objects += 1;
+        report.bytes += size;
+        if let Some(modified) = modified {
+            report.oldest = Some(report.oldest.map_or(modified, |oldest| oldest.min(modified)));
+            report.newest = Some(report.newest.map_or(modified, |newest| newest.max(modified)));
+        }
+        if let (Some(mode), Some(_)) = (&head.object_lock_mode, &head.object_lock_retain_until_date) {
+            report.locked += 1;
+            report.lock_modes.insert(mode.clone());
+        }
+        if head.object_lock_legal_hold_status.as_deref() == Some("ON") {
+            report.legal_hold += 1;
+        }
+        if let Some(encryption) = &head.server_side_encryption {
+            report.encrypted += 1;
+            report.encryption.insert(encryption.clone());
+        }
+        match tagging {
+            Ok(()) => report.correctly_tagged += 1,
+            Err(problem) => self.findings.push((key.to_string(), problem)),
+        }
+    }
+}
+
+/// Reads the tags and `head-object` headers of every backup under the prefix and prints, per
+/// tier, the object counts, dates, Object Lock and encryption coverage and tag correctness.
+pub async fn run(options: &ComplianceOptions) -> Result<ComplianceReport, Report> {
+    let objects = s3::list_objects(&options.tools, &options.s3_access, &options.bucket_name, &options.prefix).await?;
+    // Run records and the markers of running backups are not backups.
+    let objects = objects
+        .into_iter()
+        .filter(|object| !object.key.starts_with(history::PREFIX) && !object.key.starts_with(cas::PENDING_PREFIX))
+        .collect::<Vec<_>>();
+    let described = stream::iter(&objects)
+        .map(|object| async move {
+            let tags = s3::object_tags(&options.tools, &options.s3_access, &options.bucket_name, &object.key).await?;
+            let head = s3::head_object(&options.tools, &options.s3_access, &options.bucket_name, &object.key).await?;
+            Ok::<_, Report>((object, tags, head))
+        })
+        .buffer_unordered(options.concurrency.max(1))
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    let mut report = ComplianceReport::default();
+    for (object, tags, head) in described {
+        let tier = inventory::tier(&tags);
+        let tagging = match tier {
+            "untagged" => Err(String::from("carries no tier tag")),
+            _ => tags::validate(&tags).map_err(|err| err.to_string()),
+        };
+        let modified = DateTime::parse_from_rfc3339(&object.last_modified).ok().map(|modified| modified.with_timezone(&Utc));
+        report.add(&object.key, object.size, modified, tier, &head, tagging);
+    }
+    report.findings.sort();
+    info!(target: "compliance", bucket = options.bucket_name, prefix = options.prefix, objects = objects.len(), findings = report.findings.len());
+    println!("{}", render(&report, &options.bucket_name, options.format, Utc::now()));
+    Ok(report)
+}
+
+fn render(report: &ComplianceReport, bucket_name: &str, format: OutputFormat, now: DateTime<Utc>) -> String {
+    let date = |date: Option<DateTime<Utc>>| date.map(|date| date.to_rfc3339()).unwrap_or_default();
+    let list = |values: &BTreeSet<String>| values.iter().cloned().collect::<Vec<_>>();
+    match format {
+        OutputFormat::Json => {
+            let tiers = report
+                .tiers
+                .iter()
+                .map(|(tier, tier_report)| {
+                    (tier.to_string(), json!({
+                        "objects": tier_report.objects,
+                        "bytes": tier_report.bytes,
+                        "oldest": tier_report.oldest.map(|oldest| oldest.to_rfc3339()),
+                        "newest": tier_report.newest.map(|newest| newest.to_rfc3339()),
+                        "object_lock": {"locked": tier_report.locked, "unlocked": tier_report.objects - tier_report.locked, "legal_hold": tier_report.legal_hold, "modes": list(&tier_report.lock_modes)},
+                        "encryption": {"encrypted": tier_report.encrypted, "unencrypted": tier_report.objects - tier_report.encrypted, "algorithms": list(&tier_report.encryption)},
+                        "tags": {"correct": tier_report.correctly_tagged, "incorrect": tier_report.objects - tier_report.correctly_tagged},
+                    }))
+                })
+                .collect::<serde_json::Map<_, Value>>();
+            let findings = report.findings.iter().map(|(key, problem)| json!({"key": key, "problem": problem})).collect::<Vec<_>>();
+            json!({"bucket": bucket_name, "generated_at": now.to_rfc3339(), "tiers": tiers, "findings": findings}).to_string()
+        }
+        _ => {
+            let mut rows = Rows::new(&["tier", "objects", "bytes", "oldest", "newest", "locked", "unlocked", "legal_hold", "lock_modes", "encrypted", "unencrypted", "encryption", "tags_correct", "tags_incorrect"]);
+            for (tier, tier_report) in &report.tiers {
+                rows.push(vec![
+                    tier.to_string(),
+                    tier_report.objects.to_string(),
+                    tier_report.bytes.to_string(),
+                    date(tier_report.oldest),
+                    date(tier_report.newest),
+                    tier_report.locked.to_string(),
+                    (tier_report.objects - tier_report.locked).to_string(),
+                    tier_report.legal_hold.to_string(),
+                    list(&tier_report.lock_modes).join(";"),
+                    tier_report.encrypted.to_string(),
+                    (tier_report.objects - tier_report.encrypted).to_string(),
+                    list(&tier_report.encryption).join(";"),
+                    tier_report.correctly_tagged.to_string(),
+                    (tier_report.objects - tier_report.correctly_tagged).to_string(),
+                ]);
+            }
+            rows.render(format)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::MockRunner;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn tiers_report_locks_encryption_and_tagging() {
+        let listing = r#"{"Contents":[
+            {"Key":"surrealdb/a.zst","Size":10,"LastModified":"2025-01-01T04:40:00+00:00"},
+            {"Key":"surrealdb/b.zst","Size":20,"LastModified":"2025-01-31T04:40:00+00:00"},
+            {"Key":"surrealdb/c.zst","Size":5,"LastModified":"2025-02-01T04:40:00+00:00"},
+            {"Key":"_history/2025-02-01T04-30-00Z.surrealdb.json","Size":1,"LastModified":"2025-02-01T04:40:00+00:00"}
+        ]}"#;
+        let runner = Arc::new(MockRunner::new(move |call| {
+            if call.has_args(&["list-objects-v2"]) {
+                MockRunner::output(0, listing, "")
+            } else if call.has_args(&["get-object-tagging"]) && call.has_args(&["--key", "surrealdb/c.zst"]) {
+                MockRunner::output(0, r#"{"TagSet":[{"Key":"team","Value":"data"}]}"#, "")
+            } else if call.has_args(&["get-object-tagging"]) {
+                MockRunner::output(0, r#"{"TagSet":[{"Key":"standard","Value":"1"},{"Key":"monthly","Value":"1"}]}"#, "")
+            } else if call.has_args(&["head-object"]) && call.has_args(&["--key", "surrealdb/a.zst"]) {
+                MockRunner::output(0, r#"{"ObjectLockMode":"COMPLIANCE","ObjectLockRetainUntilDate":"2025-07-01T00:00:00+00:00","ServerSideEncryption":"aws:kms"}"#, "")
+            } else {
+                MockRunner::output(0, r#"{"ServerSideEncryption":"AES256","ObjectLockLegalHoldStatus":"ON"}"#, "")
+            }
+        }));
+        let options = ComplianceOptions {
+            tools: Tools::mock(runner.clone()),
+            bucket_name: String::from("bk"),
+            s3_access: S3Access::default(),
+            prefix: String::new(),
+            format: OutputFormat::Json,
+            concurrency: 2,
+        };
+        let report = run(&options).await.unwrap();
+        assert!(!runner.calls().iter().any(|call| call.has_args(&["--key", "_history/2025-02-01T04-30-00Z.surrealdb.json"])));
+        let monthly = &report.tiers["monthly"];
+        assert_eq!((monthly.objects, monthly.locked, monthly.legal_hold, monthly.encrypted, monthly.correctly_tagged), (2, 1, 1, 2, 2));
+        assert_eq!(monthly.oldest, Some("2025-01-01T04:40:00Z".parse().unwrap()));
+        assert_eq!(monthly.newest, Some("2025-01-31T04:40:00Z".parse().unwrap()));
+        assert_eq!(report.findings, [(String::from("surrealdb/c.zst"), String::from("carries no tier tag"))]);
+
+        let now = "2025-02-02T00:00:00Z".parse().unwrap();
+        let csv = render(&report, "bk", OutputFormat::Csv, now);
+        assert_eq!(csv.lines().nth(1), Some("monthly,2,30,2025-01-01T04:40:00+00:00,2025-01-31T04:40:00+00:00,1,1,1,COMPLIANCE,2,0,AES256;aws:kms,2,0"));
+        assert_eq!(csv.lines().nth(2), Some("untagged,1,5,2025-02-01T04:40:00+00:00,2025-02-01T04:40:00+00:00,0,1,1,,1,0,AES256,0,1"));
+        let json: Value = serde_json::from_str(&render(&report, "bk", OutputFormat::Json, now)).unwrap();
+        assert_eq!(json["tiers"]["untagged"]["tags"]["incorrect"], 1);
+        assert_eq!(json["findings"][0]["key"], "surrealdb/c.zst");
+    }
+}
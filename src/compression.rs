@@ -0,0 +1,131 @@
+use clap::ValueEnum;
+use color_eyre::eyre::{eyre, Report};
+use tokio::process::Command;
+
+use crate::tools::Tools;
+
+/// Compression applied to streamed exports before upload.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Zstd,
+    Gzip,
+    Lz4,
+    Xz,
+    /// Upload the export as-is.
+    None,
+}
+
+impl Compression {
+    /// Suffix appended to the storage key, so the format can be recognised from the key alone.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::Zstd => ".zst",
+            Compression::Gzip => ".gz",
+            Compression::Lz4 => ".lz4",
+            Compression::Xz => ".xz",
+            Compression::None => "",
+        }
+    }
+
+    /// `key` with the extension appended, unless a key template already ends with it.
+    pub fn with_extension(self, key: String) -> String {
+        if key.ends_with(self.extension()) {
+            key
+        } else {
+            key + self.extension()
+        }
+    }
+
+    /// The compression a storage key was written with, judged by its extension.
+    pub fn from_key(key: &str) -> Compression {
+        [Compression::Zstd, Compression::Gzip, Compression::Lz4, Compression::Xz]
+            .into_iter()
+            .find(|compression| key.ends_with(compression.extension()))
+            .unwrap_or(Compression::None)
+    }
+
+    fn levels(self) -> Option<std::ops::RangeInclusive<u32>> {
+        match self {
+            Compression::Zstd => Some(1..=22),
+            Compression::Gzip => Some(1..=9),
+            Compression::Lz4 => Some(1..=12),
+            Compression::Xz => Some(0..=9),
+            Compression::None => None,
+        }
+    }
+
+    /// Rejects levels the selected tool would not accept.
+    pub fn validate_level(self, level: Option<u32>) -> Result<(), Report> {
+        match (level, self.levels()) {
+            (None, _) => Ok(()),
+            (Some(_), None) => Err(eyre!("--compression-level cannot be used with --compression none")),
+            (Some(level), Some(range)) if !range.contains(&level) => Err(eyre!(
+                "Compression level {} is out of range for {:?} ({}..={})",
+                level,
+                self,
+                range.start(),
+                range.end()
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Filter command reading the export on stdin and writing compressed bytes to stdout,
+    /// or `None` when the export is uploaded uncompressed.
+    pub fn command(self, tools: &Tools, level: Option<u32>) -> Option<Command> {
+        let mut command = match self {
+            Compression::Zstd => {
+                let mut command = Command::new(&tools.zstd);
+                command.arg("--force").arg("--stdout");
+                match level {
+                    Some(level) if level > 19 => {
+                        command.arg("--ultra").arg(format!("-{}", level));
+                    }
+                    Some(level) => {
+                        command.arg(format!("-{}", level));
+                    }
+                    None => {
+                        command.arg("--adapt");
+                    }
+                }
+                command.arg("--rm").arg("-");
+                return Some(command);
+            }
+            Compression::Gzip => {
+                let mut command = Command::new(&tools.gzip);
+                command.arg("--stdout");
+                command
+            }
+            Compression::Lz4 => {
+                let mut command = Command::new(&tools.lz4);
+                command.arg("-z").arg("-c");
+                command
+            }
+            Compression::Xz => {
+                let mut command = Command::new(&tools.xz);
+                command.arg("--stdout");
+                command
+            }
+            Compression::None => return None,
+        };
+        if let Some(level) = level {
+            command.arg(format!("-{}", level));
+        }
+        command.arg("-");
+        Some(command)
+    }
+
+    /// Filter command reading compressed bytes on stdin and writing the export to stdout,
+    /// or `None` when the object is stored uncompressed.
+    pub fn decompress_command(self, tools: &Tools) -> Option<Command> {
+        let mut command = match self {
+            Compression::Zstd => Command::new(&tools.zstd),
+            Compression::Gzip => Command::new(&tools.gzip),
+            Compression::Lz4 => Command::new(&tools.lz4),
+            Compression::Xz => Command::new(&tools.xz),
+            Compression::None => return None,
+        };
+        command.arg("-d").arg("-c").arg("-");
+        Some(command)
+    }
+}
@@ -0,0 +1,271 @@
+use color_eyre::eyre::{eyre, Report, WrapErr};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::cron::Cron;
+use crate::tags::{Rule, Tag};
+
+/// Config file used when `--config` is not given.
+pub const DEFAULT_PATH: &str = "btagger.toml";
+
+/// Settings keyed by the long flag name in snake case, e.g. `bucket_name` or `every_n_hours`.
+pub type Profile = BTreeMap<String, toml::Value>;
+
+/// Contents of the TOML config file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Named sets of flag values, e.g. `[profiles.prod-minio]`.
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+    /// Backups performed by `run-all`, e.g. `[[jobs]]`.
+    #[serde(default)]
+    pub jobs: Vec<Job>,
+    /// Extra tag rules, e.g. `[[rules]]`, checked alongside the period tags.
+    #[serde(default)]
+    pub rules: Vec<TagRule>,
+}
+
+/// A tag for the backups triggered by `cron`, or for the first or last of them in each period
+/// starting at `period_start`, e.g. the last Friday of the month with `cron = "30 4 * * 5L"`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TagRule {
+    /// `key=value`, or a bare `key` meaning `key=1`.
+    pub tag: String,
+    pub cron: Cron,
+    pub period_start: Option<Cron>,
+}
+
+/// One backup for `run-all`: the subcommand to run and its flag values.
+#[derive(Debug, Deserialize)]
+pub struct Job {
+    /// Shown in the combined summary; defaults to the command and the job's position.
+    pub name: Option<String>,
+    /// `surrealdb`, `tikv`, `clickhouse`, `cassandra`, `elasticsearch`, `influxdb`, `neo4j`, `cockroach`, `sqlite`, `qdrant` or `nats`.
+    pub command: String,
+    #[serde(flatten)]
+    pub settings: Profile,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, Report> {
+        let text = std::fs::read_to_string(path).wrap_err_with(|| format!("Unable to read config file {}", path.display()))?;
+        let config: Config = toml::from_str(&text).wrap_err_with(|| format!("Unable to parse config file {}", path.display()))?;
+        for rule in &config.rules {
+            rule.tag.parse::<Tag>().map_err(|err| eyre!("{} in config file {}", err, path.display()))?;
+        }
+        Ok(config)
+    }
+
+    /// The `[[rules]]`, for [`crate::tags::evaluate_rules`].
+    pub fn rules(&self) -> Result<Vec<Rule>, Report> {
+        self.rules
+            .iter()
+            .map(|rule| {
+                Ok(Rule {
+                    tag: rule.tag.parse().map_err(|err: String| eyre!(err))?,
+                    cron: rule.cron.clone(),
+                    period_start: rule.period_start.clone(),
+                })
+            })
+            .collect()
+    }
+
+    pub fn profile(&self, name: &str) -> Result<&Profile, Report> {
+        self.profiles.get(name).ok_or_else(|| {
+            eyre!(
+                "No profile named '{}'; the config file defines: {}",
+                name,
+                self.profiles.keys().cloned().collect::<Vec<_>>().join(", ")
+            )
+        })
+    }
+}
+
+/// Makes every setting in `profile` the default of the flag with the same name, on the top-level
+/// command and on each subcommand that has it, so flags given on the command line still win.
+pub fn apply_profile(mut command: clap::Command, profile: &Profile) -> Result<clap::Command, Report> {
+    for (name, value) in profile {
+        let values = match value {
+            toml::Value::Array(values) => values.iter().map(flag_value).collect::<Result<Vec<_>, _>>(),
+            value => flag_value(value).map(|value| vec![value]),
+        }
+        .wrap_err_with(|| format!("Unsupported value for profile setting '{}'", name))?;
+
+        let mut known = false;
+        if command.get_arguments().any(|arg| arg.get_id() == name.as_str()) {
+            known = true;
+            command = command.mut_arg(name, |arg| with_default(arg, &values));
+        }
+        let subcommands = command
+            .get_subcommands()
+            .filter(|subcommand| subcommand.get_arguments().any(|arg| arg.get_id() == name.as_str()))
+            .map(|subcommand| subcommand.get_name().to_string())
+            .collect::<Vec<_>>();
+        for subcommand in subcommands {
+            known = true;
+            command = command.mut_subcommand(subcommand, |subcommand| subcommand.mut_arg(name, |arg| with_default(arg, &values)));
+        }
+        if !known {
+            return Err(eyre!("Unknown profile setting '{}'", name));
+        }
+    }
+    Ok(command)
+}
+
+/// Parses a job as if its subcommand had been given on the command line with no flags, so flag
+/// values come from the job and then from whatever profile was applied to `root`. The returned
+/// matches hold the subcommand.
+pub fn job_matches(root: &clap::Command, job: &Job) -> Result<clap::ArgMatches, Report> {
+    let subcommand = root
+        .find_subcommand(&job.command)
+        .cloned()
+        .ok_or_else(|| eyre!("Unknown job command '{}'", job.command))?;
+    let subcommand = apply_profile(subcommand, &job.settings)?;
+    clap::Command::new(root.get_name().to_string())
+        .subcommand(subcommand)
+        .try_get_matches_from([root.get_name(), job.command.as_str()])
+        .map_err(|err| eyre!("{}", err.render()))
+}
+
+fn with_default(arg: clap::Arg, values: &[String]) -> clap::Arg {
+    // A required flag is satisfied by its profile value.
+    arg.required(false).default_values(values.iter().cloned())
+}
+
+fn flag_value(value: &toml::Value) -> Result<String, Report> {
+    match value {
+        toml::Value::String(value) => Ok(value.clone()),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Float(value) => Ok(value.to_string()),
+        toml::Value::Boolean(value) => Ok(value.to_string()),
+        toml::Value::Datetime(value) => Ok(value.to_string()),
+        _ => Err(eyre!("expected a string, number, boolean or an array of them")),
+    }
+}
+
+/// Value of a `--{name} value` or `--{name}=value` flag, read before the full command line is
+/// parsed because it changes how the rest is parsed.
+pub fn early_flag(name: &str) -> Option<String> {
+    flag_in(std::env::args().skip(1), name)
+}
+
+fn flag_in(args: impl IntoIterator<Item = String>, name: &str) -> Option<String> {
+    let flag = format!("--{}", name);
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        }
+        if arg == flag {
+            return args.next();
+        }
+        if let Some(value) = arg.strip_prefix(&flag).and_then(|rest| rest.strip_prefix('=')) {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{Arg, ArgAction, Command};
+
+    fn command() -> Command {
+        Command::new("btagger")
+            .arg(Arg::new("cluster").long("cluster"))
+            .subcommand(
+                Command::new("tikv")
+                    .arg(Arg::new("bucket_name").long("bucket-name").required(true))
+                    .arg(Arg::new("pd_host_and_port").long("pd-host-and-port").required(true)),
+            )
+            .subcommand(
+                Command::new("surrealdb")
+                    .arg(Arg::new("bucket_name").long("bucket-name").required(true))
+                    .arg(Arg::new("cas").long("cas").action(ArgAction::SetTrue)),
+            )
+    }
+
+    fn profile(text: &str) -> Profile {
+        toml::from_str(text).unwrap()
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn profile_settings_become_flag_defaults_on_every_command_that_has_them() {
+        let command = apply_profile(command(), &profile("cluster = 'eu'\nbucket_name = 'bk'\ncas = true")).unwrap();
+        let matches = command.clone().try_get_matches_from(["btagger", "surrealdb"]).unwrap();
+        assert_eq!(matches.get_one::<String>("cluster").map(String::as_str), Some("eu"));
+        let (_, surrealdb) = matches.subcommand().unwrap();
+        assert_eq!(surrealdb.get_one::<String>("bucket_name").map(String::as_str), Some("bk"));
+        assert!(surrealdb.get_flag("cas"));
+        // The command line still wins.
+        let matches = command.try_get_matches_from(["btagger", "--cluster", "us", "surrealdb", "--bucket-name", "other"]).unwrap();
+        assert_eq!(matches.get_one::<String>("cluster").map(String::as_str), Some("us"));
+        assert_eq!(matches.subcommand().unwrap().1.get_one::<String>("bucket_name").map(String::as_str), Some("other"));
+    }
+
+    #[test]
+    fn required_flags_are_satisfied_by_a_profile() {
+        assert!(command().try_get_matches_from(["btagger", "tikv"]).is_err());
+        let command = apply_profile(command(), &profile("bucket_name = 'bk'\npd_host_and_port = 'pd:2379'")).unwrap();
+        let matches = command.try_get_matches_from(["btagger", "tikv"]).unwrap();
+        assert_eq!(matches.subcommand().unwrap().1.get_one::<String>("pd_host_and_port").map(String::as_str), Some("pd:2379"));
+    }
+
+    #[test]
+    fn unknown_and_unsupported_profile_settings_are_rejected() {
+        let err = apply_profile(command(), &profile("bucket = 'bk'")).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown profile setting 'bucket'");
+        let err = apply_profile(command(), &profile("[bucket_name]\nname = 'bk'")).unwrap_err();
+        assert_eq!(err.to_string(), "Unsupported value for profile setting 'bucket_name'");
+    }
+
+    #[test]
+    fn jobs_take_their_settings_over_the_profile() {
+        let root = apply_profile(command(), &profile("bucket_name = 'bk'\npd_host_and_port = 'pd:2379'")).unwrap();
+        let job: Job = toml::from_str("command = 'tikv'\npd_host_and_port = 'pd-b:2379'").unwrap();
+        let matches = job_matches(&root, &job).unwrap();
+        let (name, tikv) = matches.subcommand().unwrap();
+        assert_eq!(name, "tikv");
+        assert_eq!(tikv.get_one::<String>("bucket_name").map(String::as_str), Some("bk"));
+        assert_eq!(tikv.get_one::<String>("pd_host_and_port").map(String::as_str), Some("pd-b:2379"));
+    }
+
+    #[test]
+    fn invalid_jobs_are_rejected() {
+        let job = |text: &str| toml::from_str::<Job>(text).unwrap();
+        let err = job_matches(&command(), &job("command = 'mongodb'")).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown job command 'mongodb'");
+        // Only the job's own command's flags apply.
+        let err = job_matches(&command(), &job("command = 'tikv'\ncas = true")).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown profile setting 'cas'");
+        // A required flag with no value anywhere.
+        assert!(job_matches(&command(), &job("command = 'tikv'\nbucket_name = 'bk'")).is_err());
+    }
+
+    #[test]
+    fn flag_values_are_rendered_as_on_the_command_line() {
+        assert_eq!(flag_value(&toml::Value::String(String::from("bk"))).unwrap(), "bk");
+        assert_eq!(flag_value(&toml::Value::Integer(8)).unwrap(), "8");
+        assert_eq!(flag_value(&toml::Value::Float(0.5)).unwrap(), "0.5");
+        assert_eq!(flag_value(&toml::Value::Boolean(true)).unwrap(), "true");
+        assert!(flag_value(&toml::Value::Array(Vec::new())).is_err());
+        assert!(flag_value(&toml::Value::Table(toml::Table::new())).is_err());
+    }
+
+    #[test]
+    fn early_flags_are_found_in_either_form_before_a_double_dash() {
+        assert_eq!(flag_in(args(&["--profile", "prod", "tikv"]), "profile").as_deref(), Some("prod"));
+        assert_eq!(flag_in(args(&["tikv", "--profile=prod"]), "profile").as_deref(), Some("prod"));
+        assert_eq!(flag_in(args(&["--profile"]), "profile"), None);
+        assert_eq!(flag_in(args(&["--profiles=prod", "--config", "b.toml"]), "profile"), None);
+        assert_eq!(flag_in(args(&["--", "--profile", "prod"]), "profile"), None);
+    }
+}
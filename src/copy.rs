@@ -0,0 +1,70 @@
+use color_eyre::eyre::{eyre, Report, WrapErr};
+use tracing::info;
+
+use crate::cas;
+use crate::multipart;
+use crate::s3::{self, S3Access};
+use crate::tags::{Tag, TagSet};
+use crate::tools::Tools;
+
+/// Which backup to copy and where to; `key` and `latest` are mutually exclusive.
+pub struct CopyOptions {
+    pub tools: Tools,
+    pub s3_access: S3Access,
+    pub from_bucket: String,
+    pub to_bucket: String,
+    pub key: Option<String>,
+    /// Pick the newest object under `prefix` carrying all of `tags` instead of a fixed key.
+    pub latest: bool,
+    pub prefix: String,
+    pub tags: Vec<Tag>,
+}
+
+/// Server-side copies one backup to the destination bucket under the same key and re-applies its
+/// tags there, together with a split backup's numbered objects or the chunks a CAS index
+/// references that the destination lacks. Returns the copied key.
+pub async fn run(options: &CopyOptions) -> Result<String, Report> {
+    let s3_access = &options.s3_access;
+    let (key, tags) = match (&options.key, options.latest) {
+        (Some(key), false) => (
+            key.clone(),
+            s3::object_tags(&options.tools, s3_access, &options.from_bucket, key).await?,
+        ),
+        (None, true) => {
+            let mut objects = multipart::without_split_parts(s3::list_objects(&options.tools, s3_access, &options.from_bucket, &options.prefix).await?);
+            // RFC 3339 timestamps from S3 share a format, so they sort as strings.
+            objects.sort_by(|a, b| b.last_modified.cmp(&a.last_modified));
+            let mut latest = None;
+            for object in objects {
+                let tags = s3::object_tags(&options.tools, s3_access, &options.from_bucket, &object.key).await?;
+                if options.tags.iter().all(|tag| tags.contains(tag)) {
+                    latest = Some((object.key, tags));
+                    break;
+                }
+            }
+            latest.ok_or_else(|| eyre!("No backup under '{}' in {} matches the requested tags", options.prefix, options.from_bucket))?
+        }
+        _ => return Err(eyre!("Pass exactly one of --key or --latest")),
+    };
+
+    // Large objects are copied in parts, which drops the tags, so they are always set again. A
+    // split backup's numbered objects carry the manifest's tags.
+    let tagging = serde_json::to_string(&TagSet { tag_set: tags })?;
+    // An index is only of use next to its chunks, which go first.
+    let cas = cas::is_index(&key);
+    if cas {
+        cas::copy_chunks(&options.tools, s3_access, &options.from_bucket, &options.to_bucket, &key).await?;
+    }
+    for object in multipart::backup_keys(&options.tools, s3_access, &options.from_bucket, &key).await? {
+        s3::copy_object(&options.tools, s3_access, &options.from_bucket, &options.to_bucket, &object).await?;
+        s3::put_object_tagging(&options.tools, s3_access, &options.to_bucket, &object, &tagging)
+            .await
+            .wrap_err_with(|| format!("Tagging the copy of {} failed", object))?;
+        info!(target: "backup_copy", from_bucket = options.from_bucket, to_bucket = options.to_bucket, key = object, tagging);
+        println!("[COPIED] s3://{}/{} -> s3://{}/{}", options.from_bucket, object, options.to_bucket, object);
+    }
+    if cas {
+        cas::remove_pending(&options.tools, s3_access, &options.to_bucket, &key).await;
+    }
+    Ok(key)
+}
@@ -0,0 +1,239 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Timelike, Utc};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+
+/// How far [`Cron::next_after`] and [`Cron::previous_before`] look before giving up, enough for
+/// a rule that only fires on February 29th.
+const SEARCH_DAYS: i64 = 5 * 366;
+
+const MONTHS: [&str; 12] = ["JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC"];
+const WEEKDAYS: [&str; 7] = ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"];
+
+/// A five-field cron expression: minute, hour, day of month, month and day of week, in UTC.
+///
+/// Fields take lists (`1,15`), ranges (`1-5`), steps (`*/15`, `0-30/10`) and month or weekday
+/// names (`JAN`, `FRI`). The day of month also takes `L`, the last day of the month, and the day
+/// of week `5L`, the last Friday of the month, and `5#3`, its third Friday. Sunday is `0` or `7`.
+/// As in Vixie cron, a date matches either day field when both are restricted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cron {
+    source: String,
+    minutes: BTreeSet<u32>,
+    hours: BTreeSet<u32>,
+    days: Option<Days>,
+    months: BTreeSet<u32>,
+    weekdays: Option<Weekdays>,
+}
+
+/// A restricted day of month field.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Days {
+    days: BTreeSet<u32>,
+    last: bool,
+}
+
+/// A restricted day of week field, with Sunday as 0.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Weekdays {
+    weekdays: BTreeSet<u32>,
+    /// `5L`: the last such weekday of the month.
+    last: BTreeSet<u32>,
+    /// `5#3`: the weekday and which of the month it is.
+    nth: BTreeSet<(u32, u32)>,
+}
+
+impl FromStr for Cron {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = s.split_whitespace().collect::<Vec<_>>();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!("'{}' needs 5 fields: minute, hour, day of month, month and day of week", s));
+        };
+        let context = |field: &str, err: String| format!("'{}': {} field: {}", s, field, err);
+        let days = match day {
+            "*" | "?" => None,
+            _ => Some(parse_days(day).map_err(|err| context("day of month", err))?),
+        };
+        let weekdays = match weekday {
+            "*" | "?" => None,
+            _ => Some(parse_weekdays(weekday).map_err(|err| context("day of week", err))?),
+        };
+        Ok(Cron {
+            source: fields.join(" "),
+            minutes: parse_field(minute, 0, 59, &[]).map_err(|err| context("minute", err))?,
+            hours: parse_field(hour, 0, 23, &[]).map_err(|err| context("hour", err))?,
+            days,
+            months: parse_field(month, 1, 12, &MONTHS).map_err(|err| context("month", err))?,
+            weekdays,
+        })
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Cron {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl fmt::Display for Cron {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Cron {
+    /// The first time the expression matches after the minute of `after`.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let after = after.with_second(0)?.with_nanosecond(0)?;
+        let start = after.date_naive();
+        (0..SEARCH_DAYS).filter_map(|offset| start.checked_add_signed(Duration::days(offset))).filter(|date| self.matches(*date)).find_map(|date| {
+            self.hours
+                .iter()
+                .flat_map(|hour| self.minutes.iter().map(move |minute| (*hour, *minute)))
+                .filter_map(|(hour, minute)| Some(date.and_hms_opt(hour, minute, 0)?.and_utc()))
+                .find(|time| *time > after)
+        })
+    }
+
+    /// The last time the expression matches before `before`.
+    pub fn previous_before(&self, before: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let start = before.date_naive();
+        (0..SEARCH_DAYS).filter_map(|offset| start.checked_sub_signed(Duration::days(offset))).filter(|date| self.matches(*date)).find_map(|date| {
+            self.hours
+                .iter()
+                .rev()
+                .flat_map(|hour| self.minutes.iter().rev().map(move |minute| (*hour, *minute)))
+                .filter_map(|(hour, minute)| Some(date.and_hms_opt(hour, minute, 0)?.and_utc()))
+                .find(|time| *time < before)
+        })
+    }
+
+    /// Whether the expression fires at some time on `date`.
+    fn matches(&self, date: NaiveDate) -> bool {
+        if !self.months.contains(&date.month()) {
+            return false;
+        }
+        let last_day = last_day_of_month(date);
+        let day = self.days.as_ref().map(|days| days.days.contains(&date.day()) || (days.last && date.day() == last_day));
+        let weekday = self.weekdays.as_ref().map(|weekdays| {
+            let weekday = date.weekday().num_days_from_sunday();
+            weekdays.weekdays.contains(&weekday)
+                || (weekdays.last.contains(&weekday) && date.day() + 7 > last_day)
+                || weekdays.nth.contains(&(weekday, date.day().div_ceil(7)))
+        });
+        match (day, weekday) {
+            (Some(day), Some(weekday)) => day || weekday,
+            (Some(matched), None) | (None, Some(matched)) => matched,
+            (None, None) => true,
+        }
+    }
+}
+
+fn last_day_of_month(date: NaiveDate) -> u32 {
+    let (year, month) = if date.month() == 12 { (date.year() + 1, 1) } else { (date.year(), date.month() + 1) };
+    NaiveDate::from_ymd_opt(year, month, 1).and_then(|first| first.pred_opt()).map_or(31, |last| last.day())
+}
+
+/// Parses a list of values, ranges and steps between `min` and `max`, where `names` stand for
+/// the values from `min` on.
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<BTreeSet<u32>, String> {
+    let mut values = BTreeSet::new();
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|step| *step > 0).ok_or_else(|| format!("'{}' is not a valid step", step))?),
+            None => (part, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (value(start, min, max, names)?, value(end, min, max, names)?),
+            // `5/15` runs from 5 to the end.
+            None if step > 1 => (value(range, min, max, names)?, max),
+            None => {
+                let value = value(range, min, max, names)?;
+                (value, value)
+            }
+        };
+        if start > end {
+            return Err(format!("'{}' runs backwards", range));
+        }
+        values.extend((start..=end).step_by(step as usize));
+    }
+    Ok(values)
+}
+
+fn value(text: &str, min: u32, max: u32, names: &[&str]) -> Result<u32, String> {
+    let upper = text.to_ascii_uppercase();
+    let value = match names.iter().position(|name| *name == upper) {
+        Some(position) => position as u32 + min,
+        None => text.parse().map_err(|_| format!("'{}' is not a number{}", text, if names.is_empty() { "" } else { " or name" }))?,
+    };
+    match (min..=max).contains(&value) {
+        true => Ok(value),
+        false => Err(format!("{} is outside {}-{}", value, min, max)),
+    }
+}
+
+fn parse_days(field: &str) -> Result<Days, String> {
+    let mut days = Days::default();
+    for part in field.split(',') {
+        match part.eq_ignore_ascii_case("L") {
+            true => days.last = true,
+            false => days.days.extend(parse_field(part, 1, 31, &[])?),
+        }
+    }
+    Ok(days)
+}
+
+fn parse_weekdays(field: &str) -> Result<Weekdays, String> {
+    let mut weekdays = Weekdays::default();
+    // 7 is Sunday too.
+    let weekday = |text: &str| value(text, 0, 7, &WEEKDAYS).map(|weekday| weekday % 7);
+    for part in field.split(',') {
+        if let Some(day) = part.strip_suffix(['L', 'l']).filter(|day| !day.is_empty()) {
+            weekdays.last.insert(weekday(day)?);
+        } else if let Some((day, nth)) = part.split_once('#') {
+            let nth = nth.parse().ok().filter(|nth| (1..=5).contains(nth)).ok_or_else(|| format!("'{}' is not a week of the month, 1-5", nth))?;
+            weekdays.nth.insert((weekday(day)?, nth));
+        } else {
+            weekdays.weekdays.extend(parse_field(part, 0, 7, &WEEKDAYS)?.into_iter().map(|weekday| weekday % 7));
+        }
+    }
+    Ok(weekdays)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> DateTime<Utc> {
+        time.parse().unwrap()
+    }
+
+    #[test]
+    fn last_and_nth_weekdays_of_the_month() {
+        let last_friday = "30 4 * * 5L".parse::<Cron>().unwrap();
+        assert_eq!(last_friday.next_after(at("2025-01-01T00:00:00Z")), Some(at("2025-01-31T04:30:00Z")));
+        assert_eq!(last_friday.next_after(at("2025-01-31T04:30:00Z")), Some(at("2025-02-28T04:30:00Z")));
+        assert_eq!(last_friday.previous_before(at("2025-01-31T04:30:00Z")), Some(at("2024-12-27T04:30:00Z")));
+        let third_monday = "0 2 * JAN-MAR MON#3".parse::<Cron>().unwrap();
+        assert_eq!(third_monday.next_after(at("2025-02-01T00:00:00Z")), Some(at("2025-02-17T02:00:00Z")));
+        let last_day = "0 0 L */3 *".parse::<Cron>().unwrap();
+        assert_eq!(last_day.next_after(at("2024-02-01T00:00:00Z")), Some(at("2024-04-30T00:00:00Z")));
+        // Either day field, when both are restricted.
+        let first_or_sunday = "0 12 1 * 0".parse::<Cron>().unwrap();
+        assert_eq!(first_or_sunday.next_after(at("2025-03-01T12:00:00Z")), Some(at("2025-03-02T12:00:00Z")));
+        assert_eq!("*/20 * * * *".parse::<Cron>().unwrap().next_after(at("2025-03-01T12:40:30Z")), Some(at("2025-03-01T13:00:00Z")));
+    }
+
+    #[test]
+    fn malformed_expressions_are_refused_with_the_field_at_fault() {
+        assert_eq!("30 4 * *".parse::<Cron>().unwrap_err(), "'30 4 * *' needs 5 fields: minute, hour, day of month, month and day of week");
+        assert_eq!("30 24 * * *".parse::<Cron>().unwrap_err(), "'30 24 * * *': hour field: 24 is outside 0-23");
+        assert_eq!("30 4 * * 5#6".parse::<Cron>().unwrap_err(), "'30 4 * * 5#6': day of week field: '6' is not a week of the month, 1-5");
+        assert_eq!("30 4 * FOO *".parse::<Cron>().unwrap_err(), "'30 4 * FOO *': month field: 'FOO' is not a number or name");
+        assert!("30 4 10-2 * *".parse::<Cron>().is_err());
+        assert!("*/0 4 * * *".parse::<Cron>().is_err());
+    }
+}
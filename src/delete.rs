@@ -0,0 +1,90 @@
+use chrono::{DateTime, Duration, Utc};
+use color_eyre::eyre::{eyre, Report};
+use tracing::info;
+
+use crate::multipart;
+use crate::s3::{self, S3Access};
+use crate::tags::Tag;
+use crate::tools::Tools;
+
+/// Which backups to remove; an object must match every filter that is set.
+pub struct DeleteOptions {
+    pub tools: Tools,
+    pub bucket_name: String,
+    pub s3_access: S3Access,
+    /// Exact keys; when empty, everything under `prefix` is considered.
+    pub keys: Vec<String>,
+    pub prefix: String,
+    pub older_than: Option<Duration>,
+    pub tags: Vec<Tag>,
+    /// Without it the matching objects are only listed.
+    pub yes: bool,
+}
+
+/// Prints the backups matching the filters and, when confirmed, deletes them one by one with an
+/// audit log entry each. Returns how many objects matched.
+pub async fn run(options: &DeleteOptions, now: DateTime<Utc>) -> Result<usize, Report> {
+    if options.keys.is_empty() && options.older_than.is_none() && options.tags.is_empty() {
+        return Err(eyre!("Refusing to delete without --key, --older-than or --tag"));
+    }
+
+    let listing_prefixes = if options.keys.is_empty() {
+        vec![options.prefix.as_str()]
+    } else {
+        options.keys.iter().map(String::as_str).collect()
+    };
+    let mut candidates = Vec::new();
+    for listing_prefix in listing_prefixes {
+        let listing = s3::list_objects(&options.tools, &options.s3_access, &options.bucket_name, listing_prefix).await?;
+        candidates.extend(
+            listing
+                .into_iter()
+                .filter(|object| options.keys.is_empty() || options.keys.contains(&object.key)),
+        );
+    }
+    // A key is also a prefix of any longer key, so listings for several keys can overlap. A split
+    // backup goes with its manifest, which takes its numbered objects along.
+    candidates.sort_by(|a, b| a.key.cmp(&b.key));
+    candidates.dedup_by(|a, b| a.key == b.key);
+    let candidates = multipart::without_split_parts(candidates);
+    for key in &options.keys {
+        if !candidates.iter().any(|object| &object.key == key) {
+            println!("[MISSING] {}", key);
+        }
+    }
+
+    let mut matched = 0;
+    for object in candidates {
+        let modified = DateTime::parse_from_rfc3339(&object.last_modified).map(|modified| modified.with_timezone(&Utc));
+        if let Some(older_than) = options.older_than {
+            match modified {
+                Ok(modified) if now - modified >= older_than => {}
+                _ => continue,
+            }
+        }
+        if !options.tags.is_empty() {
+            let tags = s3::object_tags(&options.tools, &options.s3_access, &options.bucket_name, &object.key).await?;
+            if !options.tags.iter().all(|tag| tags.contains(tag)) {
+                continue;
+            }
+        }
+        matched += 1;
+
+        if !options.yes {
+            println!("[WOULD DELETE] {} ({} bytes, modified {})", object.key, object.size, object.last_modified);
+            continue;
+        }
+        // The numbered objects go first, so a failure leaves the manifest to retry with.
+        let mut keys = multipart::backup_keys(&options.tools, &options.s3_access, &options.bucket_name, &object.key).await?;
+        keys.rotate_left(1);
+        for key in keys {
+            s3::delete_object(&options.tools, &options.s3_access, &options.bucket_name, &key).await?;
+            info!(target: "audit", action = "delete", bucket = options.bucket_name, key, backup = object.key, size = object.size, last_modified = object.last_modified, operator = std::env::var("USER").unwrap_or_default());
+            println!("[DELETED] {}", key);
+        }
+    }
+    if !options.yes && matched > 0 {
+        println!("{} object(s) match; rerun with --yes to delete them", matched);
+    }
+    Ok(matched)
+}
@@ -0,0 +1,169 @@
+use color_eyre::eyre::{eyre, ContextCompat, Report, WrapErr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::process::Command;
+use tracing::info;
+
+use crate::keys::{KeyTemplate, KeyVars};
+use crate::process;
+use crate::s3::{self, S3Access};
+use crate::size::ByteSize;
+use crate::tools::Tools;
+use crate::verify;
+
+/// Where trained dictionaries are kept, by backup prefix and dictionary ID. They carry no tags, so
+/// lifecycle rules never expire one that older backups still need.
+pub const DICTIONARY_PREFIX: &str = "_dictionaries/";
+
+const FRAME_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const DICTIONARY_MAGIC: [u8; 4] = [0x37, 0xa4, 0x30, 0xec];
+
+/// Which SurrealDB backups to train a dictionary on.
+pub struct TrainOptions {
+    pub tools: Tools,
+    pub bucket_name: String,
+    pub s3_access: S3Access,
+    pub namespace: String,
+    pub database: String,
+    /// Layout the backups were written with, to find the newest one.
+    pub key_template: KeyTemplate,
+    pub cluster: Option<String>,
+    /// Largest dictionary zstd may produce.
+    pub max_size: ByteSize,
+    /// Size of the samples the export is cut into for training.
+    pub sample_size: ByteSize,
+}
+
+/// Downloads the newest backup of the database, trains a zstd dictionary on it and stores the
+/// dictionary for the backup's prefix, where `surrealdb --zstd-dictionary` picks it up. Returns
+/// the dictionary's key.
+pub async fn train(options: &TrainOptions) -> Result<String, Report> {
+    let vars = KeyVars {
+        engine: "surrealdb",
+        cluster: options.cluster.as_deref(),
+        namespace: Some(&options.namespace),
+        database: Some(&options.database),
+    };
+    let key = verify::newest_backup(&options.tools, &options.s3_access, &options.bucket_name, &options.key_template, &vars).await?;
+    static STARTED: AtomicUsize = AtomicUsize::new(0);
+    let scratch = std::env::temp_dir().join(format!("btagger-train-{}-{}", std::process::id(), STARTED.fetch_add(1, Ordering::Relaxed)));
+    let (export, dictionary) = (scratch.with_extension("surql"), scratch.with_extension("zdict"));
+    let result = train_on(options, &key, &export, &dictionary).await;
+    for path in [&export, &dictionary] {
+        let _ = tokio::fs::remove_file(path).await;
+    }
+    result
+}
+
+async fn train_on(options: &TrainOptions, key: &str, export: &Path, dictionary: &Path) -> Result<String, Report> {
+    let bytes = verify::download(&options.tools, &options.s3_access, &options.bucket_name, key, export).await?;
+    info!(target: "dictionary", key, bytes, "Training a dictionary");
+    let mut command = Command::new(&options.tools.zstd);
+    command
+        .arg("--train")
+        .arg(format!("-B{}", options.sample_size.0))
+        .arg(format!("--maxdict={}", options.max_size.0))
+        .arg(export)
+        .arg("-o").arg(dictionary);
+    process::succeeded(options.tools.runner.run(command, None).await).wrap_err_with(|| format!("Unable to train a dictionary on {}", key))?;
+
+    let contents = tokio::fs::read(dictionary).await.wrap_err_with(|| format!("Unable to read {}", dictionary.display()))?;
+    let id = dictionary_id(&contents).wrap_err("zstd wrote no dictionary ID")?;
+    let dictionary_key = format!("{}{}/{}.zdict", DICTIONARY_PREFIX, backup_prefix(key), id);
+    s3::put_object(&options.tools, &options.s3_access, &options.bucket_name, &dictionary_key, dictionary, None).await?;
+    info!(target: "dictionary", key = dictionary_key, id, size = contents.len(), "Stored the dictionary");
+    println!("{}", dictionary_key);
+    Ok(dictionary_key)
+}
+
+/// The directory part of a backup key, which its dictionaries are kept under.
+pub fn backup_prefix(key: &str) -> &str {
+    key.rsplit_once('/').map_or("", |(prefix, _)| prefix)
+}
+
+/// Downloads the newest dictionary trained for backups under `prefix` to `path`, returning its
+/// key; `None` when none has been trained yet.
+pub async fn fetch_current(tools: &Tools, s3_access: &S3Access, bucket_name: &str, prefix: &str, path: &Path) -> Result<Option<String>, Report> {
+    let dictionaries = format!("{}{}/", DICTIONARY_PREFIX, prefix);
+    let newest = s3::list_objects(tools, s3_access, bucket_name, &dictionaries)
+        .await?
+        .into_iter()
+        // Not a dictionary of a deeper prefix.
+        .filter(|object| !object.key[dictionaries.len()..].contains('/'))
+        .max_by(|a, b| a.last_modified.cmp(&b.last_modified));
+    match newest {
+        Some(object) => {
+            fetch(tools, s3_access, bucket_name, &object.key, path).await?;
+            Ok(Some(object.key))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Downloads the dictionary with `id` to `path`, whichever prefix it was trained for.
+pub async fn fetch_by_id(tools: &Tools, s3_access: &S3Access, bucket_name: &str, id: u32, path: &Path) -> Result<String, Report> {
+    let name = format!("/{}.zdict", id);
+    let key = s3::list_objects(tools, s3_access, bucket_name, DICTIONARY_PREFIX)
+        .await?
+        .into_iter()
+        .map(|object| object.key)
+        .find(|key| key.ends_with(&name))
+        .ok_or_else(|| eyre!("The backup was compressed with zstd dictionary {}, which is not under {}", id, DICTIONARY_PREFIX))?;
+    fetch(tools, s3_access, bucket_name, &key, path).await?;
+    Ok(key)
+}
+
+async fn fetch(tools: &Tools, s3_access: &S3Access, bucket_name: &str, key: &str, path: &Path) -> Result<(), Report> {
+    let contents = s3::read_object(tools, s3_access, bucket_name, key).await?;
+    tokio::fs::write(path, contents).await.wrap_err_with(|| format!("Unable to write {}", path.display()))
+}
+
+/// A scratch path for a downloaded dictionary.
+pub fn scratch_path() -> PathBuf {
+    static FETCHED: AtomicUsize = AtomicUsize::new(0);
+    std::env::temp_dir().join(format!("btagger-{}-{}.zdict", std::process::id(), FETCHED.fetch_add(1, Ordering::Relaxed)))
+}
+
+/// The ID of a dictionary file zstd trained.
+fn dictionary_id(dictionary: &[u8]) -> Option<u32> {
+    if dictionary.get(..4)? != DICTIONARY_MAGIC {
+        return None;
+    }
+    Some(u32::from_le_bytes(dictionary.get(4..8)?.try_into().ok()?)).filter(|id| *id != 0)
+}
+
+/// The dictionary ID recorded in the header of the zstd frame `header` starts with; `None` for a
+/// frame compressed without one. At most 18 bytes are looked at.
+pub fn frame_dictionary_id(header: &[u8]) -> Option<u32> {
+    if header.get(..4)? != FRAME_MAGIC {
+        return None;
+    }
+    let descriptor = *header.get(4)?;
+    // A window descriptor byte follows unless the frame is a single segment.
+    let start = if descriptor & 0x20 != 0 { 5 } else { 6 };
+    let id = match descriptor & 0x03 {
+        0 => return None,
+        1 => u32::from(*header.get(start)?),
+        2 => u32::from(u16::from_le_bytes(header.get(start..start + 2)?.try_into().ok()?)),
+        _ => u32::from_le_bytes(header.get(start..start + 4)?.try_into().ok()?),
+    };
+    Some(id).filter(|id| *id != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dictionary_ids_are_read_from_dictionaries_and_frames() {
+        // Headers as written by `zstd --train` and `zstd --adapt -D`.
+        assert_eq!(dictionary_id(&[0x37, 0xa4, 0x30, 0xec, 0x1c, 0x57, 0xda, 0x6b, 0x16]), Some(0x6bda571c));
+        assert_eq!(frame_dictionary_id(&[0x28, 0xb5, 0x2f, 0xfd, 0x07, 0x68, 0x1c, 0x57, 0xda, 0x6b, 0xac]), Some(0x6bda571c));
+        // Single segment, one-byte ID.
+        assert_eq!(frame_dictionary_id(&[0x28, 0xb5, 0x2f, 0xfd, 0x21, 0x2a, 0x10]), Some(42));
+        // No dictionary, or not zstd at all.
+        assert_eq!(frame_dictionary_id(&[0x28, 0xb5, 0x2f, 0xfd, 0x04, 0x58, 0x61]), None);
+        assert_eq!(frame_dictionary_id(b"DEFINE TABLE"), None);
+        assert_eq!(backup_prefix("surrealdb/app/main/2025-01-01.04-30.zst"), "surrealdb/app/main");
+    }
+}
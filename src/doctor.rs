@@ -0,0 +1,222 @@
+use clap::ValueEnum;
+use color_eyre::eyre::{Report, WrapErr};
+use std::process::Output;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tracing::info;
+
+use crate::s3::{self, Aws, S3Access};
+use crate::tools::Tools;
+
+/// Object written (and removed again) to prove upload and tagging permissions.
+const PROBE_KEY: &str = ".btagger-doctor-probe";
+
+/// Inputs for the environment checks; anything left unset is skipped.
+pub struct DoctorOptions {
+    pub tools: Tools,
+    pub bucket_name: Option<String>,
+    pub s3_access: S3Access,
+    pub surrealdb_address: Option<String>,
+    pub pd_host_and_port: Option<String>,
+    pub tags: String,
+    /// Also try each S3 operation btagger uses against the bucket, and compare what the store
+    /// supports with what `--s3-compat-mode` expects.
+    pub compat: bool,
+}
+
+struct Check {
+    name: String,
+    passed: bool,
+    detail: String,
+}
+
+/// Runs every applicable check, prints a pass/fail checklist to stdout and reports whether all passed.
+pub async fn run(options: &DoctorOptions) -> Result<bool, Report> {
+    let mut checks = Vec::new();
+
+    let tools = &options.tools;
+    for (tool, path, version_arg) in [
+        ("aws", &tools.aws, "--version"),
+        ("zstd", &tools.zstd, "--version"),
+        ("surreal", &tools.surreal, "version"),
+        ("tikv-br", &tools.tikv_br, "--version"),
+    ] {
+        let mut command = Command::new(path);
+        command.arg(version_arg);
+        let output = tools.runner.run(command, None).await;
+        checks.push(from_output(format!("{} version ({})", tool, path.display()), output));
+    }
+
+    if let Some(bucket_name) = &options.bucket_name {
+        let aws = Aws::new(&options.tools, &options.s3_access);
+        let run = |command| options.tools.runner.run(command, None);
+        checks.push(from_output(
+            String::from("s3 head-bucket"),
+            run(aws.head_bucket(bucket_name)).await,
+        ));
+        let probe_body = std::env::temp_dir().join(format!("btagger-doctor-{}", std::process::id()));
+        tokio::fs::write(&probe_body, b"")
+            .await
+            .wrap_err_with(|| format!("Unable to create probe file {}", probe_body.display()))?;
+        let put = run(aws.put_object(bucket_name, PROBE_KEY, &probe_body, None)).await;
+        let _ = tokio::fs::remove_file(&probe_body).await;
+        let put = from_output(String::from("s3 put-object (probe)"), put);
+        let uploaded = put.passed;
+        checks.push(put);
+        if uploaded && options.compat {
+            checks.extend(compat_checks(options, bucket_name).await);
+        }
+        if uploaded {
+            // Tagged as backups are, so a store that only takes sidecars passes too.
+            let tagged = s3::put_object_tagging(&options.tools, &options.s3_access, bucket_name, PROBE_KEY, &options.tags).await;
+            checks.push(from_result(String::from("s3 put-object-tagging (probe)"), tagged));
+            let deleted = s3::delete_object(&options.tools, &options.s3_access, bucket_name, PROBE_KEY).await;
+            checks.push(from_result(String::from("s3 delete-object (probe)"), deleted));
+        }
+    }
+
+    if let Some(address) = &options.surrealdb_address {
+        let mut command = Command::new(&options.tools.surreal);
+        command
+            .arg("isready")
+            .arg("-e").arg(format!("http://{}", address));
+        let output = options.tools.runner.run(command, None).await;
+        checks.push(from_output(String::from("surrealdb connectivity"), output));
+    }
+
+    if let Some(pd_host_and_port) = &options.pd_host_and_port {
+        checks.push(tcp_check(String::from("tikv pd connectivity"), pd_host_and_port).await);
+    }
+
+    for check in &checks {
+        info!(target: "doctor_check", name = check.name, passed = check.passed, detail = check.detail);
+        println!("[{}] {}: {}", if check.passed { "PASS" } else { "FAIL" }, check.name, check.detail);
+    }
+    Ok(checks.iter().all(|check| check.passed))
+}
+
+/// Runs each S3 operation btagger relies on directly, without the sidecar and create-bucket
+/// fallbacks, and checks the answers against the compat mode: a store lacking an operation the
+/// mode expects fails, as does any error other than NotImplemented.
+async fn compat_checks(options: &DoctorOptions, bucket_name: &str) -> Vec<Check> {
+    let compat = options.s3_access.compat;
+    let mode = compat.to_possible_value().map(|value| value.get_name().to_string()).unwrap_or_default();
+    let aws = Aws::new(&options.tools, &options.s3_access);
+    let operations = [
+        ("create-bucket", aws.create_bucket(bucket_name), compat.creates_buckets(), "BucketAlreadyOwnedByYou"),
+        ("list-objects-v2", aws.list_objects(bucket_name, PROBE_KEY), true, ""),
+        ("put-object-tagging", aws.put_object_tagging(bucket_name, PROBE_KEY, &options.tags), compat.tags_objects(), ""),
+        ("get-object-tagging", aws.get_object_tagging(bucket_name, PROBE_KEY), compat.tags_objects(), ""),
+        ("get-bucket-versioning", aws.get_bucket_configuration(bucket_name, "get-bucket-versioning"), compat.versioning(), ""),
+        // A bucket without rules answers NoSuchLifecycleConfiguration, which shows the operation exists.
+        ("get-bucket-lifecycle-configuration", aws.get_bucket_configuration(bucket_name, "get-bucket-lifecycle-configuration"), compat.tag_lifecycle(), "NoSuchLifecycleConfiguration"),
+    ];
+    let mut checks = Vec::new();
+    for (operation, command, expected, supported_error) in operations {
+        let name = format!("s3 compat {}", operation);
+        let output = match options.tools.runner.run(command, None).await {
+            Ok(output) => output,
+            Err(err) => {
+                checks.push(Check { name, passed: false, detail: err.to_string() });
+                continue;
+            }
+        };
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let check = if output.status.success() || (!supported_error.is_empty() && stderr.contains(supported_error)) {
+            Check { name, passed: true, detail: String::from("supported") }
+        } else if s3::not_implemented(&stderr) && !expected {
+            Check { name, passed: true, detail: format!("not supported, as --s3-compat-mode {} expects", mode) }
+        } else if s3::not_implemented(&stderr) {
+            Check { name, passed: false, detail: format!("not supported, but --s3-compat-mode {} uses it; pick the mode of the store, e.g. seaweedfs or garage", mode) }
+        } else {
+            Check { name, passed: false, detail: stderr.lines().last().unwrap_or_default().trim().to_string() }
+        };
+        checks.push(check);
+    }
+    checks
+}
+
+fn from_output(name: String, output: std::io::Result<Output>) -> Check {
+    match output {
+        Ok(output) => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            // Version banners land on either stream depending on the tool.
+            let text = if stdout.trim().is_empty() { stderr } else { stdout };
+            let detail = text.lines().next().unwrap_or_default().trim().to_string();
+            Check {
+                name,
+                passed: output.status.success(),
+                detail: if detail.is_empty() { format!("exited with {}", output.status) } else { detail },
+            }
+        }
+        Err(err) => Check {
+            name,
+            passed: false,
+            detail: err.to_string(),
+        },
+    }
+}
+
+fn from_result(name: String, result: Result<(), Report>) -> Check {
+    match result {
+        Ok(()) => Check { name, passed: true, detail: String::from("ok") },
+        Err(err) => Check { name, passed: false, detail: format!("{:#}", err) },
+    }
+}
+
+async fn tcp_check(name: String, host_and_port: &str) -> Check {
+    let connected = match tokio::time::timeout(Duration::from_secs(5), TcpStream::connect(host_and_port)).await {
+        Ok(connected) => connected.map_err(|err| err.to_string()),
+        Err(_) => Err(String::from("timed out after 5s")),
+    };
+    match connected {
+        Ok(_) => Check { name, passed: true, detail: format!("connected to {}", host_and_port) },
+        Err(detail) => Check { name, passed: false, detail },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::MockRunner;
+    use crate::s3::S3Compat;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn compat_checks_compare_the_store_with_the_mode() {
+        let runner = Arc::new(MockRunner::new(|call| {
+            if call.has_args(&["create-bucket"]) {
+                MockRunner::output(254, "", "An error occurred (BucketAlreadyOwnedByYou) when calling the CreateBucket operation")
+            } else if call.has_args(&["get-bucket-lifecycle-configuration"]) {
+                MockRunner::output(254, "", "An error occurred (NotImplemented) when calling the GetBucketLifecycleConfiguration operation")
+            } else if call.has_args(&["get-bucket-versioning"]) {
+                MockRunner::output(254, "", "An error occurred (AccessDenied) when calling the GetBucketVersioning operation: Access Denied")
+            } else {
+                MockRunner::output(0, "{}", "")
+            }
+        }));
+        let tools = Tools::mock(runner.clone());
+        let mut options = DoctorOptions {
+            tools,
+            bucket_name: Some(String::from("bk")),
+            s3_access: S3Access { compat: S3Compat::Ceph, ..S3Access::default() },
+            surrealdb_address: None,
+            pd_host_and_port: None,
+            tags: String::from(r#"{"TagSet":[]}"#),
+            compat: true,
+        };
+        let results = |checks: Vec<Check>| checks.into_iter().map(|check| (check.name, check.passed, check.detail)).collect::<Vec<_>>();
+        let checks = results(compat_checks(&options, "bk").await);
+        assert_eq!(checks[0], (String::from("s3 compat create-bucket"), true, String::from("supported")));
+        assert_eq!(checks[3], (String::from("s3 compat get-object-tagging"), true, String::from("supported")));
+        assert_eq!(checks[4].2, "An error occurred (AccessDenied) when calling the GetBucketVersioning operation: Access Denied");
+        assert!(!checks[5].1);
+        assert!(checks[5].2.starts_with("not supported, but --s3-compat-mode ceph uses it"), "{}", checks[5].2);
+
+        options.s3_access.compat = S3Compat::Seaweedfs;
+        let checks = results(compat_checks(&options, "bk").await);
+        assert_eq!(checks[5], (String::from("s3 compat get-bucket-lifecycle-configuration"), true, String::from("not supported, as --s3-compat-mode seaweedfs expects")));
+    }
+}
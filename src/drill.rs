@@ -0,0 +1,231 @@
+use color_eyre::eyre::{eyre, Report, WrapErr};
+use tokio::process::Command;
+use tracing::info;
+
+use crate::failure::Failure;
+use crate::keys::{KeyTemplate, KeyVars};
+use crate::process;
+use crate::restore::{self, RestoreOptions};
+use crate::retention;
+use crate::s3::{self, Credentials, S3Access};
+use crate::secret::Secret;
+use crate::summary::BackupReport;
+use crate::surreal;
+use crate::tools::Tools;
+use crate::verify;
+
+/// Where a drill restores to.
+pub enum DrillTarget {
+    /// A scratch SurrealDB server; each drill imports into a database of its own there.
+    Surrealdb {
+        /// Namespace and database the backups were taken from.
+        namespace: String,
+        database: String,
+        key_template: KeyTemplate,
+        cluster: Option<String>,
+        address: String,
+        password: Option<Secret>,
+        /// Namespace on the scratch server the drill databases go in.
+        target_namespace: String,
+        /// Leave the drill database in place for inspection instead of removing it.
+        keep: bool,
+    },
+    /// A scratch TiKV cluster, which `tikv-br restore raw` writes over.
+    Tikv {
+        /// Directly above the backups; each path segment below it is one backup.
+        prefix: String,
+        pd_host_and_port: String,
+    },
+}
+
+/// Which backups a drill picks from, and how it proves the one it picked.
+pub struct DrillOptions {
+    pub tools: Tools,
+    pub bucket_name: String,
+    pub s3_access: S3Access,
+    pub target: DrillTarget,
+    /// SurrealQL run against the restored database; each must return at least one record.
+    pub queries: Vec<String>,
+    /// Picks the backup among those in the bucket.
+    pub random: u64,
+}
+
+/// Restores a randomly picked backup into the scratch target and runs the validation queries
+/// against it. The restored key comes back as a backup report, so a drill gets the run summary,
+/// catalog record and notifications a backup gets; a failed restore or query is a
+/// [`Failure::Verify`].
+pub async fn run(options: &DrillOptions) -> Result<BackupReport, Report> {
+    let key = match &options.target {
+        DrillTarget::Surrealdb { namespace, database, key_template, cluster, .. } => {
+            let vars = KeyVars { engine: "surrealdb", cluster: cluster.as_deref(), namespace: Some(namespace), database: Some(database) };
+            let backups = verify::backups(&options.tools, &options.s3_access, &options.bucket_name, key_template, &vars).await?;
+            pick(backups.into_iter().map(|object| object.key).collect(), options.random)
+                .ok_or_else(|| eyre!("No backups of {}/{} in {} to drill", namespace, database, options.bucket_name))?
+        }
+        DrillTarget::Tikv { prefix, .. } => {
+            let objects = s3::list_objects(&options.tools, &options.s3_access, &options.bucket_name, prefix).await?;
+            pick(retention::group(prefix, objects)?.into_keys().collect(), options.random)
+                .ok_or_else(|| eyre!("No backups under {} in {} to drill", prefix, options.bucket_name))?
+        }
+    };
+    info!(target: "drill", bucket = options.bucket_name, key, "Backup picked");
+    println!("[DRILL] {}", key);
+    drill(options, &key).await.wrap_err_with(|| format!("Drill of {} failed", key)).wrap_err(Failure::Verify)?;
+    info!(target: "audit", action = "drill", bucket = options.bucket_name, key, operator = std::env::var("USER").unwrap_or_default());
+    println!("[DRILL PASSED] {}", key);
+    Ok(BackupReport { storage_key: key, bytes: 0, raw_bytes: None, success: true, stderr_tail: None, tikv_stats: None, signal: None })
+}
+
+/// One of `keys`, chosen by `random`.
+fn pick(mut keys: Vec<String>, random: u64) -> Option<String> {
+    match keys.len() {
+        0 => None,
+        len => Some(keys.swap_remove((random % len as u64) as usize)),
+    }
+}
+
+async fn drill(options: &DrillOptions, key: &str) -> Result<(), Report> {
+    match &options.target {
+        DrillTarget::Surrealdb { namespace, database, key_template, cluster, address, password, target_namespace, keep } => {
+            let target_database = format!("drill_{}", chrono::Utc::now().format("%Y%m%d%H%M%S"));
+            let restore = RestoreOptions {
+                tools: options.tools.clone(),
+                bucket_name: options.bucket_name.clone(),
+                s3_access: options.s3_access.clone(),
+                namespace: namespace.clone(),
+                database: database.clone(),
+                key: Some(key.to_string()),
+                before: None,
+                tags: Vec::new(),
+                key_template: key_template.clone(),
+                cluster: cluster.clone(),
+                address: address.clone(),
+                password: password.clone(),
+                target_namespace: Some(target_namespace.clone()),
+                target_database: Some(target_database.clone()),
+                yes: true,
+                require_empty: true,
+                force: false,
+                print_only: false,
+            };
+            let endpoint = format!("http://{}", address);
+            let mut result = restore::run(&restore).await.map(|_| ());
+            if result.is_ok() {
+                result = validate(&options.tools, &endpoint, password.as_ref(), target_namespace, &target_database, &options.queries).await;
+            }
+            if !keep {
+                let removed = surreal::query(&options.tools, &endpoint, password.as_ref(), target_namespace, &target_database, &format!("REMOVE DATABASE `{}`;", target_database)).await;
+                if let Err(err) = removed {
+                    tracing::warn!(target: "drill", error = format!("{:#}", err), database = target_database, "Unable to remove the drill database");
+                }
+            }
+            result
+        }
+        DrillTarget::Tikv { pd_host_and_port, .. } => {
+            if !options.queries.is_empty() {
+                return Err(eyre!("Validation queries need a SurrealDB target; a TiKV drill only proves the restore"));
+            }
+            restore_tikv(options, key, pd_host_and_port).await
+        }
+    }
+}
+
+/// Runs each query against the restored database, failing at the first that errors or returns
+/// no records.
+async fn validate(tools: &Tools, endpoint: &str, password: Option<&Secret>, namespace: &str, database: &str, queries: &[String]) -> Result<(), Report> {
+    for query in queries {
+        let result = surreal::query(tools, endpoint, password, namespace, database, query).await.wrap_err_with(|| format!("Validation query failed: {}", query))?;
+        if surreal::first_object(&result).is_none() {
+            return Err(eyre!("Validation query returned no records: {}", query));
+        }
+        info!(target: "drill", query, "Validation query passed");
+        println!("[OK] {}", query);
+    }
+    Ok(())
+}
+
+/// `tikv-br restore raw` of the backup under `key` into the scratch cluster, with credentials
+/// handed over as for backups.
+async fn restore_tikv(options: &DrillOptions, key: &str, pd_host_and_port: &str) -> Result<(), Report> {
+    let s3_access = &options.s3_access;
+    let mut command = Command::new(&options.tools.tikv_br);
+    command
+        .kill_on_drop(true)
+        .arg("restore")
+        .arg("raw")
+        .arg(format!("--pd={}", pd_host_and_port))
+        .arg(format!("--send-credentials-to-tikv={}", !matches!(s3_access.credentials, Credentials::Irsa)))
+        .arg(format!("--storage=s3://{}/{}", options.bucket_name, key));
+    if let Some(endpoint) = &s3_access.endpoint {
+        command.arg(format!("--s3.endpoint={}", endpoint));
+    }
+    if let Some(region) = &s3_access.region {
+        command.arg(format!("--s3.region={}", region));
+    }
+    if s3_access.path_style_config.is_some() {
+        command.arg("--s3.force-path-style=true");
+    }
+    match &s3_access.credentials {
+        Credentials::Static(id, key) => {
+            command.env("AWS_ACCESS_KEY_ID", id.expose()).env("AWS_SECRET_ACCESS_KEY", key.expose());
+        }
+        Credentials::Profile(profile) => {
+            command.env("AWS_PROFILE", profile);
+        }
+        Credentials::Env | Credentials::Irsa => {}
+    }
+    process::succeeded(options.tools.runner.run_streaming(command, "tikv-br").await)
+        .wrap_err_with(|| format!("tikv-br restore into {} failed", pd_host_and_port))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::MockRunner;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn tikv_drills_restore_a_picked_backup_into_the_scratch_cluster() {
+        let listing = r#"{"Contents":[
+            {"Key":"tikv/2025-01-01.04-30/backupmeta","Size":10,"LastModified":"2025-01-01T04:40:00+00:00"},
+            {"Key":"tikv/2025-01-01.04-30/1_2.sst","Size":90,"LastModified":"2025-01-01T04:39:00+00:00"},
+            {"Key":"tikv/2025-01-02.04-30/backupmeta","Size":10,"LastModified":"2025-01-02T04:40:00+00:00"}
+        ]}"#;
+        let runner = Arc::new(MockRunner::new(move |call| match call.has_args(&["list-objects-v2"]) {
+            true => MockRunner::output(0, listing, ""),
+            false => MockRunner::output(0, "", ""),
+        }));
+        let options = DrillOptions {
+            tools: Tools::mock(runner.clone()),
+            bucket_name: String::from("bk"),
+            s3_access: S3Access { credentials: Credentials::Static(Secret::new("id"), Secret::new("key")), ..S3Access::default() },
+            target: DrillTarget::Tikv { prefix: String::from("tikv/"), pd_host_and_port: String::from("scratch-pd:2379") },
+            queries: Vec::new(),
+            random: 3,
+        };
+        let report = run(&options).await.unwrap();
+        assert_eq!(report.storage_key, "tikv/2025-01-02.04-30");
+        let calls = runner.calls();
+        let restore = calls.iter().find(|call| call.program == "tikv-br").unwrap();
+        assert!(restore.has_args(&["restore", "raw", "--pd=scratch-pd:2379", "--send-credentials-to-tikv=true", "--storage=s3://bk/tikv/2025-01-02.04-30"]));
+        assert_eq!(restore.env("AWS_ACCESS_KEY_ID"), Some("id"));
+
+        let options = DrillOptions { queries: vec![String::from("SELECT * FROM user LIMIT 1")], ..options };
+        let err = run(&options).await.unwrap_err();
+        assert_eq!(Failure::of(&err), Failure::Verify);
+    }
+
+    #[tokio::test]
+    async fn queries_returning_no_records_fail_the_drill() {
+        let runner = Arc::new(MockRunner::new(|call| match call.stdin.as_deref() {
+            Some(b"SELECT * FROM user LIMIT 1") => MockRunner::output(0, r#"[[{"id":"user:1"}]]"#, ""),
+            _ => MockRunner::output(0, "[[]]", ""),
+        }));
+        let tools = Tools::mock(runner);
+        let queries = |queries: &[&str]| queries.iter().map(|query| query.to_string()).collect::<Vec<_>>();
+        assert!(validate(&tools, "http://scratch:8000", None, "drill", "drill_1", &queries(&["SELECT * FROM user LIMIT 1"])).await.is_ok());
+        let err = validate(&tools, "http://scratch:8000", None, "drill", "drill_1", &queries(&["SELECT * FROM user LIMIT 1", "SELECT * FROM order LIMIT 1"])).await.unwrap_err();
+        assert_eq!(err.to_string(), "Validation query returned no records: SELECT * FROM order LIMIT 1");
+    }
+}
@@ -0,0 +1,213 @@
+use color_eyre::eyre::{eyre, Report, WrapErr};
+use serde_json::{json, Value};
+use tokio::process::Command;
+use tracing::info;
+
+use crate::failure::Failure;
+use crate::notify::curl_config;
+use crate::process::{self, before_deadline};
+use crate::secret::Secret;
+use crate::summary::{BackupReport, EmptyBackup, Timings};
+use crate::tags::Tag;
+use crate::tools::Tools;
+
+/// An Elasticsearch or OpenSearch cluster to snapshot into one of its S3 repositories.
+pub struct ElasticsearchOptions {
+    pub tools: Tools,
+    /// The bucket the repository must write to.
+    pub bucket_name: String,
+    /// Base URL of the cluster, e.g. `http://localhost:9200`.
+    pub address: String,
+    pub user: Option<String>,
+    pub password: Option<Secret>,
+    /// Snapshot repository, registered on the cluster with type `s3`.
+    pub repository: String,
+    /// Index patterns to snapshot; every index when empty.
+    pub indices: Vec<String>,
+    /// Snapshot name, unique to the run.
+    pub snapshot: String,
+    /// Stored in the snapshot's metadata.
+    pub tags: Vec<Tag>,
+    pub min_expected_bytes: u64,
+    pub allow_empty: bool,
+    pub deadline: Option<tokio::time::Instant>,
+    pub timings: Timings,
+}
+
+/// Takes a snapshot into the repository and waits for it. The repository's blobs are shared
+/// between snapshots, so they are not tagged; expiring one with a lifecycle rule would break every
+/// snapshot that reuses it. The period tags go into the snapshot's metadata instead, and the run is
+/// recorded like any other. A snapshot that failed, or that is empty or undersized, is deleted.
+pub async fn backup(options: &ElasticsearchOptions) -> Result<BackupReport, Report> {
+    let timings = &options.timings;
+    let base_path = repository_base_path(options, &request(options, "GET", &format!("_snapshot/{}", options.repository), None).await?)?;
+
+    let body = json!({
+        "indices": if options.indices.is_empty() { String::from("*") } else { options.indices.join(",") },
+        "include_global_state": true,
+        "metadata": {
+            "taken_by": "btagger",
+            "tags": options.tags.iter().map(|tag| (tag.key.clone(), Value::from(tag.value.clone()))).collect::<serde_json::Map<_, _>>(),
+        },
+    });
+    let snapshot_path = format!("_snapshot/{}/{}", options.repository, options.snapshot);
+    let created = timings.time("export", before_deadline(options.deadline, "elasticsearch snapshot", request(options, "PUT", &format!("{}?wait_for_completion=true", snapshot_path), Some(&body))))
+        .await
+        .and_then(|created| created)
+        .wrap_err(Failure::Export { stage: String::from("elasticsearch snapshot") })?;
+    let snapshot = &created["snapshot"];
+    let state = snapshot["state"].as_str().unwrap_or_default();
+    let indices = snapshot["indices"].as_array().map_or(0, Vec::len);
+    info!(target: "elasticsearch_snapshot_output", snapshot = options.snapshot, state, indices, shards = %snapshot["shards"]);
+
+    let bytes = match state {
+        "SUCCESS" => request(options, "GET", &format!("{}/_status", snapshot_path), None)
+            .await
+            .map(|status| status["snapshots"][0]["stats"]["total"]["size_in_bytes"].as_u64().unwrap_or_default())?,
+        _ => 0,
+    };
+    let failure = match state {
+        "SUCCESS" if indices == 0 && !options.allow_empty => Some(Report::new(EmptyBackup(format!("Snapshot {}", options.snapshot)))),
+        "SUCCESS" if bytes < options.min_expected_bytes => Some(
+            eyre!("Snapshot {} is only {} bytes, below --min-expected-bytes {}", options.snapshot, bytes, options.min_expected_bytes)
+                .wrap_err(Failure::Export { stage: String::from("size check") }),
+        ),
+        "SUCCESS" => None,
+        // A partial snapshot is kept by the cluster, but is no backup of the selected indices.
+        _ => Some(eyre!("Snapshot {} finished in state {}: {}", options.snapshot, state, snapshot["failures"]).wrap_err(Failure::Export { stage: String::from("elasticsearch snapshot") })),
+    };
+    if let Some(failure) = failure {
+        let removed = request(options, "DELETE", &snapshot_path, None).await;
+        info!(target: "elasticsearch_delete_snapshot_output", snapshot = options.snapshot, success = removed.is_ok(), error = removed.err().map(|err| format!("{:#}", err)));
+        return Err(failure);
+    }
+
+    Ok(BackupReport {
+        // The snapshot's own metadata blob, the one object in the repository that is not shared.
+        storage_key: format!("{}snap-{}.dat", base_path, snapshot["uuid"].as_str().unwrap_or_default()),
+        bytes,
+        raw_bytes: None,
+        success: true,
+        stderr_tail: None,
+        tikv_stats: None,
+        signal: None,
+    })
+}
+
+/// Checks that the repository writes to the backup bucket, and returns its base path with a
+/// trailing slash, or an empty one.
+fn repository_base_path(options: &ElasticsearchOptions, repositories: &Value) -> Result<String, Report> {
+    let repository = &repositories[&options.repository];
+    let settings = &repository["settings"];
+    match (repository["type"].as_str(), settings["bucket"].as_str()) {
+        (Some("s3"), Some(bucket)) if bucket == options.bucket_name => {}
+        (Some("s3"), bucket) => {
+            return Err(eyre!("Repository {} writes to bucket {}, not {}", options.repository, bucket.unwrap_or("-"), options.bucket_name).wrap_err(Failure::Config));
+        }
+        (kind, _) => return Err(eyre!("Repository {} is of type {}, not s3", options.repository, kind.unwrap_or("-")).wrap_err(Failure::Config)),
+    }
+    Ok(match settings["base_path"].as_str().map(|path| path.trim_matches('/')) {
+        Some(path) if !path.is_empty() => format!("{}/", path),
+        _ => String::new(),
+    })
+}
+
+/// Sends a request to the cluster and returns the JSON it answers with. The URL and the login go
+/// in curl's config on stdin, so the password stays out of process listings.
+async fn request(options: &ElasticsearchOptions, method: &str, path: &str, body: Option<&Value>) -> Result<Value, Report> {
+    let mut command = Command::new(&options.tools.curl);
+    command
+        .kill_on_drop(true)
+        .arg("--silent")
+        .arg("--show-error")
+        .arg("--fail")
+        .arg("--request").arg(method)
+        .arg("--header").arg("Content-Type: application/json")
+        .arg("--config").arg("-");
+    let url = format!("{}/{}", options.address.trim_end_matches('/'), path);
+    let login = options.user.as_ref().map(|user| format!("{}:{}", user, options.password.as_ref().map_or("", Secret::expose)));
+    let body = body.map(Value::to_string);
+    let mut config = vec![("url", url.as_str())];
+    config.extend(login.as_deref().map(|login| ("user", login)));
+    config.extend(body.as_deref().map(|body| ("data-binary", body)));
+    let output = process::succeeded(options.tools.runner.run(command, Some(curl_config(&config).into_bytes())).await)
+        .wrap_err_with(|| format!("{} {} failed", method, path))?;
+    serde_json::from_slice(&output.stdout).wrap_err_with(|| format!("Unable to parse the answer to {} {}", method, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::{Call, MockRunner};
+    use std::sync::Arc;
+
+    fn options(runner: Arc<MockRunner>) -> ElasticsearchOptions {
+        ElasticsearchOptions {
+            tools: Tools::mock(runner),
+            bucket_name: String::from("bk"),
+            address: String::from("https://es.internal:9200/"),
+            user: Some(String::from("elastic")),
+            password: Some(Secret::new("es-pw")),
+            repository: String::from("backups"),
+            indices: vec![String::from("logs-*")],
+            snapshot: String::from("btagger-20250101-043000"),
+            tags: vec![Tag { key: String::from("monthly"), value: String::from("1") }],
+            min_expected_bytes: 0,
+            allow_empty: false,
+            deadline: None,
+            timings: Timings::default(),
+        }
+    }
+
+    fn config(call: &Call) -> String {
+        String::from_utf8(call.stdin.clone().unwrap()).unwrap()
+    }
+
+    fn cluster(state: &'static str) -> impl Fn(&Call) -> std::process::Output {
+        move |call| {
+            let config = config(call);
+            if config.contains("/_status\"") {
+                MockRunner::output(0, r#"{"snapshots":[{"stats":{"total":{"size_in_bytes":4096}}}]}"#, "")
+            } else if config.contains("wait_for_completion") {
+                let snapshot = json!({"snapshot": {"uuid": "dXVpZA", "state": state, "indices": ["logs-1"], "shards": {"failed": 0}}});
+                MockRunner::output(0, &snapshot.to_string(), "")
+            } else if call.has_args(&["--request", "GET"]) {
+                MockRunner::output(0, r#"{"backups":{"type":"s3","settings":{"bucket":"bk","base_path":"/es/prod/"}}}"#, "")
+            } else {
+                MockRunner::output(0, r#"{"acknowledged":true}"#, "")
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn snapshot_carries_the_tags_and_points_at_its_own_blob() {
+        let runner = Arc::new(MockRunner::new(cluster("SUCCESS")));
+        let report = backup(&options(runner.clone())).await.unwrap();
+        assert_eq!(report.storage_key, "es/prod/snap-dXVpZA.dat");
+        assert_eq!(report.bytes, 4096);
+
+        let calls = runner.calls();
+        assert_eq!(calls.len(), 3);
+        assert!(calls.iter().all(|call| !call.args.iter().any(|arg| arg.contains("es-pw"))));
+        let snapshot = config(&calls[1]);
+        assert!(snapshot.contains("url = \"https://es.internal:9200/_snapshot/backups/btagger-20250101-043000?wait_for_completion=true\""), "{}", snapshot);
+        assert!(snapshot.contains("user = \"elastic:es-pw\""));
+        assert!(snapshot.contains(r#"\"tags\":{\"monthly\":\"1\"}"#), "{}", snapshot);
+        assert!(snapshot.contains(r#"\"indices\":\"logs-*\""#));
+    }
+
+    #[tokio::test]
+    async fn partial_snapshot_is_deleted_and_a_foreign_bucket_refused() {
+        let runner = Arc::new(MockRunner::new(cluster("PARTIAL")));
+        let err = backup(&options(runner.clone())).await.unwrap_err();
+        assert_eq!(Failure::of(&err).code(), "export");
+        let calls = runner.calls();
+        assert!(calls[2].has_args(&["--request", "DELETE"]));
+        assert!(config(&calls[2]).contains("/_snapshot/backups/btagger-20250101-043000\""));
+
+        let runner = Arc::new(MockRunner::new(cluster("SUCCESS")));
+        let err = backup(&ElasticsearchOptions { bucket_name: String::from("other"), ..options(runner.clone()) }).await.unwrap_err();
+        assert_eq!(Failure::of(&err).code(), "config");
+        assert_eq!(runner.calls().len(), 1);
+    }
+}
@@ -0,0 +1,121 @@
+use color_eyre::eyre::Report;
+
+use crate::process::StageFailed;
+use crate::summary::{BackupReport, EmptyBackup};
+
+/// What a run failed at, for automation to branch on. It is attached to error reports as
+/// context, and becomes the exit code and the `error_code`/`error_stage` of the run summary.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// Flags, profiles or the config file are unusable.
+    Config,
+    /// The backup held no data.
+    Empty,
+    /// Producing the backup failed, e.g. in `surreal export` or tikv-br.
+    Export { stage: String },
+    /// Storing the backup failed.
+    Upload,
+    /// The backup was stored but could not be tagged.
+    Tagging,
+    /// A stored backup did not verify.
+    Verify,
+    /// Anything else.
+    Other,
+}
+
+impl Failure {
+    /// Finds the failure behind `report`. Explicit context wins, then the typed errors raised
+    /// along the backup pipeline.
+    pub fn of(report: &Report) -> Failure {
+        if let Some(failure) = report.downcast_ref::<Failure>() {
+            return failure.clone();
+        }
+        if report.downcast_ref::<EmptyBackup>().is_some() {
+            return Failure::Empty;
+        }
+        match report.downcast_ref::<StageFailed>().map(|failed| failed.stage.as_str()) {
+            Some("upload") => Failure::Upload,
+            Some(stage) => Failure::Export { stage: stage.to_string() },
+            None => Failure::Other,
+        }
+    }
+
+    /// The failure behind a backup's outcome, if it failed.
+    pub fn of_backup(result: &Result<BackupReport, Report>) -> Option<Failure> {
+        match result {
+            Ok(report) if report.success => None,
+            // Only a failing tikv-br is reported as an unsuccessful backup rather than an error.
+            Ok(_) => Some(Failure::Export { stage: String::from("tikv-br backup") }),
+            Err(err) => Some(Failure::of(err)),
+        }
+    }
+
+    /// Machine-readable name, as in the run summary's `error_code`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Failure::Config => "config",
+            Failure::Empty => "empty",
+            Failure::Export { .. } => "export",
+            Failure::Upload => "upload",
+            Failure::Tagging => "tagging",
+            Failure::Verify => "verify",
+            Failure::Other => "error",
+        }
+    }
+
+    /// Process exit code; 2 matches clap's code for unusable flags.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Failure::Other => 1,
+            Failure::Config => 2,
+            Failure::Empty => 3,
+            Failure::Export { .. } => 4,
+            Failure::Upload => 5,
+            Failure::Tagging => 6,
+            Failure::Verify => 7,
+        }
+    }
+
+    pub fn stage(&self) -> Option<&str> {
+        match self {
+            Failure::Export { stage } => Some(stage),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Failure::Config => f.write_str("Invalid configuration"),
+            Failure::Empty => f.write_str("The backup is empty"),
+            Failure::Export { stage } => write!(f, "Exporting the backup failed during {}", stage),
+            Failure::Upload => f.write_str("Storing the backup failed"),
+            Failure::Tagging => f.write_str("Tagging the backup failed"),
+            Failure::Verify => f.write_str("Verification failed"),
+            Failure::Other => f.write_str("The run failed"),
+        }
+    }
+}
+
+impl std::error::Error for Failure {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process;
+    use color_eyre::eyre::{eyre, WrapErr};
+
+    #[test]
+    fn failures_are_found_under_later_context() {
+        let stage = |name: &'static str| process::first_failure([(name, Err(eyre!("broken pipe")))]).unwrap_err();
+        assert_eq!(Failure::of(&stage("surreal export").wrap_err("Job app failed")), Failure::Export { stage: String::from("surreal export") });
+        assert_eq!(Failure::of(&stage("upload")), Failure::Upload);
+        assert_eq!(Failure::of(&stage("empty check").wrap_err(EmptyBackup(String::from("Export")))), Failure::Empty);
+
+        let tagging = Err::<(), _>(eyre!("AccessDenied")).wrap_err(Failure::Tagging).wrap_err("tikv/k").unwrap_err();
+        assert_eq!(Failure::of(&tagging), Failure::Tagging);
+        assert_eq!(Failure::of(&tagging).exit_code(), 6);
+        assert_eq!(Failure::of(&eyre!("something else")), Failure::Other);
+    }
+}
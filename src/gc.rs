@@ -0,0 +1,175 @@
+use chrono::{DateTime, Duration, Utc};
+use color_eyre::eyre::Report;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use std::collections::HashSet;
+use tracing::info;
+
+use crate::cas;
+use crate::s3::{self, S3Access};
+use crate::tools::Tools;
+
+/// Which bucket to collect unreferenced chunks in.
+pub struct GcOptions {
+    pub tools: Tools,
+    pub bucket_name: String,
+    pub s3_access: S3Access,
+    /// Chunks modified more recently than this are kept, as the backup writing them may not have
+    /// stored its index yet.
+    pub grace: Duration,
+    /// Only report what would be deleted.
+    pub dry_run: bool,
+    pub concurrency: usize,
+}
+
+/// Deletes the chunks under `chunks/` that no index left in the bucket references, except those
+/// within the grace period. Every index is read before anything is deleted, so one that cannot be
+/// read ends the run without deleting, and so does a backup still storing chunks, which may be
+/// reusing any of them. Returns how many chunks were, or would be, deleted.
+pub async fn run(options: &GcOptions, now: DateTime<Utc>) -> Result<usize, Report> {
+    let objects = s3::list_objects(&options.tools, &options.s3_access, &options.bucket_name, "").await?;
+    let indexes = objects.iter().filter(|object| cas::is_index(&object.key)).collect::<Vec<_>>();
+    let referenced = stream::iter(&indexes)
+        .map(|index| cas::read_index(&options.tools, &options.s3_access, &options.bucket_name, &index.key))
+        .buffer_unordered(options.concurrency.max(1))
+        .try_fold(HashSet::new(), |mut referenced, index| async move {
+            referenced.extend(index.chunks.into_iter().map(|chunk| chunk.key));
+            Ok(referenced)
+        })
+        .await?;
+
+    let chunks = objects.iter().filter(|object| object.key.starts_with(cas::CHUNK_PREFIX)).collect::<Vec<_>>();
+    let present = chunks.iter().map(|object| object.key.as_str()).collect::<HashSet<_>>();
+    let mut missing = referenced.iter().filter(|key| !present.contains(key.as_str())).collect::<Vec<_>>();
+    missing.sort();
+    for key in missing {
+        println!("[MISSING] {}", key);
+    }
+    let (mut recent, mut unreferenced) = (0, Vec::new());
+    for object in chunks.into_iter().filter(|object| !referenced.contains(&object.key)) {
+        // A chunk whose age is unknown is treated as recent.
+        let modified = DateTime::parse_from_rfc3339(&object.last_modified).map(|modified| modified.with_timezone(&Utc));
+        match modified {
+            Ok(modified) if now - modified >= options.grace => unreferenced.push(object),
+            _ => recent += 1,
+        }
+    }
+    // Listed only now, so a backup that started after the listing above is seen too. Markers past
+    // the grace period are left over from failed runs.
+    let (running, stale): (Vec<_>, Vec<_>) = s3::list_objects(&options.tools, &options.s3_access, &options.bucket_name, cas::PENDING_PREFIX)
+        .await?
+        .into_iter()
+        .partition(|marker| {
+            DateTime::parse_from_rfc3339(&marker.last_modified).map_or(true, |modified| now - modified.with_timezone(&Utc) < options.grace)
+        });
+    if !running.is_empty() {
+        for marker in &running {
+            println!("[RUNNING] {}", marker.key.trim_start_matches(cas::PENDING_PREFIX));
+        }
+        println!("{} backup(s) are storing chunks and may reuse unreferenced ones; nothing deleted", running.len());
+        return Ok(0);
+    }
+    let bytes = unreferenced.iter().map(|object| object.size).sum::<u64>();
+    info!(target: "gc", indexes = indexes.len(), referenced = referenced.len(), unreferenced = unreferenced.len(), recent, bytes, "Chunk reachability");
+
+    if options.dry_run {
+        for object in &unreferenced {
+            println!("[WOULD DELETE] {} ({} bytes, modified {})", object.key, object.size, object.last_modified);
+        }
+    } else {
+        stream::iter(unreferenced.iter().copied().chain(&stale))
+            .map(|object| async move {
+                s3::delete_object(&options.tools, &options.s3_access, &options.bucket_name, &object.key).await?;
+                info!(target: "audit", action = "gc", bucket = options.bucket_name, key = object.key, size = object.size, last_modified = object.last_modified, operator = std::env::var("USER").unwrap_or_default());
+                println!("[DELETED] {}", object.key);
+                Ok::<_, Report>(())
+            })
+            .buffer_unordered(options.concurrency.max(1))
+            .try_collect::<Vec<_>>()
+            .await?;
+    }
+    println!(
+        "{} index(es) reference {} chunk(s); {} unreferenced chunk(s), {} bytes, {}; {} unreferenced chunk(s) within the grace period kept",
+        indexes.len(),
+        referenced.len(),
+        unreferenced.len(),
+        bytes,
+        if options.dry_run { "would be deleted" } else { "deleted" },
+        recent
+    );
+    Ok(unreferenced.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::MockRunner;
+    use std::sync::Arc;
+
+    async fn collect(dry_run: bool, pending: &'static str) -> (usize, Vec<String>) {
+        let listing = r#"{"Contents":[
+            {"Key":"surrealdb/app/main/2025-01-02.04-30.cas.json","Size":90,"LastModified":"2025-01-02T04:31:00+00:00"},
+            {"Key":"chunks/aa/aa01.zst","Size":10,"LastModified":"2025-01-01T04:30:00+00:00"},
+            {"Key":"chunks/bb/bb02.zst","Size":20,"LastModified":"2025-01-01T04:30:00+00:00"},
+            {"Key":"chunks/cc/cc03.zst","Size":30,"LastModified":"2025-01-02T23:00:00+00:00"}
+        ]}"#;
+        let runner = Arc::new(MockRunner::new(move |call| {
+            if call.has_args(&["--prefix", "_cas-pending/"]) {
+                MockRunner::output(0, pending, "")
+            } else if call.has_args(&["list-objects-v2"]) {
+                MockRunner::output(0, listing, "")
+            } else if call.has_args(&["cp", "s3://bk/surrealdb/app/main/2025-01-02.04-30.cas.json", "-"]) {
+                MockRunner::output(0, r#"{"chunks":[{"key":"chunks/aa/aa01.zst","bytes":100},{"key":"chunks/dd/dd04.zst","bytes":5}],"bytes":105}"#, "")
+            } else {
+                MockRunner::output(0, "", "")
+            }
+        }));
+        let options = GcOptions {
+            tools: Tools::mock(runner.clone()),
+            bucket_name: String::from("bk"),
+            s3_access: S3Access::default(),
+            grace: Duration::hours(24),
+            dry_run,
+            concurrency: 2,
+        };
+        let now = "2025-01-03T04:30:00Z".parse::<DateTime<Utc>>().unwrap();
+        let collected = run(&options, now).await.unwrap();
+        let deleted = runner
+            .calls()
+            .into_iter()
+            .filter(|call| call.has_args(&["delete-object"]))
+            .map(|call| call.args.iter().skip_while(|arg| *arg != "--key").nth(1).cloned().unwrap_or_default())
+            .collect();
+        (collected, deleted)
+    }
+
+    #[tokio::test]
+    async fn only_unreferenced_chunks_past_the_grace_period_are_deleted() {
+        // aa01 is referenced and cc03 is within the grace period.
+        let (collected, deleted) = collect(false, "").await;
+        assert_eq!(collected, 1);
+        assert_eq!(deleted, vec!["chunks/bb/bb02.zst"]);
+    }
+
+    #[tokio::test]
+    async fn a_backup_storing_chunks_holds_off_deletion() {
+        // It started after the chunks were listed and may have picked bb02 to reuse.
+        let pending = r#"{"Contents":[{"Key":"_cas-pending/surrealdb/app/main/2025-01-03.04-30.cas.json","Size":0,"LastModified":"2025-01-03T04:30:00+00:00"}]}"#;
+        let (collected, deleted) = collect(false, pending).await;
+        assert_eq!(collected, 0);
+        assert!(deleted.is_empty());
+
+        // One left over from a failed run is collected with the chunks.
+        let stale = r#"{"Contents":[{"Key":"_cas-pending/surrealdb/app/main/2025-01-01.04-30.cas.json","Size":0,"LastModified":"2025-01-01T04:30:00+00:00"}]}"#;
+        let (collected, deleted) = collect(false, stale).await;
+        assert_eq!(collected, 1);
+        assert!(deleted.contains(&String::from("chunks/bb/bb02.zst")));
+        assert!(deleted.contains(&String::from("_cas-pending/surrealdb/app/main/2025-01-01.04-30.cas.json")));
+    }
+
+    #[tokio::test]
+    async fn dry_run_deletes_nothing() {
+        let (collected, deleted) = collect(true, "").await;
+        assert_eq!(collected, 1);
+        assert!(deleted.is_empty());
+    }
+}
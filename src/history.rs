@@ -0,0 +1,191 @@
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{Report, WrapErr};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tracing::info;
+
+use crate::output::{OutputFormat, Rows};
+use crate::s3::{self, S3Access};
+use crate::status;
+use crate::summary::RunSummary;
+use crate::tools::Tools;
+
+/// Where run records are kept in the backup bucket. Untagged, they match no lifecycle rule.
+pub const PREFIX: &str = "_history/";
+
+/// One backup run, stored as a small JSON object of its own.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RunRecord {
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+    pub command: String,
+    pub success: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>,
+    pub keys: Vec<String>,
+    pub bytes: u64,
+    /// Size of the export before compression, where the run measured it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_bytes: Option<u64>,
+    pub version: String,
+}
+
+impl RunRecord {
+    pub fn new(summary: &RunSummary, started: DateTime<Utc>, finished: DateTime<Utc>) -> Self {
+        RunRecord {
+            started,
+            finished,
+            command: summary.command.clone(),
+            success: summary.success,
+            error_code: summary.error_code.map(String::from),
+            keys: summary.storage_keys.clone(),
+            bytes: summary.bytes,
+            raw_bytes: summary.raw_bytes,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+
+    /// Sorts by start time; the suffix keeps `run-all --parallel` jobs apart.
+    pub fn key(&self) -> String {
+        static WRITTEN: AtomicUsize = AtomicUsize::new(0);
+        format!(
+            "{}{}.{}.{}-{}.json",
+            PREFIX,
+            self.started.format("%Y-%m-%dT%H-%M-%SZ"),
+            self.command,
+            std::process::id(),
+            WRITTEN.fetch_add(1, Ordering::Relaxed)
+        )
+    }
+}
+
+/// Stores `record` under [`PREFIX`].
+pub async fn append(tools: &Tools, s3_access: &S3Access, bucket_name: &str, record: &RunRecord) -> Result<String, Report> {
+    let key = record.key();
+    let body = std::env::temp_dir().join(format!("btagger-{}", key.replace('/', "-")));
+    tokio::fs::write(&body, serde_json::to_vec(record)?)
+        .await
+        .wrap_err_with(|| format!("Unable to write {}", body.display()))?;
+    let stored = s3::put_object(tools, s3_access, bucket_name, &key, &body, None).await;
+    let _ = tokio::fs::remove_file(&body).await;
+    stored?;
+    info!(target: "run_history", bucket = bucket_name, key, "Run recorded");
+    Ok(key)
+}
+
+/// Which run records to show.
+pub struct HistoryOptions {
+    pub tools: Tools,
+    pub bucket_name: String,
+    pub s3_access: S3Access,
+    /// How many of the newest runs.
+    pub last: usize,
+    /// JSON prints one record per line.
+    pub format: OutputFormat,
+    pub concurrency: usize,
+}
+
+/// Prints the newest `last` run records, oldest first. Returns how many were shown.
+pub async fn run(options: &HistoryOptions) -> Result<usize, Report> {
+    let mut keys = s3::list_objects(&options.tools, &options.s3_access, &options.bucket_name, PREFIX)
+        .await?
+        .into_iter()
+        .map(|object| object.key)
+        .collect::<Vec<_>>();
+    keys.sort();
+    let keys = &keys[keys.len().saturating_sub(options.last)..];
+    let records = stream::iter(keys)
+        .map(|key| async move {
+            let body = s3::read_object(&options.tools, &options.s3_access, &options.bucket_name, key).await?;
+            serde_json::from_slice::<RunRecord>(&body).wrap_err_with(|| format!("{} is not a run record", key))
+        })
+        .buffered(options.concurrency.max(1))
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    match options.format {
+        OutputFormat::Table => {
+            println!("{:<16}  {:<9}  {:<7}  {:>12}  {:<8}  KEY", "STARTED (UTC)", "COMMAND", "RESULT", "BYTES", "DURATION");
+            for record in &records {
+                println!("{}", line(record));
+            }
+        }
+        OutputFormat::Json => {
+            for record in &records {
+                println!("{}", serde_json::to_string(record)?);
+            }
+        }
+        format => println!("{}", rows(&records).render(format)),
+    }
+    Ok(records.len())
+}
+
+fn result(record: &RunRecord) -> &str {
+    match (record.success, &record.error_code) {
+        (true, _) => "ok",
+        (false, Some(code)) => code.as_str(),
+        (false, None) => "failed",
+    }
+}
+
+fn line(record: &RunRecord) -> String {
+    format!(
+        "{}  {:<9}  {:<7}  {:>12}  {:<8}  {}",
+        record.started.format("%Y-%m-%d %H:%M"),
+        record.command,
+        result(record),
+        record.bytes,
+        status::human(record.finished - record.started),
+        if record.keys.is_empty() { String::from("-") } else { record.keys.join(" ") }
+    )
+}
+
+/// The records with full start and finish times, for CSV and Markdown.
+fn rows(records: &[RunRecord]) -> Rows {
+    let mut rows = Rows::new(&["started", "finished", "command", "result", "bytes", "duration", "keys", "version"]);
+    for record in records {
+        rows.push(vec![
+            record.started.to_rfc3339(),
+            record.finished.to_rfc3339(),
+            record.command.clone(),
+            result(record).to_string(),
+            record.bytes.to_string(),
+            status::human(record.finished - record.started),
+            record.keys.join(" "),
+            record.version.clone(),
+        ]);
+    }
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn records_sort_by_start_and_read_back_as_lines() {
+        let at = |minute| Utc.with_ymd_and_hms(2025, 1, 1, 4, minute, 0).unwrap();
+        let record = RunRecord {
+            started: at(30),
+            finished: at(42),
+            command: String::from("tikv"),
+            success: false,
+            error_code: Some(String::from("tagging")),
+            keys: vec![String::from("tikv/2025-01-01.04-30")],
+            bytes: 2048,
+            raw_bytes: None,
+            version: String::from("0.1.0"),
+        };
+        let key = record.key();
+        assert!(key.starts_with("_history/2025-01-01T04-30-00Z.tikv."), "{}", key);
+        assert!(key < RunRecord { started: at(31), ..record.clone() }.key());
+        assert_eq!(serde_json::from_str::<RunRecord>(&serde_json::to_string(&record).unwrap()).unwrap(), record);
+        assert_eq!(line(&record), "2025-01-01 04:30  tikv       tagging          2048  0h12m     tikv/2025-01-01.04-30");
+        assert_eq!(
+            rows(&[record]).render(OutputFormat::Csv).lines().nth(1),
+            Some("2025-01-01T04:30:00+00:00,2025-01-01T04:42:00+00:00,tikv,tagging,2048,0h12m,tikv/2025-01-01.04-30,0.1.0")
+        );
+    }
+}
@@ -0,0 +1,33 @@
+use color_eyre::eyre::{eyre, Report, WrapErr};
+use tokio::process::Command;
+use tracing::info;
+
+use crate::process;
+
+/// Runs a user-supplied hook through the platform shell with `env` added to its environment,
+/// failing when it cannot be started or exits unsuccessfully. Its output goes to stderr, as
+/// stdout is reserved for the run summary.
+pub async fn run(name: &str, command_line: &str, env: &[(&str, String)]) -> Result<(), Report> {
+    #[cfg(windows)]
+    let mut command = {
+        let mut command = Command::new("cmd");
+        command.arg("/C").arg(command_line);
+        command
+    };
+    #[cfg(not(windows))]
+    let mut command = {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(command_line);
+        command
+    };
+    command.envs(env.iter().map(|(key, value)| (key, value))).stdout(std::io::stderr());
+    let status = command
+        .status()
+        .await
+        .wrap_err_with(|| format!("Unable to start the {} hook", name))?;
+    info!(target: "hook", name, success = status.success(), exit_code = status.code(), signal = process::signal(status).map(process::signal_name));
+    if !status.success() {
+        return Err(eyre!("The {} hook exited with {}", name, status));
+    }
+    Ok(())
+}
@@ -0,0 +1,233 @@
+use clap::ValueEnum;
+use color_eyre::eyre::{eyre, Report, WrapErr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::process::Command;
+use tracing::info;
+
+use crate::archive::{directory_size, ArchiveUpload};
+use crate::compression::Compression;
+use crate::failure::Failure;
+use crate::multipart::{ObjectMetadata, Split, Spool};
+use crate::process::{self, before_deadline};
+use crate::s3::{self, S3Access};
+use crate::secret::Secret;
+use crate::size::ByteSize;
+use crate::summary::{BackupReport, EmptyBackup, Timings};
+use crate::tools::Tools;
+
+/// Which InfluxDB, and so which backup tool.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InfluxVersion {
+    /// 1.x, backed up with `influxd backup -portable` over the RPC port.
+    V1,
+    /// 2.x, backed up with `influx backup` over the HTTP API.
+    #[default]
+    V2,
+}
+
+impl InfluxVersion {
+    pub fn default_address(self) -> &'static str {
+        match self {
+            InfluxVersion::V1 => "localhost:8088",
+            InfluxVersion::V2 => "http://localhost:8086",
+        }
+    }
+}
+
+/// An InfluxDB server to back up, and where to.
+pub struct InfluxdbOptions {
+    pub tools: Tools,
+    pub bucket_name: String,
+    pub s3_access: S3Access,
+    pub create_bucket: bool,
+    pub version: InfluxVersion,
+    pub address: String,
+    /// API token for 2.x, handed to influx as INFLUX_TOKEN.
+    pub token: Option<Secret>,
+    /// 2.x organization.
+    pub org: Option<String>,
+    /// 1.x database or 2.x bucket; everything when unset.
+    pub database: Option<String>,
+    pub tags: String,
+    pub storage_key: String,
+    pub compression: Compression,
+    pub compression_level: Option<u32>,
+    pub part_size: ByteSize,
+    pub part_retries: u32,
+    pub spool: Option<Spool>,
+    pub split: Option<Split>,
+    pub min_expected_bytes: u64,
+    pub allow_empty: bool,
+    pub concurrency: usize,
+    pub deadline: Option<tokio::time::Instant>,
+    pub timings: Timings,
+}
+
+/// Backs the server up into a scratch directory, then uploads the directory as one archive and
+/// tags it. The scratch directory is removed whatever the outcome.
+pub async fn backup(options: &InfluxdbOptions) -> Result<BackupReport, Report> {
+    // The counter keeps `run-all --parallel` jobs apart.
+    static STARTED: AtomicUsize = AtomicUsize::new(0);
+    let directory = std::env::temp_dir().join(format!("btagger-influxdb-{}-{}", std::process::id(), STARTED.fetch_add(1, Ordering::Relaxed)));
+    let result = backup_through(options, &directory).await;
+    if let Err(err) = tokio::fs::remove_dir_all(&directory).await {
+        if err.kind() != std::io::ErrorKind::NotFound {
+            tracing::warn!(target: "influxdb", directory = %directory.display(), error = %err, "Unable to remove the backup directory");
+        }
+    }
+    result
+}
+
+async fn backup_through(options: &InfluxdbOptions, directory: &Path) -> Result<BackupReport, Report> {
+    let metadata = ObjectMetadata::now();
+    let (tools, s3_access, bucket_name, key) = (&options.tools, &options.s3_access, &options.bucket_name, &options.storage_key);
+    let timings = &options.timings;
+    if options.create_bucket {
+        let created = timings.time("bucket_ensure", s3::ensure_bucket(tools, s3_access, bucket_name)).await.wrap_err(Failure::Upload)?;
+        info!(target: "aws_create_bucket_output", bucket = bucket_name, created);
+    }
+
+    tokio::fs::create_dir_all(directory)
+        .await
+        .wrap_err_with(|| format!("Unable to create {}", directory.display()))?;
+    let stage = match options.version {
+        InfluxVersion::V1 => "influxd backup",
+        InfluxVersion::V2 => "influx backup",
+    };
+    let output = timings.time("export", before_deadline(options.deadline, stage, tools.runner.run(backup_command(options, directory), None)))
+        .await
+        .and_then(process::succeeded)
+        .wrap_err(Failure::Export { stage: String::from(stage) })?;
+    info!(target: "influxdb_backup_output", stderr = process::captured("influx-backup", &output.stderr));
+
+    let (files, raw_bytes) = directory_size(directory).await?;
+    if files == 0 && !options.allow_empty {
+        return Err(Report::new(EmptyBackup(format!("InfluxDB backup of {}", options.database.as_deref().unwrap_or("every database")))));
+    }
+    // Checked before uploading, so nothing needs removing.
+    if raw_bytes < options.min_expected_bytes {
+        return Err(eyre!("Backup is only {} bytes, below --min-expected-bytes {}", raw_bytes, options.min_expected_bytes)
+            .wrap_err(Failure::Export { stage: String::from("size check") }));
+    }
+
+    let archive = ArchiveUpload {
+        tools,
+        s3_access,
+        bucket_name,
+        compression: options.compression,
+        compression_level: options.compression_level,
+        part_size: options.part_size,
+        part_retries: options.part_retries,
+        spool: options.spool.clone(),
+        split: options.split.clone(),
+        concurrency: options.concurrency,
+        deadline: options.deadline,
+        metadata,
+    };
+    let bytes = timings.time("upload", archive.upload(directory, &[PathBuf::from(".")], key)).await?;
+    info!(target: "backup_size", key, raw_bytes, bytes, files);
+
+    timings.time("tagging", s3::put_object_tagging(tools, s3_access, bucket_name, key, &options.tags)).await.wrap_err(Failure::Tagging)?;
+    info!(target: "aws_put_object_tagging_output", key);
+    Ok(BackupReport {
+        storage_key: key.clone(),
+        bytes,
+        raw_bytes: Some(raw_bytes),
+        success: true,
+        stderr_tail: None,
+        tikv_stats: None,
+        signal: None,
+    })
+}
+
+fn backup_command(options: &InfluxdbOptions, directory: &Path) -> Command {
+    let mut command = match options.version {
+        InfluxVersion::V1 => {
+            let mut command = Command::new(&options.tools.influxd);
+            command.arg("backup").arg("-portable").arg("-host").arg(&options.address);
+            if let Some(database) = &options.database {
+                command.arg("-database").arg(database);
+            }
+            command
+        }
+        InfluxVersion::V2 => {
+            let mut command = Command::new(&options.tools.influx);
+            command.arg("backup").arg("--host").arg(&options.address);
+            if let Some(org) = &options.org {
+                command.arg("--org").arg(org);
+            }
+            if let Some(database) = &options.database {
+                command.arg("--bucket").arg(database);
+            }
+            if let Some(token) = &options.token {
+                // Read by influx, and kept out of process listings.
+                command.env("INFLUX_TOKEN", token.expose());
+            }
+            command
+        }
+    };
+    command.kill_on_drop(true).arg(directory);
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::MockRunner;
+    use std::sync::Arc;
+
+    fn options(runner: Arc<MockRunner>, version: InfluxVersion) -> InfluxdbOptions {
+        InfluxdbOptions {
+            tools: Tools::mock(runner),
+            bucket_name: String::from("bk"),
+            s3_access: S3Access::default(),
+            create_bucket: false,
+            version,
+            address: String::from(version.default_address()),
+            token: Some(Secret::new("influx-token")),
+            org: Some(String::from("acme")),
+            database: Some(String::from("telemetry")),
+            tags: String::from(r#"{"TagSet":[]}"#),
+            storage_key: String::from("influxdb/2025-01-01.04-30.tar.zst"),
+            compression: Compression::Zstd,
+            compression_level: None,
+            part_size: ByteSize(8 * 1024 * 1024),
+            part_retries: 0,
+            spool: None,
+            split: None,
+            min_expected_bytes: 0,
+            allow_empty: false,
+            concurrency: 4,
+            deadline: None,
+            timings: Timings::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_backup_is_refused_and_the_directory_removed() {
+        let runner = Arc::new(MockRunner::new(|_| MockRunner::output(0, "", "")));
+        let err = backup(&options(runner.clone(), InfluxVersion::V2)).await.unwrap_err();
+        assert_eq!(Failure::of(&err).code(), "empty");
+
+        let calls = runner.calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].program, "influx");
+        assert!(calls[0].has_args(&["backup", "--host", "http://localhost:8086", "--org", "acme", "--bucket", "telemetry"]));
+        assert_eq!(calls[0].env("INFLUX_TOKEN"), Some("influx-token"));
+        let directory = PathBuf::from(calls[0].args.last().unwrap());
+        assert!(!directory.exists());
+    }
+
+    #[tokio::test]
+    async fn v1_backs_up_over_the_rpc_port() {
+        let runner = Arc::new(MockRunner::new(|_| MockRunner::output(1, "", "backup: dial tcp: connection refused")));
+        let err = backup(&options(runner.clone(), InfluxVersion::V1)).await.unwrap_err();
+        assert_eq!(Failure::of(&err).stage(), Some("influxd backup"));
+
+        let calls = runner.calls();
+        assert_eq!(calls[0].program, "influxd");
+        assert!(calls[0].has_args(&["backup", "-portable", "-host", "localhost:8088", "-database", "telemetry"]));
+        assert_eq!(calls[0].env("INFLUX_TOKEN"), None);
+    }
+}
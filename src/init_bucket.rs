@@ -0,0 +1,130 @@
+use clap::ValueEnum;
+use color_eyre::eyre::{eyre, Report, WrapErr};
+use serde_json::json;
+use tracing::info;
+
+use crate::lifecycle;
+use crate::s3::{self, Aws, S3Access};
+use crate::simulate::RetentionPolicy;
+use crate::tags::TagMerge;
+use crate::tools::Tools;
+
+/// Object Lock retention mode; governance can be lifted by users with special permission.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ObjectLockMode {
+    Governance,
+    Compliance,
+}
+
+impl ObjectLockMode {
+    fn as_str(self) -> &'static str {
+        match self {
+            ObjectLockMode::Governance => "GOVERNANCE",
+            ObjectLockMode::Compliance => "COMPLIANCE",
+        }
+    }
+}
+
+/// How to set up a new backup bucket.
+pub struct InitBucketOptions {
+    pub tools: Tools,
+    pub bucket_name: String,
+    pub s3_access: S3Access,
+    pub retention: RetentionPolicy,
+    /// How the backups are tagged, which the lifecycle rules filter on.
+    pub tag_merge: TagMerge,
+    /// Without it the bucket must already exist.
+    pub create_bucket: bool,
+    /// Default Object Lock retention for new objects, in days.
+    pub object_lock: Option<(ObjectLockMode, u32)>,
+    /// Deny deleting objects, versions and the bucket itself to everyone but `allow_delete_by`.
+    pub deny_deletes: bool,
+    /// IAM principal ARNs still allowed to delete, e.g. the role running `btagger delete`.
+    pub allow_delete_by: Vec<String>,
+}
+
+/// Creates the bucket unless it exists or `create_bucket` is off, then enables versioning and
+/// applies the lifecycle configuration, Object Lock defaults and delete policy. Steps the store's
+/// `--s3-compat-mode` does not support are skipped. Each step is printed as it completes.
+pub async fn run(options: &InitBucketOptions) -> Result<(), Report> {
+    let (tools, s3_access, bucket_name) = (&options.tools, &options.s3_access, options.bucket_name.as_str());
+
+    if options.create_bucket && !s3_access.compat.creates_buckets() {
+        s3::ensure_bucket(tools, s3_access, bucket_name).await?;
+        println!("[EXISTS] {}", bucket_name);
+    } else if options.create_bucket {
+        let mut create = Aws::new(tools, s3_access).create_bucket(bucket_name);
+        // A new bucket gets Object Lock at creation; an existing one has it switched on below, once
+        // versioning is enabled.
+        if options.object_lock.is_some() {
+            create.arg("--object-lock-enabled-for-bucket");
+        }
+        let created = tools.runner.run(create, None).await.wrap_err("failed to execute process")?;
+        let stderr = String::from_utf8_lossy(&created.stderr);
+        if created.status.success() {
+            println!("[CREATED] {}", bucket_name);
+        } else if s3::bucket_already_exists(&stderr) {
+            info!(target: "aws_create_bucket_output", error = stderr.trim(), "Bucket not created");
+            println!("[EXISTS] {}", bucket_name);
+        } else {
+            return Err(eyre!("create-bucket failed for {}: {}", bucket_name, stderr.trim()));
+        }
+    }
+
+    if s3_access.compat.versioning() {
+        let versioning = json!({"Status": "Enabled"}).to_string();
+        s3::put_bucket_configuration(tools, s3_access, bucket_name, "put-bucket-versioning", "--versioning-configuration", &versioning).await?;
+        println!("[VERSIONING] enabled");
+    } else {
+        println!("[VERSIONING] skipped, not supported by the store");
+    }
+
+    // The rules expire backups by their period tags, which such stores cannot filter on.
+    if s3_access.compat.tag_lifecycle() {
+        let lifecycle = lifecycle::configuration(&options.retention, options.tag_merge).to_string();
+        s3::put_bucket_configuration(tools, s3_access, bucket_name, "put-bucket-lifecycle-configuration", "--lifecycle-configuration", &lifecycle)
+            .await?;
+        println!("[LIFECYCLE] applied");
+    } else {
+        println!("[LIFECYCLE] skipped, the store cannot expire by tag; schedule `btagger retain --enforce delete` instead");
+    }
+
+    if let Some((mode, days)) = options.object_lock {
+        let object_lock = json!({
+            "ObjectLockEnabled": "Enabled",
+            "Rule": {"DefaultRetention": {"Mode": mode.as_str(), "Days": days}},
+        })
+        .to_string();
+        s3::put_bucket_configuration(tools, s3_access, bucket_name, "put-object-lock-configuration", "--object-lock-configuration", &object_lock)
+            .await?;
+        println!("[OBJECT LOCK] {} for {} days", mode.as_str(), days);
+    }
+
+    if options.deny_deletes {
+        let policy = delete_policy(bucket_name, &options.allow_delete_by).to_string();
+        s3::put_bucket_configuration(tools, s3_access, bucket_name, "put-bucket-policy", "--policy", &policy).await?;
+        println!("[POLICY] deletes denied to all but {} principal(s)", options.allow_delete_by.len());
+    }
+    info!(target: "init_bucket", bucket = bucket_name, object_lock = options.object_lock.is_some(), deny_deletes = options.deny_deletes);
+    Ok(())
+}
+
+/// A bucket policy denying deletes to every principal not listed. Lifecycle expiration is not
+/// subject to bucket policies and keeps working.
+fn delete_policy(bucket_name: &str, allow_delete_by: &[String]) -> serde_json::Value {
+    let mut statement = json!({
+        "Sid": "DenyDeletes",
+        "Effect": "Deny",
+        "Principal": "*",
+        "Action": [
+            "s3:DeleteObject",
+            "s3:DeleteObjectVersion",
+            "s3:DeleteBucket",
+        ],
+        "Resource": [format!("arn:aws:s3:::{}", bucket_name), format!("arn:aws:s3:::{}/*", bucket_name)],
+    });
+    if !allow_delete_by.is_empty() {
+        statement["Condition"] = json!({"ArnNotLike": {"aws:PrincipalArn": allow_delete_by}});
+    }
+    json!({"Version": "2012-10-17", "Statement": [statement]})
+}
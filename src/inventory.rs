@@ -0,0 +1,211 @@
+use clap::ValueEnum;
+use color_eyre::eyre::Report;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use tracing::info;
+
+use crate::output::{OutputFormat, Rows};
+use crate::s3::{self, S3Access};
+use crate::tags::{self, Tag};
+use crate::tools::Tools;
+
+/// How `btagger inventory` prints the breakdown.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InventoryFormat {
+    /// Aligned columns, one section per tier and per engine.
+    Table,
+    Json,
+    /// One line per engine and tier.
+    Csv,
+    /// The tier and engine sections as Markdown tables.
+    Markdown,
+    /// Prometheus text exposition, e.g. for node_exporter's textfile collector.
+    Prometheus,
+}
+
+/// Which objects to count and how to print them.
+pub struct InventoryOptions {
+    pub tools: Tools,
+    pub bucket_name: String,
+    pub s3_access: S3Access,
+    /// Only objects under this prefix; the whole bucket when empty.
+    pub prefix: String,
+    pub format: InventoryFormat,
+    pub concurrency: usize,
+}
+
+/// Objects and their total size.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Totals {
+    pub objects: u64,
+    pub bytes: u64,
+}
+
+impl Totals {
+    fn add(&mut self, bytes: u64) {
+        self.objects += 1;
+        self.bytes += bytes;
+    }
+}
+
+/// Totals per engine prefix and tier.
+#[derive(Debug, Default)]
+pub struct Inventory {
+    pub cells: BTreeMap<(String, &'static str), Totals>,
+}
+
+impl Inventory {
+    fn by<F: Fn(&(String, &'static str)) -> String>(&self, label: F) -> BTreeMap<String, Totals> {
+        let mut sums = BTreeMap::<String, Totals>::new();
+        for (cell, totals) in &self.cells {
+            let sum = sums.entry(label(cell)).or_default();
+            sum.objects += totals.objects;
+            sum.bytes += totals.bytes;
+        }
+        sums
+    }
+
+    pub fn tiers(&self) -> BTreeMap<String, Totals> {
+        self.by(|(_, tier)| tier.to_string())
+    }
+
+    pub fn engines(&self) -> BTreeMap<String, Totals> {
+        self.by(|(engine, _)| engine.clone())
+    }
+
+    pub fn total(&self) -> Totals {
+        self.cells.values().fold(Totals::default(), |sum, totals| Totals { objects: sum.objects + totals.objects, bytes: sum.bytes + totals.bytes })
+    }
+}
+
+/// Reads the tags of every object under the prefix and prints the objects and bytes per tier and
+/// per engine.
+pub async fn run(options: &InventoryOptions) -> Result<Inventory, Report> {
+    let objects = s3::list_objects(&options.tools, &options.s3_access, &options.bucket_name, &options.prefix).await?;
+    let tagged = stream::iter(&objects)
+        .map(|object| async move {
+            let tags = s3::object_tags(&options.tools, &options.s3_access, &options.bucket_name, &object.key).await?;
+            Ok::<_, Report>((object, tier(&tags)))
+        })
+        .buffer_unordered(options.concurrency.max(1))
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    let mut inventory = Inventory::default();
+    for (object, tier) in tagged {
+        inventory.cells.entry((engine(&object.key).to_string(), tier)).or_default().add(object.size);
+    }
+    let total = inventory.total();
+    info!(target: "inventory", bucket = options.bucket_name, prefix = options.prefix, objects = total.objects, bytes = total.bytes);
+    println!("{}", render(&inventory, &options.bucket_name, options.format));
+    Ok(inventory)
+}
+
+/// The tier that keeps an object longest, which is what its storage is paid for; `untagged` when
+/// it carries none. A `retention=<tier>` tag from `--tag-merge retention` counts as that tier.
+pub fn tier(tags: &[Tag]) -> &'static str {
+    tags::tiers(tags).last().copied().unwrap_or("untagged")
+}
+
+/// The first path segment of `key`, e.g. `tikv` or `surrealdb`.
+fn engine(key: &str) -> &str {
+    key.split_once('/').map_or("-", |(engine, _)| engine)
+}
+
+fn render(inventory: &Inventory, bucket_name: &str, format: InventoryFormat) -> String {
+    match format {
+        InventoryFormat::Table => {
+            let total = inventory.total();
+            let mut lines = vec![format!("{:<16} {:>10} {:>16}", "TIER", "OBJECTS", "BYTES")];
+            lines.extend(inventory.tiers().iter().map(|(tier, totals)| format!("{:<16} {:>10} {:>16}", tier, totals.objects, totals.bytes)));
+            lines.push(String::new());
+            lines.push(format!("{:<16} {:>10} {:>16}", "ENGINE", "OBJECTS", "BYTES"));
+            lines.extend(inventory.engines().iter().map(|(engine, totals)| format!("{:<16} {:>10} {:>16}", engine, totals.objects, totals.bytes)));
+            lines.push(String::new());
+            lines.push(format!("{:<16} {:>10} {:>16}", "TOTAL", total.objects, total.bytes));
+            lines.join("\n")
+        }
+        InventoryFormat::Json => {
+            let section = |sums: BTreeMap<String, Totals>| {
+                sums.into_iter()
+                    .map(|(name, totals)| (name, json!({"objects": totals.objects, "bytes": totals.bytes})))
+                    .collect::<serde_json::Map<_, Value>>()
+            };
+            let total = inventory.total();
+            json!({
+                "bucket": bucket_name,
+                "objects": total.objects,
+                "bytes": total.bytes,
+                "tiers": section(inventory.tiers()),
+                "engines": section(inventory.engines()),
+            })
+            .to_string()
+        }
+        InventoryFormat::Csv => {
+            let mut rows = Rows::new(&["engine", "tier", "objects", "bytes"]);
+            for ((engine, tier), totals) in &inventory.cells {
+                rows.push(vec![engine.clone(), tier.to_string(), totals.objects.to_string(), totals.bytes.to_string()]);
+            }
+            rows.render(OutputFormat::Csv)
+        }
+        InventoryFormat::Markdown => {
+            let section = |column: &'static str, sums: BTreeMap<String, Totals>| {
+                let mut rows = Rows::new(&[column, "objects", "bytes"]);
+                for (name, totals) in sums.into_iter().chain([(String::from("**total**"), inventory.total())]) {
+                    rows.push(vec![name, totals.objects.to_string(), totals.bytes.to_string()]);
+                }
+                rows.render(OutputFormat::Markdown)
+            };
+            format!("{}\n\n{}", section("tier", inventory.tiers()), section("engine", inventory.engines()))
+        }
+        // One series per engine and tier; sum by either label for the breakdowns.
+        InventoryFormat::Prometheus => {
+            let label = |value: &str| value.replace('\\', "\\\\").replace('"', "\\\"");
+            let mut lines = Vec::new();
+            for (metric, help, value) in [
+                ("btagger_inventory_objects", "Objects in the backup bucket.", (|totals: &Totals| totals.objects) as fn(&Totals) -> u64),
+                ("btagger_inventory_bytes", "Bytes stored in the backup bucket.", |totals: &Totals| totals.bytes),
+            ] {
+                lines.push(format!("# HELP {} {}", metric, help));
+                lines.push(format!("# TYPE {} gauge", metric));
+                for ((engine, tier), totals) in &inventory.cells {
+                    lines.push(format!("{}{{bucket=\"{}\",engine=\"{}\",tier=\"{}\"}} {}", metric, label(bucket_name), label(engine), tier, value(totals)));
+                }
+            }
+            lines.join("\n")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn objects_count_once_under_their_longest_tier() {
+        let tags = |keys: &[&str]| keys.iter().map(|key| key.parse::<Tag>().unwrap()).collect::<Vec<_>>();
+        assert_eq!(tier(&tags(&["standard", "nightly", "monthly"])), "monthly");
+        assert_eq!(tier(&tags(&["standard", "yearly=0"])), "standard");
+        assert_eq!(tier(&tags(&["team=data"])), "untagged");
+        assert_eq!(tier(&tags(&["retention=quarterly"])), "quarterly");
+        assert_eq!(engine("surrealdb/ns/db/2025.zst"), "surrealdb");
+        assert_eq!(engine("loose.zst"), "-");
+
+        let mut inventory = Inventory::default();
+        inventory.cells.insert((String::from("tikv"), "nightly"), Totals { objects: 2, bytes: 300 });
+        inventory.cells.insert((String::from("surrealdb"), "nightly"), Totals { objects: 1, bytes: 50 });
+        inventory.cells.insert((String::from("tikv"), "yearly"), Totals { objects: 1, bytes: 100 });
+        assert_eq!(inventory.tiers()["nightly"], Totals { objects: 3, bytes: 350 });
+        assert_eq!(inventory.engines()["tikv"], Totals { objects: 3, bytes: 400 });
+
+        let json: Value = serde_json::from_str(&render(&inventory, "bk", InventoryFormat::Json)).unwrap();
+        assert_eq!(json["bytes"], 450);
+        assert_eq!(json["tiers"]["yearly"]["objects"], 1);
+        let prometheus = render(&inventory, "bk", InventoryFormat::Prometheus);
+        assert!(prometheus.contains("btagger_inventory_bytes{bucket=\"bk\",engine=\"tikv\",tier=\"nightly\"} 300\n"), "{}", prometheus);
+        assert!(prometheus.starts_with("# HELP btagger_inventory_objects"));
+        assert_eq!(render(&inventory, "bk", InventoryFormat::Csv), "engine,tier,objects,bytes\nsurrealdb,nightly,1,50\ntikv,nightly,2,300\ntikv,yearly,1,100");
+        assert!(render(&inventory, "bk", InventoryFormat::Markdown).contains("| nightly | 3 | 350 |\n| yearly | 1 | 100 |\n| **total** | 4 | 450 |\n\n| engine | objects | bytes |"));
+    }
+}
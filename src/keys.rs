@@ -0,0 +1,341 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, TimeZone, Utc};
+use clap::ValueEnum;
+use color_eyre::eyre::{eyre, Report};
+use std::str::FromStr;
+
+/// strftime format of the `{timestamp}` variable. A leading `+`, as written for `date +FORMAT`,
+/// is accepted and dropped.
+#[derive(Clone, Debug)]
+pub struct TimestampFormat(String);
+
+impl FromStr for TimestampFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let format = s.strip_prefix('+').unwrap_or(s);
+        if format.is_empty() {
+            return Err("timestamp format is empty".to_string());
+        }
+        check_strftime(format)?;
+        Ok(TimestampFormat(format.to_string()))
+    }
+}
+
+impl std::fmt::Display for TimestampFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl TimestampFormat {
+    pub fn format(&self, time: DateTime<Utc>) -> String {
+        time.format(&self.0).to_string()
+    }
+}
+
+/// Characters AWS lists as safe in object keys, plus `/` to separate levels.
+fn is_safe_key_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!-_.*'()/".contains(c)
+}
+
+/// Rejects formats chrono cannot parse, and those whose output (checked on a sample time) holds
+/// characters that are not safe in S3 keys, such as the spaces and colons of `%c` or `%T`.
+fn check_strftime(format: &str) -> Result<(), String> {
+    if StrftimeItems::new(format).any(|item| item == Item::Error) {
+        return Err(format!("'{}' is not a valid strftime format", format));
+    }
+    let sample = Utc.with_ymd_and_hms(2000, 12, 31, 23, 59, 59).unwrap().format(format).to_string();
+    if let Some(c) = sample.chars().find(|c| !is_safe_key_char(*c)) {
+        return Err(format!("'{}' produces '{}', and {:?} is not safe in S3 keys", format, sample, c));
+    }
+    Ok(())
+}
+
+/// Layout of storage keys, e.g. `{engine}/{cluster}/{namespace}/{date}/{time}`. Literal text may
+/// also hold strftime sequences such as `%Y`, which are formatted with the backup time.
+#[derive(Clone, Debug)]
+pub struct KeyTemplate {
+    source: String,
+    parts: Vec<Part>,
+}
+
+#[derive(Clone, Debug)]
+enum Part {
+    Literal(String),
+    Variable(Variable),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Variable {
+    Engine,
+    Cluster,
+    Namespace,
+    Database,
+    Date,
+    Time,
+    Timestamp,
+}
+
+impl Variable {
+    const ALL: [(&'static str, Variable); 7] = [
+        ("engine", Variable::Engine),
+        ("cluster", Variable::Cluster),
+        ("namespace", Variable::Namespace),
+        ("database", Variable::Database),
+        ("date", Variable::Date),
+        ("time", Variable::Time),
+        ("timestamp", Variable::Timestamp),
+    ];
+
+    fn name(self) -> &'static str {
+        Variable::ALL.iter().find(|(_, variable)| *variable == self).map(|(name, _)| *name).unwrap_or_default()
+    }
+}
+
+/// Built-in SurrealDB key layout, for when no --key-template is given.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyLayout {
+    /// `surrealdb/<namespace>/<timestamp>`, as written before databases were part of the key.
+    Namespace,
+    /// `surrealdb/<namespace>/<database>/<timestamp>`.
+    #[default]
+    Database,
+}
+
+/// Values of the non-time variables for one backup.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KeyVars<'a> {
+    pub engine: &'a str,
+    pub cluster: Option<&'a str>,
+    pub namespace: Option<&'a str>,
+    pub database: Option<&'a str>,
+}
+
+impl FromStr for KeyTemplate {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut rest = source;
+        while let Some(open) = rest.find('{') {
+            if open > 0 {
+                parts.push(Part::Literal(rest[..open].to_string()));
+            }
+            let close = rest[open..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed '{{' in key template '{}'", source))?;
+            let name = &rest[open + 1..open + close];
+            let variable = Variable::ALL
+                .iter()
+                .find(|(known, _)| *known == name)
+                .map(|(_, variable)| *variable)
+                .ok_or_else(|| {
+                    format!(
+                        "unknown variable '{{{}}}' in key template; use {}",
+                        name,
+                        Variable::ALL.iter().map(|(name, _)| format!("{{{}}}", name)).collect::<Vec<_>>().join(", ")
+                    )
+                })?;
+            parts.push(Part::Variable(variable));
+            rest = &rest[open + close + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+        for part in &parts {
+            if let Part::Literal(text) = part {
+                check_strftime(text).map_err(|err| format!("key template '{}': {}", source, err))?;
+            }
+        }
+        if !parts.iter().any(is_time_dependent) {
+            return Err(format!("key template '{}' has no date or time in it, so every backup would get the same key", source));
+        }
+        Ok(KeyTemplate { source: source.to_string(), parts })
+    }
+}
+
+impl std::fmt::Display for KeyTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl KeyTemplate {
+    /// The built-in layout of `vars.engine`: `surrealdb/<namespace>[/<database>]/<timestamp>`,
+    /// `<engine>/<database>/<timestamp>` for ClickHouse, Neo4j and SQLite, and for an InfluxDB bucket,
+    /// CockroachDB database, Qdrant collection or NATS stream when one is given, and
+    /// `<engine>/<timestamp>` otherwise.
+    pub fn default_for(layout: KeyLayout, vars: &KeyVars) -> KeyTemplate {
+        let source = match (vars.engine, layout) {
+            ("surrealdb", KeyLayout::Namespace) => "surrealdb/{namespace}/{timestamp}",
+            ("surrealdb", KeyLayout::Database) => "surrealdb/{namespace}/{database}/{timestamp}",
+            ("clickhouse", _) => "clickhouse/{database}/{timestamp}",
+            ("neo4j", _) => "neo4j/{database}/{timestamp}",
+            ("sqlite", _) => "sqlite/{database}/{timestamp}",
+            ("influxdb" | "cockroach" | "qdrant" | "nats", _) if vars.database.is_some() => "{engine}/{database}/{timestamp}",
+            _ => "{engine}/{timestamp}",
+        };
+        source.parse().expect("built-in key templates are valid")
+    }
+
+    /// The key of a backup taken at `time`; `{timestamp}` uses `timestamp_format`.
+    pub fn render(&self, vars: &KeyVars, time: DateTime<Utc>, timestamp_format: &TimestampFormat) -> Result<String, Report> {
+        let mut key = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => key.push_str(&time.format(text).to_string()),
+                Part::Variable(Variable::Date) => key.push_str(&time.format("%Y-%m-%d").to_string()),
+                Part::Variable(Variable::Time) => key.push_str(&time.format("%H-%M").to_string()),
+                Part::Variable(Variable::Timestamp) => key.push_str(&timestamp_format.format(time)),
+                Part::Variable(variable) => key.push_str(self.value(*variable, vars)?),
+            }
+        }
+        Ok(key)
+    }
+
+    /// The fixed start of every key this template renders for `vars`, for listing them.
+    pub fn prefix(&self, vars: &KeyVars) -> Result<String, Report> {
+        let mut prefix = String::new();
+        for part in self.parts.iter().take_while(|part| !is_time_dependent(part)) {
+            match part {
+                Part::Literal(text) => prefix.push_str(text),
+                Part::Variable(variable) => prefix.push_str(self.value(*variable, vars)?),
+            }
+        }
+        Ok(prefix)
+    }
+
+    /// Whether `key` could have been rendered by this template for `vars`. Time-dependent parts
+    /// match anything but nothing, up to wherever the rest of the template matches.
+    pub fn matches(&self, key: &str, vars: &KeyVars) -> bool {
+        let mut fixed = Vec::new();
+        for part in &self.parts {
+            fixed.push(match part {
+                _ if is_time_dependent(part) => None,
+                Part::Literal(text) => Some(text.as_str()),
+                Part::Variable(variable) => match self.value(*variable, vars) {
+                    Ok(value) => Some(value),
+                    Err(_) => return false,
+                },
+            });
+        }
+        matches_parts(&fixed, key, false)
+    }
+
+    fn value<'a>(&self, variable: Variable, vars: &KeyVars<'a>) -> Result<&'a str, Report> {
+        let value = match variable {
+            Variable::Engine => Some(vars.engine),
+            Variable::Cluster => vars.cluster,
+            Variable::Namespace => vars.namespace,
+            Variable::Database => vars.database,
+            _ => None,
+        };
+        value.ok_or_else(|| match variable {
+            Variable::Cluster => eyre!("Key template '{}' uses {{cluster}}, but --cluster is not set", self.source),
+            _ => eyre!("Key template '{}' uses {{{}}}, which {} backups do not have", self.source, variable.name(), vars.engine),
+        })
+    }
+}
+
+/// Whether `rest` is made of `parts`, where `None` stands for a time-dependent part. After one,
+/// every place the next fixed part occurs is tried, since a timestamp may hold the same text.
+fn matches_parts(parts: &[Option<&str>], rest: &str, skipping: bool) -> bool {
+    match parts.split_first() {
+        None => if skipping { !rest.is_empty() } else { rest.is_empty() },
+        Some((None, parts)) => matches_parts(parts, rest, true),
+        Some((Some(fixed), parts)) if skipping => rest.match_indices(fixed).any(|(at, _)| at > 0 && matches_parts(parts, &rest[at + fixed.len()..], false)),
+        Some((Some(fixed), parts)) => rest.strip_prefix(fixed).is_some_and(|rest| matches_parts(parts, rest, false)),
+    }
+}
+
+fn is_time_dependent(part: &Part) -> bool {
+    match part {
+        Part::Literal(text) => text.contains('%'),
+        Part::Variable(variable) => matches!(variable, Variable::Date | Variable::Time | Variable::Timestamp),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> KeyVars<'static> {
+        KeyVars { engine: "surrealdb", cluster: Some("eu"), namespace: Some("app"), database: Some("main") }
+    }
+
+    fn at() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2025, 1, 2, 4, 30, 5).unwrap()
+    }
+
+    #[test]
+    fn rendered_keys_match_their_template() {
+        let timestamp = "+%Y-%m-%d.%H-%M".parse::<TimestampFormat>().unwrap();
+        let template = "{engine}/{cluster}/{namespace}/{date}/{time}".parse::<KeyTemplate>().unwrap();
+        assert_eq!(template.to_string(), "{engine}/{cluster}/{namespace}/{date}/{time}");
+        let key = template.render(&vars(), at(), &timestamp).unwrap();
+        assert_eq!(key, "surrealdb/eu/app/2025-01-02/04-30");
+        assert!(template.matches(&key, &vars()));
+        assert!(!template.matches("surrealdb/us/app/2025-01-02/04-30", &vars()));
+        assert!(!template.matches("surrealdb/eu/app/", &vars()));
+
+        let template = "backups/{database}/%Y/{timestamp}.{namespace}".parse::<KeyTemplate>().unwrap();
+        let key = template.render(&vars(), at(), &timestamp).unwrap();
+        assert_eq!(key, "backups/main/2025/2025-01-02.04-30.app");
+        assert!(template.matches(&key, &vars()));
+        assert!(!template.matches("backups/main/2025/2025-01-02.04-30.other", &vars()));
+
+        let default = KeyTemplate::default_for(KeyLayout::Database, &vars());
+        assert_eq!(default.render(&vars(), at(), &timestamp).unwrap(), "surrealdb/app/main/2025-01-02.04-30");
+        let tikv = KeyVars { engine: "tikv", ..KeyVars::default() };
+        assert_eq!(KeyTemplate::default_for(KeyLayout::Database, &tikv).to_string(), "{engine}/{timestamp}");
+    }
+
+    #[test]
+    fn default_keys_name_the_database_when_one_is_given() {
+        let timestamp = "+%Y-%m-%d.%H-%M".parse::<TimestampFormat>().unwrap();
+        for engine in ["influxdb", "cockroach", "qdrant", "nats"] {
+            let whole = KeyVars { engine, ..KeyVars::default() };
+            let key = KeyTemplate::default_for(KeyLayout::Database, &whole).render(&whole, at(), &timestamp).unwrap();
+            assert_eq!(key, format!("{}/2025-01-02.04-30", engine));
+            let one = KeyVars { database: Some("orders"), ..whole };
+            let key = KeyTemplate::default_for(KeyLayout::Database, &one).render(&one, at(), &timestamp).unwrap();
+            assert_eq!(key, format!("{}/orders/2025-01-02.04-30", engine));
+        }
+    }
+
+    #[test]
+    fn prefixes_stop_at_the_first_time_dependent_part() {
+        let prefix = |template: &str| template.parse::<KeyTemplate>().unwrap().prefix(&vars()).unwrap();
+        assert_eq!(prefix("{engine}/{cluster}/{namespace}/{date}/{database}"), "surrealdb/eu/app/");
+        // A literal holding a strftime sequence is time-dependent as a whole.
+        assert_eq!(prefix("{engine}/{namespace}/%Y/{timestamp}"), "surrealdb/app");
+        assert_eq!(prefix("{timestamp}/{engine}"), "");
+
+        let template = "{engine}/{cluster}/{timestamp}".parse::<KeyTemplate>().unwrap();
+        let vars = KeyVars { cluster: None, ..vars() };
+        assert_eq!(template.prefix(&vars).unwrap_err().to_string(), "Key template '{engine}/{cluster}/{timestamp}' uses {cluster}, but --cluster is not set");
+        assert!(!template.matches("surrealdb/eu/2025-01-02.04-30", &vars));
+    }
+
+    #[test]
+    fn invalid_templates_are_rejected() {
+        let err = |template: &str| template.parse::<KeyTemplate>().unwrap_err();
+        assert!(err("{engine}/{host}/{timestamp}").starts_with("unknown variable '{host}' in key template; use {engine}, {cluster},"));
+        assert_eq!(err("{engine}/{timestamp"), "unclosed '{' in key template '{engine}/{timestamp'");
+        assert_eq!(err("{engine}/{namespace}"), "key template '{engine}/{namespace}' has no date or time in it, so every backup would get the same key");
+        assert!(err("{engine}/%T").contains("is not safe in S3 keys"));
+    }
+
+    #[test]
+    fn timestamp_formats_must_make_safe_keys() {
+        assert_eq!("+%Y%m%d".parse::<TimestampFormat>().unwrap().format(at()), "20250102");
+        assert_eq!("".parse::<TimestampFormat>().unwrap_err(), "timestamp format is empty");
+        assert_eq!("%Y-%".parse::<TimestampFormat>().unwrap_err(), "'%Y-%' is not a valid strftime format");
+        assert_eq!(
+            "%c".parse::<TimestampFormat>().unwrap_err(),
+            "'%c' produces 'Sun Dec 31 23:59:59 2000', and ' ' is not safe in S3 keys"
+        );
+        assert!("%H:%M".parse::<TimestampFormat>().unwrap_err().ends_with("and ':' is not safe in S3 keys"));
+    }
+}
@@ -0,0 +1,193 @@
+use clap::ValueEnum;
+use color_eyre::eyre::Report;
+use serde_json::{json, Value};
+
+use crate::simulate::{RetentionPolicy, TIERS};
+use crate::tags::TagMerge;
+
+/// Storage class transitions of each tier in [`TIERS`], as days after creation.
+const TRANSITIONS: [&[(i64, &str)]; TIERS.len()] = [
+    &[(1, "GLACIER_IR")],
+    &[(2, "GLACIER_IR")],
+    &[(7, "GLACIER_IR")],
+    &[(35, "GLACIER_IR"), (95, "DEEP_ARCHIVE")],
+    &[(95, "GLACIER_IR"), (190, "DEEP_ARCHIVE")],
+    &[(365, "GLACIER_IR"), (730, "DEEP_ARCHIVE")],
+];
+
+/// The README's lifecycle configuration as `put-bucket-lifecycle-configuration` JSON, with the
+/// expirations taken from `retention`. Rules run from the shortest- to the longest-lived tier,
+/// each excluding the tiers above it, and end with the version and upload cleanup. Backups tagged
+/// with a single tier by `merge` are matched on that tag alone, with no exclusions.
+pub fn configuration(retention: &RetentionPolicy, merge: TagMerge) -> Value {
+    let mut rules = Vec::new();
+    for (index, tier) in TIERS.iter().enumerate() {
+        let days = retention.days(tier).unwrap_or_default();
+        let mut tags = match merge {
+            TagMerge::Retention => vec![json!({"Key": "retention", "Value": tier})],
+            TagMerge::All | TagMerge::Highest => vec![json!({"Key": tier, "Value": "1"})],
+        };
+        // `standard` is on every backup, so it is not among the tiers excluded by higher ones.
+        if merge == TagMerge::All {
+            tags.extend(TIERS[index.max(1)..].iter().filter(|higher| *higher != tier).map(|higher| json!({"Key": higher, "Value": "0"})));
+        }
+        let filter = match tags.len() {
+            1 => json!({"Tag": tags[0]}),
+            _ => json!({"And": {"Tags": tags}}),
+        };
+        // S3 rejects transitions that come on or after the expiration.
+        let transitions = TRANSITIONS[index]
+            .iter()
+            .filter(|(after, _)| *after < days)
+            .map(|(after, class)| json!({"Days": after, "StorageClass": class}))
+            .collect::<Vec<_>>();
+        let mut rule = json!({
+            "ID": tier,
+            "Filter": filter,
+            "Status": "Enabled",
+            "Expiration": {"Days": days},
+        });
+        if !transitions.is_empty() {
+            rule["Transitions"] = Value::Array(transitions);
+        }
+        rules.push(rule);
+    }
+    rules.push(json!({
+        "ID": "cleanup",
+        "Filter": {"Prefix": ""},
+        "Status": "Enabled",
+        "Expiration": {"ExpiredObjectDeleteMarker": true},
+        "NoncurrentVersionExpiration": {"NoncurrentDays": 1},
+        "AbortIncompleteMultipartUpload": {"DaysAfterInitiation": 1},
+    }));
+    json!({"Rules": rules})
+}
+
+/// Which store `lifecycle` writes the rules for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Flavor {
+    /// `put-bucket-lifecycle-configuration` JSON.
+    #[default]
+    Aws,
+    /// `mc ilm import` JSON, or `mc ilm rule add` commands with --mc-target.
+    Minio,
+}
+
+/// `configuration` as MinIO takes it. MinIO allows one transition per rule, to a remote tier set
+/// up with `mc ilm tier add` rather than a storage class, so rules keep their first transition,
+/// to `tier`, or none without one. It cleans up stale multipart uploads by itself and rejects
+/// AbortIncompleteMultipartUpload.
+pub fn minio(configuration: &Value, tier: Option<&str>) -> Value {
+    let rules = configuration["Rules"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|rule| {
+            let mut rule = rule.clone();
+            if let Some(rule) = rule.as_object_mut() {
+                let first = rule.remove("Transitions").and_then(|transitions| transitions.get(0).cloned());
+                if let (Some(first), Some(tier)) = (first, tier) {
+                    rule.insert(String::from("Transition"), json!({"Days": first["Days"], "StorageClass": tier}));
+                }
+                rule.remove("AbortIncompleteMultipartUpload");
+            }
+            rule
+        })
+        .collect();
+    json!({"Rules": Value::Array(rules)})
+}
+
+/// One `mc ilm rule add` command per rule of the MinIO configuration, for `target`, an
+/// `alias/bucket` of mc.
+pub fn mc_commands(minio: &Value, target: &str) -> String {
+    let mut commands = String::new();
+    for rule in minio["Rules"].as_array().into_iter().flatten() {
+        let mut command = String::from("mc ilm rule add");
+        let filter = &rule["Filter"];
+        let tags = filter["Tag"].as_object().map(|_| vec![&filter["Tag"]]).or_else(|| filter["And"]["Tags"].as_array().map(|tags| tags.iter().collect())).unwrap_or_default();
+        if !tags.is_empty() {
+            let tags = tags.iter().map(|tag| format!("{}={}", tag["Key"].as_str().unwrap_or_default(), tag["Value"].as_str().unwrap_or_default())).collect::<Vec<_>>();
+            command.push_str(&format!(" --tags '{}'", tags.join("&")));
+        }
+        if let Some(days) = rule["Expiration"]["Days"].as_i64() {
+            command.push_str(&format!(" --expire-days {}", days));
+        }
+        if rule["Expiration"]["ExpiredObjectDeleteMarker"] == json!(true) {
+            command.push_str(" --expire-delete-marker");
+        }
+        if let Some(days) = rule["NoncurrentVersionExpiration"]["NoncurrentDays"].as_i64() {
+            command.push_str(&format!(" --noncurrent-expire-days {}", days));
+        }
+        if let (Some(days), Some(tier)) = (rule["Transition"]["Days"].as_i64(), rule["Transition"]["StorageClass"].as_str()) {
+            command.push_str(&format!(" --transition-days {} --transition-tier {}", days, tier));
+        }
+        commands.push_str(&format!("{} {}\n", command, target));
+    }
+    commands
+}
+
+/// Prints the lifecycle rules for `retention` in `flavor`, for applying by hand.
+pub fn run(retention: &RetentionPolicy, merge: TagMerge, flavor: Flavor, minio_tier: Option<&str>, mc_target: Option<&str>) -> Result<(), Report> {
+    let configuration = configuration(retention, merge);
+    let output = match (flavor, mc_target) {
+        (Flavor::Aws, _) => serde_json::to_string_pretty(&configuration)?,
+        (Flavor::Minio, None) => serde_json::to_string_pretty(&minio(&configuration, minio_tier))?,
+        (Flavor::Minio, Some(target)) => mc_commands(&minio(&configuration, minio_tier), target).trim_end().to_string(),
+    };
+    println!("{}", output);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules_exclude_longer_lived_tiers() {
+        let configuration = configuration(&RetentionPolicy::default(), TagMerge::All);
+        let rules = configuration["Rules"].as_array().unwrap();
+        assert_eq!(rules.iter().map(|rule| rule["ID"].as_str().unwrap()).collect::<Vec<_>>(), [&TIERS[..], &["cleanup"]].concat());
+        assert_eq!(
+            rules[0]["Filter"]["And"]["Tags"],
+            json!([
+                {"Key": "standard", "Value": "1"},
+                {"Key": "nightly", "Value": "0"},
+                {"Key": "weekly", "Value": "0"},
+                {"Key": "monthly", "Value": "0"},
+                {"Key": "quarterly", "Value": "0"},
+                {"Key": "yearly", "Value": "0"},
+            ])
+        );
+        assert_eq!(rules[3]["Filter"]["And"]["Tags"].as_array().unwrap().len(), 3);
+        assert_eq!(rules[5]["Filter"], json!({"Tag": {"Key": "yearly", "Value": "1"}}));
+        assert_eq!(rules[3]["Expiration"]["Days"], 190);
+    }
+
+    #[test]
+    fn single_tag_rules_match_the_retention_value() {
+        let configuration = configuration(&RetentionPolicy::default(), TagMerge::Retention);
+        assert_eq!(configuration["Rules"][0]["Filter"], json!({"Tag": {"Key": "retention", "Value": "standard"}}));
+        assert_eq!(configuration["Rules"][3]["Filter"], json!({"Tag": {"Key": "retention", "Value": "monthly"}}));
+        assert_eq!(configuration["Rules"][3]["Expiration"]["Days"], 190);
+    }
+
+    #[test]
+    fn transitions_after_the_expiration_are_dropped() {
+        let configuration = configuration(&"monthly=60,standard=1".parse().unwrap(), TagMerge::All);
+        assert_eq!(configuration["Rules"][3]["Transitions"], json!([{"Days": 35, "StorageClass": "GLACIER_IR"}]));
+        assert_eq!(configuration["Rules"][0].get("Transitions"), None);
+    }
+
+    #[test]
+    fn minio_rules_transition_to_a_remote_tier_once() {
+        let minio = minio(&configuration(&RetentionPolicy::default(), TagMerge::All), Some("WARM"));
+        assert_eq!(minio["Rules"][3]["Transition"], json!({"Days": 35, "StorageClass": "WARM"}));
+        assert_eq!(minio["Rules"][3].get("Transitions"), None);
+        assert_eq!(minio["Rules"][6].get("AbortIncompleteMultipartUpload"), None);
+        let commands = mc_commands(&minio, "local/backups");
+        assert_eq!(commands.lines().next(), Some("mc ilm rule add --tags 'standard=1&nightly=0&weekly=0&monthly=0&quarterly=0&yearly=0' --expire-days 3 --transition-days 1 --transition-tier WARM local/backups"));
+        assert_eq!(commands.lines().last(), Some("mc ilm rule add --expire-delete-marker --noncurrent-expire-days 1 local/backups"));
+        let expiring = super::minio(&configuration(&RetentionPolicy::default(), TagMerge::Retention), None);
+        assert_eq!(mc_commands(&expiring, "local/backups").lines().nth(5), Some("mc ilm rule add --tags 'retention=yearly' --expire-days 1096 local/backups"));
+    }
+}
@@ -1,19 +1,116 @@
-use chrono::{DateTime, Duration, Utc};
-use clap::{command, Parser, Subcommand};
-use color_eyre::eyre::{ContextCompat, Result};
-use color_eyre::{eyre::Report, eyre::WrapErr, Section};
-use cron_parser::parse;
-use serde::{Deserialize, Serialize};
-use std::os::unix::process::ExitStatusExt;
-use std::process::{Command, Output, Stdio};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use color_eyre::eyre::{eyre, ContextCompat, Result};
+use color_eyre::{eyre::Report, eyre::WrapErr};
+use futures::stream::{self, StreamExt};
+use serde::Deserialize;
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::pin::Pin;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::process::Command;
 use tracing::{info, instrument};
+use tracing_subscriber::EnvFilter;
 use valuable::Valuable;
 
+mod archive;
+mod calendar;
+mod cas;
+mod cassandra;
+mod clickhouse;
+mod cockroach;
+mod compliance;
+mod compression;
+mod config;
+mod copy;
+mod cron;
+mod delete;
+mod dictionary;
+mod doctor;
+mod drill;
+mod elasticsearch;
+mod failure;
+mod gc;
+mod history;
+mod hooks;
+mod influxdb;
+mod init_bucket;
+mod inventory;
+mod keys;
+mod lifecycle;
+mod multipart;
+mod nats;
+mod neo4j;
+mod notify;
+mod output;
+mod priority;
+mod process;
+mod qdrant;
+mod replication;
+mod restore;
+mod resume;
+mod retention;
+mod s3;
+mod secret;
+mod simulate;
+mod size;
+mod sqlite;
+mod state;
+mod status;
+mod tag_object;
+mod tags;
+mod summary;
+mod surreal;
+mod timeline;
+mod tools;
+mod untag;
+mod verify;
+
+use calendar::Calendar;
+use compression::Compression;
+use config::Config;
+use failure::Failure;
+use init_bucket::ObjectLockMode;
+use inventory::InventoryFormat;
+use multipart::{MultipartUpload, ObjectMetadata, Split, Spool};
+use notify::NotifyOn;
+use output::OutputFormat;
+use process::before_deadline;
+use retention::{Enforcement, GfsPolicy};
+use keys::{KeyLayout, KeyTemplate, KeyVars, TimestampFormat};
+use s3::{CredentialSource, Credentials, S3Access, S3Args, S3Compat};
+use secret::Secret;
+use simulate::RetentionPolicy;
+use size::ByteSize;
+use summary::{BackupReport, EmptyBackup, JobSummary, RunAllSummary, RunSummary, TikvStats, Timings};
+use tags::{PeriodAnchor, Schedule, Tag, TagFormat, TagMerge, TagSet, TagStyle, WindowStrategy};
+use tools::{ToolArgs, Tools};
+
 /// Backup TiKV/SurrealDB S3 Tags
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 #[command(propagate_version = true)]
 struct Args {
+    /// Log more: '-v' for debug, '-vv' for trace. Overrides RUST_LOG
+    #[arg(short, long, action = clap::ArgAction::Count, global = true, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Log errors only. Overrides RUST_LOG; command output on stdout is unaffected
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// TOML config file holding named profiles
+    #[arg(long, env = "BTAGGER_CONFIG", default_value = config::DEFAULT_PATH, global=true)]
+    config: std::path::PathBuf,
+
+    /// Config file profile whose settings become the defaults of the matching flags
+    #[arg(long, env = "BTAGGER_PROFILE", global=true)]
+    profile: Option<String>,
+
     /// Matching every n hours
     #[arg(short = 'n', long, default_value_t = 4, global=true)]
     every_n_hours: i64,
@@ -23,20 +120,210 @@ struct Args {
     minutes_offset_from_hour: i64,
 
     /// Hours forward from midnight to offset match by
-    #[arg(short = 'h', long, default_value_t = 0, global=true)]
+    #[arg(long, default_value_t = 0, global=true)]
     day_offset_in_hours: i64,
 
     /// Matching window for clock skew and/or job trigger delay
     #[arg(short, long, default_value_t = 20, global=true)]
     lag_window_in_minutes: i64,
 
-    /// Storage key timestamp format string
-    #[arg(short, long, default_value_t = String::from("+%Y-%m-%d.%H-%M"), global=true)]
-    format_timestamp: String,
+    /// How a run is matched to each period's trigger: 'pre-shift' looks for the next trigger from --window-pre-shift before now, 'symmetric' for one within half the lag window either side of now
+    #[arg(long, value_enum, default_value_t = WindowStrategy::PreShift, global=true)]
+    window_strategy: WindowStrategy,
+
+    /// How far before now 'pre-shift' starts looking for the trigger, e.g. '10m'; a run started later than this after its trigger misses it. Defaults to a quarter of the lag window
+    #[arg(long, global=true)]
+    window_pre_shift: Option<humantime::Duration>,
+
+    /// TOML file of '[[dates]]' with a 'date' and a 'tag', e.g. 'fy-close=2025', added to the backup of that day
+    #[arg(long, global=true)]
+    calendar: Option<std::path::PathBuf>,
+
+    /// Whether monthly, quarterly and yearly tags go on the first backup of their period or the last
+    #[arg(long, value_enum, default_value_t = PeriodAnchor::End, global=true)]
+    period_anchor: PeriodAnchor,
+
+    /// How the tier tags of a backup matching several periods are written: 'all' of them, only the 'highest', or one 'retention=<tier>' tag, for stores limiting tags per object
+    #[arg(long, value_enum, default_value_t = TagMerge::All, global=true)]
+    tag_merge: TagMerge,
+
+    /// 'single' writes one 'retention=<tier>' tag holding the longest-lived matched tier, for lifecycle rules matching on its value; the same as '--tag-merge retention'
+    #[arg(long, value_enum, default_value_t = TagStyle::Multi, conflicts_with = "tag_merge", global=true)]
+    tag_style: TagStyle,
+
+    /// Storage key timestamp strftime format; a leading '+' is ignored and the result must be safe in S3 keys
+    #[arg(short, long, default_value = "+%Y-%m-%d.%H-%M", global=true)]
+    format_timestamp: TimestampFormat,
+
+    /// Storage key layout, e.g. '{engine}/{cluster}/{namespace}/{date}/{time}'; strftime sequences are allowed.
+    /// Defaults to --key-layout for SurrealDB and 'tikv/{timestamp}'
+    #[arg(long, global=true)]
+    key_template: Option<KeyTemplate>,
+
+    /// Built-in SurrealDB key layout when --key-template is unset; 'namespace' finds backups written before databases were in the key
+    #[arg(long, value_enum, default_value_t = KeyLayout::Database, global=true)]
+    key_layout: KeyLayout,
+
+    /// Cluster name substituted for {cluster} in --key-template
+    #[arg(long, global=true)]
+    cluster: Option<String>,
+
+    /// Compression applied to streamed exports; the storage key extension follows it
+    #[arg(long, value_enum, default_value_t = Compression::Zstd, global=true)]
+    compression: Compression,
+
+    /// Compression level; when unset the tool's default (adaptive for zstd) is used
+    #[arg(long, global=true)]
+    compression_level: Option<u32>,
+
+    /// Fail backups smaller than this, e.g. '1MiB': a SurrealDB export before compression, or all TiKV backup files
+    #[arg(long, global=true)]
+    min_expected_bytes: Option<ByteSize>,
+
+    /// Upload and tag backups holding no data instead of failing with exit code 3; not for neo4j, whose dumps are not checked
+    #[arg(long, global=true)]
+    allow_empty: bool,
+
+    /// Multipart upload part size for streamed exports (at least 5MiB)
+    #[arg(long, default_value = "64MiB", global=true)]
+    part_size: ByteSize,
+
+    /// Retries per multipart upload part before the upload is aborted
+    #[arg(long, default_value_t = 3, global=true)]
+    part_retries: u32,
+
+    /// Write the compressed export to this directory in full before uploading it, instead of
+    /// streaming it; needs room for the whole backup
+    #[arg(long, global=true)]
+    spool_dir: Option<std::path::PathBuf>,
+
+    /// Space that must be free in --spool-dir before a backup starts spooling
+    #[arg(long, default_value = "1GiB", global=true)]
+    spool_min_free: ByteSize,
+
+    /// Cut streamed backups into numbered objects of at most this size, e.g. '5GiB', with a
+    /// manifest under the backup's key
+    #[arg(long, global=true)]
+    split_size: Option<ByteSize>,
+
+    /// Parts uploaded, or objects tagged, at the same time
+    #[arg(long, default_value_t = 4, global=true)]
+    concurrency: usize,
+
+    /// Give up on a backup that runs longer than this, e.g. '2h' or '90m'; children are killed
+    #[arg(long, global=true)]
+    timeout: Option<humantime::Duration>,
+
+    /// Most of a command's stdout or stderr put into a log line or error; longer output keeps its head and tail
+    #[arg(long, default_value = "64KiB", global=true)]
+    max_captured_output: ByteSize,
+
+    /// Directory the whole of any output cut down by --max-captured-output is written to, named in the log line
+    #[arg(long, global=true)]
+    captured_output_dir: Option<std::path::PathBuf>,
+
+    /// Sleep a random interval up to this long before starting, e.g. '5m', so instances triggered by the same cron tick are spread out; tags still follow the tick
+    #[arg(long, global=true)]
+    startup_jitter: Option<humantime::Duration>,
+
+    /// Niceness, 0 to 19, to run at, so exports and compression on a shared node yield CPU to the database
+    #[arg(long, global=true, value_parser = clap::value_parser!(i32).range(0..=19))]
+    nice: Option<i32>,
+
+    /// I/O scheduling class to run in: 'idle' only reads and writes when the disk is otherwise unused
+    #[arg(long, value_enum, global=true)]
+    io_class: Option<priority::IoClass>,
+
+    /// Priority within --io-class best-effort, from 0 (highest) to 7 (lowest)
+    #[arg(long, default_value_t = 7, global=true, value_parser = clap::value_parser!(u8).range(0..=7))]
+    io_level: u8,
+
+    /// Shell command run before a backup starts, e.g. to pause writes; its failure aborts the run
+    #[arg(long, global=true)]
+    pre_cmd: Option<String>,
+
+    /// Shell command run after a successful backup, with BTAGGER_* variables describing the run
+    #[arg(long, global=true)]
+    post_success_cmd: Option<String>,
+
+    /// Shell command run after a failed backup, with BTAGGER_* variables describing the run
+    #[arg(long, global=true)]
+    post_failure_cmd: Option<String>,
+
+    /// Do not record backup runs under _history/ in the backup bucket
+    #[arg(long, global=true)]
+    no_history: bool,
+
+    /// Local SQLite catalog, kept with the sqlite3 CLI, that backup runs are also recorded in
+    #[arg(long, env = "BTAGGER_STATE_DB", global=true)]
+    state_db: Option<std::path::PathBuf>,
+
+    /// Slack incoming webhook URL that finished backups are announced on, as --notify-on selects
+    #[arg(long, env = "BTAGGER_SLACK_WEBHOOK", global=true)]
+    slack_webhook: Option<Secret>,
+
+    /// PagerDuty Events API v2 routing key; failed backups trigger an incident that the next success resolves
+    #[arg(long, env = "PAGERDUTY_ROUTING_KEY", global=true)]
+    pagerduty_routing_key: Option<Secret>,
+
+    /// Which finished backups are announced on Slack and by mail: only failed ones, or all of them
+    #[arg(long, value_enum, default_value_t = NotifyOn::Failure, global=true)]
+    notify_on: NotifyOn,
+
+    /// SMTP server, as 'host' or 'host:port', that finished backups are mailed through, as --notify-on selects
+    #[arg(long, requires = "mail_to", global=true)]
+    smtp_host: Option<String>,
+
+    /// Recipient of the notification mails; repeatable
+    #[arg(long, requires = "smtp_host", global=true)]
+    mail_to: Vec<String>,
+
+    /// Sender of the notification mails
+    #[arg(long, default_value = "btagger@localhost", global=true)]
+    mail_from: String,
+
+    /// SMTP login; the connection must then use TLS
+    #[arg(long, requires = "smtp_password", global=true)]
+    smtp_user: Option<String>,
+
+    /// Password for --smtp-user
+    #[arg(long, env = "BTAGGER_SMTP_PASSWORD", requires = "smtp_user", global=true)]
+    smtp_password: Option<Secret>,
 
-    /// Path containing 'bin/aws', 'bin/zstd', 'bin/surreal' and 'bin/tikv-br'.
-    #[arg(short, long, default_value_t = String::from("/"))]
-    bin_path: String,
+    /// Where S3 credentials come from; inferred from --aws-id/--aws-key, then --aws-profile, then 'irsa' when unset
+    #[arg(long, value_enum, global=true)]
+    credential_source: Option<CredentialSource>,
+
+    /// Profile from the shared AWS config and credentials files, exported as AWS_PROFILE to aws and tikv-br
+    #[arg(long, global=true)]
+    aws_profile: Option<String>,
+
+    /// Use the backup bucket as it is, for credentials that may not create buckets
+    #[arg(long, global=true)]
+    no_create_bucket: bool,
+
+    /// Region of the backup bucket, for aws, tikv-br and new buckets; each tool's default when unset
+    #[arg(long, global=true)]
+    aws_region: Option<String>,
+
+    /// Address buckets as https://endpoint/bucket rather than https://bucket.endpoint, as MinIO and some other stores need
+    #[arg(long, global=true)]
+    s3_force_path_style: bool,
+
+    /// The S3 implementation behind the endpoint, which decides the operations attempted: e.g. tags go in '<key>.tags.json' sidecars on Garage
+    #[arg(long, value_enum, default_value_t = S3Compat::Aws, global = true)]
+    s3_compat_mode: S3Compat,
+
+    /// App ID aws adds to the user agent of every S3 request, so access logs and CloudTrail attribute the traffic to btagger; empty to leave it out
+    #[arg(long, global = true, default_value = concat!("btagger-", env!("CARGO_PKG_VERSION")))]
+    s3_user_agent: String,
+
+    /// Accept the request charges of a Requester Pays bucket on object operations, billing them to the credentials' account
+    #[arg(long, global = true)]
+    s3_request_payer: bool,
+
+    #[command(flatten)]
+    tools: ToolArgs,
 
     #[command(subcommand)]
     command: Commands,
@@ -50,17 +337,8 @@ enum Commands {
         #[arg(short = 'B', long)]
         bucket_name: String,
 
-        /// S3 service endpoint address. Leave unspecified to use host defaults.
-        #[arg(short = 'e', long)]
-        aws_endpoint: String,
-
-        /// S3 access key ID. Leave unspecified to use host defaults.
-        #[arg(short = 'i', long)]
-        aws_id: String,
-
-        /// S3 secret access Key. Leave unspecified to use host defaults.
-        #[arg(short = 'k', long)]
-        aws_key: String,
+        #[command(flatten)]
+        s3: S3Args,
 
         /// SurrealDB namespace to backup.
         #[arg(short = 'N', long)]
@@ -74,9 +352,30 @@ enum Commands {
         #[arg(short, long)]
         address: String,
 
-        /// SurrealDB server password.
+        /// SurrealDB root password. Leave unspecified for a server without authentication.
         #[arg(short, long)]
-        password: String,
+        password: Option<Secret>,
+
+        /// Export only these tables, comma separated.
+        #[arg(long, value_delimiter = ',', conflicts_with = "exclude_tables")]
+        only_tables: Vec<String>,
+
+        /// Export every table but these, comma separated.
+        #[arg(long, value_delimiter = ',')]
+        exclude_tables: Vec<String>,
+
+        /// Export definitions without records.
+        #[arg(long)]
+        schema_only: bool,
+
+        /// Compress with the zstd dictionary trained for these backups by train-dictionary, if any.
+        #[arg(long)]
+        zstd_dictionary: bool,
+
+        /// Store the export as content-defined chunks under chunks/, uploading only those the bucket
+        /// lacks, with an index per backup that is tagged in its place.
+        #[arg(long, conflicts_with = "zstd_dictionary")]
+        cas: bool,
     },
     /// TiKV backup command.
     Tikv {
@@ -84,198 +383,1816 @@ enum Commands {
         #[arg(short = 'B', long)]
         bucket_name: String,
 
-        /// S3 service endpoint address. Leave unspecified to use host defaults.
-        #[arg(short = 'e', long)]
-        aws_endpoint: String,
-
-        /// S3 access key ID. Leave unspecified to use host defaults.
-        #[arg(short = 'i', long)]
-        aws_id: String,
-
-        /// S3 secret access Key. Leave unspecified to use host defaults.
-        #[arg(short = 'k', long)]
-        aws_key: String,
+        #[command(flatten)]
+        s3: S3Args,
 
         /// TiKV placement driver address: '{host}:{port}'.
         #[arg(short, long)]
         pd_host_and_port: String,
+
+        /// How S3 credentials are handed to tikv-br. They are never placed in the storage URL.
+        #[arg(short = 'c', long, value_enum, default_value_t = TikvCredentialMode::Env)]
+        credential_mode: TikvCredentialMode,
+    },
+    /// ClickHouse backup command, written by the server with BACKUP ... TO S3.
+    ///
+    /// The S3 endpoint and keys are handed to the server: give the endpoint as the server reaches
+    /// it, and leave the keys out for the server's own credentials.
+    Clickhouse {
+        /// Backup target bucket name.
+        #[arg(short = 'B', long)]
+        bucket_name: String,
+
+        #[command(flatten)]
+        s3: S3Args,
+
+        /// ClickHouse native protocol address: '{host}' or '{host}:{port}'.
+        #[arg(short, long, default_value = "localhost:9000")]
+        address: String,
+
+        /// ClickHouse user.
+        #[arg(short, long, default_value = "default")]
+        user: String,
+
+        /// ClickHouse password; handed to clickhouse-client as CLICKHOUSE_PASSWORD.
+        #[arg(short, long, env = "CLICKHOUSE_PASSWORD")]
+        password: Option<Secret>,
+
+        /// Database to back up.
+        #[arg(short, long)]
+        database: String,
+    },
+    /// Cassandra or ScyllaDB backup command, from a `nodetool snapshot` of the local node.
+    Cassandra {
+        /// Backup target bucket name.
+        #[arg(short = 'B', long)]
+        bucket_name: String,
+
+        #[command(flatten)]
+        s3: S3Args,
+
+        /// JMX address nodetool connects to: '{host}' or '{host}:{port}'.
+        #[arg(short, long, default_value = "localhost:7199")]
+        address: String,
+
+        /// The node's data directory, where the snapshot is written; /var/lib/scylla/data for ScyllaDB.
+        #[arg(long, default_value = "/var/lib/cassandra/data")]
+        data_dir: std::path::PathBuf,
+
+        /// Snapshot only these keyspaces, comma separated. Every keyspace by default.
+        #[arg(long, value_delimiter = ',')]
+        keyspaces: Vec<String>,
+    },
+    /// Elasticsearch or OpenSearch backup command, a snapshot into an S3 repository of the cluster.
+    ///
+    /// The S3 endpoint and keys are only used for the run record; the repository has its own.
+    Elasticsearch {
+        /// Bucket the repository writes to; the run is recorded there too.
+        #[arg(short = 'B', long)]
+        bucket_name: String,
+
+        #[command(flatten)]
+        s3: S3Args,
+
+        /// Cluster URL.
+        #[arg(short, long, default_value = "http://localhost:9200")]
+        address: String,
+
+        /// Cluster user. Leave unspecified for a cluster without authentication.
+        #[arg(short, long)]
+        user: Option<String>,
+
+        /// Cluster password.
+        #[arg(short, long, env = "ELASTIC_PASSWORD", requires = "user")]
+        password: Option<Secret>,
+
+        /// Snapshot repository, registered on the cluster with type s3 and the bucket above.
+        #[arg(short, long)]
+        repository: String,
+
+        /// Snapshot only these indices or patterns, comma separated. Every index by default.
+        #[arg(long, value_delimiter = ',')]
+        indices: Vec<String>,
+    },
+    /// InfluxDB backup command, archiving the output of `influxd backup` (1.x) or `influx backup` (2.x).
+    Influxdb {
+        /// Backup target bucket name.
+        #[arg(short = 'B', long)]
+        bucket_name: String,
+
+        #[command(flatten)]
+        s3: S3Args,
+
+        /// InfluxDB major version.
+        #[arg(long, value_enum, default_value_t = influxdb::InfluxVersion::V2)]
+        influx_version: influxdb::InfluxVersion,
+
+        /// Server address: the RPC '{host}:{port}' for 1.x (localhost:8088), the URL for 2.x (http://localhost:8086).
+        #[arg(short, long)]
+        address: Option<String>,
+
+        /// 2.x API token; handed to influx as INFLUX_TOKEN.
+        #[arg(short, long, env = "INFLUX_TOKEN")]
+        token: Option<Secret>,
+
+        /// 2.x organization.
+        #[arg(short, long)]
+        org: Option<String>,
+
+        /// Back up only this 1.x database or 2.x bucket. Everything by default.
+        #[arg(short, long)]
+        database: Option<String>,
+    },
+    /// Neo4j backup command, streaming `neo4j-admin database dump` on the Neo4j host.
+    Neo4j {
+        /// Backup target bucket name.
+        #[arg(short = 'B', long)]
+        bucket_name: String,
+
+        #[command(flatten)]
+        s3: S3Args,
+
+        /// Database to dump.
+        #[arg(short, long, default_value = "neo4j")]
+        database: String,
+    },
+    /// CockroachDB backup command, written by the cluster with BACKUP INTO.
+    ///
+    /// The S3 endpoint and keys are handed to the cluster: give the endpoint as the nodes reach it,
+    /// and leave the keys out for the nodes' own credentials.
+    Cockroach {
+        /// Backup target bucket name.
+        #[arg(short = 'B', long)]
+        bucket_name: String,
+
+        #[command(flatten)]
+        s3: S3Args,
+
+        /// Connection URL, e.g. 'postgresql://root@crdb:26257?sslcert=...'; handed to cockroach as COCKROACH_URL.
+        #[arg(short, long, env = "COCKROACH_URL")]
+        url: Option<Secret>,
+
+        /// Back up only this database. The whole cluster by default.
+        #[arg(short, long)]
+        database: Option<String>,
+    },
+    /// SQLite backup command, copying a live database file with sqlite3's .backup.
+    Sqlite {
+        /// Backup target bucket name.
+        #[arg(short = 'B', long)]
+        bucket_name: String,
+
+        #[command(flatten)]
+        s3: S3Args,
+
+        /// Database file to back up.
+        file: std::path::PathBuf,
+    },
+    /// Qdrant backup command, downloading a snapshot taken through the REST API.
+    Qdrant {
+        /// Backup target bucket name.
+        #[arg(short = 'B', long)]
+        bucket_name: String,
+
+        #[command(flatten)]
+        s3: S3Args,
+
+        /// Base URL of the Qdrant REST API.
+        #[arg(short, long, default_value = "http://localhost:6333")]
+        address: String,
+
+        /// API key, sent as the api-key header.
+        #[arg(long, env = "QDRANT_API_KEY")]
+        api_key: Option<Secret>,
+
+        /// Snapshot only this collection. The whole storage by default.
+        #[arg(short, long)]
+        collection: Option<String>,
+    },
+    /// NATS JetStream backup command, archiving what 'nats stream backup' or 'nats account backup' writes.
+    Nats {
+        /// Backup target bucket name.
+        #[arg(short = 'B', long)]
+        bucket_name: String,
+
+        #[command(flatten)]
+        s3: S3Args,
+
+        /// Server URLs, e.g. 'nats://nats:4222'; handed to nats as NATS_URL.
+        #[arg(short, long, env = "NATS_URL")]
+        url: Option<Secret>,
+
+        /// Credentials file; handed to nats as NATS_CREDS.
+        #[arg(long, env = "NATS_CREDS")]
+        creds: Option<std::path::PathBuf>,
+
+        /// Saved nats context to connect with.
+        #[arg(long)]
+        context: Option<String>,
+
+        /// Back up only this stream. Every stream of the account by default.
+        #[arg(short, long)]
+        stream: Option<String>,
     },
     /// Just print the tags.
-    Tags,
+    Tags {
+        /// How to print them: the S3 TagSet JSON, the 'Key=Value&Key=Value' form, shell exports or YAML
+        #[arg(short, long, value_enum, default_value_t = TagFormat::Json)]
+        output: TagFormat,
+
+        /// Also print, before the tags, how each period rule was matched: its cron expression, the trigger found, the offset from now, the window and the outcome
+        #[arg(long)]
+        explain: bool,
+    },
+    /// Validate binaries, S3 permissions and database connectivity.
+    Doctor {
+        /// Bucket to probe; S3 checks are skipped when unset.
+        #[arg(short = 'B', long)]
+        bucket_name: Option<String>,
+
+        #[command(flatten)]
+        s3: S3Args,
+
+        /// SurrealDB server address to check.
+        #[arg(short, long)]
+        address: Option<String>,
+
+        /// TiKV placement driver address to check: '{host}:{port}'.
+        #[arg(short, long)]
+        pd_host_and_port: Option<String>,
+
+        /// Also try each S3 operation btagger uses and report which the store supports, against what --s3-compat-mode expects.
+        #[arg(long, requires = "bucket_name")]
+        compat: bool,
+    },
+    /// Check that the newest backup under each prefix is within the expected cadence.
+    Status {
+        /// Backup bucket name.
+        #[arg(short = 'B', long)]
+        bucket_name: String,
+
+        #[command(flatten)]
+        s3: S3Args,
+
+        /// Key prefix to check, e.g. 'tikv/' or 'surrealdb/<namespace>/'. Repeatable.
+        #[arg(short = 'P', long, required = true)]
+        prefix: Vec<String>,
+
+        /// How to print the result per prefix: status lines, JSON, CSV or Markdown.
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Table)]
+        output: OutputFormat,
+    },
+    /// Chart the backups under a prefix with their tiers, marking gaps longer than --every-n-hours plus the lag window.
+    Timeline {
+        /// Backup bucket name.
+        #[arg(short = 'B', long)]
+        bucket_name: String,
+
+        #[command(flatten)]
+        s3: S3Args,
+
+        /// Key prefix directly above the backups, e.g. 'tikv/' or 'surrealdb/<namespace>/<database>/'.
+        #[arg(short = 'P', long)]
+        prefix: String,
+
+        /// How to print the backups: the ASCII chart, JSON, CSV or Markdown.
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Table)]
+        output: OutputFormat,
+
+        /// Same as --output json.
+        #[arg(long, conflicts_with = "output")]
+        json: bool,
+    },
+    /// Count the objects and bytes in the bucket per retention tier and per engine prefix.
+    Inventory {
+        /// Backup bucket name.
+        #[arg(short = 'B', long)]
+        bucket_name: String,
+
+        #[command(flatten)]
+        s3: S3Args,
+
+        /// Only count objects under this prefix. The whole bucket by default.
+        #[arg(short = 'P', long, default_value = "")]
+        prefix: String,
+
+        /// How to print the breakdown: a table, JSON, CSV, Markdown or Prometheus text exposition
+        #[arg(short, long, value_enum, default_value_t = InventoryFormat::Table)]
+        output: InventoryFormat,
+    },
+    /// Report per tier on object counts and dates, Object Lock, encryption and tagging, for auditors.
+    ComplianceReport {
+        /// Backup bucket name.
+        #[arg(short = 'B', long)]
+        bucket_name: String,
+
+        #[command(flatten)]
+        s3: S3Args,
+
+        /// Only report on objects under this prefix. The whole bucket by default.
+        #[arg(short = 'P', long, default_value = "")]
+        prefix: String,
+
+        /// How to print the report: JSON with every tagging finding, or a table, CSV or Markdown with one row per tier
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Json)]
+        output: OutputFormat,
+    },
+    /// Finish the uploads a failed spooled backup left in --spool-dir, without exporting again.
+    Resume {
+        #[command(flatten)]
+        s3: S3Args,
+    },
+    /// Show the newest backup runs recorded under _history/ in the bucket.
+    History {
+        /// Backup bucket name.
+        #[arg(short = 'B', long)]
+        bucket_name: String,
+
+        #[command(flatten)]
+        s3: S3Args,
+
+        /// How many runs to show.
+        #[arg(long, default_value_t = 20)]
+        last: usize,
+
+        /// How to print the runs: a table, one JSON record per line, CSV or Markdown.
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Table)]
+        output: OutputFormat,
+
+        /// Same as --output json.
+        #[arg(long, conflicts_with = "output")]
+        json: bool,
+    },
+    /// Export or import the --state-db catalog, e.g. to move it to another host.
+    State {
+        #[command(subcommand)]
+        action: state::StateAction,
+    },
+    /// Remove specific backups; matching objects are only listed unless --yes is given.
+    Delete {
+        /// Backup bucket name.
+        #[arg(short = 'B', long)]
+        bucket_name: String,
+
+        #[command(flatten)]
+        s3: S3Args,
+
+        /// Exact key to delete. Repeatable.
+        #[arg(long)]
+        key: Vec<String>,
+
+        /// Only consider keys under this prefix when no --key is given, e.g. 'surrealdb/<namespace>/'.
+        #[arg(short = 'P', long, default_value = "")]
+        prefix: String,
+
+        /// Only delete objects last modified longer ago than this, e.g. '30d'.
+        #[arg(long)]
+        older_than: Option<humantime::Duration>,
+
+        /// Only delete objects carrying this tag: 'key=value', or 'key' for 'key=1'. Repeatable; all must match.
+        #[arg(long)]
+        tag: Vec<Tag>,
+
+        /// Actually delete; without it the matching objects are only listed.
+        #[arg(short = 'y', long)]
+        yes: bool,
+    },
+    /// Delete the --cas chunks that no backup index in the bucket references any more.
+    Gc {
+        /// Backup bucket name.
+        #[arg(short = 'B', long)]
+        bucket_name: String,
+
+        #[command(flatten)]
+        s3: S3Args,
+
+        /// Keep unreferenced chunks modified within this long, e.g. '24h', as a running backup may not have stored its index yet.
+        #[arg(long, default_value = "24h")]
+        grace: humantime::Duration,
+
+        /// Only list the chunks that would be deleted.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Server-side copy a backup to another bucket and re-apply its tags, e.g. to promote monthly backups to an archive.
+    Copy {
+        /// Bucket holding the backup.
+        #[arg(long)]
+        from_bucket: String,
+
+        /// Bucket to copy the backup into, under the same key.
+        #[arg(long)]
+        to_bucket: String,
+
+        #[command(flatten)]
+        s3: S3Args,
+
+        /// Key of the backup to copy.
+        #[arg(long, required_unless_present = "latest", conflicts_with = "latest")]
+        key: Option<String>,
+
+        /// Copy the newest backup under --prefix that carries every --tag.
+        #[arg(long)]
+        latest: bool,
+
+        /// Key prefix searched by --latest, e.g. 'surrealdb/<namespace>/'.
+        #[arg(short = 'P', long, default_value = "", requires = "latest")]
+        prefix: String,
+
+        /// Tag the --latest backup must carry: 'key=value', or 'key' for 'key=1'. Repeatable.
+        #[arg(long, requires = "latest")]
+        tag: Vec<Tag>,
+    },
+    /// Prepare a backup bucket: create it, enable versioning, and apply the lifecycle rules, Object Lock and a delete policy.
+    InitBucket {
+        /// Backup bucket name.
+        #[arg(short = 'B', long)]
+        bucket_name: String,
+
+        #[command(flatten)]
+        s3: S3Args,
+
+        /// Days each tier is retained by the lifecycle rules; tiers left out keep the README values.
+        #[arg(long, default_value = "standard=3,nightly=7,weekly=35,monthly=190,quarterly=370,yearly=1096")]
+        retention: RetentionPolicy,
+
+        /// Lock new objects against deletion for this many days by default.
+        #[arg(long)]
+        object_lock_days: Option<u32>,
+
+        /// Object Lock mode for --object-lock-days.
+        #[arg(long, value_enum, default_value_t = ObjectLockMode::Governance, requires = "object_lock_days")]
+        object_lock_mode: ObjectLockMode,
+
+        /// Set a bucket policy denying object and bucket deletes to everyone but --allow-delete-by.
+        #[arg(long)]
+        deny_deletes: bool,
+
+        /// IAM principal ARN still allowed to delete, e.g. an operator role. Repeatable.
+        #[arg(long, requires = "deny_deletes")]
+        allow_delete_by: Vec<String>,
+    },
+    /// Replicate monthly and yearly backups to a DR bucket in another account or region. The replication role must already exist.
+    SetupReplication {
+        /// Backup bucket to replicate from.
+        #[arg(short = 'B', long)]
+        bucket_name: String,
+
+        #[command(flatten)]
+        s3: S3Args,
+
+        /// DR bucket, by name or ARN. It must have versioning enabled.
+        #[arg(long)]
+        destination_bucket: String,
+
+        /// Account ID owning the DR bucket, making it the owner of the replicas.
+        #[arg(long)]
+        destination_account: Option<String>,
+
+        /// ARN of the IAM role S3 assumes to replicate.
+        #[arg(long)]
+        role_arn: String,
+
+        /// Storage class of the replicas, e.g. 'DEEP_ARCHIVE'; defaults to the source object's.
+        #[arg(long)]
+        storage_class: Option<String>,
+
+        /// Tag selecting objects to replicate: 'key=value', or 'key' for 'key=1'. Repeatable; any may match.
+        #[arg(long, default_values = ["monthly", "yearly"])]
+        tag: Vec<Tag>,
+    },
+    /// Apply a grandfather-father-son retention policy to the backups under a prefix, by retagging or deleting them.
+    Retain {
+        /// Backup bucket name.
+        #[arg(short = 'B', long)]
+        bucket_name: String,
+
+        #[command(flatten)]
+        s3: S3Args,
+
+        /// Prefix directly above the backups, e.g. 'tikv/' or 'surrealdb/<namespace>/'.
+        #[arg(short = 'P', long)]
+        prefix: String,
+
+        /// Backups kept per tier; tiers left out keep 7 daily, 5 weekly, 6 monthly and 3 yearly.
+        #[arg(long, default_value = "daily=7,weekly=5,monthly=6,yearly=3")]
+        keep: GfsPolicy,
+
+        /// Retag backups for the lifecycle rules to expire, or delete them directly.
+        #[arg(long, value_enum, default_value_t = Enforcement::Tags)]
+        enforce: Enforcement,
+
+        /// Actually change objects; without it the changes are only listed.
+        #[arg(short = 'y', long)]
+        yes: bool,
+    },
+    /// Remove tier tags that have outlived their retention, for stores whose lifecycle rules cannot filter on tags.
+    UntagExpired {
+        /// Backup bucket name.
+        #[arg(short = 'B', long)]
+        bucket_name: String,
+
+        #[command(flatten)]
+        s3: S3Args,
+
+        /// Only consider keys under this prefix, e.g. 'surrealdb/<namespace>/'.
+        #[arg(short = 'P', long, default_value = "")]
+        prefix: String,
+
+        /// Days each tier is retained; tiers left out keep the README lifecycle values.
+        #[arg(long, default_value = "standard=3,nightly=7,weekly=35,monthly=190,quarterly=370,yearly=1096")]
+        retention: RetentionPolicy,
+
+        /// Actually remove the tags; without it the affected objects are only listed.
+        #[arg(short = 'y', long)]
+        yes: bool,
+    },
+    /// Apply the computed tags to existing objects, e.g. so manual backups join the lifecycle scheme.
+    TagObject {
+        /// Bucket holding the objects.
+        #[arg(short = 'B', long, alias = "bucket")]
+        bucket_name: String,
+
+        #[command(flatten)]
+        s3: S3Args,
+
+        /// Key of an object to tag. Repeatable.
+        #[arg(long, required_unless_present = "prefix", conflicts_with = "prefix")]
+        key: Vec<String>,
+
+        /// Tag every object under this prefix instead.
+        #[arg(short = 'P', long)]
+        prefix: Option<String>,
+
+        /// Compute the tags as of this time instead of now, e.g. when the backup was taken: '2025-01-31T04:30:00Z'.
+        #[arg(long)]
+        at: Option<DateTime<Utc>>,
+    },
+    /// Check that a SurrealDB backup downloads and decompresses, or with --deep that it restores.
+    Verify {
+        /// Backup bucket name.
+        #[arg(short = 'B', long)]
+        bucket_name: String,
+
+        #[command(flatten)]
+        s3: S3Args,
+
+        /// SurrealDB namespace the backup was taken from.
+        #[arg(short = 'N', long)]
+        namespace: String,
+
+        /// SurrealDB database the backup was taken from.
+        #[arg(short, long)]
+        database: String,
+
+        /// Backup to verify; defaults to the newest one of the namespace that fits --key-template.
+        #[arg(long)]
+        key: Option<String>,
+
+        /// Import into a temporary in-memory SurrealDB and count the rows of every table.
+        #[arg(long)]
+        deep: bool,
+    },
+    /// Train a zstd dictionary on the newest SurrealDB backup, for `surrealdb --zstd-dictionary`.
+    TrainDictionary {
+        /// Backup bucket name.
+        #[arg(short = 'B', long)]
+        bucket_name: String,
+
+        #[command(flatten)]
+        s3: S3Args,
+
+        /// SurrealDB namespace the backups are taken from.
+        #[arg(short = 'N', long)]
+        namespace: String,
+
+        /// SurrealDB database the backups are taken from.
+        #[arg(short, long)]
+        database: String,
+
+        /// Largest dictionary to train, e.g. '110KiB'.
+        #[arg(long, default_value = "110KiB")]
+        max_size: ByteSize,
+
+        /// Size of the samples the export is cut into for training.
+        #[arg(long, default_value = "16KiB")]
+        sample_size: ByteSize,
+    },
+    /// Import a SurrealDB backup, optionally into another namespace and database.
+    Restore {
+        /// Backup bucket name.
+        #[arg(short = 'B', long)]
+        bucket_name: String,
+
+        #[command(flatten)]
+        s3: S3Args,
+
+        /// SurrealDB namespace the backup was taken from.
+        #[arg(short = 'N', long)]
+        namespace: String,
+
+        /// SurrealDB database the backup was taken from.
+        #[arg(short, long)]
+        database: String,
+
+        /// Backup to restore; defaults to the newest one of the database that fits --key-template, --before and --tag.
+        #[arg(long, conflicts_with_all = ["before", "tag"])]
+        key: Option<String>,
+
+        /// Restore the newest backup written before this time, e.g. '2025-01-31T00:00:00Z'.
+        #[arg(long)]
+        before: Option<DateTime<Utc>>,
+
+        /// Tag the restored backup must carry: 'key=value', or 'key' for 'key=1'. Repeatable; all must match.
+        #[arg(long)]
+        tag: Vec<Tag>,
+
+        /// SurrealDB server address to import into.
+        #[arg(short, long)]
+        address: String,
+
+        /// SurrealDB root password. Leave unspecified for a server without authentication.
+        #[arg(short, long)]
+        password: Option<Secret>,
+
+        /// Namespace to import into; defaults to --namespace.
+        #[arg(long)]
+        target_namespace: Option<String>,
+
+        /// Database to import into; defaults to --database.
+        #[arg(long)]
+        target_database: Option<String>,
+
+        /// Actually import; without it the restore is only described.
+        #[arg(short = 'y', long)]
+        yes: bool,
+
+        /// Refuse to import into a target database holding any table, even one the backup does not restore.
+        #[arg(long, conflicts_with = "force")]
+        require_empty: bool,
+
+        /// Import even into tables that already exist in the target database.
+        #[arg(long)]
+        force: bool,
+
+        /// Print the key that would be restored and exit.
+        #[arg(long, conflicts_with = "yes")]
+        print_only: bool,
+    },
+    /// Restore a randomly picked backup into a scratch SurrealDB or TiKV cluster and validate it.
+    Drill {
+        /// Backup bucket name.
+        #[arg(short = 'B', long)]
+        bucket_name: String,
+
+        #[command(flatten)]
+        s3: S3Args,
+
+        /// Scratch SurrealDB server address to restore into.
+        #[arg(short, long, required_unless_present = "pd_host_and_port", conflicts_with = "pd_host_and_port")]
+        address: Option<String>,
+
+        /// Scratch SurrealDB root password. Leave unspecified for a server without authentication.
+        #[arg(short, long, requires = "address")]
+        password: Option<Secret>,
+
+        /// SurrealDB namespace the backups are taken from.
+        #[arg(short = 'N', long, requires = "address")]
+        namespace: Option<String>,
+
+        /// SurrealDB database the backups are taken from.
+        #[arg(short, long, requires = "address")]
+        database: Option<String>,
+
+        /// Namespace on the scratch server each drill restores into a fresh database of.
+        #[arg(long, default_value = "drill", requires = "address")]
+        target_namespace: String,
+
+        /// Keep the drill database on the scratch server instead of removing it afterwards.
+        #[arg(long, requires = "address")]
+        keep: bool,
+
+        /// Scratch TiKV placement driver address to restore into: '{host}:{port}'.
+        #[arg(long)]
+        pd_host_and_port: Option<String>,
+
+        /// Key prefix directly above the TiKV backups, e.g. 'tikv/'.
+        #[arg(long, requires = "pd_host_and_port")]
+        prefix: Option<String>,
+
+        /// SurrealQL validation query that must return at least one record. Repeatable.
+        #[arg(long)]
+        query: Vec<String>,
+    },
+    /// Run every backup job listed in the config file, sharing one tag computation.
+    RunAll {
+        /// Jobs run at the same time.
+        #[arg(long, default_value_t = 1)]
+        parallel: usize,
+    },
+    /// Print the lifecycle rules for a retention policy, for AWS or MinIO, to apply by hand.
+    Lifecycle {
+        /// Days each tier is retained; tiers left out keep the README lifecycle values.
+        #[arg(long, default_value = "standard=3,nightly=7,weekly=35,monthly=190,quarterly=370,yearly=1096")]
+        retention: RetentionPolicy,
+
+        /// Store the rules are written for: 'aws' JSON for put-bucket-lifecycle-configuration, or 'minio' JSON for 'mc ilm import'.
+        #[arg(long, value_enum, default_value_t = lifecycle::Flavor::Aws)]
+        flavor: lifecycle::Flavor,
+
+        /// MinIO remote tier, from 'mc ilm tier add', taking each rule's first transition; without it MinIO rules only expire.
+        #[arg(long)]
+        minio_tier: Option<String>,
+
+        /// Print 'mc ilm rule add' commands for this 'alias/bucket' instead of JSON.
+        #[arg(long)]
+        mc_target: Option<String>,
+    },
+    /// Simulate how many backups of each tier a retention policy keeps over a date range.
+    SimulateRetention {
+        /// First day of simulated backups: 'YYYY-MM-DD'.
+        #[arg(long)]
+        from: NaiveDate,
+
+        /// Last day of simulated backups: 'YYYY-MM-DD'.
+        #[arg(long)]
+        to: NaiveDate,
+
+        /// Days each tier is retained; tiers left out keep the README lifecycle values.
+        #[arg(long, default_value = "standard=3,nightly=7,weekly=35,monthly=190,quarterly=370,yearly=1096")]
+        retention: RetentionPolicy,
+
+        /// Days between report rows.
+        #[arg(long, default_value_t = 1)]
+        step_days: i64,
+
+        /// Estimated size of a single backup, for the storage column.
+        #[arg(long, default_value = "1GiB")]
+        backup_size: ByteSize,
+
+        /// How to print the days: a table with the peak, JSON, CSV or Markdown.
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Table)]
+        output: OutputFormat,
+    },
 }
 
-#[derive(Serialize, Valuable)]
-#[serde(rename_all = "PascalCase")]
-struct TagSet {
-    tag_set: Vec<Tag>,
+/// Delivery mechanism for the S3 credentials used by tikv-br.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum TikvCredentialMode {
+    /// Export `AWS_ACCESS_KEY_ID`/`AWS_SECRET_ACCESS_KEY` into the tikv-br environment.
+    Env,
+    /// Write a private shared-credentials file and point `AWS_SHARED_CREDENTIALS_FILE` at it.
+    File,
 }
 
-#[derive(Serialize, Clone, Valuable)]
-#[serde(rename_all = "PascalCase")]
-struct Tag {
-    key: String,
-    value: String,
+/// The S3 endpoint and credentials of a command, following --credential-source. When that is not
+/// given, the source is inferred: --aws-id and --aws-key, then --aws-profile, then each tool's
+/// default chain.
+fn s3_access(args: &Args, s3: S3Args) -> Result<S3Access, Report> {
+    resolve_s3_access(args, s3).wrap_err(Failure::Config)
+}
+
+fn resolve_s3_access(args: &Args, s3: S3Args) -> Result<S3Access, Report> {
+    // Empty values, as older configurations pass to mean unset, are treated as not given.
+    let endpoint = s3.aws_endpoint.filter(|endpoint| !endpoint.trim().is_empty());
+    let id = s3.aws_id.filter(|id| !id.expose().trim().is_empty());
+    let key = s3.aws_key.filter(|key| !key.expose().trim().is_empty());
+    if let Some(endpoint) = &endpoint {
+        if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+            return Err(eyre!("--aws-endpoint '{}' must start with http:// or https://", endpoint));
+        }
+    }
+    match (&id, &key) {
+        (Some(_), None) => return Err(eyre!("--aws-id was given without --aws-key")),
+        (None, Some(_)) => return Err(eyre!("--aws-key was given without --aws-id")),
+        _ => {}
+    }
+    let source = args.credential_source.unwrap_or(match (&id, &key, &args.aws_profile) {
+        (Some(_), Some(_), _) => CredentialSource::Static,
+        (_, _, Some(_)) => CredentialSource::Profile,
+        _ => CredentialSource::Irsa,
+    });
+    if id.is_some() && source != CredentialSource::Static {
+        return Err(eyre!("--aws-id and --aws-key only apply with --credential-source static"));
+    }
+    if args.aws_profile.is_some() && source != CredentialSource::Profile {
+        return Err(eyre!("--aws-profile only applies with --credential-source profile"));
+    }
+    let credentials = match source {
+        CredentialSource::Static => match (id, key) {
+            (Some(id), Some(key)) => Credentials::Static(id, key),
+            _ => return Err(eyre!("--credential-source static needs --aws-id and --aws-key")),
+        },
+        CredentialSource::Profile => Credentials::Profile(
+            args.aws_profile.clone().ok_or_else(|| eyre!("--credential-source profile needs --aws-profile"))?,
+        ),
+        CredentialSource::Env => {
+            if std::env::var_os("AWS_ACCESS_KEY_ID").is_none() || std::env::var_os("AWS_SECRET_ACCESS_KEY").is_none() {
+                return Err(eyre!("--credential-source env needs AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY in the environment"));
+            }
+            Credentials::Env
+        }
+        CredentialSource::Irsa => Credentials::Irsa,
+    };
+    let user_agent = Some(args.s3_user_agent.clone()).filter(|user_agent| !user_agent.is_empty());
+    // The characters a user agent token allows; aws rejects longer app IDs.
+    if let Some(user_agent) = &user_agent {
+        if user_agent.len() > 50 || !user_agent.chars().all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)) {
+            return Err(eyre!("--s3-user-agent '{}' must be at most 50 letters, digits or !#$%&'*+-.^_`|~", user_agent));
+        }
+    }
+    let mut s3_access = S3Access {
+        endpoint,
+        region: args.aws_region.clone(),
+        credentials,
+        path_style_config: None,
+        compat: args.s3_compat_mode,
+        user_agent,
+        request_payer: args.s3_request_payer,
+    };
+    let path_style = args.s3_force_path_style || args.s3_compat_mode.path_style();
+    if path_style {
+        s3_access.force_path_style()?;
+    }
+    info!(target: "s3_access", endpoint = s3_access.endpoint.as_deref(), region = s3_access.region.as_deref(), source = ?source, path_style, compat = ?args.s3_compat_mode, user_agent = s3_access.user_agent.as_deref(), request_payer = s3_access.request_payer);
+    Ok(s3_access)
+}
+
+/// The --key-template in effect for a backup with `vars`.
+fn key_template(args: &Args, vars: &KeyVars) -> KeyTemplate {
+    args.key_template.clone().unwrap_or_else(|| KeyTemplate::default_for(args.key_layout, vars))
+}
+
+/// The --spool-dir in effect, if any, for a backup tagged with `tags`.
+fn spool(args: &Args, tags: &str) -> Option<Spool> {
+    args.spool_dir.clone().map(|dir| Spool { dir, min_free: args.spool_min_free.0, tags: tags.to_string() })
+}
+
+/// A backup driver that has been set up but not started.
+type BackupFuture<'a> = Pin<Box<dyn Future<Output = Result<BackupReport, Report>> + 'a>>;
+
+fn main() {
+    let log_filter = install_tracing();
+    if let Err(report) = start(log_filter) {
+        // Error reports bypass tracing, so they get redacted on their own way out.
+        eprintln!("Error: {}", secret::redact(&format!("{:?}", report)));
+        // Each kind of failure exits with a code of its own, so schedulers can branch on it.
+        std::process::exit(Failure::of(&report).exit_code());
+    }
 }
 
-#[instrument]
-fn main() -> Result<(), Report> {
-    install_tracing();
+/// Parses the command line and lowers the priority of the run, when asked to, before the runtime
+/// starts its worker threads: priorities belong to threads, and a new thread takes its priority
+/// from the thread starting it.
+fn start(log_filter: LogFilter) -> Result<(), Report> {
     color_eyre::install()?;
+    let started = Instant::now();
 
-    info!("Processing CLI flags");
-    let args = Args::parse();
-    let checks = periods(
-        args.day_offset_in_hours,
-        args.minutes_offset_from_hour,
-        args.every_n_hours,
-    );
+    // The profile changes flag defaults, so it is loaded before the command line is parsed.
+    let mut command = Args::command();
+    if let Some(profile) = config::early_flag("profile").or_else(|| std::env::var("BTAGGER_PROFILE").ok()) {
+        let path = config::early_flag("config")
+            .or_else(|| std::env::var("BTAGGER_CONFIG").ok())
+            .unwrap_or_else(|| config::DEFAULT_PATH.to_string());
+        let config = Config::load(std::path::Path::new(&path)).wrap_err(Failure::Config)?;
+        command = config::apply_profile(command, config.profile(&profile).wrap_err(Failure::Config)?).wrap_err(Failure::Config)?;
+    }
+    let args = Args::from_arg_matches(&command.clone().get_matches()).unwrap_or_else(|err| err.exit());
+    log_filter.reload(verbosity_filter(args.quiet, args.verbose))?;
+    info!("Processed CLI flags");
+    info!(profile = args.profile, config = %args.config.display(), "Resolved configuration");
+    let tools = Tools::resolve(&args.tools);
+    if args.nice.is_some() || args.io_class.is_some() {
+        // A runtime of this thread alone, so no thread escapes the lowering.
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        runtime.block_on(priority::lower(&tools, args.nice, args.io_class, args.io_level))?;
+    }
+    tokio::runtime::Runtime::new()?.block_on(run(args, command, tools, started))
+}
+
+#[instrument(skip_all)]
+async fn run(mut args: Args, command: clap::Command, tools: Tools, started: Instant) -> Result<(), Report> {
+    process::set_capture(args.max_captured_output.0, args.captured_output_dir.clone());
+    args.compression.validate_level(args.compression_level).wrap_err(Failure::Config)?;
+    let schedule = Schedule {
+        every_n_hours: args.every_n_hours,
+        minutes_offset_from_hour: args.minutes_offset_from_hour,
+        day_offset_in_hours: args.day_offset_in_hours,
+        lag_window_in_minutes: args.lag_window_in_minutes,
+        window_strategy: args.window_strategy,
+        period_anchor: args.period_anchor,
+        window_pre_shift: args.window_pre_shift.map(|shift| Duration::from_std(*shift)).transpose().wrap_err("--window-pre-shift is too long").wrap_err(Failure::Config)?,
+    };
 
     let now = Utc::now();
     info!(
         "Capturing current UTC time and adjusting within lag window: {}",
         now.to_rfc3339()
     );
-    // Need to subtract a few minutes to catch the current trigger.
-    // 1/4 of the lag window feels right.
-    let now_comparison_value = now
-        .checked_sub_signed(Duration::minutes(args.lag_window_in_minutes / 4))
-        .wrap_err("Unable to apply jitter to current UTC timestamp")
-        .suggestion("Check the system clock")?;
-
-    // Add default tag every time
-    let mut tags: Vec<Tag> = Vec::new();
-    tags.push(Tag {
-        key: String::from("standard"),
-        value: String::from("1"),
-    });
 
     info!("Processing list of tag checks");
-    for check in checks {
-        if let Ok(next) = parse(check.0.as_str(), &now_comparison_value) {
-            let next_when = if check.2 {
-                next.checked_sub_signed(Duration::days(1))
-                    .wrap_err("Unable to adjust next matching run time for period end")
-                    .suggestion("Check the system clock")?
-            } else {
-                next
+    let tag_computation = Instant::now();
+    let calendar = match &args.calendar {
+        Some(path) => Calendar::load(path).wrap_err(Failure::Config)?,
+        None => Calendar::default(),
+    };
+    // The config file is optional here; `run-all` and `--profile` are the ones that need it.
+    let rules = match args.config.exists() {
+        true => Config::load(&args.config).and_then(|config| config.rules()).wrap_err(Failure::Config)?,
+        false => Vec::new(),
+    };
+    let mut evaluations = tags::evaluate(&schedule, now)?;
+    evaluations.extend(tags::evaluate_rules(&schedule, rules.clone(), now)?);
+    evaluations.extend(calendar::evaluate(&schedule, &calendar, now)?);
+    for evaluation in &evaluations {
+        info!(target: "match_attempt_results", tag = evaluation.tag.as_value(), when = evaluation.when.to_rfc3339(), diff_seconds = evaluation.diff.num_seconds(), matched = evaluation.matched, reason = evaluation.reason);
+    }
+    let tags = tags::merge(tags::matched(&evaluations), args.tag_style.merge(args.tag_merge));
+    let tag_computation = tag_computation.elapsed();
+    let tag_set_string = serde_json::to_string(&TagSet { tag_set: tags.clone() })?;
+    info!(tag_set_string);
+    // Stores tagging through sidecars take any tag set, so this only fails once a backup is tagged.
+    if let Err(err) = tags::validate(&tags) {
+        tracing::warn!(target: "tags", error = format!("{:#}", err), "The tag set breaks the S3 tagging limits, so put-object-tagging will refuse it");
+    }
+
+    // The tags were computed for the cron tick; only the work itself is spread out.
+    if let Some(max) = args.startup_jitter {
+        let delay = jitter(*max, RandomState::new().build_hasher().finish());
+        info!(delay = %humantime::format_duration(delay), max = %max, "Sleeping before starting, within --startup-jitter");
+        tokio::time::sleep(delay).await;
+    }
+    let deadline = args.timeout.map(|timeout| tokio::time::Instant::now() + *timeout);
+
+    match std::mem::replace(&mut args.command, Commands::Tags { output: TagFormat::Json, explain: false }) {
+        backup @ (Commands::Surrealdb { .. } | Commands::Tikv { .. } | Commands::Clickhouse { .. } | Commands::Cassandra { .. } | Commands::Elasticsearch { .. } | Commands::Influxdb { .. } | Commands::Neo4j { .. } | Commands::Cockroach { .. } | Commands::Sqlite { .. } | Commands::Qdrant { .. } | Commands::Nats { .. }) => {
+            let (summary, result) = run_backup(&args, &tools, backup, &tags, tag_computation, now, deadline).await?;
+            // The summary goes out even when the backup failed, so wrappers always get a result line.
+            summary.print()?;
+            match Failure::of_backup(&result) {
+                Some(Failure::Export { stage }) if result.is_ok() => Err(Report::new(Failure::Export { stage })),
+                _ => result.map(|_| ()),
+            }
+        }
+        Commands::RunAll { parallel } => {
+            let config = Config::load(&args.config).wrap_err(Failure::Config)?;
+            if config.jobs.is_empty() {
+                return Err(eyre!("{} defines no [[jobs]]", args.config.display()).wrap_err(Failure::Config));
+            }
+            let mut jobs = Vec::new();
+            for (index, job) in config.jobs.iter().enumerate() {
+                let name = job.name.clone().unwrap_or_else(|| format!("{}-{}", job.command, index + 1));
+                let matches = config::job_matches(&command, job)
+                    .wrap_err_with(|| format!("Job {} is invalid", name))
+                    .wrap_err(Failure::Config)?;
+                match Commands::from_arg_matches(&matches)? {
+                    backup @ (Commands::Surrealdb { .. } | Commands::Tikv { .. } | Commands::Clickhouse { .. } | Commands::Cassandra { .. } | Commands::Elasticsearch { .. } | Commands::Influxdb { .. } | Commands::Neo4j { .. } | Commands::Cockroach { .. } | Commands::Sqlite { .. } | Commands::Qdrant { .. } | Commands::Nats { .. }) => jobs.push((name, job.command.clone(), backup)),
+                    _ => return Err(eyre!("Job {} must run surrealdb, tikv, clickhouse, cassandra, elasticsearch, influxdb, neo4j, cockroach, sqlite, qdrant or nats, not {}", name, job.command)),
+                }
+            }
+            // Every job shares the tags computed above; --parallel bounds how many run at once. Each
+            // job gets the whole --timeout from when it starts, not from when run-all did.
+            let results = stream::iter(jobs)
+                .map(|(name, command, backup)| {
+                    let (args, tools, tags) = (&args, &tools, &tags);
+                    async move {
+                        info!(target: "run_all", job = name, "Starting job");
+                        let started = Instant::now();
+                        let deadline = args.timeout.map(|timeout| tokio::time::Instant::now() + *timeout);
+                        let outcome = run_backup(args, tools, backup, tags, tag_computation, now, deadline).await;
+                        (name, command, started, outcome)
+                    }
+                })
+                .buffered(parallel.max(1))
+                .collect::<Vec<_>>()
+                .await;
+            let mut summaries = Vec::new();
+            let mut failures = Vec::new();
+            let mut kinds = Vec::new();
+            for (name, command, started, outcome) in results {
+                // A job that could not even be set up, e.g. for a bad key template, fails on its own
+                // and leaves the other jobs' results standing.
+                let (summary, result) = match outcome {
+                    Ok(outcome) => outcome,
+                    Err(err) => {
+                        let result = Err(err);
+                        let mut summary = RunSummary::new(&command, tags.clone(), started, &Timings::default(), &result);
+                        summary.error_code = Failure::of_backup(&result).map(|failure| failure.code());
+                        (summary, result)
+                    }
+                };
+                kinds.extend(Failure::of_backup(&result));
+                if let Err(err) = result {
+                    failures.push(format!("{}: {:#}", name, err));
+                } else if !summary.success {
+                    failures.push(format!("{}: backup reported failure", name));
+                }
+                summaries.push(JobSummary { name, summary });
+            }
+            RunAllSummary::new(summaries, started).print()?;
+            match failures.is_empty() {
+                true => Ok(()),
+                false => Err(run_all_error(&failures, &kinds)),
+            }
+        }
+        Commands::Tags { output, explain } => {
+            if explain {
+                print!("{}", tags::explain(&schedule, now, &evaluations));
+            }
+            print!("{}", output.render(&tags)?);
+            return Ok(());
+        }
+        Commands::Doctor { bucket_name, s3, address, pd_host_and_port, compat } => {
+            let s3_access = s3_access(&args, s3)?;
+            let options = doctor::DoctorOptions {
+                tools,
+                bucket_name,
+                s3_access,
+                surrealdb_address: address,
+                pd_host_and_port,
+                tags: tag_set_string,
+                compat,
+            };
+            if !doctor::run(&options).await? {
+                return Err(eyre!("One or more doctor checks failed"));
+            }
+            return Ok(());
+        }
+        Commands::Status { bucket_name, s3, prefix, output } => {
+            let s3_access = s3_access(&args, s3)?;
+            let options = status::StatusOptions {
+                tools,
+                bucket_name,
+                s3_access,
+                prefixes: prefix,
+                max_age: Duration::hours(args.every_n_hours) + Duration::minutes(args.lag_window_in_minutes),
+                format: output,
+            };
+            if !status::run(&options, now).await? {
+                return Err(eyre!("Backups are stale or missing"));
+            }
+            return Ok(());
+        }
+        Commands::Timeline { bucket_name, s3, prefix, output, json } => {
+            let s3_access = s3_access(&args, s3)?;
+            let options = timeline::TimelineOptions {
+                tools,
+                bucket_name,
+                s3_access,
+                prefix,
+                max_gap: Duration::hours(args.every_n_hours) + Duration::minutes(args.lag_window_in_minutes),
+                format: if json { OutputFormat::Json } else { output },
+                concurrency: args.concurrency,
+            };
+            timeline::run(&options, now).await?;
+            return Ok(());
+        }
+        Commands::Inventory { bucket_name, s3, prefix, output } => {
+            let s3_access = s3_access(&args, s3)?;
+            let options = inventory::InventoryOptions { tools, bucket_name, s3_access, prefix, format: output, concurrency: args.concurrency };
+            inventory::run(&options).await?;
+            return Ok(());
+        }
+        Commands::ComplianceReport { bucket_name, s3, prefix, output } => {
+            let s3_access = s3_access(&args, s3)?;
+            let options = compliance::ComplianceOptions { tools, bucket_name, s3_access, prefix, format: output, concurrency: args.concurrency };
+            compliance::run(&options).await?;
+            return Ok(());
+        }
+        Commands::Resume { s3 } => {
+            let s3_access = s3_access(&args, s3)?;
+            // The bucket, key and tags of each upload come from its journal.
+            let spool = spool(&args, "").ok_or_else(|| eyre!("resume needs --spool-dir").wrap_err(Failure::Config))?;
+            let options = resume::ResumeOptions { tools, s3_access, spool, part_retries: args.part_retries, concurrency: args.concurrency };
+            resume::run(&options).await?;
+            return Ok(());
+        }
+        Commands::History { bucket_name, s3, last, output, json } => {
+            let s3_access = s3_access(&args, s3)?;
+            let format = if json { OutputFormat::Json } else { output };
+            let options = history::HistoryOptions { tools, bucket_name, s3_access, last, format, concurrency: args.concurrency };
+            history::run(&options).await?;
+            return Ok(());
+        }
+        Commands::State { action } => {
+            let path = args.state_db.as_deref().ok_or_else(|| eyre!("state needs --state-db").wrap_err(Failure::Config))?;
+            state::run(&tools, path, action).await?;
+            return Ok(());
+        }
+        Commands::Delete { bucket_name, s3, key, prefix, older_than, tag, yes } => {
+            let s3_access = s3_access(&args, s3)?;
+            let options = delete::DeleteOptions {
+                tools,
+                bucket_name,
+                s3_access,
+                keys: key,
+                prefix,
+                older_than: older_than.map(|older_than| Duration::from_std(*older_than)).transpose()?,
+                tags: tag,
+                yes,
+            };
+            delete::run(&options, now).await?;
+            return Ok(());
+        }
+        Commands::Gc { bucket_name, s3, grace, dry_run } => {
+            let s3_access = s3_access(&args, s3)?;
+            let options = gc::GcOptions {
+                tools,
+                bucket_name,
+                s3_access,
+                grace: Duration::from_std(*grace)?,
+                dry_run,
+                concurrency: args.concurrency,
+            };
+            gc::run(&options, now).await?;
+            return Ok(());
+        }
+        Commands::Copy { from_bucket, to_bucket, s3, key, latest, prefix, tag } => {
+            let s3_access = s3_access(&args, s3)?;
+            let options = copy::CopyOptions {
+                tools,
+                s3_access,
+                from_bucket,
+                to_bucket,
+                key,
+                latest,
+                prefix,
+                tags: tag,
+            };
+            copy::run(&options).await?;
+            return Ok(());
+        }
+        Commands::InitBucket { bucket_name, s3, retention, object_lock_days, object_lock_mode, deny_deletes, allow_delete_by } => {
+            let s3_access = s3_access(&args, s3)?;
+            let options = init_bucket::InitBucketOptions {
+                tools,
+                bucket_name,
+                s3_access,
+                retention,
+                tag_merge: args.tag_style.merge(args.tag_merge),
+                create_bucket: !args.no_create_bucket,
+                object_lock: object_lock_days.map(|days| (object_lock_mode, days)),
+                deny_deletes,
+                allow_delete_by,
+            };
+            init_bucket::run(&options).await?;
+            return Ok(());
+        }
+        Commands::SetupReplication { bucket_name, s3, destination_bucket, destination_account, role_arn, storage_class, tag } => {
+            let s3_access = s3_access(&args, s3)?;
+            let options = replication::ReplicationOptions {
+                tools,
+                bucket_name,
+                s3_access,
+                destination_bucket,
+                destination_account,
+                role_arn,
+                storage_class,
+                tags: tag,
+            };
+            replication::run(&options).await?;
+            return Ok(());
+        }
+        Commands::Retain { bucket_name, s3, prefix, keep, enforce, yes } => {
+            let s3_access = s3_access(&args, s3)?;
+            let options = retention::RetainOptions {
+                tools,
+                bucket_name,
+                s3_access,
+                prefix,
+                policy: keep,
+                enforcement: enforce,
+                yes,
+            };
+            retention::run(&options).await?;
+            return Ok(());
+        }
+        Commands::UntagExpired { bucket_name, s3, prefix, retention, yes } => {
+            let s3_access = s3_access(&args, s3)?;
+            let options = untag::UntagOptions {
+                tools,
+                bucket_name,
+                s3_access,
+                prefix,
+                retention,
+                yes,
+            };
+            untag::run(&options, now).await?;
+            return Ok(());
+        }
+        Commands::TagObject { bucket_name, s3, key, prefix, at } => {
+            let s3_access = s3_access(&args, s3)?;
+            let tags = match at {
+                Some(at) => {
+                    let mut evaluations = tags::evaluate(&schedule, at)?;
+                    evaluations.extend(tags::evaluate_rules(&schedule, rules, at)?);
+                    evaluations.extend(calendar::evaluate(&schedule, &calendar, at)?);
+                    tags::merge(tags::matched(&evaluations), args.tag_style.merge(args.tag_merge))
+                }
+                None => tags,
+            };
+            let options = tag_object::TagObjectOptions {
+                tools,
+                bucket_name,
+                s3_access,
+                keys: key,
+                prefix,
+                tags,
+                concurrency: args.concurrency,
+            };
+            tag_object::run(&options).await?;
+            return Ok(());
+        }
+        Commands::Verify { bucket_name, s3, namespace, database, key, deep } => {
+            let s3_access = s3_access(&args, s3)?;
+            let options = verify::VerifyOptions {
+                tools,
+                bucket_name,
+                s3_access,
+                namespace,
+                database,
+                key,
+                key_template: key_template(&args, &KeyVars { engine: "surrealdb", ..KeyVars::default() }),
+                cluster: args.cluster.clone(),
+                deep,
+            };
+            verify::run(&options).await.wrap_err(Failure::Verify)?;
+            return Ok(());
+        }
+        Commands::TrainDictionary { bucket_name, s3, namespace, database, max_size, sample_size } => {
+            let s3_access = s3_access(&args, s3)?;
+            let options = dictionary::TrainOptions {
+                tools,
+                bucket_name,
+                s3_access,
+                namespace,
+                database,
+                key_template: key_template(&args, &KeyVars { engine: "surrealdb", ..KeyVars::default() }),
+                cluster: args.cluster.clone(),
+                max_size,
+                sample_size,
+            };
+            dictionary::train(&options).await?;
+            return Ok(());
+        }
+        Commands::Restore { bucket_name, s3, namespace, database, key, before, tag, address, password, target_namespace, target_database, yes, require_empty, force, print_only } => {
+            let s3_access = s3_access(&args, s3)?;
+            let options = restore::RestoreOptions {
+                tools,
+                bucket_name,
+                s3_access,
+                namespace,
+                database,
+                key,
+                before,
+                tags: tag,
+                key_template: key_template(&args, &KeyVars { engine: "surrealdb", ..KeyVars::default() }),
+                cluster: args.cluster.clone(),
+                address,
+                password,
+                target_namespace,
+                target_database,
+                yes,
+                require_empty,
+                force,
+                print_only,
+            };
+            restore::run(&options).await?;
+            return Ok(());
+        }
+        Commands::Drill { bucket_name, s3, address, password, namespace, database, target_namespace, keep, pd_host_and_port, prefix, query } => {
+            let s3_access = s3_access(&args, s3)?;
+            let target = match (address, pd_host_and_port) {
+                (Some(address), _) => drill::DrillTarget::Surrealdb {
+                    namespace: namespace.ok_or_else(|| eyre!("A SurrealDB drill needs --namespace").wrap_err(Failure::Config))?,
+                    database: database.ok_or_else(|| eyre!("A SurrealDB drill needs --database").wrap_err(Failure::Config))?,
+                    key_template: key_template(&args, &KeyVars { engine: "surrealdb", ..KeyVars::default() }),
+                    cluster: args.cluster.clone(),
+                    address,
+                    password,
+                    target_namespace,
+                    keep,
+                },
+                (None, Some(pd_host_and_port)) => drill::DrillTarget::Tikv {
+                    prefix: prefix.ok_or_else(|| eyre!("A TiKV drill needs --prefix").wrap_err(Failure::Config))?,
+                    pd_host_and_port,
+                },
+                (None, None) => return Err(eyre!("drill needs --address or --pd-host-and-port").wrap_err(Failure::Config)),
+            };
+            let options = drill::DrillOptions {
+                tools: tools.clone(),
+                bucket_name: bucket_name.clone(),
+                s3_access,
+                target,
+                queries: query,
+                random: RandomState::new().build_hasher().finish(),
+            };
+            let started = Instant::now();
+            let timings = Timings::default();
+            let result = timings.time("drill", drill::run(&options)).await;
+            // A drill is recorded and alerted on like a backup, so a failed one is not missed.
+            let mut summary = RunSummary::new("drill", Vec::new(), started, &timings, &result);
+            if let Some(failure) = Failure::of_backup(&result) {
+                summary.error_code = Some(failure.code());
+                summary.error_stage = failure.stage().map(String::from);
+            }
+            if let Some(path) = &args.state_db {
+                let record = history::RunRecord::new(&summary, now, Utc::now());
+                if let Err(err) = (state::StateDb { tools: &tools, path }).record_run(&record).await {
+                    tracing::warn!(target: "state_db", error = format!("{:#}", err), "Unable to record the run");
+                }
+            }
+            let outcome = notify::Outcome {
+                summary: &summary,
+                bucket: &bucket_name,
+                prefix: None,
+                error: result.as_ref().err().map(|err| secret::redact(&format!("{:#}", err))),
+                stderr_tail: None,
             };
-            let diff = next_when - now;
-            if diff.num_seconds().abs() < (args.lag_window_in_minutes * 60) {
-                tags.push(check.1.clone());
+            send_notifications(&args, &tools, &outcome, result.is_ok()).await;
+            summary.print()?;
+            result.map(|_| ())
+        }
+        Commands::Lifecycle { retention, flavor, minio_tier, mc_target } => {
+            if flavor != lifecycle::Flavor::Minio && (minio_tier.is_some() || mc_target.is_some()) {
+                return Err(eyre!("--minio-tier and --mc-target need --flavor minio").wrap_err(Failure::Config));
             }
-            info!(target: "match_attempt_results", tag = check.1.as_value(), when = next_when.to_rfc3339(), matched = diff.num_seconds().abs() < args.lag_window_in_minutes);
+            lifecycle::run(&retention, args.tag_style.merge(args.tag_merge), flavor, minio_tier.as_deref(), mc_target.as_deref())
+        }
+        Commands::SimulateRetention { from, to, retention, step_days, backup_size, output } => {
+            simulate::run(&schedule, &retention, from, to, step_days, backup_size, output)
         }
     }
-    let tag_set_string = serde_json::to_string(&TagSet { tag_set: tags })?;
-    info!(tag_set_string);
+}
+
+/// A delay up to `max`, picked by `random`.
+fn jitter(max: std::time::Duration, random: u64) -> std::time::Duration {
+    let max = u64::try_from(max.as_millis()).unwrap_or(u64::MAX);
+    std::time::Duration::from_millis(random % max.saturating_add(1))
+}
 
-    match args.command {
-        Commands::Surrealdb {bucket_name, aws_endpoint, aws_id, aws_key, namespace, database, address, password } => {
+/// The error of a `run-all` whose jobs failed. When every job failed alike the run exits like a
+/// single such job would.
+fn run_all_error(failures: &[String], kinds: &[Failure]) -> Report {
+    let report = eyre!("{} job(s) failed:\n{}", failures.len(), failures.join("\n"));
+    match kinds.first() {
+        Some(first) if kinds.iter().all(|kind| kind.code() == first.code()) => report.wrap_err(first.clone()),
+        _ => report,
+    }
+}
+
+/// Runs one backup command between its hooks, returning the run summary and the
+/// backup's outcome; the outer error is for commands that are not backups.
+async fn run_backup(
+    args: &Args,
+    tools: &Tools,
+    backup: Commands,
+    tags: &[Tag],
+    tag_computation: std::time::Duration,
+    now: DateTime<Utc>,
+    deadline: Option<tokio::time::Instant>,
+) -> Result<(RunSummary, Result<BackupReport, Report>), Report> {
+    let started = Instant::now();
+    let timings = Timings::default();
+    timings.record("tag_computation", tag_computation);
+    let tag_set_string = serde_json::to_string(&TagSet { tag_set: tags.to_vec() })?;
+    let hook_tags = tag_set_string.clone();
+    let spool = spool(args, &tag_set_string);
+    let split = args.split_size.map(|size| Split { size: size.0, tags: tag_set_string.clone() });
+    // Each numbered object is its own multipart upload, which the spool journal does not track.
+    if spool.is_some() && split.is_some() {
+        return Err(eyre!("--split-size cannot be combined with --spool-dir").wrap_err(Failure::Config));
+    }
+    let min_expected_bytes = args.min_expected_bytes.map_or(0, |size| size.0);
+    // The fixed start of the job's keys tells its notifications apart from other jobs'.
+    let (command, bucket_name, key_prefix, history_access, backup): (_, _, String, _, BackupFuture) = match backup {
+        Commands::Surrealdb {bucket_name, s3, namespace, database, address, password, only_tables, exclude_tables, schema_only, zstd_dictionary, cas } => {
+            // Chunks are stored as separate small objects, which neither spooling nor splitting applies to.
+            if cas && (spool.is_some() || split.is_some()) {
+                return Err(eyre!("--cas cannot be combined with --spool-dir or --split-size").wrap_err(Failure::Config));
+            }
             // Check for S3 override parameters, ie- MinIO.
-            let s3_endpoint = if aws_endpoint.trim().is_empty() || aws_id.trim().is_empty() || aws_key.trim().is_empty() { 
-                None 
-            } else { Some((aws_endpoint, aws_id, aws_key))};
+            let s3_access = s3_access(args, s3)?;
+            let vars = KeyVars { engine: "surrealdb", cluster: args.cluster.as_deref(), namespace: Some(&namespace), database: Some(&database) };
+            let storage_key = key_template(args, &vars).render(&vars, now, &args.format_timestamp).wrap_err(Failure::Config)?;
+            let key_prefix = key_template(args, &vars).prefix(&vars).wrap_err(Failure::Config)?;
+            let filter = surreal::ExportFilter { only_tables, exclude_tables, schema_only };
+            // Filtered exports sit in a directory of their own next to the full ones.
+            let storage_key = match (filter.label(), storage_key.rsplit_once('/')) {
+                (Some(label), Some((directory, name))) => format!("{}/{}/{}", directory, label, name),
+                (Some(label), None) => format!("{}/{}", label, storage_key),
+                (None, _) => storage_key,
+            };
+            let storage_key = match cas {
+                true => cas::index_key(&storage_key),
+                false => args.compression.with_extension(storage_key),
+            };
             // Command::new will thow if the required binaries do not exist.
-            let command_output = surrealdb_backup(now, args.bin_path, bucket_name, namespace, database, address, password, tag_set_string, s3_endpoint, args.format_timestamp)?;
-            info!(target: "surrealdb_backup_output", success=command_output.status.success(), exit_code=command_output.status.code().or(Some(0)), stdout=String::from_utf8(command_output.stdout)?, stderr=String::from_utf8(command_output.stderr)?);
+            let options = SurrealdbOptions {
+                tools: tools.clone(),
+                bucket_name: bucket_name.clone(),
+                s3_access: s3_access.clone(),
+                create_bucket: !args.no_create_bucket,
+                address,
+                password,
+                namespace,
+                database,
+                filter,
+                tags: tag_set_string,
+                storage_key,
+                min_expected_bytes,
+                allow_empty: args.allow_empty,
+                compression: args.compression,
+                compression_level: args.compression_level,
+                zstd_dictionary,
+                cas,
+                part_size: args.part_size,
+                part_retries: args.part_retries,
+                spool: spool.clone(),
+                split: split.clone(),
+                concurrency: args.concurrency,
+                deadline,
+                timings: timings.clone(),
+            };
+            ("surrealdb", bucket_name, key_prefix, s3_access, Box::pin(async move { surrealdb_backup(&options).await }))
         }
-        Commands::Tikv {bucket_name, aws_endpoint, aws_id, aws_key, pd_host_and_port } => {
+        Commands::Tikv {bucket_name, s3, pd_host_and_port, credential_mode } => {
             // Check for S3 override parameters, ie- MinIO.
-            let s3_endpoint = if aws_endpoint.trim().is_empty() || aws_id.trim().is_empty() || aws_key.trim().is_empty() { 
-                None 
-            } else { Some((aws_endpoint, aws_id, aws_key))};
+            let s3_access = s3_access(args, s3)?;
+            let vars = KeyVars { engine: "tikv", cluster: args.cluster.as_deref(), ..KeyVars::default() };
+            let storage_key = key_template(args, &vars).render(&vars, now, &args.format_timestamp).wrap_err(Failure::Config)?;
+            let key_prefix = key_template(args, &vars).prefix(&vars).wrap_err(Failure::Config)?;
             // Command::new will thow if the required binaries do not exist.
-            tikv_backup(now, args.bin_path, bucket_name, pd_host_and_port, tag_set_string, s3_endpoint, args.format_timestamp)?;
+            let options = TikvOptions {
+                tools: tools.clone(),
+                bucket_name: bucket_name.clone(),
+                s3_access: s3_access.clone(),
+                create_bucket: !args.no_create_bucket,
+                pd_host_and_port,
+                credential_mode,
+                tags: tag_set_string,
+                storage_key,
+                min_expected_bytes,
+                allow_empty: args.allow_empty,
+                concurrency: args.concurrency,
+                deadline,
+                timings: timings.clone(),
+            };
+            ("tikv", bucket_name, key_prefix, s3_access, Box::pin(async move { tikv_backup(&options).await }))
+        }
+        Commands::Clickhouse { bucket_name, s3, address, user, password, database } => {
+            let s3_access = s3_access(args, s3)?;
+            let vars = KeyVars { engine: "clickhouse", cluster: args.cluster.as_deref(), database: Some(&database), ..KeyVars::default() };
+            let storage_key = key_template(args, &vars).render(&vars, now, &args.format_timestamp).wrap_err(Failure::Config)?;
+            let key_prefix = key_template(args, &vars).prefix(&vars).wrap_err(Failure::Config)?;
+            let options = clickhouse::ClickhouseOptions {
+                tools: tools.clone(),
+                bucket_name: bucket_name.clone(),
+                s3_access: s3_access.clone(),
+                create_bucket: !args.no_create_bucket,
+                address,
+                user,
+                password,
+                database,
+                tags: tag_set_string,
+                storage_key,
+                min_expected_bytes,
+                allow_empty: args.allow_empty,
+                concurrency: args.concurrency,
+                deadline,
+                timings: timings.clone(),
+            };
+            ("clickhouse", bucket_name, key_prefix, s3_access, Box::pin(async move { clickhouse::backup(&options).await }))
+        }
+        Commands::Cassandra { bucket_name, s3, address, data_dir, keyspaces } => {
+            let s3_access = s3_access(args, s3)?;
+            let vars = KeyVars { engine: "cassandra", cluster: args.cluster.as_deref(), ..KeyVars::default() };
+            let storage_key = key_template(args, &vars).render(&vars, now, &args.format_timestamp).wrap_err(Failure::Config)?;
+            let key_prefix = key_template(args, &vars).prefix(&vars).wrap_err(Failure::Config)?;
+            let options = cassandra::CassandraOptions {
+                tools: tools.clone(),
+                bucket_name: bucket_name.clone(),
+                s3_access: s3_access.clone(),
+                create_bucket: !args.no_create_bucket,
+                address,
+                data_dir,
+                keyspaces,
+                snapshot: format!("btagger-{}", now.format("%Y%m%dT%H%M%SZ")),
+                tags: tag_set_string,
+                storage_key,
+                compression: args.compression,
+                compression_level: args.compression_level,
+                part_size: args.part_size,
+                part_retries: args.part_retries,
+                spool: spool.clone(),
+                split: split.clone(),
+                min_expected_bytes,
+                allow_empty: args.allow_empty,
+                concurrency: args.concurrency,
+                deadline,
+                timings: timings.clone(),
+            };
+            ("cassandra", bucket_name, key_prefix, s3_access, Box::pin(async move { cassandra::backup(&options).await }))
+        }
+        Commands::Elasticsearch { bucket_name, s3, address, user, password, repository, indices } => {
+            let s3_access = s3_access(args, s3)?;
+            // Snapshots are keyed by the repository, not by a key template.
+            let key_prefix = repository.clone();
+            let options = elasticsearch::ElasticsearchOptions {
+                tools: tools.clone(),
+                bucket_name: bucket_name.clone(),
+                address,
+                user,
+                password,
+                repository,
+                indices,
+                // Snapshot names must be lowercase.
+                snapshot: format!("btagger-{}", now.format("%Y%m%d-%H%M%S")),
+                tags: tags.to_vec(),
+                min_expected_bytes,
+                allow_empty: args.allow_empty,
+                deadline,
+                timings: timings.clone(),
+            };
+            ("elasticsearch", bucket_name, key_prefix, s3_access, Box::pin(async move { elasticsearch::backup(&options).await }))
+        }
+        Commands::Influxdb { bucket_name, s3, influx_version, address, token, org, database } => {
+            let s3_access = s3_access(args, s3)?;
+            let vars = KeyVars { engine: "influxdb", cluster: args.cluster.as_deref(), database: database.as_deref(), ..KeyVars::default() };
+            let storage_key = key_template(args, &vars).render(&vars, now, &args.format_timestamp).wrap_err(Failure::Config)?;
+            let key_prefix = key_template(args, &vars).prefix(&vars).wrap_err(Failure::Config)?;
+            let options = influxdb::InfluxdbOptions {
+                tools: tools.clone(),
+                bucket_name: bucket_name.clone(),
+                s3_access: s3_access.clone(),
+                create_bucket: !args.no_create_bucket,
+                version: influx_version,
+                address: address.unwrap_or_else(|| influx_version.default_address().to_string()),
+                token,
+                org,
+                database,
+                tags: tag_set_string,
+                storage_key: args.compression.with_extension(format!("{}.tar", storage_key)),
+                compression: args.compression,
+                compression_level: args.compression_level,
+                part_size: args.part_size,
+                part_retries: args.part_retries,
+                spool: spool.clone(),
+                split: split.clone(),
+                min_expected_bytes,
+                allow_empty: args.allow_empty,
+                concurrency: args.concurrency,
+                deadline,
+                timings: timings.clone(),
+            };
+            ("influxdb", bucket_name, key_prefix, s3_access, Box::pin(async move { influxdb::backup(&options).await }))
+        }
+        Commands::Neo4j { bucket_name, s3, database } => {
+            // The dump is an opaque archive streamed straight to the bucket, so an empty database
+            // cannot be told apart and there is nothing for --allow-empty to allow.
+            if args.allow_empty {
+                return Err(eyre!("--allow-empty does not apply to neo4j, whose dumps are not checked for emptiness; use --min-expected-bytes").wrap_err(Failure::Config));
+            }
+            let s3_access = s3_access(args, s3)?;
+            let vars = KeyVars { engine: "neo4j", cluster: args.cluster.as_deref(), database: Some(&database), ..KeyVars::default() };
+            let storage_key = key_template(args, &vars).render(&vars, now, &args.format_timestamp).wrap_err(Failure::Config)?;
+            let key_prefix = key_template(args, &vars).prefix(&vars).wrap_err(Failure::Config)?;
+            let options = neo4j::Neo4jOptions {
+                tools: tools.clone(),
+                bucket_name: bucket_name.clone(),
+                s3_access: s3_access.clone(),
+                create_bucket: !args.no_create_bucket,
+                database,
+                tags: tag_set_string,
+                storage_key: args.compression.with_extension(format!("{}.dump", storage_key)),
+                compression: args.compression,
+                compression_level: args.compression_level,
+                part_size: args.part_size,
+                part_retries: args.part_retries,
+                spool: spool.clone(),
+                split: split.clone(),
+                min_expected_bytes,
+                concurrency: args.concurrency,
+                deadline,
+                timings: timings.clone(),
+            };
+            ("neo4j", bucket_name, key_prefix, s3_access, Box::pin(async move { neo4j::backup(&options).await }))
         }
-        Commands::Tags => {
-            print!("{}", tag_set_string);
+        Commands::Cockroach { bucket_name, s3, url, database } => {
+            let s3_access = s3_access(args, s3)?;
+            let vars = KeyVars { engine: "cockroach", cluster: args.cluster.as_deref(), database: database.as_deref(), ..KeyVars::default() };
+            let storage_key = key_template(args, &vars).render(&vars, now, &args.format_timestamp).wrap_err(Failure::Config)?;
+            let key_prefix = key_template(args, &vars).prefix(&vars).wrap_err(Failure::Config)?;
+            let options = cockroach::CockroachOptions {
+                tools: tools.clone(),
+                bucket_name: bucket_name.clone(),
+                s3_access: s3_access.clone(),
+                create_bucket: !args.no_create_bucket,
+                url,
+                database,
+                tags: tag_set_string,
+                storage_key,
+                min_expected_bytes,
+                allow_empty: args.allow_empty,
+                concurrency: args.concurrency,
+                deadline,
+                timings: timings.clone(),
+            };
+            ("cockroach", bucket_name, key_prefix, s3_access, Box::pin(async move { cockroach::backup(&options).await }))
+        }
+        Commands::Sqlite { bucket_name, s3, file } => {
+            let s3_access = s3_access(args, s3)?;
+            // The file's stem names the database in keys, e.g. 'app' for /srv/app/app.db.
+            let database = file.file_stem().map(|stem| stem.to_string_lossy().to_string());
+            let vars = KeyVars { engine: "sqlite", cluster: args.cluster.as_deref(), database: database.as_deref(), ..KeyVars::default() };
+            let storage_key = key_template(args, &vars).render(&vars, now, &args.format_timestamp).wrap_err(Failure::Config)?;
+            let key_prefix = key_template(args, &vars).prefix(&vars).wrap_err(Failure::Config)?;
+            let options = sqlite::SqliteOptions {
+                tools: tools.clone(),
+                bucket_name: bucket_name.clone(),
+                s3_access: s3_access.clone(),
+                create_bucket: !args.no_create_bucket,
+                path: file,
+                tags: tag_set_string,
+                storage_key: args.compression.with_extension(format!("{}.db", storage_key)),
+                compression: args.compression,
+                compression_level: args.compression_level,
+                part_size: args.part_size,
+                part_retries: args.part_retries,
+                spool: spool.clone(),
+                split: split.clone(),
+                min_expected_bytes,
+                allow_empty: args.allow_empty,
+                concurrency: args.concurrency,
+                deadline,
+                timings: timings.clone(),
+            };
+            ("sqlite", bucket_name, key_prefix, s3_access, Box::pin(async move { sqlite::backup(&options).await }))
+        }
+        Commands::Qdrant { bucket_name, s3, address, api_key, collection } => {
+            let s3_access = s3_access(args, s3)?;
+            let vars = KeyVars { engine: "qdrant", cluster: args.cluster.as_deref(), database: collection.as_deref(), ..KeyVars::default() };
+            let storage_key = key_template(args, &vars).render(&vars, now, &args.format_timestamp).wrap_err(Failure::Config)?;
+            let key_prefix = key_template(args, &vars).prefix(&vars).wrap_err(Failure::Config)?;
+            let options = qdrant::QdrantOptions {
+                tools: tools.clone(),
+                bucket_name: bucket_name.clone(),
+                s3_access: s3_access.clone(),
+                create_bucket: !args.no_create_bucket,
+                address,
+                api_key,
+                collection,
+                tags: tag_set_string,
+                storage_key: args.compression.with_extension(format!("{}.snapshot", storage_key)),
+                compression: args.compression,
+                compression_level: args.compression_level,
+                part_size: args.part_size,
+                part_retries: args.part_retries,
+                spool: spool.clone(),
+                split: split.clone(),
+                min_expected_bytes,
+                allow_empty: args.allow_empty,
+                concurrency: args.concurrency,
+                deadline,
+                timings: timings.clone(),
+            };
+            ("qdrant", bucket_name, key_prefix, s3_access, Box::pin(async move { qdrant::backup(&options).await }))
+        }
+        Commands::Nats { bucket_name, s3, url, creds, context, stream } => {
+            let s3_access = s3_access(args, s3)?;
+            let vars = KeyVars { engine: "nats", cluster: args.cluster.as_deref(), database: stream.as_deref(), ..KeyVars::default() };
+            let storage_key = key_template(args, &vars).render(&vars, now, &args.format_timestamp).wrap_err(Failure::Config)?;
+            let key_prefix = key_template(args, &vars).prefix(&vars).wrap_err(Failure::Config)?;
+            let options = nats::NatsOptions {
+                tools: tools.clone(),
+                bucket_name: bucket_name.clone(),
+                s3_access: s3_access.clone(),
+                create_bucket: !args.no_create_bucket,
+                url,
+                creds,
+                context,
+                stream,
+                tags: tag_set_string,
+                storage_key: args.compression.with_extension(format!("{}.tar", storage_key)),
+                compression: args.compression,
+                compression_level: args.compression_level,
+                part_size: args.part_size,
+                part_retries: args.part_retries,
+                spool: spool.clone(),
+                split: split.clone(),
+                min_expected_bytes,
+                allow_empty: args.allow_empty,
+                concurrency: args.concurrency,
+                deadline,
+                timings: timings.clone(),
+            };
+            ("nats", bucket_name, key_prefix, s3_access, Box::pin(async move { nats::backup(&options).await }))
+        }
+        _ => return Err(eyre!("Not a backup command")),
+    };
+    // The pre hook gates the backup; when it fails nothing is exported, but the run still
+    // ends with a summary and the failure hook like any other failed backup.
+    let result = match &args.pre_cmd {
+        Some(pre_cmd) => {
+            let env = [
+                ("BTAGGER_COMMAND", command.to_string()),
+                ("BTAGGER_BUCKET", bucket_name.clone()),
+                ("BTAGGER_TAGS", hook_tags.clone()),
+            ];
+            match hooks::run("pre", pre_cmd, &env).await {
+                Ok(()) => backup.await,
+                Err(err) => Err(err),
+            }
+        }
+        None => backup.await,
+    };
+    let mut summary = RunSummary::new(command, tags.to_vec(), started, &timings, &result);
+    if let Some(failure) = Failure::of_backup(&result) {
+        summary.error_code = Some(failure.code());
+        summary.error_stage = failure.stage().map(String::from);
+    }
+    // The record is written next to the backups and to the local catalog, but failing to write
+    // it fails nothing.
+    let record = history::RunRecord::new(&summary, now, Utc::now());
+    if !args.no_history {
+        if let Err(err) = history::append(tools, &history_access, &bucket_name, &record).await {
+            tracing::warn!(target: "run_history", error = format!("{:#}", err), "Unable to record the run");
+        }
+    }
+    if let Some(path) = &args.state_db {
+        if let Err(err) = (state::StateDb { tools, path }).record_run(&record).await {
+            tracing::warn!(target: "state_db", error = format!("{:#}", err), "Unable to record the run");
+        }
+    }
+
+    let succeeded = matches!(&result, Ok(report) if report.success);
+    let hook = if succeeded { &args.post_success_cmd } else { &args.post_failure_cmd };
+    if let Some(hook) = hook {
+        let mut env = vec![
+            ("BTAGGER_COMMAND", summary.command.clone()),
+            ("BTAGGER_BUCKET", bucket_name.clone()),
+            ("BTAGGER_KEY", summary.storage_keys.join(" ")),
+            ("BTAGGER_TAGS", hook_tags),
+            ("BTAGGER_DURATION", format!("{:.3}", started.elapsed().as_secs_f64())),
+            ("BTAGGER_BYTES", summary.bytes.to_string()),
+            ("BTAGGER_RAW_BYTES", summary.raw_bytes.map(|bytes| bytes.to_string()).unwrap_or_default()),
+            ("BTAGGER_SUCCESS", succeeded.to_string()),
+        ];
+        if let Err(err) = &result {
+            env.push(("BTAGGER_ERROR", secret::redact(&format!("{:#}", err))));
+        }
+        // A failing hook is reported but does not change the outcome of the backup.
+        let name = if succeeded { "post-success" } else { "post-failure" };
+        if let Err(err) = hooks::run(name, hook, &env).await {
+            tracing::warn!(target: "hook", error = format!("{:#}", err), "Hook failed");
         }
     }
-    Ok(())
+    let stderr_tail = match &result {
+        Ok(report) => report.stderr_tail.clone(),
+        Err(err) => err.chain().find_map(|err| err.downcast_ref::<process::CommandFailed>()).map(|failed| failed.stderr_tail.clone()),
+    };
+    let outcome = notify::Outcome {
+        summary: &summary,
+        bucket: &bucket_name,
+        prefix: Some(&key_prefix),
+        error: result.as_ref().err().map(|err| secret::redact(&format!("{:#}", err))),
+        stderr_tail: stderr_tail.map(|tail| secret::redact(&tail)),
+    };
+    send_notifications(args, tools, &outcome, succeeded).await;
+    Ok((summary, result))
 }
 
-fn periods(
-    day_offset_in_hours: i64,
-    minutes_offset_from_hour: i64,
-    every_n_hours: i64,
-) -> Vec<(String, Tag, bool)> {
-    // always tag as standard, so manual runs get tagged for lifecycle rules
-    // let standard = (
-    //     format!("{} {}/{} * * *", minutes_offset_from_hour, day_offset_in_hours, every_n_hours),
-    //     Tag {
-    //         key: String::from("standard"),
-    //         value: String::from("1"),
-    //     },
-    //     false,
-    // );
-
-    return vec![
-        (
-            format!(
-                "{} {} * * *",
-                minutes_offset_from_hour,
-                every_n_hours + day_offset_in_hours
-            ),
-            Tag {
-                key: String::from("nightly"),
-                value: String::from("1"),
-            },
-            false,
-        ),
-        (
-            format!(
-                "{} {} * * 6",
-                minutes_offset_from_hour,
-                every_n_hours + day_offset_in_hours
-            ),
-            Tag {
-                key: String::from("weekly"),
-                value: String::from("1"),
-            },
-            false,
-        ),
-        (
-            format!(
-                "{} {} 1 * *",
-                minutes_offset_from_hour,
-                every_n_hours + day_offset_in_hours
-            ),
-            Tag {
-                key: String::from("monthly"),
-                value: String::from("1"),
-            },
-            true,
-        ),
-        (
-            format!(
-                "{} {} 1 */3 *",
-                minutes_offset_from_hour,
-                every_n_hours + day_offset_in_hours
-            ),
-            Tag {
-                key: String::from("quarterly"),
-                value: String::from("1"),
-            },
-            true,
-        ),
-        (
-            format!(
-                "{} {} 1 1 *",
-                minutes_offset_from_hour,
-                every_n_hours + day_offset_in_hours
-            ),
-            Tag {
-                key: String::from("yearly"),
-                value: String::from("1"),
-            },
-            true,
-        ),
-    ];
+/// Sends the outcome of a run to the notification channels given by flags. Like the hooks, a
+/// notification that cannot be sent does not change the outcome.
+async fn send_notifications(args: &Args, tools: &Tools, outcome: &notify::Outcome<'_>, succeeded: bool) {
+    if let Some(webhook) = args.slack_webhook.as_ref().filter(|_| args.notify_on.wants(succeeded)) {
+        if let Err(err) = notify::slack(tools, webhook, &notify::slack_message(outcome)).await {
+            tracing::warn!(target: "notify", error = format!("{:#}", err), "Notification failed");
+        }
+    }
+    // PagerDuty hears about every backup, regardless of --notify-on, so that successes resolve.
+    if let Some(routing_key) = &args.pagerduty_routing_key {
+        if let Err(err) = notify::pagerduty(tools, &notify::pagerduty_event(outcome, routing_key)).await {
+            tracing::warn!(target: "notify", error = format!("{:#}", err), "Notification failed");
+        }
+    }
+    if let Some(smtp_host) = args.smtp_host.as_ref().filter(|_| args.notify_on.wants(succeeded)) {
+        let mail = notify::Mail {
+            smtp_host: smtp_host.clone(),
+            from: args.mail_from.clone(),
+            to: args.mail_to.clone(),
+            credentials: args.smtp_user.clone().zip(args.smtp_password.clone()),
+        };
+        if let Err(err) = notify::mail(tools, &mail, &notify::mail_message(outcome, &mail, Utc::now())).await {
+            tracing::warn!(target: "notify", error = format!("{:#}", err), "Notification failed");
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 struct ListObjectResult {
+    #[serde(default)]
     contents: Vec<Object>,
 }
 
@@ -283,314 +2200,670 @@ struct ListObjectResult {
 #[serde(rename_all = "PascalCase")]
 struct Object {
     key: String,
+    #[serde(default)]
+    size: u64,
+    #[serde(default)]
+    last_modified: String,
 }
 
-fn tikv_backup(
-    time: DateTime<Utc>,
-    bin_path: String,
+
+/// A TiKV cluster to back up with `tikv-br backup raw`, and where to.
+struct TikvOptions {
+    tools: Tools,
     bucket_name: String,
+    s3_access: S3Access,
+    create_bucket: bool,
+    /// Placement driver address, `host:port`.
     pd_host_and_port: String,
+    credential_mode: TikvCredentialMode,
+    /// Tag set JSON applied to every object of the backup.
     tags: String,
-    s3_endpoint: Option<(String, String, String)>,
-    format_string: String,
-) -> Result<String, Report> {
-    let storage_key = format!("tikv/{}", time.format(format_string.as_str()).to_string().replace("+", ""));
+    storage_key: String,
+    min_expected_bytes: u64,
+    allow_empty: bool,
+    concurrency: usize,
+    deadline: Option<tokio::time::Instant>,
+    timings: Timings,
+}
+
+async fn tikv_backup(options: &TikvOptions) -> Result<BackupReport, Report> {
+    let (tools, s3_access, bucket_name, storage_key) = (&options.tools, &options.s3_access, &options.bucket_name, &options.storage_key);
+    let timings = &options.timings;
     // Existing values:
     // tikv-br backup raw --pd=tidb-cluster-pd.tidb-admin:2379 --send-credentials-to-tikv=false
-    let endpoint_is_some = s3_endpoint.is_some();
-    let mut aws_endpoint: String = String::new();
-    let mut aws_id: String = String::new();
-    let mut aws_key: String = String::new();
-    if let Some(s3_endpoint) = s3_endpoint {
-        aws_endpoint = s3_endpoint.0;
-        aws_id = s3_endpoint.1;
-        aws_key = s3_endpoint.2;
-    }
-    let _s3_create_bucket_command_output = if endpoint_is_some {
-        Command::new(format!("{}/bin/aws", bin_path))
-            .env("AWS_ACCESS_KEY_ID", &aws_id)
-            .env("AWS_SECRET_ACCESS_KEY", &aws_key)
-            .arg("s3api")
-            .arg("create-bucket")
-            .arg("--endpoint-url").arg(&aws_endpoint)
-            .arg("--bucket").arg(&bucket_name)
-            .arg("--output").arg("json")
-            .output()
-            .unwrap_or_else(|err| {
-                info!("Error executing command: {}", err);
-                // Return a default or empty Output struct to continue
-                std::process::Output {
-                    status: std::process::ExitStatus::from_raw(1), // Example error status
-                    stdout: Vec::new(),
-                    stderr: Vec::new(),
-                }
-            })
-    } else {
-        Command::new(format!("{}/bin/aws", bin_path))
-            .arg("s3api")
-            .arg("create-bucket")
-            .arg("--bucket").arg(&bucket_name)
-            .arg("--output").arg("json")
-            .output()
-            .unwrap_or_else(|err| {
-                info!("Error executing command: {}", err);
-                // Return a default or empty Output struct to continue
-                std::process::Output {
-                    status: std::process::ExitStatus::from_raw(1), // Example error status
-                    stdout: Vec::new(),
-                    stderr: Vec::new(),
-                }
-            })
-    };
-    // We want to pass in the TiKV PD address and port
-    // may need to pass endpoint address like this: --s3.endpoint http://xxx
-    let tikv_br_command_result = if endpoint_is_some {
-        Command::new(format!("{}/bin/tikv-br", bin_path))
-            .arg("backup")
-            .arg("raw")
-            .arg(format!("--pd={}", pd_host_and_port))
-            .arg(format!("--send-credentials-to-tikv={}", endpoint_is_some))
-            .arg(format!("--s3.endpoint={}", &aws_endpoint))
-            .arg(format!("--storage=s3://{}/{}?access-key={}&secret-access-key={}", bucket_name, storage_key, aws_id, aws_key))
-            .output()
-            .wrap_err("failed to execute process")?
-        } else {
-        Command::new(format!("{}/bin/tikv-br", bin_path))
-            .arg("backup")
-            .arg("raw")
-            .arg(format!("--pd={}", pd_host_and_port))
-            .arg(format!("--send-credentials-to-tikv={}", endpoint_is_some))
-            .arg(format!("--storage=s3://{}/{}", bucket_name, storage_key))
-            .output()
-            .wrap_err("failed to execute process")?
-        };
-    
+    // Create bucket if not exists; one that already exists is fine, other failures end the backup.
+    if options.create_bucket {
+        let created = timings.time("bucket_ensure", s3::ensure_bucket(tools, s3_access, bucket_name)).await.wrap_err(Failure::Upload)?;
+        info!(target: "aws_create_bucket_output", bucket = bucket_name, created);
+    }
+    // We want to pass in the TiKV PD address and port.
+    // Credentials are handed over through the environment (or a private credentials file) and
+    // forwarded to the TiKV nodes by tikv-br, so they never show up in process listings. With
+    // IRSA or instance roles the TiKV nodes use their own.
+    let send_credentials = !matches!(s3_access.credentials, Credentials::Irsa);
+    let mut tikv_br_command = Command::new(&tools.tikv_br);
+    tikv_br_command
+        .kill_on_drop(true)
+        .arg("backup")
+        .arg("raw")
+        .arg(format!("--pd={}", options.pd_host_and_port))
+        .arg(format!("--send-credentials-to-tikv={}", send_credentials))
+        .arg(format!("--storage=s3://{}/{}", bucket_name, storage_key));
+    if let Some(endpoint) = &s3_access.endpoint {
+        tikv_br_command.arg(format!("--s3.endpoint={}", endpoint));
+    }
+    if let Some(region) = &s3_access.region {
+        tikv_br_command.arg(format!("--s3.region={}", region));
+    }
+    if s3_access.path_style_config.is_some() {
+        tikv_br_command.arg("--s3.force-path-style=true");
+    }
+    let mut credentials_file = None;
+    match (&s3_access.credentials, options.credential_mode) {
+        (Credentials::Static(id, key), TikvCredentialMode::Env) => {
+            tikv_br_command
+                .env("AWS_ACCESS_KEY_ID", id.expose())
+                .env("AWS_SECRET_ACCESS_KEY", key.expose());
+        }
+        (Credentials::Static(id, key), TikvCredentialMode::File) => {
+            let path = write_credentials_file(id, key)?;
+            tikv_br_command.env("AWS_SHARED_CREDENTIALS_FILE", &path);
+            credentials_file = Some(path);
+        }
+        (Credentials::Profile(profile), _) => {
+            tikv_br_command.env("AWS_PROFILE", profile);
+        }
+        (Credentials::Env | Credentials::Irsa, _) => {}
+    }
+    let tikv_br_command_result = timings.time("export", before_deadline(options.deadline, "tikv-br backup", tools.runner.run_streaming(tikv_br_command, "tikv-br")))
+        .await
+        .and_then(|output| output.wrap_err("failed to execute process"));
+    if let Some(path) = credentials_file {
+        std::fs::remove_file(&path)
+            .wrap_err_with(|| format!("Unable to remove credentials file {}", path.display()))?;
+    }
+    let tikv_br_command_result = tikv_br_command_result?;
+
     let tikv_br_stdout = String::from_utf8(tikv_br_command_result.stdout)?;
-    info!(target: "tikv_backup_output", success=tikv_br_command_result.status.success(), exit_code=tikv_br_command_result.status.code().or(Some(0)), stdout=tikv_br_stdout, stderr=String::from_utf8(tikv_br_command_result.stderr)?);
-
-    let s3_command_output = if endpoint_is_some {
-        Command::new(format!("{}/bin/aws", bin_path))
-            .env("AWS_ACCESS_KEY_ID", &aws_id)
-            .env("AWS_SECRET_ACCESS_KEY", &aws_key)
-            .arg("s3api")
-            .arg("list-objects")
-            .arg("--endpoint-url").arg(&aws_endpoint)
-            .arg("--bucket").arg(&bucket_name)
-            .arg("--prefix").arg(&storage_key)
-            .arg("--output").arg("json")
-            .output()
-            .wrap_err("failed to execute process")?
-    } else {
-        Command::new(format!("{}/bin/aws", bin_path))
-            .arg("s3api")
-            .arg("list-objects")
-            .arg("--bucket").arg(&bucket_name)
-            .arg("--prefix").arg(&storage_key)
-            .arg("--output").arg("json")
-            .output()
-            .wrap_err("failed to execute process")?
-    };
-    // TODO: list all the files that were pushed up by the distributed backup command.
-    // LIST_RESP=`${nixpkgs.awscli}/bin/aws s3api list-objects --bucket ${backupBucket} --prefix $KEY --output json`
-    let list_response = String::from_utf8(s3_command_output.stdout.clone())?;
-    info!(target: "aws_list_objects_output", success=s3_command_output.status.success(), exit_code=s3_command_output.status.code().or(Some(0)), stdout=list_response, stderr=String::from_utf8(s3_command_output.stderr)?);
-
-    let list_object_result = serde_json::from_str::<ListObjectResult>(list_response.as_str())?;
-    let object_keys = list_object_result
-        .contents
-        .iter()
-        .map(|o| o.key.as_str())
-        .collect::<Vec<_>>();
+    let tikv_br_stderr = String::from_utf8(tikv_br_command_result.stderr)?;
+    info!(target: "tikv_backup_output", success=tikv_br_command_result.status.success(), exit_code=tikv_br_command_result.status.code(), signal=process::signal(tikv_br_command_result.status).map(process::signal_name));
+    // tikv-br ends with a summary line counting what it backed up; zero means an empty cluster
+    // or key range, and nothing worth keeping.
+    let stats = TikvStats::parse(&tikv_br_stdout).or_else(|| TikvStats::parse(&tikv_br_stderr));
+    if let Some(stats) = &stats {
+        info!(target: "tikv_backup_stats", total_kv = stats.total_kv, total_ranges = stats.total_ranges, ranges_failed = stats.ranges_failed, data_size = stats.data_size, checksum = stats.checksum);
+    }
+    let empty = tikv_br_command_result.status.success() && !options.allow_empty && stats.as_ref().and_then(TikvStats::backed_up) == Some(0);
+
+    let objects = timings.time("list", s3::list_objects(tools, s3_access, bucket_name, storage_key)).await?;
+    info!(target: "aws_list_objects_output", key = storage_key, objects = objects.len());
+    let object_keys = objects.iter().map(|o| o.key.as_str()).collect::<Vec<_>>();
+    let bytes = objects.iter().map(|o| o.size).sum();
     // KEYS=`${nixpkgs.jq}/bin/jq '.Contents[] | .Key' <<< "$LIST_RESP"`
     // ${echo} $KEYS | ${nixpkgs.uutils-coreutils-noprefix}/bin/tr " " "\n"
 
-    for key in object_keys {
-        let _s3_command_output = if endpoint_is_some {
-            Command::new(format!("{}/bin/aws", bin_path))
-                .env("AWS_ACCESS_KEY_ID", &aws_id)
-                .env("AWS_SECRET_ACCESS_KEY", &aws_key)
-                .arg("s3api")
-                .arg("put-object-tagging")
-                .arg("--endpoint-url").arg(&aws_endpoint)
-                .arg("--bucket").arg(&bucket_name)
-                .arg("--tagging").arg(&tags)
-                .arg("--key").arg(&key)
-                .output()
-                .wrap_err("failed to execute process")
-        } else {
-            Command::new(format!("{}/bin/aws", bin_path))
-                .arg("s3api")
-                .arg("put-object-tagging")
-                .arg("--bucket").arg(&bucket_name)
-                .arg("--tagging").arg(&tags)
-                .arg("--key").arg(&key)
-                .output()
-                .wrap_err("failed to execute process")
-        }?;
-        info!(target: "aws_put_object_tagging_output", key=key, success=_s3_command_output.status.success(), exit_code=_s3_command_output.status.code().or(Some(0)), stdout=String::from_utf8(_s3_command_output.stdout)?, stderr=String::from_utf8(_s3_command_output.stderr)?);
+    // A backup smaller than expected most likely missed its data, e.g. an empty or unreachable cluster.
+    let too_small = tikv_br_command_result.status.success() && bytes < options.min_expected_bytes;
+    // Whatever a failed tikv-br run left behind is incomplete. Untagged objects match no lifecycle
+    // rule and would be kept forever, so they are removed instead of being tagged.
+    let object_keys = if tikv_br_command_result.status.success() && !too_small && !empty {
+        object_keys
+    } else {
+        let removed = s3::delete_prefix(tools, s3_access, bucket_name, storage_key).await;
+        info!(target: "aws_remove_partial_backup_output", key=storage_key, objects=object_keys.len(), success=removed.is_ok(), error=removed.err().map(|err| format!("{:#}", err)));
+        Vec::new()
+    };
+    if empty {
+        return Err(EmptyBackup(format!("TiKV backup {}", storage_key)).into());
+    }
+    if too_small {
+        return Err(eyre!("TiKV backup {} is only {} bytes, below --min-expected-bytes {}", storage_key, bytes, options.min_expected_bytes)
+            .wrap_err(Failure::Export { stage: String::from("size check") }));
     }
+    // Tag with bounded concurrency, as the `xargs -rP 4` below did.
+    timings.time("tagging", before_deadline(options.deadline, "tagging", s3::tag_objects(tools, s3_access, bucket_name, &object_keys, &options.tags, options.concurrency)))
+        .await?
+        .wrap_err(Failure::Tagging)?;
     // TODO: Apply tags to all keys returned from list operation.
     // ${nixpkgs.findutils}/bin/xargs -rP 4 -n 1 ${nixpkgs.awscli}/bin/aws s3api put-object-tagging \
     // --bucket ${backupBucket} \
     // --tagging "{\"TagSet\":[{\"Key\":\"thirdofhalfday\",\"Value\":\"1\"}$TAGS]}" \
     // --key <<< "$KEYS"
 
-    return Ok(tikv_br_stdout);
+    Ok(BackupReport {
+        storage_key: storage_key.clone(),
+        bytes,
+        raw_bytes: None,
+        success: tikv_br_command_result.status.success(),
+        stderr_tail: (!tikv_br_command_result.status.success()).then(|| process::stderr_tail(tikv_br_stderr.as_bytes())),
+        tikv_stats: stats,
+        signal: process::signal(tikv_br_command_result.status),
+    })
+}
+
+/// Writes an AWS shared-credentials file readable only by the current user.
+fn write_credentials_file(aws_id: &Secret, aws_key: &Secret) -> Result<std::path::PathBuf, Report> {
+    use std::io::Write;
+
+    // Parallel run-all jobs each write one.
+    static WRITTEN: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!("btagger-{}-{}.credentials", std::process::id(), WRITTEN.fetch_add(1, std::sync::atomic::Ordering::Relaxed)));
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    // Elsewhere the per-user temp directory is what keeps the file private.
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(&path)
+        .wrap_err_with(|| format!("Unable to create credentials file {}", path.display()))?;
+    write!(
+        file,
+        "[default]\naws_access_key_id = {}\naws_secret_access_key = {}\n",
+        aws_id.expose(),
+        aws_key.expose()
+    )?;
+    Ok(path)
+}
+
+/// What the upload stage of a SurrealDB backup produced.
+enum Uploaded {
+    Parts(multipart::UploadedParts),
+    Chunks(cas::Stored),
 }
 
-fn surrealdb_backup(
-    time: DateTime<Utc>,
-    bin_path: String,
+/// A SurrealDB database to export, and how to store the export.
+struct SurrealdbOptions {
+    tools: Tools,
     bucket_name: String,
+    s3_access: S3Access,
+    create_bucket: bool,
+    /// HTTP address of the server, `host:port`.
+    address: String,
+    password: Option<Secret>,
     namespace: String,
     database: String,
-    address: String,
-    password: String,
+    filter: surreal::ExportFilter,
+    /// Tag set JSON applied to the backup object.
     tags: String,
-    s3_endpoint: Option<(String, String, String)>,
-    format_string: String,
-) -> Result<Output, Report> {
-    let endpoint_is_some = s3_endpoint.is_some();
-    let mut aws_endpoint: String = String::new();
-    let mut aws_id: String = String::new();
-    let mut aws_key: String = String::new();
-    if let Some(s3_endpoint) = s3_endpoint {
-        aws_endpoint = s3_endpoint.0;
-        aws_id = s3_endpoint.1;
-        aws_key = s3_endpoint.2;
-    }
-    // Create bucket if not exists, ignore errors.
-    let _s3_create_bucket_command_output = if endpoint_is_some {
-        Command::new(format!("{}/bin/aws", bin_path))
-            .env("AWS_ACCESS_KEY_ID", &aws_id)
-            .env("AWS_SECRET_ACCESS_KEY", &aws_key)
-            .arg("s3api")
-            .arg("create-bucket")
-            .arg("--endpoint-url").arg(&aws_endpoint)
-            .arg("--bucket").arg(&bucket_name)
-            .arg("--output").arg("json")
-            .output()
-            .unwrap_or_else(|err| {
-                info!("Error executing command: {}", err);
-                // Return a default or empty Output struct to continue
-                std::process::Output {
-                    status: std::process::ExitStatus::from_raw(1), // Example error status
-                    stdout: Vec::new(),
-                    stderr: Vec::new(),
+    storage_key: String,
+    min_expected_bytes: u64,
+    allow_empty: bool,
+    compression: Compression,
+    compression_level: Option<u32>,
+    zstd_dictionary: bool,
+    /// Store the export as content-addressed chunks instead of one object.
+    cas: bool,
+    part_size: ByteSize,
+    part_retries: u32,
+    spool: Option<Spool>,
+    split: Option<Split>,
+    concurrency: usize,
+    deadline: Option<tokio::time::Instant>,
+    timings: Timings,
+}
+
+async fn surrealdb_backup(options: &SurrealdbOptions) -> Result<BackupReport, Report> {
+    let (tools, s3_access, bucket_name, storage_key) = (&options.tools, &options.s3_access, &options.bucket_name, &options.storage_key);
+    let timings = &options.timings;
+    if options.zstd_dictionary && options.compression != Compression::Zstd {
+        return Err(eyre!("--zstd-dictionary needs --compression zstd").wrap_err(Failure::Config));
+    }
+    // Create bucket if not exists; one that already exists is fine, other failures end the backup.
+    if options.create_bucket {
+        let created = timings.time("bucket_ensure", s3::ensure_bucket(tools, s3_access, bucket_name)).await.wrap_err(Failure::Upload)?;
+        info!(target: "aws_create_bucket_output", bucket = bucket_name, created);
+    }
+    // KEY=surrealdb/$NS/${ds}.zst
+
+    let endpoint = format!("http://{}", options.address);
+    let existing_tables = match options.filter.exclude_tables.is_empty() {
+        true => Vec::new(),
+        false => surreal::tables(tools, &endpoint, options.password.as_ref(), &options.namespace, &options.database)
+            .await
+            .wrap_err("Unable to list the tables to export")?,
+    };
+    let tables = options.filter.tables(&existing_tables);
+    // Without --tables surreal would export every table instead of none.
+    if !options.filter.exclude_tables.is_empty() && tables.is_empty() {
+        return Err(eyre!("--exclude-tables leaves no tables in {}/{} to export", options.namespace, options.database));
+    }
+    let dictionary = match options.zstd_dictionary {
+        true => {
+            let path = dictionary::scratch_path();
+            let prefix = dictionary::backup_prefix(storage_key);
+            match dictionary::fetch_current(tools, s3_access, bucket_name, prefix, &path).await.wrap_err("Unable to fetch the zstd dictionary")? {
+                Some(key) => {
+                    info!(target: "dictionary", key, "Compressing with the dictionary");
+                    Some(path)
                 }
-            })
-    } else {
-        Command::new(format!("{}/bin/aws", bin_path))
-            .arg("s3api")
-            .arg("create-bucket")
-            .arg("--bucket").arg(&bucket_name)
-            .arg("--output").arg("json")
-            .output()
-            .unwrap_or_else(|err| {
-                info!("Error executing command: {}", err);
-                // Return a default or empty Output struct to continue
-                std::process::Output {
-                    status: std::process::ExitStatus::from_raw(1), // Example error status
-                    stdout: Vec::new(),
-                    stderr: Vec::new(),
+                None => {
+                    info!(target: "dictionary", prefix, "No dictionary trained for these backups yet, compressing without one");
+                    None
                 }
-            })
+            }
+        }
+        false => None,
     };
-    let time_part = time.format(format_string.as_str()).to_string().replace("+", "");
-    let storage_key = format!("surrealdb/{}/{}.zst", namespace, time_part);
-    // KEY=surrealdb/$NS/${ds}.zst
-
-    let mut s3_cp_command_output = if endpoint_is_some {
-        Command::new(format!("{}/bin/aws", bin_path))
-            .env("AWS_ACCESS_KEY_ID", aws_id.clone())
-            .env("AWS_SECRET_ACCESS_KEY", aws_key.clone())
-            .stdin(Stdio::piped())
-            .arg("s3")
-            .arg("cp")
-            .arg("--endpoint-url").arg(aws_endpoint.clone())
-            .arg("-")
-            .arg(format!("s3://{}/{}", bucket_name, storage_key))
-            .spawn()
-            .wrap_err("failed to execute process")
-    } else {
-        Command::new(format!("{}/bin/aws", bin_path))
-            .stdin(Stdio::piped())
-            .arg("s3")
-            .arg("cp")
-            .arg("-")
-            .arg(format!("s3://{}/{}", bucket_name, storage_key))
-            .spawn()
-            .wrap_err("failed to execute process")
-    }?;
-    let mut zstd_command_output = Command::new(format!("{}/bin/zstd", bin_path))
-        .stdin(Stdio::piped())
-        .arg("--force")
-        .arg("--stdout")
-        .arg("--adapt")
-        .arg("--rm")
-        .arg("-")
-        .stdout(s3_cp_command_output.stdin.take().wrap_err("failed to pipe")?)
-        .spawn()
-        .wrap_err("failed to execute process")?;
-    let surrealdb_command_output = Command::new(format!("{}/bin/surreal", bin_path))
+    let mut surrealdb_command = Command::new(&tools.surreal);
+    surrealdb_command
+        .kill_on_drop(true)
         .arg("export")
-        .arg("-e").arg(format!("http://{}", address))
-        .arg("-u").arg("root")
-        .arg("-p").arg(password)
-        .arg("--namespace").arg(namespace)
-        .arg("--database").arg(database)
-        .arg("-").stdout(zstd_command_output.stdin.take().wrap_err("failed to pipe")?)
-        .spawn()
-        .wrap_err("failed to execute process")?;
-    let s3_command_output = s3_cp_command_output.wait_with_output().wrap_err("failed to wait for the piped run")?;
-    info!("{}", String::from_utf8(surrealdb_command_output.wait_with_output()?.stderr)?);
+        .arg("-e").arg(&endpoint);
+    if let Some(password) = &options.password {
+        surrealdb_command.arg("-u").arg("root").arg("-p").arg(password.expose());
+    }
+    let mut surrealdb_command_output = process::spawn(
+        surrealdb_command
+            .args(options.filter.args(&tables))
+            .arg("--namespace").arg(&options.namespace)
+            .arg("--database").arg(&options.database)
+            .arg("-").stdout(Stdio::piped())
+            .stderr(Stdio::piped()),
+    )?;
+    let export_stdout = surrealdb_command_output.stdout.take().wrap_err("failed to pipe")?;
+    let chunks = options.cas.then(|| cas::ChunkStore {
+        tools,
+        s3_access,
+        bucket_name,
+        key: storage_key,
+        compression: options.compression,
+        compression_level: options.compression_level,
+        concurrency: options.concurrency,
+        deadline: options.deadline,
+    });
+    // The compressor, when there is one, sits between the export and the upload. Chunks are
+    // compressed one by one once cut, so their store reads the export itself.
+    let (compressor_command_output, upload_source, relay_input): (_, Box<dyn AsyncRead + Unpin>, Box<dyn AsyncWrite + Send + Unpin>) = match options.compression.command(tools, options.compression_level).filter(|_| !options.cas) {
+        Some(mut compressor) => {
+            if let Some(path) = &dictionary {
+                compressor.arg("-D").arg(path);
+            }
+            let mut child = process::spawn(
+                compressor
+                    .kill_on_drop(true)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped()),
+            )?;
+            let compressor_stdin = child.stdin.take().wrap_err("failed to pipe")?;
+            let stdout = child.stdout.take().wrap_err("failed to pipe")?;
+            (Some(child), Box::new(stdout), Box::new(compressor_stdin))
+        }
+        None => {
+            let (relay_input, upload_source) = tokio::io::duplex(64 * 1024);
+            (None, Box::new(upload_source), Box::new(relay_input))
+        }
+    };
+    // Relay the export through this process so its size and records can be counted.
+    let relay = tokio::spawn(surreal::relay(export_stdout, relay_input));
+    let metadata = ObjectMetadata::now();
+    let upload = Arc::new(MultipartUpload {
+        tools: tools.clone(),
+        s3_access: s3_access.clone(),
+        bucket_name: bucket_name.clone(),
+        key: storage_key.clone(),
+        part_size: options.part_size.0,
+        part_retries: options.part_retries,
+        concurrency: options.concurrency,
+        deadline: options.deadline,
+        spool: options.spool.clone(),
+        split: options.split.clone(),
+        metadata: Some(metadata.clone()),
+    });
+    // Every stage is waited on, together, so none is left running or unreaped when another breaks.
+    let (export_result, relay_result, compressor_result, upload_result) = tokio::join!(
+        timings.time("export", async {
+            before_deadline(options.deadline, "surreal export", process::wait_streaming(surrealdb_command_output, "surreal export"))
+                .await
+                .and_then(process::succeeded)
+        }),
+        async {
+            relay
+                .await
+                .map_err(|_| eyre!("The export relay task panicked"))?
+                .wrap_err("Unable to relay the export")
+        },
+        timings.time("compress", async {
+            match compressor_command_output {
+                Some(child) => before_deadline(options.deadline, "compression", child.wait_with_output())
+                    .await
+                    .and_then(process::succeeded)
+                    .map(|_| ()),
+                None => Ok(()),
+            }
+        }),
+        timings.time("upload", async {
+            match &chunks {
+                Some(chunks) => chunks.store(upload_source).await.map(Uploaded::Chunks),
+                None => upload.upload(upload_source).await.map(Uploaded::Parts),
+            }
+        }),
+    );
+    if let Some(path) = &dictionary {
+        let _ = tokio::fs::remove_file(path).await;
+    }
+    let (uncompressed_bytes, records) = relay_result.as_ref().copied().unwrap_or_default();
+    // The parts only become an object once every stage is known to have succeeded; a truncated
+    // export must never land under the key, let alone be tagged.
+    let (uploaded, upload_result) = match upload_result {
+        Ok(uploaded) => (Some(uploaded), Ok(())),
+        Err(err) => (None, Err(err)),
+    };
+    // An export smaller than expected most likely missed its data, e.g. after an auth failure
+    // that left surreal exporting an empty database.
+    let size_result = match uncompressed_bytes < options.min_expected_bytes {
+        true => Err(eyre!("Export is only {} bytes, below --min-expected-bytes {}", uncompressed_bytes, options.min_expected_bytes)),
+        false => Ok(()),
+    };
+    // A schema-only export has no records by design, and a broken one is reported as broken.
+    let empty = records == 0 && export_result.is_ok() && relay_result.is_ok();
+    let empty_result = match empty && !options.filter.schema_only && !options.allow_empty {
+        true => Err(Report::new(EmptyBackup(format!("Export of {}/{}", options.namespace, options.database)))),
+        false => Ok(()),
+    };
+    // A spooled upload that broke on its own is kept for `btagger resume`, unless what was
+    // spooled is itself broken.
+    let resumable = export_result.is_ok() && relay_result.is_ok() && compressor_result.is_ok() && size_result.is_ok() && empty_result.is_ok();
+    if let Err(err) = process::first_failure([
+        ("surreal export", export_result.map(|_| ())),
+        ("relay", relay_result.map(|_| ())),
+        ("compression", compressor_result),
+        ("upload", upload_result),
+        ("size check", size_result),
+        ("empty check", empty_result),
+    ]) {
+        match uploaded {
+            Some(Uploaded::Parts(uploaded)) => upload.abort(uploaded).await,
+            // New chunks that no index refers to are left for `btagger gc`.
+            Some(Uploaded::Chunks(_)) => {}
+            None if !resumable => upload.discard().await,
+            None => {}
+        }
+        return Err(err);
+    }
+    // A backup stored as chunks counts what it added to the bucket.
+    let bytes = match uploaded.wrap_err("Upload finished without parts")? {
+        Uploaded::Parts(uploaded) => timings.time("upload", upload.complete(uploaded, Some(uncompressed_bytes))).await.wrap_err(Failure::Upload)?,
+        Uploaded::Chunks(stored) => {
+            let index_bytes = timings
+                .time("upload", cas::put_index(tools, s3_access, bucket_name, storage_key, &stored.index, Some(&metadata.at_end(Some(uncompressed_bytes)))))
+                .await
+                .wrap_err(Failure::Upload)?;
+            info!(target: "cas", key = storage_key, chunks = stored.index.chunks.len(), new_chunks = stored.new_chunks, new_bytes = stored.new_bytes, "Stored the index");
+            stored.new_bytes + index_bytes
+        }
+    };
+    info!(
+        target: "backup_size",
+        key = storage_key,
+        raw_bytes = uncompressed_bytes,
+        bytes,
+        records,
+        ratio = format!("{:.2}", uncompressed_bytes as f64 / bytes.max(1) as f64)
+    );
     // ${surreal}/bin/surreal export -e http://${surrealdb.address} -u root -p ${surrealdb.password} --namespace $NS --database calamu - \
     // | ${nixpkgs.zstd}/bin/zstd --force --stdout --adapt --rm - \
     // | ${nixpkgs.awscli}/bin/aws s3 cp - s3://${backupBucket}/$KEY
+    // The `aws s3 cp -` stage is replaced by our own multipart upload.
 
-    let _s3_command_output = if endpoint_is_some {
-        Command::new(format!("{}/bin/aws", bin_path))
-            .env("AWS_ACCESS_KEY_ID", aws_id.clone())
-            .env("AWS_SECRET_ACCESS_KEY", aws_key.clone())
-            .arg("s3api")
-            .arg("put-object-tagging")
-            .arg("--endpoint-url").arg(aws_endpoint.clone())
-            .arg("--bucket").arg(bucket_name)
-            .arg("--tagging").arg(tags)
-            .arg("--key").arg(storage_key)
-            .output()
-            .wrap_err("failed to execute process")?
-    } else {
-        Command::new(format!("{}/bin/aws", bin_path))
-            .arg("s3api")
-            .arg("put-object-tagging")
-            .arg("--bucket").arg(bucket_name)
-            .arg("--tagging").arg(tags)
-            .arg("--key").arg(storage_key)
-            .output()
-            .wrap_err("failed to execute process")?
-    };
-    info!("{}", String::from_utf8(_s3_command_output.stdout)?);
+    timings.time("tagging", s3::put_object_tagging(tools, s3_access, bucket_name, storage_key, &options.tags)).await.wrap_err(Failure::Tagging)?;
+    info!(target: "aws_put_object_tagging_output", key=storage_key);
     // ${nixpkgs.awscli}/bin/aws s3api put-object-tagging \
     // --bucket ${backupBucket} \
     // --tagging "{\"TagSet\":[{\"Key\":\"thirdofhalfday\",\"Value\":\"1\"}$TAGS]}" \
     // --key $KEY
-    return Ok(s3_command_output);
+
+    Ok(BackupReport {
+        storage_key: storage_key.clone(),
+        bytes,
+        raw_bytes: Some(uncompressed_bytes),
+        success: true,
+        stderr_tail: None,
+        tikv_stats: None,
+        signal: None,
+    })
+}
+
+/// Swaps the log filter once the command line, and with it `-q`/`-v`, has been parsed.
+type LogFilter = tracing_subscriber::reload::Handle<EnvFilter, tracing_subscriber::Registry>;
+
+/// `-q` shows only errors and `-v`/`-vv` add debug/trace output; otherwise `RUST_LOG` applies,
+/// defaulting to info.
+fn verbosity_filter(quiet: bool, verbose: u8) -> EnvFilter {
+    match (quiet, verbose) {
+        (true, _) => EnvFilter::new("error"),
+        (false, 0) => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        (false, 1) => EnvFilter::new("debug"),
+        (false, _) => EnvFilter::new("trace"),
+    }
 }
 
-fn install_tracing() {
+fn install_tracing() -> LogFilter {
     use tracing_error::ErrorLayer;
     use tracing_subscriber::prelude::*;
-    use tracing_subscriber::{fmt, EnvFilter};
+    use tracing_subscriber::{fmt, reload};
 
-    let fmt_layer = fmt::layer().with_writer(|| std::io::stderr()).with_target(false);
-    let filter_layer = EnvFilter::try_from_default_env()
-        .or_else(|_| EnvFilter::try_new("info"))
-        .unwrap();
+    let fmt_layer = fmt::layer()
+        .with_writer(|| secret::RedactingWriter(std::io::stderr())).with_target(false);
+    let (filter_layer, log_filter) = reload::Layer::new(verbosity_filter(false, 0));
 
     tracing_subscriber::registry()
         .with(filter_layer)
         .with(fmt_layer)
         .with(ErrorLayer::default())
         .init();
+    log_filter
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use process::{Call, MockRunner};
+    use std::process::Output;
+
+    #[test]
+    fn run_all_exits_like_a_single_job_only_when_every_job_failed_alike() {
+        let failures = [String::from("a: failed"), String::from("b: failed")];
+        let alike = run_all_error(&failures, &[Failure::Upload, Failure::Upload]);
+        assert_eq!(Failure::of(&alike), Failure::Upload);
+        assert!(format!("{:#}", alike).contains("2 job(s) failed:\na: failed\nb: failed"));
+        // Stages differ, but the exit code is the same.
+        let export = |stage: &str| Failure::Export { stage: stage.to_string() };
+        assert_eq!(Failure::of(&run_all_error(&failures, &[export("surreal export"), export("tikv-br backup")])), export("surreal export"));
+        assert_eq!(Failure::of(&run_all_error(&failures, &[Failure::Upload, Failure::Empty])), Failure::Other);
+        assert_eq!(Failure::of(&run_all_error(&failures, &[])), Failure::Other);
+    }
+
+    #[test]
+    fn startup_jitter_stays_within_its_maximum() {
+        let max = std::time::Duration::from_secs(300);
+        assert_eq!(jitter(max, 0), std::time::Duration::ZERO);
+        assert_eq!(jitter(max, 300_000), max);
+        assert!([u64::MAX, 123_456_789].iter().all(|random| jitter(max, *random) <= max));
+        assert_eq!(jitter(std::time::Duration::ZERO, 42), std::time::Duration::ZERO);
+    }
+
+    #[test]
+    fn s3_access_rejects_partial_or_mismatched_flags() {
+        let args = |extra: &[&str]| Args::try_parse_from([&["btagger"], extra, &["status", "-B", "b", "-P", "p"]].concat()).unwrap();
+        let s3 = |endpoint: Option<&str>, id: Option<&str>, key: Option<&str>| S3Args {
+            aws_endpoint: endpoint.map(String::from),
+            aws_id: id.map(Secret::new),
+            aws_key: key.map(Secret::new),
+        };
+
+        let access = s3_access(&args(&[]), s3(Some("https://s3.internal"), None, None)).unwrap();
+        assert_eq!(access.endpoint.as_deref(), Some("https://s3.internal"));
+        assert!(matches!(access.credentials, Credentials::Irsa));
+        assert!(matches!(s3_access(&args(&[]), s3(None, Some("id"), Some("key"))).unwrap().credentials, Credentials::Static(..)));
+
+        assert!(s3_access(&args(&[]), s3(None, Some("id"), None)).is_err());
+        assert!(s3_access(&args(&[]), s3(Some("s3.internal"), None, None)).is_err());
+        assert!(s3_access(&args(&["--credential-source", "irsa"]), s3(None, Some("id"), Some("key"))).is_err());
+        assert!(s3_access(&args(&["--aws-profile", "prod"]), s3(None, Some("id"), Some("key"))).is_err());
+        assert!(s3_access(&args(&["--s3-user-agent", "backup tool"]), s3(None, None, None)).is_err());
+        assert_eq!(s3_access(&args(&["--s3-user-agent", ""]), s3(None, None, None)).unwrap().user_agent, None);
+    }
+
+    /// Answers like a bucket holding two tikv-br output files, with tikv-br exiting `tikv_br_code`.
+    fn s3_with_backup(tikv_br_code: i32) -> impl Fn(&Call) -> Output {
+        move |call| {
+            if call.program == "tikv-br" {
+                MockRunner::output(tikv_br_code, "", "")
+            } else if call.has_args(&["list-objects-v2"]) {
+                MockRunner::output(0, r#"{"Contents":[{"Key":"tikv/k/1.sst","Size":10},{"Key":"tikv/k/2.sst","Size":5}]}"#, "")
+            } else {
+                MockRunner::output(0, "{}", "")
+            }
+        }
+    }
+
+    fn tikv_options(runner: &Arc<MockRunner>, s3_access: S3Access) -> TikvOptions {
+        TikvOptions {
+            tools: Tools::mock(runner.clone()),
+            bucket_name: String::from("bk"),
+            s3_access,
+            create_bucket: true,
+            pd_host_and_port: String::from("pd:2379"),
+            credential_mode: TikvCredentialMode::Env,
+            tags: String::from(r#"{"TagSet":[{"Key":"standard","Value":"1"}]}"#),
+            storage_key: String::from("tikv/k"),
+            min_expected_bytes: 0,
+            allow_empty: false,
+            concurrency: 4,
+            deadline: None,
+            timings: Timings::default(),
+        }
+    }
+
+    async fn tikv(runner: &Arc<MockRunner>, s3_access: S3Access) -> Result<BackupReport, Report> {
+        tikv_backup(&tikv_options(runner, s3_access)).await
+    }
+
+    #[tokio::test]
+    async fn tikv_backup_tolerates_an_existing_bucket_but_not_other_create_errors() {
+        let failing_create = |stderr: &'static str| {
+            let backup = s3_with_backup(0);
+            move |call: &Call| match call.has_args(&["create-bucket"]) {
+                true => MockRunner::output(254, "", stderr),
+                false => backup(call),
+            }
+        };
+        let runner = Arc::new(MockRunner::new(failing_create("An error occurred (BucketAlreadyOwnedByYou) when calling the CreateBucket operation")));
+        assert!(tikv(&runner, S3Access::minio()).await.unwrap().success);
+
+        let runner = Arc::new(MockRunner::new(failing_create("An error occurred (AccessDenied) when calling the CreateBucket operation")));
+        assert!(tikv(&runner, S3Access::minio()).await.is_err());
+        assert!(runner.calls().iter().all(|call| call.program != "tikv-br"));
+    }
+
+    #[tokio::test]
+    async fn tikv_backup_passes_the_endpoint_to_every_call() {
+        let runner = Arc::new(MockRunner::new(s3_with_backup(0)));
+        let report = tikv(&runner, S3Access::minio()).await.unwrap();
+        assert_eq!((report.storage_key.as_str(), report.bytes, report.success), ("tikv/k", 15, true));
+
+        let calls = runner.calls();
+        let tikv_br = calls.iter().find(|call| call.program == "tikv-br").unwrap();
+        assert!(tikv_br.has_args(&["--storage=s3://bk/tikv/k", "--s3.endpoint=http://minio:9000"]));
+        assert!(tikv_br.has_args(&["--send-credentials-to-tikv=true"]));
+        assert_eq!(tikv_br.env("AWS_ACCESS_KEY_ID"), Some("id"));
+        // Credentials never reach the command line.
+        assert!(!tikv_br.args.iter().any(|arg| arg.contains("key") && arg.contains("id")));
+        for call in calls.iter().filter(|call| call.program == "aws") {
+            assert!(call.has_args(&["--endpoint-url", "http://minio:9000"]), "{:?}", call.args);
+            assert_eq!(call.env("AWS_SECRET_ACCESS_KEY"), Some("key"));
+        }
+        let mut tagged = calls
+            .iter()
+            .filter(|call| call.has_args(&["put-object-tagging"]))
+            .map(|call| call.args.last().unwrap().as_str())
+            .collect::<Vec<_>>();
+        tagged.sort();
+        assert_eq!(tagged, ["tikv/k/1.sst", "tikv/k/2.sst"]);
+    }
+
+    #[tokio::test]
+    async fn tikv_backup_uses_host_defaults_without_an_endpoint() {
+        let runner = Arc::new(MockRunner::new(s3_with_backup(0)));
+        tikv(&runner, S3Access::default()).await.unwrap();
+        for call in runner.calls() {
+            assert!(!call.args.iter().any(|arg| arg.contains("endpoint")), "{:?}", call.args);
+            assert_eq!(call.env("AWS_ACCESS_KEY_ID"), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn tikv_backup_with_an_instance_role_keeps_credentials_on_the_nodes() {
+        let runner = Arc::new(MockRunner::new(s3_with_backup(0)));
+        let access = S3Access { endpoint: Some(String::from("https://s3.internal")), credentials: Credentials::Irsa, ..S3Access::default() };
+        tikv(&runner, access).await.unwrap();
+        let calls = runner.calls();
+        let tikv_br = calls.iter().find(|call| call.program == "tikv-br").unwrap();
+        assert!(tikv_br.has_args(&["--send-credentials-to-tikv=false"]));
+        assert!(tikv_br.has_args(&["--s3.endpoint=https://s3.internal"]));
+        for call in calls.iter().filter(|call| call.program == "aws") {
+            assert!(call.has_args(&["--endpoint-url", "https://s3.internal"]), "{:?}", call.args);
+            assert_eq!(call.env("AWS_ACCESS_KEY_ID"), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn tikv_backup_below_the_expected_size_is_removed_instead_of_tagged() {
+        let runner = Arc::new(MockRunner::new(s3_with_backup(0)));
+        let result = tikv_backup(&TikvOptions { min_expected_bytes: 16, ..tikv_options(&runner, S3Access::minio()) }).await;
+        assert!(result.is_err());
+        let calls = runner.calls();
+        assert!(calls.iter().any(|call| call.has_args(&["rm", "s3://bk/tikv/k", "--recursive"])));
+        assert!(!calls.iter().any(|call| call.has_args(&["put-object-tagging"])));
+    }
+
+    #[tokio::test]
+    async fn empty_tikv_backup_is_refused_unless_allowed() {
+        let summary = r#"[2025/01/01 04:30:00.000 +00:00] [INFO] [collector.go:67] ["Raw backup success summary"] [total-ranges=0] [ranges-succeed=0] [ranges-failed=0] [total-take=1.2s] [total-kv=0] [data-size=0B]"#;
+        assert_eq!(TikvStats::parse(summary).and_then(|stats| stats.backed_up()), Some(0));
+        assert_eq!(TikvStats::parse(&summary.replace("[total-kv=0]", "[total-kv=120]")).and_then(|stats| stats.backed_up()), Some(120));
+        assert_eq!(TikvStats::parse("Backup started"), None);
+        let json = r#"{"level":"INFO","time":"2025/01/01 04:30:00.000 +00:00","message":"Raw backup success summary","total-ranges":3,"ranges-failed":0,"total-kv":1200,"data-size":"2.136MB","checksum":"a1b2c3"}"#;
+        let stats = TikvStats { total_kv: Some(1200), total_ranges: Some(3), ranges_failed: Some(0), data_size: Some(2_136_000), checksum: Some(String::from("a1b2c3")) };
+        assert_eq!(TikvStats::parse(json), Some(stats.clone()));
+        assert_eq!(TikvStats::parse(&summary.replace("[total-kv=0] [data-size=0B]", "[total-kv=1200] [data-size=2.136MB] [checksum=a1b2c3]").replace("[total-ranges=0]", "[total-ranges=3]")), Some(stats));
+
+        let empty = move |call: &Call| match call.program == "tikv-br" {
+            true => MockRunner::output(0, "", summary),
+            false => s3_with_backup(0)(call),
+        };
+        for allow_empty in [false, true] {
+            let runner = Arc::new(MockRunner::new(empty));
+            let result = tikv_backup(&TikvOptions { allow_empty, ..tikv_options(&runner, S3Access::minio()) }).await;
+            let tagged = runner.calls().iter().any(|call| call.has_args(&["put-object-tagging"]));
+            match allow_empty {
+                false => assert!(result.unwrap_err().chain().any(|err| err.is::<EmptyBackup>()) && !tagged),
+                true => assert!(result.unwrap().success && tagged),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_tikv_backup_is_removed_instead_of_tagged() {
+        let runner = Arc::new(MockRunner::new(s3_with_backup(1)));
+        let report = tikv(&runner, S3Access::default()).await.unwrap();
+        assert!(!report.success);
+        let calls = runner.calls();
+        assert!(calls.iter().any(|call| call.has_args(&["s3", "rm", "s3://bk/tikv/k", "--recursive"])));
+        assert!(!calls.iter().any(|call| call.has_args(&["put-object-tagging"])));
+    }
+
+    #[tokio::test]
+    async fn tagging_failure_fails_the_backup() {
+        let runner = Arc::new(MockRunner::new(|call: &Call| {
+            if call.has_args(&["put-object-tagging"]) {
+                MockRunner::output(254, "", "AccessDenied")
+            } else {
+                s3_with_backup(0)(call)
+            }
+        }));
+        let err = tikv(&runner, S3Access::default()).await.unwrap_err();
+        assert!(format!("{:#}", err).contains("AccessDenied"));
+    }
 }
@@ -0,0 +1,831 @@
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{eyre, Report, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeekExt, BufReader};
+use tokio::process::Command;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::process::{self, before_deadline};
+use crate::s3::{self, Aws, S3Access};
+use crate::tools::Tools;
+use crate::Object;
+
+/// S3 rejects non-final parts smaller than this.
+pub const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+
+/// User metadata of a backup's objects, so an audit can tell how each was made without the run's
+/// logs. S3 fixes it when an object is created.
+#[derive(Clone, Debug)]
+pub struct ObjectMetadata {
+    pub started: DateTime<Utc>,
+}
+
+impl ObjectMetadata {
+    pub fn now() -> Self {
+        ObjectMetadata { started: Utc::now() }
+    }
+
+    /// For objects created while the stream is still running: `backup-started` and
+    /// `tool-version`.
+    pub fn at_start(&self) -> String {
+        format!("backup-started={},tool-version={}", self.started.to_rfc3339_opts(chrono::SecondsFormat::Secs, true), env!("CARGO_PKG_VERSION"))
+    }
+
+    /// For objects written once the stream has ended, e.g. a split manifest or CAS index: adds
+    /// `backup-duration` in seconds and, when counted, `uncompressed-bytes`.
+    pub fn at_end(&self, uncompressed_bytes: Option<u64>) -> String {
+        let duration = (Utc::now() - self.started).to_std().unwrap_or_default();
+        let mut metadata = format!("{},backup-duration={:.3}", self.at_start(), duration.as_secs_f64());
+        if let Some(bytes) = uncompressed_bytes {
+            metadata.push_str(&format!(",uncompressed-bytes={}", bytes));
+        }
+        metadata
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CreateMultipartUploadResult {
+    upload_id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct UploadPartResult {
+    e_tag: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct CompletedPart {
+    e_tag: String,
+    part_number: u32,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct CompletedMultipartUpload<'a> {
+    parts: &'a [CompletedPart],
+}
+
+/// Parts of an upload that has not been completed yet, so nothing is visible under the key.
+pub struct UploadedParts {
+    /// The one object, or with a split upload the numbered objects the stream was cut into.
+    objects: Vec<PendingObject>,
+    pub bytes: u64,
+}
+
+/// One multipart upload of [`UploadedParts`].
+struct PendingObject {
+    key: String,
+    upload_id: String,
+    parts: Vec<CompletedPart>,
+    bytes: u64,
+}
+
+/// Cut the stream into objects of at most `size` bytes, `<key>.part0001` onwards, for stores that
+/// limit the size of a single object. The manifest listing them goes under the key itself.
+#[derive(Clone, Debug)]
+pub struct Split {
+    pub size: u64,
+    /// Tag set JSON the backup is tagged with; the numbered objects get it too, so lifecycle
+    /// rules expire them along with the manifest.
+    pub tags: String,
+}
+
+/// What a split backup stores under its key: the numbered objects to concatenate, in order.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SplitManifest {
+    pub objects: Vec<SplitObject>,
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SplitObject {
+    pub key: String,
+    pub bytes: u64,
+}
+
+/// The key of the `number`th object of a split backup, counting from 1.
+pub fn split_key(key: &str, number: usize) -> String {
+    format!("{}.part{:04}", key, number)
+}
+
+/// The key of the split backup `key` is one of the numbered objects of, if it is one.
+pub fn split_of(key: &str) -> Option<&str> {
+    let (base, number) = key.rsplit_once(".part")?;
+    (number.len() >= 4 && number.bytes().all(|byte| byte.is_ascii_digit())).then_some(base)
+}
+
+/// The objects holding the data of the backup at `key`, in order: the key itself, or the
+/// numbered objects its manifest lists when the backup was split.
+pub async fn data_keys(tools: &Tools, s3_access: &S3Access, bucket_name: &str, key: &str) -> Result<Vec<String>, Report> {
+    let first = split_key(key, 1);
+    let objects = s3::list_objects(tools, s3_access, bucket_name, key).await?;
+    if !objects.iter().any(|object| object.key == first) {
+        return Ok(vec![key.to_string()]);
+    }
+    let manifest = s3::read_object(tools, s3_access, bucket_name, key).await?;
+    let manifest = serde_json::from_slice::<SplitManifest>(&manifest).wrap_err_with(|| format!("Unable to parse the split manifest {}", key))?;
+    Ok(manifest.objects.into_iter().map(|object| object.key).collect())
+}
+
+/// Every object of the backup at `key`, for copying or deleting it whole: the key and, when the
+/// backup was split, the numbered objects its manifest lists.
+pub async fn backup_keys(tools: &Tools, s3_access: &S3Access, bucket_name: &str, key: &str) -> Result<Vec<String>, Report> {
+    let mut keys = data_keys(tools, s3_access, bucket_name, key).await?;
+    if keys != [key] {
+        keys.insert(0, key.to_string());
+    }
+    Ok(keys)
+}
+
+/// `objects` without the numbered objects of split backups whose manifest is among them, so
+/// that each backup is listed once, by its key.
+pub fn without_split_parts(objects: Vec<Object>) -> Vec<Object> {
+    let keys = objects.iter().map(|object| object.key.clone()).collect::<HashSet<_>>();
+    objects.into_iter().filter(|object| !split_of(&object.key).is_some_and(|manifest| keys.contains(manifest))).collect()
+}
+
+/// Local disk the compressed stream is written to in full before any of it is uploaded.
+#[derive(Clone, Debug)]
+pub struct Spool {
+    pub dir: PathBuf,
+    /// Space that must be free in `dir` before spooling starts.
+    pub min_free: u64,
+    /// Tag set JSON the backup is tagged with, kept in the journal for `btagger resume`.
+    pub tags: String,
+}
+
+impl Spool {
+    /// The spool file for `key`, and the journal of its upload next to it.
+    pub fn paths(&self, key: &str) -> (PathBuf, PathBuf) {
+        let name = format!("{}{}", SPOOL_PREFIX, key.replace('/', "_"));
+        (self.dir.join(&name), self.dir.join(format!("{}{}", name, JOURNAL_SUFFIX)))
+    }
+
+    /// Journals of the uploads left behind in the spool directory.
+    pub async fn journals(&self) -> Result<Vec<PathBuf>, Report> {
+        let mut entries = tokio::fs::read_dir(&self.dir)
+            .await
+            .wrap_err_with(|| format!("Unable to read {}", self.dir.display()))?;
+        let mut journals = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with(SPOOL_PREFIX) && name.ends_with(JOURNAL_SUFFIX) {
+                journals.push(entry.path());
+            }
+        }
+        journals.sort();
+        Ok(journals)
+    }
+}
+
+const SPOOL_PREFIX: &str = "btagger-spool-";
+const JOURNAL_SUFFIX: &str = ".upload.json";
+
+/// What it takes to pick a spooled upload up again: rewritten after every part, so the parts it
+/// lists are uploaded.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Journal {
+    pub bucket_name: String,
+    pub key: String,
+    pub upload_id: String,
+    pub part_size: u64,
+    pub tags: String,
+    pub parts: Vec<CompletedPart>,
+}
+
+impl Journal {
+    pub async fn read(path: &Path) -> Result<Journal, Report> {
+        let contents = tokio::fs::read(path).await.wrap_err_with(|| format!("Unable to read {}", path.display()))?;
+        serde_json::from_slice(&contents).wrap_err_with(|| format!("Unable to parse {}", path.display()))
+    }
+
+    /// Replaces the journal at `path` in one step, so a crash leaves the previous version.
+    async fn write(&self, path: &Path) -> Result<(), Report> {
+        let staged = path.with_extension("tmp");
+        tokio::fs::write(&staged, serde_json::to_vec(self)?)
+            .await
+            .wrap_err_with(|| format!("Unable to write {}", staged.display()))?;
+        tokio::fs::rename(&staged, path)
+            .await
+            .wrap_err_with(|| format!("Unable to write {}", path.display()))
+    }
+}
+
+/// Streams a reader into `s3://{bucket_name}/{key}` one part at a time.
+pub struct MultipartUpload {
+    pub tools: Tools,
+    pub s3_access: S3Access,
+    pub bucket_name: String,
+    pub key: String,
+    pub part_size: u64,
+    pub part_retries: u32,
+    /// Parts uploaded at the same time; reading the stream pauses while this many are in flight.
+    pub concurrency: usize,
+    pub deadline: Option<Instant>,
+    /// Spool the stream to disk first, so a flaky store only costs part retries rather than the
+    /// whole export.
+    pub spool: Option<Spool>,
+    pub split: Option<Split>,
+    /// User metadata of the objects: what is known at the start goes on the numbered objects as
+    /// their uploads are created, and all of it on the manifest.
+    pub metadata: Option<ObjectMetadata>,
+}
+
+impl MultipartUpload {
+    /// Uploads everything `reader` yields as parts, leaving the caller to [`complete`] the object
+    /// once the producer is known to have succeeded, or to [`abort`] it. Any failure here,
+    /// including hitting the deadline, aborts the upload so no orphaned parts are left behind;
+    /// a spooled upload is kept instead, for `btagger resume`.
+    ///
+    /// [`complete`]: MultipartUpload::complete
+    /// [`abort`]: MultipartUpload::abort
+    pub async fn upload(self: &Arc<Self>, reader: impl AsyncRead + Unpin) -> Result<UploadedParts, Report> {
+        if self.part_size < MIN_PART_SIZE {
+            return Err(eyre!("Part size must be at least {} bytes", MIN_PART_SIZE));
+        }
+        if self.split.as_ref().is_some_and(|split| split.size < MIN_PART_SIZE) {
+            return Err(eyre!("Split size must be at least {} bytes", MIN_PART_SIZE));
+        }
+        match &self.spool {
+            Some(spool) => self.upload_spooled(spool, reader).await,
+            None => self.upload_from(reader).await,
+        }
+    }
+
+    /// Writes `reader` to a file in the spool directory, then uploads the file, journaling each
+    /// part. The upload is only started once the stream has ended.
+    async fn upload_spooled(self: &Arc<Self>, spool: &Spool, mut reader: impl AsyncRead + Unpin) -> Result<UploadedParts, Report> {
+        let available = available_space(&self.tools, &spool.dir).await?;
+        if available < spool.min_free {
+            return Err(eyre!(
+                "Only {} bytes are free in {}, below --spool-min-free {}",
+                available,
+                spool.dir.display(),
+                spool.min_free
+            ));
+        }
+        let (path, _) = spool.paths(&self.key);
+        let spooled = async {
+            let mut file = tokio::fs::File::create(&path)
+                .await
+                .wrap_err_with(|| format!("Unable to create {}", path.display()))?;
+            let bytes = before_deadline(self.deadline, "spool", tokio::io::copy(&mut reader, &mut file))
+                .await?
+                .wrap_err_with(|| format!("Unable to spool the export to {}", path.display()))?;
+            file.sync_all().await.wrap_err_with(|| format!("Unable to spool the export to {}", path.display()))?;
+            info!(target: "multipart_upload", key = self.key, path = %path.display(), bytes, "Spooled the export");
+            let upload_id = self.create(&self.key).await?;
+            Ok::<_, Report>(Journal {
+                bucket_name: self.bucket_name.clone(),
+                key: self.key.clone(),
+                upload_id,
+                part_size: self.part_size,
+                tags: spool.tags.clone(),
+                parts: Vec::new(),
+            })
+        }
+        .await;
+        match spooled {
+            Ok(journal) => self.resume(spool, journal).await,
+            Err(err) => {
+                remove_spooled(spool, &self.key).await;
+                Err(err)
+            }
+        }
+    }
+
+    /// Uploads the parts of the spooled file that `journal` does not list yet. On failure the
+    /// multipart upload, the file and the journal are all kept, so this can be called again.
+    pub async fn resume(self: &Arc<Self>, spool: &Spool, mut journal: Journal) -> Result<UploadedParts, Report> {
+        let (path, journal_path) = spool.paths(&self.key);
+        let result = before_deadline(self.deadline, "upload", self.upload_remaining(&path, &journal_path, &mut journal)).await;
+        match result {
+            Ok(Ok(bytes)) => Ok(UploadedParts {
+                objects: vec![PendingObject { key: self.key.clone(), upload_id: journal.upload_id, parts: journal.parts, bytes }],
+                bytes,
+            }),
+            Ok(Err(err)) | Err(err) => {
+                warn!(target: "multipart_upload", key = self.key, upload_id = journal.upload_id, parts = journal.parts.len(), journal = %journal_path.display(), "Kept the spooled upload for btagger resume");
+                Err(err.wrap_err(format!("Upload of {} stopped; `btagger resume --spool-dir {}` continues it", self.key, spool.dir.display())))
+            }
+        }
+    }
+
+    /// Discards a spooled upload whose export turned out to be broken: the multipart upload is
+    /// aborted and the spool file and journal removed.
+    pub async fn discard(&self) {
+        let Some(spool) = &self.spool else { return };
+        let (_, journal_path) = spool.paths(&self.key);
+        if let Ok(journal) = Journal::read(&journal_path).await {
+            self.abort_upload(&self.key, &journal.upload_id).await;
+        }
+        remove_spooled(spool, &self.key).await;
+    }
+
+    async fn create(&self, key: &str) -> Result<String, Report> {
+        let mut command = self.aws();
+        command
+            .arg("s3api")
+            .arg("create-multipart-upload")
+            .arg("--bucket").arg(&self.bucket_name)
+            .arg("--key").arg(key)
+            .arg("--output").arg("json")
+            .args(self.s3_access.object_options());
+        if let Some(metadata) = &self.metadata {
+            command.arg("--metadata").arg(metadata.at_start());
+        }
+        let output = self.tools.runner.run(command, None).await.wrap_err("failed to execute process")?;
+        if !output.status.success() {
+            return Err(eyre!("create-multipart-upload failed: {}", process::captured("create-multipart-upload", &output.stderr)));
+        }
+        let upload_id = serde_json::from_slice::<CreateMultipartUploadResult>(&output.stdout)
+            .wrap_err("Unable to parse create-multipart-upload response")?
+            .upload_id;
+        info!(target: "multipart_upload", key, upload_id, "Started multipart upload");
+        Ok(upload_id)
+    }
+
+    async fn upload_from(self: &Arc<Self>, reader: impl AsyncRead + Unpin) -> Result<UploadedParts, Report> {
+        let Some(split) = &self.split else {
+            let object = self.upload_object(&self.key, reader).await?;
+            return Ok(UploadedParts { bytes: object.bytes, objects: vec![object] });
+        };
+        let mut reader = BufReader::new(reader);
+        let mut objects = Vec::new();
+        loop {
+            let key = split_key(&self.key, objects.len() + 1);
+            let uploaded = self.upload_object(&key, (&mut reader).take(split.size)).await;
+            // A stream that ends exactly at a split boundary must not leave an empty object behind.
+            let more = match uploaded {
+                Ok(object) => {
+                    objects.push(object);
+                    reader.fill_buf().await.map(|buffer| !buffer.is_empty()).wrap_err("Unable to read the export stream")
+                }
+                Err(err) => Err(err),
+            };
+            match more {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(err) => {
+                    for object in &objects {
+                        self.abort_upload(&object.key, &object.upload_id).await;
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        info!(target: "multipart_upload", key = self.key, objects = objects.len(), "Split the upload");
+        Ok(UploadedParts { bytes: objects.iter().map(|object| object.bytes).sum(), objects })
+    }
+
+    async fn upload_object(self: &Arc<Self>, key: &str, reader: impl AsyncRead + Unpin) -> Result<PendingObject, Report> {
+        let upload_id = self.create(key).await?;
+        let result = before_deadline(self.deadline, "upload", self.upload_parts(key, &upload_id, reader)).await;
+        match result {
+            Ok(Ok((parts, bytes))) => Ok(PendingObject { key: key.to_string(), upload_id, parts, bytes }),
+            Ok(Err(err)) | Err(err) => {
+                self.abort_upload(key, &upload_id).await;
+                Err(err)
+            }
+        }
+    }
+
+    /// Assembles the uploaded parts into the object and returns its size, aborting on failure.
+    /// `uncompressed_bytes`, when the caller counted them, go into the manifest's metadata.
+    pub async fn complete(&self, uploaded: UploadedParts, uncompressed_bytes: Option<u64>) -> Result<u64, Report> {
+        let result = before_deadline(self.deadline, "upload", self.complete_objects(&uploaded, uncompressed_bytes)).await;
+        match (result, &self.spool) {
+            (Ok(Ok(())), spool) => {
+                if let Some(spool) = spool {
+                    remove_spooled(spool, &self.key).await;
+                }
+                Ok(uploaded.bytes)
+            }
+            // Every part is journaled, so resuming only has to complete the upload.
+            (Ok(Err(err)) | Err(err), Some(spool)) => {
+                Err(err.wrap_err(format!("Upload of {} stopped; `btagger resume --spool-dir {}` continues it", self.key, spool.dir.display())))
+            }
+            (Ok(Err(err)) | Err(err), None) => {
+                for object in &uploaded.objects {
+                    self.abort_upload(&object.key, &object.upload_id).await;
+                    // The numbered objects completed before the failure are of no use alone.
+                    if self.split.is_some() {
+                        let removed = s3::delete_object(&self.tools, &self.s3_access, &self.bucket_name, &object.key).await;
+                        info!(target: "aws_remove_partial_backup_output", key = object.key, success = removed.is_ok(), error = removed.err().map(|err| format!("{:#}", err)));
+                    }
+                }
+                Err(err)
+            }
+        }
+    }
+
+    async fn complete_objects(&self, uploaded: &UploadedParts, uncompressed_bytes: Option<u64>) -> Result<(), Report> {
+        for object in &uploaded.objects {
+            self.complete_upload(object).await?;
+        }
+        let Some(split) = &self.split else { return Ok(()) };
+        for object in &uploaded.objects {
+            s3::put_object_tagging(&self.tools, &self.s3_access, &self.bucket_name, &object.key, &split.tags).await?;
+        }
+        let manifest = SplitManifest {
+            objects: uploaded.objects.iter().map(|object| SplitObject { key: object.key.clone(), bytes: object.bytes }).collect(),
+            bytes: uploaded.bytes,
+        };
+        let path = self.scratch_path(&format!("manifest-{}", self.key.replace('/', "_")));
+        tokio::fs::write(&path, serde_json::to_vec(&manifest)?)
+            .await
+            .wrap_err("Unable to write the split manifest")?;
+        let metadata = self.metadata.as_ref().map(|metadata| metadata.at_end(uncompressed_bytes));
+        let stored = s3::put_object(&self.tools, &self.s3_access, &self.bucket_name, &self.key, &path, metadata.as_deref()).await;
+        let _ = tokio::fs::remove_file(&path).await;
+        stored?;
+        info!(target: "multipart_upload", key = self.key, objects = manifest.objects.len(), bytes = manifest.bytes, "Stored the split manifest");
+        Ok(())
+    }
+
+    /// Discards the uploaded parts without creating the object.
+    pub async fn abort(&self, uploaded: UploadedParts) {
+        for object in &uploaded.objects {
+            self.abort_upload(&object.key, &object.upload_id).await;
+        }
+        if let Some(spool) = &self.spool {
+            remove_spooled(spool, &self.key).await;
+        }
+    }
+
+    /// Uploads the parts of the file at `path` missing from `journal`, recording each in it as it
+    /// finishes, and returns the file's size.
+    async fn upload_remaining(self: &Arc<Self>, path: &Path, journal_path: &Path, journal: &mut Journal) -> Result<u64, Report> {
+        let mut file = tokio::fs::File::open(path)
+            .await
+            .wrap_err_with(|| format!("Unable to read {}", path.display()))?;
+        let bytes = file.metadata().await?.len();
+        // S3 needs at least one part, even for an empty file.
+        let count = bytes.div_ceil(self.part_size).max(1) as u32;
+        journal.write(journal_path).await?;
+        let mut in_flight = JoinSet::new();
+        for part_number in 1..=count {
+            if journal.parts.iter().any(|part| part.part_number == part_number) {
+                continue;
+            }
+            while in_flight.len() >= self.concurrency.max(1) {
+                journal.parts.push(joined(in_flight.join_next().await)?);
+                journal.write(journal_path).await?;
+            }
+            let mut buffer = vec![0u8; self.part_size as usize];
+            file.seek(SeekFrom::Start(u64::from(part_number - 1) * self.part_size)).await?;
+            let filled = fill(&mut file, &mut buffer).await.wrap_err_with(|| format!("Unable to read {}", path.display()))?;
+            buffer.truncate(filled);
+            let upload = Arc::clone(self);
+            let upload_id = journal.upload_id.clone();
+            in_flight.spawn(async move { upload.upload_part(&upload.key, &upload_id, part_number, buffer).await });
+        }
+        while let Some(part) = in_flight.join_next().await {
+            journal.parts.push(joined(Some(part))?);
+            journal.write(journal_path).await?;
+        }
+        journal.parts.sort_by_key(|part| part.part_number);
+        Ok(bytes)
+    }
+
+    async fn upload_parts(
+        self: &Arc<Self>,
+        key: &str,
+        upload_id: &str,
+        mut reader: impl AsyncRead + Unpin,
+    ) -> Result<(Vec<CompletedPart>, u64), Report> {
+        let mut parts = Vec::new();
+        let mut in_flight = JoinSet::new();
+        let mut bytes = 0;
+        let mut part_number = 0;
+        loop {
+            let mut buffer = vec![0u8; self.part_size as usize];
+            let filled = fill(&mut reader, &mut buffer).await.wrap_err("Unable to read the export stream")?;
+            // S3 needs at least one part, even for an empty stream.
+            if filled == 0 && part_number > 0 {
+                break;
+            }
+            buffer.truncate(filled);
+            part_number += 1;
+            bytes += filled as u64;
+            while in_flight.len() >= self.concurrency.max(1) {
+                parts.push(joined(in_flight.join_next().await)?);
+            }
+            let upload = Arc::clone(self);
+            let (key, upload_id) = (key.to_string(), upload_id.to_string());
+            in_flight.spawn(async move { upload.upload_part(&key, &upload_id, part_number, buffer).await });
+            if filled < self.part_size as usize {
+                break;
+            }
+        }
+        while let Some(part) = in_flight.join_next().await {
+            parts.push(joined(Some(part))?);
+        }
+        parts.sort_by_key(|part| part.part_number);
+        Ok((parts, bytes))
+    }
+
+    async fn complete_upload(&self, uploaded: &PendingObject) -> Result<(), Report> {
+        let manifest = self.scratch_path("parts.json");
+        tokio::fs::write(&manifest, serde_json::to_vec(&CompletedMultipartUpload { parts: &uploaded.parts })?)
+            .await
+            .wrap_err("Unable to write multipart manifest")?;
+        let mut command = self.aws();
+        command
+            .arg("s3api")
+            .arg("complete-multipart-upload")
+            .arg("--bucket").arg(&self.bucket_name)
+            .arg("--key").arg(&uploaded.key)
+            .arg("--upload-id").arg(&uploaded.upload_id)
+            .arg("--multipart-upload").arg(format!("file://{}", manifest.display()))
+            .args(self.s3_access.object_options());
+        let output = self.tools.runner.run(command, None).await;
+        let _ = tokio::fs::remove_file(&manifest).await;
+        let output = output.wrap_err("failed to execute process")?;
+        if !output.status.success() {
+            return Err(eyre!("complete-multipart-upload failed: {}", process::captured("complete-multipart-upload", &output.stderr)));
+        }
+        info!(target: "multipart_upload", key = uploaded.key, parts = uploaded.parts.len(), bytes = uploaded.bytes, "Completed multipart upload");
+        Ok(())
+    }
+
+    async fn upload_part(&self, key: &str, upload_id: &str, part_number: u32, body: Vec<u8>) -> Result<CompletedPart, Report> {
+        let path = self.scratch_path(&format!("part-{}", part_number));
+        tokio::fs::write(&path, body)
+            .await
+            .wrap_err_with(|| format!("Unable to spool part {}", part_number))?;
+        let mut attempt = 0;
+        let result = loop {
+            attempt += 1;
+            let mut command = self.aws();
+            command
+                .arg("s3api")
+                .arg("upload-part")
+                .arg("--bucket").arg(&self.bucket_name)
+                .arg("--key").arg(key)
+                .arg("--upload-id").arg(upload_id)
+                .arg("--part-number").arg(part_number.to_string())
+                .arg("--body").arg(&path)
+                .arg("--output").arg("json")
+                .args(self.s3_access.object_options());
+            let output = self.tools.runner.run(command, None).await;
+            let error = match output {
+                Ok(output) if output.status.success() => {
+                    break serde_json::from_slice::<UploadPartResult>(&output.stdout)
+                        .map(|part| CompletedPart { e_tag: part.e_tag, part_number })
+                        .wrap_err("Unable to parse upload-part response");
+                }
+                Ok(output) => process::captured("upload-part", &output.stderr),
+                Err(err) => err.to_string(),
+            };
+            if attempt > self.part_retries {
+                break Err(eyre!("upload-part {} failed after {} attempts: {}", part_number, attempt, error));
+            }
+            warn!(target: "multipart_upload", part_number, attempt, error, "Retrying part upload");
+            tokio::time::sleep(Duration::from_secs(1 << attempt.min(5))).await;
+        };
+        let _ = tokio::fs::remove_file(&path).await;
+        result
+    }
+
+    async fn abort_upload(&self, key: &str, upload_id: &str) {
+        let mut command = self.aws();
+        command
+            .arg("s3api")
+            .arg("abort-multipart-upload")
+            .arg("--bucket").arg(&self.bucket_name)
+            .arg("--key").arg(key)
+            .arg("--upload-id").arg(upload_id)
+            .args(self.s3_access.object_options());
+        let output = self.tools.runner.run(command, None).await;
+        match output {
+            Ok(output) if output.status.success() => {
+                info!(target: "multipart_upload", key, upload_id, "Aborted multipart upload")
+            }
+            Ok(output) => warn!(target: "multipart_upload", key, upload_id, stderr = process::captured("abort-multipart-upload", &output.stderr), "Unable to abort multipart upload"),
+            Err(err) => warn!(target: "multipart_upload", key, upload_id, error = %err, "Unable to abort multipart upload"),
+        }
+    }
+
+    fn aws(&self) -> Command {
+        let mut command = Aws::new(&self.tools, &self.s3_access).command();
+        // Abandoned parts (failure elsewhere, deadline) must not keep uploading.
+        command.kill_on_drop(true);
+        command
+    }
+
+    /// A temporary file of its own for every call, so uploads running side by side, as in
+    /// `run-all --parallel`, never share part bodies or manifests.
+    fn scratch_path(&self, name: &str) -> PathBuf {
+        static WRITTEN: AtomicUsize = AtomicUsize::new(0);
+        std::env::temp_dir().join(format!("btagger-{}-{}-{}", std::process::id(), WRITTEN.fetch_add(1, Ordering::Relaxed), name))
+    }
+}
+
+/// Removes the spool file and journal of `key`.
+async fn remove_spooled(spool: &Spool, key: &str) {
+    let (path, journal_path) = spool.paths(key);
+    for path in [path, journal_path] {
+        if let Err(err) = tokio::fs::remove_file(&path).await {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                warn!(target: "multipart_upload", path = %path.display(), error = %err, "Unable to remove the spool file");
+            }
+        }
+    }
+}
+
+/// Bytes available to unprivileged users in the filesystem holding `dir`, as reported by df.
+async fn available_space(tools: &Tools, dir: &Path) -> Result<u64, Report> {
+    let mut command = Command::new(&tools.df);
+    command.arg("-Pk").arg(dir);
+    let output = process::succeeded(tools.runner.run(command, None).await)
+        .wrap_err_with(|| format!("Unable to check the space free in {}", dir.display()))?;
+    // POSIX output: a header, then `filesystem blocks used available capacity mount`.
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout
+        .lines()
+        .nth(1)
+        .and_then(|line| line.split_whitespace().nth(3))
+        .and_then(|available| available.parse::<u64>().ok())
+        .map(|kilobytes| kilobytes * 1024)
+        .ok_or_else(|| eyre!("Unable to parse df output for {}: {}", dir.display(), stdout))
+}
+
+fn joined(
+    part: Option<Result<Result<CompletedPart, Report>, tokio::task::JoinError>>,
+) -> Result<CompletedPart, Report> {
+    part.ok_or_else(|| eyre!("No part upload in flight"))?
+        .wrap_err("Part upload task failed")?
+}
+
+/// Reads until `buffer` is full or the stream ends, returning how much was read.
+async fn fill(reader: &mut (impl AsyncRead + Unpin), buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]).await {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::MockRunner;
+
+    fn upload(runner: Arc<MockRunner>, spool: Option<Spool>, split: Option<Split>) -> Arc<MultipartUpload> {
+        Arc::new(MultipartUpload {
+            tools: Tools::mock(runner),
+            s3_access: S3Access::default(),
+            bucket_name: String::from("bk"),
+            key: String::from("surrealdb/ns/db/2025-01-01.04-30.zst"),
+            part_size: MIN_PART_SIZE,
+            part_retries: 0,
+            concurrency: 2,
+            deadline: None,
+            spool,
+            split,
+            metadata: None,
+        })
+    }
+
+    fn df(available_kilobytes: u64) -> String {
+        format!("Filesystem 1024-blocks Used Available Capacity Mounted on\n/dev/sda1 100000000 1 {} 1% /\n", available_kilobytes)
+    }
+
+    #[tokio::test]
+    async fn spooled_stream_is_journaled_until_completed() {
+        let dir = std::env::temp_dir().join(format!("btagger-spool-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let runner = Arc::new(MockRunner::new(|call| match call.program.as_str() {
+            "df" => MockRunner::output(0, &df(4 * 1024 * 1024), ""),
+            _ if call.has_args(&["create-multipart-upload"]) => MockRunner::output(0, r#"{"UploadId":"u-1"}"#, ""),
+            _ => MockRunner::output(0, r#"{"ETag":"\"e\""}"#, ""),
+        }));
+        let spool = Spool { dir: dir.clone(), min_free: 1024 * 1024 * 1024, tags: String::from(r#"{"TagSet":[]}"#) };
+        let upload = upload(runner.clone(), Some(spool.clone()), None);
+        let uploaded = upload.upload(&b"DEFINE TABLE person;\n"[..]).await.unwrap();
+        assert_eq!(uploaded.bytes, 21);
+        let calls = runner.calls();
+        assert_eq!(calls[0].program, "df");
+        assert!(calls[1].has_args(&["create-multipart-upload"]));
+        let (file, journal) = spool.paths(&upload.key);
+        assert_eq!(file, dir.join("btagger-spool-surrealdb_ns_db_2025-01-01.04-30.zst"));
+        let journaled = Journal::read(&journal).await.unwrap();
+        assert_eq!((journaled.upload_id.as_str(), journaled.parts.len()), ("u-1", 1));
+
+        upload.complete(uploaded, None).await.unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn split_stream_becomes_numbered_objects_and_a_manifest() {
+        let runner = Arc::new(MockRunner::new(|call| match call.has_args(&["create-multipart-upload"]) {
+            true => MockRunner::output(0, r#"{"UploadId":"u"}"#, ""),
+            false => MockRunner::output(0, r#"{"ETag":"\"e\""}"#, ""),
+        }));
+        let split = Split { size: MIN_PART_SIZE, tags: String::from(r#"{"TagSet":[{"Key":"nightly","Value":"1"}]}"#) };
+        let mut upload = upload(runner.clone(), None, Some(split));
+        let metadata = ObjectMetadata { started: "2025-01-01T04:30:00Z".parse().unwrap() };
+        Arc::get_mut(&mut upload).unwrap().metadata = Some(metadata);
+        // Exactly two split sizes: no empty third object.
+        let uploaded = upload.upload(&vec![1u8; 2 * MIN_PART_SIZE as usize][..]).await.unwrap();
+        assert_eq!(upload.complete(uploaded, Some(42)).await.unwrap(), 2 * MIN_PART_SIZE);
+
+        let calls = runner.calls();
+        let created = calls.iter().filter(|call| call.has_args(&["create-multipart-upload"])).collect::<Vec<_>>();
+        assert_eq!(created.len(), 2);
+        assert!(created[1].has_args(&["--key", "surrealdb/ns/db/2025-01-01.04-30.zst.part0002"]));
+        // Metadata goes on with the objects as they are created, not with a copy afterwards.
+        let at_start = format!("backup-started=2025-01-01T04:30:00Z,tool-version={}", env!("CARGO_PKG_VERSION"));
+        assert!(created.iter().all(|call| call.has_args(&["--metadata", &at_start])));
+        let tagged = calls.iter().filter(|call| call.has_args(&["put-object-tagging"])).count();
+        assert_eq!(tagged, 2);
+        let manifest = calls.last().unwrap();
+        assert!(manifest.has_args(&["put-object", "--bucket", "bk", "--key", "surrealdb/ns/db/2025-01-01.04-30.zst"]));
+        // The manifest is written after the stream ended, so it carries the whole run.
+        let at_end = &manifest.args[manifest.args.iter().position(|arg| arg == "--metadata").unwrap() + 1];
+        assert!(at_end.starts_with(&format!("{},backup-duration=", at_start)) && at_end.ends_with(",uncompressed-bytes=42"), "{}", at_end);
+        assert!(!calls.iter().any(|call| call.has_args(&["--metadata-directive"])));
+    }
+
+    #[tokio::test]
+    async fn uploads_running_side_by_side_keep_their_own_scratch_files() {
+        // What each part body and completion manifest held when aws was called on it.
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let runner = Arc::new(MockRunner::new(move |call| {
+            let file = |option: &str| call.args.iter().position(|arg| arg == option).map(|at| call.args[at + 1].trim_start_matches("file://").to_string());
+            if let Some(path) = file("--body").or_else(|| file("--multipart-upload")) {
+                recorded.lock().unwrap().push((path.clone(), std::fs::read(&path).unwrap()));
+            }
+            match call.has_args(&["create-multipart-upload"]) {
+                true => MockRunner::output(0, r#"{"UploadId":"u"}"#, ""),
+                false => MockRunner::output(0, r#"{"ETag":"\"e\""}"#, ""),
+            }
+        }));
+        let first = upload(runner.clone(), None, None);
+        let mut second = upload(runner.clone(), None, None);
+        Arc::get_mut(&mut second).unwrap().key = String::from("surrealdb/other/db/2025-01-01.04-30.zst");
+        let (a, b) = (vec![b'a'; 64], vec![b'b'; 64]);
+        let (first_parts, second_parts) = tokio::join!(first.upload(&a[..]), second.upload(&b[..]));
+        let (first_done, second_done) = tokio::join!(first.complete(first_parts.unwrap(), None), second.complete(second_parts.unwrap(), None));
+        assert_eq!((first_done.unwrap(), second_done.unwrap()), (64, 64));
+
+        let seen = seen.lock().unwrap();
+        let bodies = seen.iter().filter(|(_, contents)| contents == &a || contents == &b).collect::<Vec<_>>();
+        assert_eq!(bodies.len(), 2);
+        assert_ne!(bodies[0].0, bodies[1].0);
+        let manifests = seen.iter().filter(|(path, _)| path.ends_with("parts.json")).map(|(path, _)| path).collect::<Vec<_>>();
+        assert_eq!(manifests.len(), 2);
+        assert_ne!(manifests[0], manifests[1]);
+    }
+
+    #[tokio::test]
+    async fn too_little_free_space_refuses_before_starting_the_upload() {
+        let runner = Arc::new(MockRunner::new(|_| MockRunner::output(0, &df(1024), "")));
+        let upload = upload(runner.clone(), Some(Spool { dir: PathBuf::from("/var/spool/btagger"), min_free: 1024 * 1024 * 1024, tags: String::new() }), None);
+        let err = upload.upload(&b"data"[..]).await.err().unwrap();
+        assert!(format!("{:#}", err).contains("Only 1048576 bytes are free in /var/spool/btagger"), "{:#}", err);
+        assert_eq!(runner.calls().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_split_backup_is_listed_once_and_handled_with_its_numbered_objects() {
+        let object = |key: &str| Object { key: key.to_string(), size: 1, last_modified: String::from("2025-01-01T04:30:00+00:00") };
+        let listed = vec![object("k.zst"), object("k.zst.part0001"), object("k.zst.part0002"), object("gone.zst.part0001"), object("other.zst")];
+        let keys = without_split_parts(listed).into_iter().map(|object| object.key).collect::<Vec<_>>();
+        // A numbered object whose manifest is missing is left in, so it is not overlooked.
+        assert_eq!(keys, ["k.zst", "gone.zst.part0001", "other.zst"]);
+
+        let runner = Arc::new(MockRunner::new(|call| {
+            if call.has_args(&["list-objects-v2"]) && call.has_args(&["--prefix", "k.zst"]) {
+                MockRunner::output(0, r#"{"Contents":[{"Key":"k.zst","Size":9},{"Key":"k.zst.part0001","Size":5},{"Key":"k.zst.part0002","Size":4}]}"#, "")
+            } else if call.has_args(&["s3://bk/k.zst", "-"]) {
+                MockRunner::output(0, r#"{"objects":[{"key":"k.zst.part0001","bytes":5},{"key":"k.zst.part0002","bytes":4}],"bytes":9}"#, "")
+            } else {
+                MockRunner::output(0, r#"{"Contents":[{"Key":"other.zst","Size":9}]}"#, "")
+            }
+        }));
+        let tools = Tools::mock(runner);
+        let access = S3Access::default();
+        assert_eq!(backup_keys(&tools, &access, "bk", "k.zst").await.unwrap(), ["k.zst", "k.zst.part0001", "k.zst.part0002"]);
+        assert_eq!(backup_keys(&tools, &access, "bk", "other.zst").await.unwrap(), ["other.zst"]);
+    }
+}
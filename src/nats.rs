@@ -0,0 +1,202 @@
+use color_eyre::eyre::{eyre, Report, WrapErr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::process::Command;
+use tracing::info;
+
+use crate::archive::{directory_size, ArchiveUpload};
+use crate::compression::Compression;
+use crate::failure::Failure;
+use crate::multipart::{ObjectMetadata, Split, Spool};
+use crate::process::{self, before_deadline};
+use crate::s3::{self, S3Access};
+use crate::secret::Secret;
+use crate::size::ByteSize;
+use crate::summary::{BackupReport, EmptyBackup, Timings};
+use crate::tools::Tools;
+
+/// A NATS JetStream stream or account to back up, and where to.
+pub struct NatsOptions {
+    pub tools: Tools,
+    pub bucket_name: String,
+    pub s3_access: S3Access,
+    pub create_bucket: bool,
+    /// Server URLs, handed to nats as NATS_URL; its own default when unset.
+    pub url: Option<Secret>,
+    /// Credentials file, handed to nats as NATS_CREDS.
+    pub creds: Option<PathBuf>,
+    /// Saved nats context to connect with.
+    pub context: Option<String>,
+    /// Back up this stream rather than every stream of the account.
+    pub stream: Option<String>,
+    pub tags: String,
+    pub storage_key: String,
+    pub compression: Compression,
+    pub compression_level: Option<u32>,
+    pub part_size: ByteSize,
+    pub part_retries: u32,
+    pub spool: Option<Spool>,
+    pub split: Option<Split>,
+    pub min_expected_bytes: u64,
+    pub allow_empty: bool,
+    pub concurrency: usize,
+    pub deadline: Option<tokio::time::Instant>,
+    pub timings: Timings,
+}
+
+/// Backs the stream or account up into a scratch directory with the nats CLI, then uploads the
+/// directory as one archive and tags it. As with InfluxDB, the scratch directory is removed
+/// whatever the outcome.
+pub async fn backup(options: &NatsOptions) -> Result<BackupReport, Report> {
+    // The counter keeps `run-all --parallel` jobs apart.
+    static STARTED: AtomicUsize = AtomicUsize::new(0);
+    let directory = std::env::temp_dir().join(format!("btagger-nats-{}-{}", std::process::id(), STARTED.fetch_add(1, Ordering::Relaxed)));
+    let result = backup_through(options, &directory).await;
+    if let Err(err) = tokio::fs::remove_dir_all(&directory).await {
+        if err.kind() != std::io::ErrorKind::NotFound {
+            tracing::warn!(target: "nats", directory = %directory.display(), error = %err, "Unable to remove the backup directory");
+        }
+    }
+    result
+}
+
+async fn backup_through(options: &NatsOptions, directory: &Path) -> Result<BackupReport, Report> {
+    let metadata = ObjectMetadata::now();
+    let (tools, s3_access, bucket_name, key) = (&options.tools, &options.s3_access, &options.bucket_name, &options.storage_key);
+    let timings = &options.timings;
+    if options.create_bucket {
+        let created = timings.time("bucket_ensure", s3::ensure_bucket(tools, s3_access, bucket_name)).await.wrap_err(Failure::Upload)?;
+        info!(target: "aws_create_bucket_output", bucket = bucket_name, created);
+    }
+
+    tokio::fs::create_dir_all(directory)
+        .await
+        .wrap_err_with(|| format!("Unable to create {}", directory.display()))?;
+    let stage = match options.stream {
+        Some(_) => "nats stream backup",
+        None => "nats account backup",
+    };
+    // nats creates the target itself, so it is given a directory that does not exist yet.
+    let target = PathBuf::from("backup");
+    let output = timings.time("export", before_deadline(options.deadline, stage, tools.runner.run(backup_command(options, &directory.join(&target)), None)))
+        .await
+        .and_then(process::succeeded)
+        .wrap_err(Failure::Export { stage: String::from(stage) })?;
+    info!(target: "nats_backup_output", stdout = process::captured("nats-backup", &output.stdout));
+
+    let (files, raw_bytes) = directory_size(directory).await?;
+    if files == 0 && !options.allow_empty {
+        return Err(Report::new(EmptyBackup(format!("NATS backup of {}", options.stream.as_deref().unwrap_or("every stream")))));
+    }
+    // Checked before uploading, so nothing needs removing.
+    if raw_bytes < options.min_expected_bytes {
+        return Err(eyre!("Backup is only {} bytes, below --min-expected-bytes {}", raw_bytes, options.min_expected_bytes)
+            .wrap_err(Failure::Export { stage: String::from("size check") }));
+    }
+
+    let archive = ArchiveUpload {
+        tools,
+        s3_access,
+        bucket_name,
+        compression: options.compression,
+        compression_level: options.compression_level,
+        part_size: options.part_size,
+        part_retries: options.part_retries,
+        spool: options.spool.clone(),
+        split: options.split.clone(),
+        concurrency: options.concurrency,
+        deadline: options.deadline,
+        metadata,
+    };
+    let bytes = timings.time("upload", archive.upload(directory, &[target], key)).await?;
+    info!(target: "backup_size", key, raw_bytes, bytes, files);
+
+    timings.time("tagging", s3::put_object_tagging(tools, s3_access, bucket_name, key, &options.tags)).await.wrap_err(Failure::Tagging)?;
+    info!(target: "aws_put_object_tagging_output", key);
+    Ok(BackupReport {
+        storage_key: key.clone(),
+        bytes,
+        raw_bytes: Some(raw_bytes),
+        success: true,
+        stderr_tail: None,
+        tikv_stats: None,
+        signal: None,
+    })
+}
+
+fn backup_command(options: &NatsOptions, target: &Path) -> Command {
+    let mut command = Command::new(&options.tools.nats);
+    command.kill_on_drop(true);
+    if let Some(context) = &options.context {
+        command.arg("--context").arg(context);
+    }
+    match &options.stream {
+        Some(stream) => command.arg("stream").arg("backup").arg(stream),
+        None => command.arg("account").arg("backup"),
+    };
+    command.arg(target);
+    if let Some(url) = &options.url {
+        // Read by nats; URLs may carry a user and password, so they stay out of process listings.
+        command.env("NATS_URL", url.expose());
+    }
+    if let Some(creds) = &options.creds {
+        command.env("NATS_CREDS", creds);
+    }
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::MockRunner;
+    use std::sync::Arc;
+
+    fn options(runner: Arc<MockRunner>, stream: Option<&str>) -> NatsOptions {
+        NatsOptions {
+            tools: Tools::mock(runner),
+            bucket_name: String::from("bk"),
+            s3_access: S3Access::default(),
+            create_bucket: false,
+            url: Some(Secret::new("nats://user:pw@nats:4222")),
+            creds: Some(PathBuf::from("/etc/nats/backup.creds")),
+            context: None,
+            stream: stream.map(String::from),
+            tags: String::from(r#"{"TagSet":[]}"#),
+            storage_key: String::from("nats/2025-01-01.04-30.tar.zst"),
+            compression: Compression::Zstd,
+            compression_level: None,
+            part_size: ByteSize(8 * 1024 * 1024),
+            part_retries: 0,
+            spool: None,
+            split: None,
+            min_expected_bytes: 0,
+            allow_empty: false,
+            concurrency: 4,
+            deadline: None,
+            timings: Timings::default(),
+        }
+    }
+
+    #[test]
+    fn stream_or_account_backup_with_the_connection_in_the_environment() {
+        let runner = Arc::new(MockRunner::new(|_| MockRunner::output(0, "", "")));
+        let command = backup_command(&options(runner.clone(), Some("ORDERS")), Path::new("/tmp/x/backup"));
+        let args = command.as_std().get_args().map(|arg| arg.to_str().unwrap()).collect::<Vec<_>>();
+        assert_eq!(args, ["stream", "backup", "ORDERS", "/tmp/x/backup"]);
+        let envs = command.as_std().get_envs().map(|(name, value)| (name.to_str().unwrap(), value.unwrap().to_str().unwrap())).collect::<Vec<_>>();
+        assert!(envs.contains(&("NATS_URL", "nats://user:pw@nats:4222")));
+        assert!(envs.contains(&("NATS_CREDS", "/etc/nats/backup.creds")));
+
+        let command = backup_command(&NatsOptions { context: Some(String::from("prod")), ..options(runner, None) }, Path::new("/tmp/x/backup"));
+        let args = command.as_std().get_args().map(|arg| arg.to_str().unwrap()).collect::<Vec<_>>();
+        assert_eq!(args, ["--context", "prod", "account", "backup", "/tmp/x/backup"]);
+    }
+
+    #[tokio::test]
+    async fn nothing_backed_up_is_refused_unless_allowed() {
+        let runner = Arc::new(MockRunner::new(|_| MockRunner::output(0, "", "")));
+        let err = backup(&options(runner.clone(), Some("ORDERS"))).await.unwrap_err();
+        assert_eq!(Failure::of(&err).code(), "empty");
+        assert_eq!(runner.calls().len(), 1);
+    }
+}
@@ -0,0 +1,126 @@
+use color_eyre::eyre::{eyre, Report, WrapErr};
+use tokio::process::Command;
+use tracing::info;
+
+use crate::archive::ArchiveUpload;
+use crate::compression::Compression;
+use crate::failure::Failure;
+use crate::multipart::{ObjectMetadata, Split, Spool};
+use crate::s3::{self, S3Access};
+use crate::size::ByteSize;
+use crate::summary::{BackupReport, Timings};
+use crate::tools::Tools;
+
+/// A Neo4j database to dump, and where to. There is no `allow_empty`: the dump is streamed
+/// without being read, so only `min_expected_bytes` guards against one that holds no data.
+pub struct Neo4jOptions {
+    pub tools: Tools,
+    pub bucket_name: String,
+    pub s3_access: S3Access,
+    pub create_bucket: bool,
+    pub database: String,
+    pub tags: String,
+    pub storage_key: String,
+    pub compression: Compression,
+    pub compression_level: Option<u32>,
+    pub part_size: ByteSize,
+    pub part_retries: u32,
+    pub spool: Option<Spool>,
+    pub split: Option<Split>,
+    pub min_expected_bytes: u64,
+    pub concurrency: usize,
+    pub deadline: Option<tokio::time::Instant>,
+    pub timings: Timings,
+}
+
+/// Streams `neo4j-admin database dump` through the compressor into the bucket and tags the
+/// object. neo4j-admin works on the store files, so btagger runs on the Neo4j host.
+pub async fn backup(options: &Neo4jOptions) -> Result<BackupReport, Report> {
+    let metadata = ObjectMetadata::now();
+    let (tools, s3_access, bucket_name, key) = (&options.tools, &options.s3_access, &options.bucket_name, &options.storage_key);
+    let timings = &options.timings;
+    if options.create_bucket {
+        let created = timings.time("bucket_ensure", s3::ensure_bucket(tools, s3_access, bucket_name)).await.wrap_err(Failure::Upload)?;
+        info!(target: "aws_create_bucket_output", bucket = bucket_name, created);
+    }
+
+    let dump = ArchiveUpload {
+        tools,
+        s3_access,
+        bucket_name,
+        compression: options.compression,
+        compression_level: options.compression_level,
+        part_size: options.part_size,
+        part_retries: options.part_retries,
+        spool: options.spool.clone(),
+        split: options.split.clone(),
+        concurrency: options.concurrency,
+        deadline: options.deadline,
+        metadata,
+    };
+    let bytes = timings.time("upload", dump.stream("neo4j-admin dump", dump_command(options), None, key)).await?;
+    info!(target: "backup_size", key, bytes);
+    // The dump is only measured once uploaded, so an undersized one is removed again.
+    if bytes < options.min_expected_bytes {
+        let removed = s3::delete_object(tools, s3_access, bucket_name, key).await;
+        info!(target: "aws_remove_partial_backup_output", key, success = removed.is_ok(), error = removed.err().map(|err| format!("{:#}", err)));
+        return Err(eyre!("Dump is only {} bytes, below --min-expected-bytes {}", bytes, options.min_expected_bytes)
+            .wrap_err(Failure::Export { stage: String::from("size check") }));
+    }
+
+    timings.time("tagging", s3::put_object_tagging(tools, s3_access, bucket_name, key, &options.tags)).await.wrap_err(Failure::Tagging)?;
+    info!(target: "aws_put_object_tagging_output", key);
+    Ok(BackupReport {
+        storage_key: key.clone(),
+        bytes,
+        raw_bytes: None,
+        success: true,
+        stderr_tail: None,
+        tikv_stats: None,
+        signal: None,
+    })
+}
+
+fn dump_command(options: &Neo4jOptions) -> Command {
+    let mut command = Command::new(&options.tools.neo4j_admin);
+    command
+        .arg("database")
+        .arg("dump")
+        .arg(&options.database)
+        .arg("--to-stdout");
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::MockRunner;
+    use std::sync::Arc;
+
+    #[test]
+    fn dump_goes_to_stdout() {
+        let options = Neo4jOptions {
+            tools: Tools::mock(Arc::new(MockRunner::new(|_| MockRunner::output(0, "", "")))),
+            bucket_name: String::from("bk"),
+            s3_access: S3Access::default(),
+            create_bucket: false,
+            database: String::from("movies"),
+            tags: String::from(r#"{"TagSet":[]}"#),
+            storage_key: String::from("neo4j/movies/2025-01-01.04-30.dump.zst"),
+            compression: Compression::Zstd,
+            compression_level: None,
+            part_size: ByteSize(8 * 1024 * 1024),
+            part_retries: 0,
+            spool: None,
+            split: None,
+            min_expected_bytes: 0,
+            concurrency: 4,
+            deadline: None,
+            timings: Timings::default(),
+        };
+        let command = dump_command(&options);
+        let args = command.as_std().get_args().map(|arg| arg.to_str().unwrap()).collect::<Vec<_>>();
+        assert_eq!(command.as_std().get_program(), "neo4j-admin");
+        assert_eq!(args, ["database", "dump", "movies", "--to-stdout"]);
+    }
+}
@@ -0,0 +1,349 @@
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use color_eyre::eyre::{Report, WrapErr};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::process::Command;
+use tracing::info;
+
+use crate::process;
+use crate::secret::Secret;
+use crate::summary::RunSummary;
+use crate::tools::Tools;
+
+/// Which finished backups are announced.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum NotifyOn {
+    /// Only failed backups.
+    #[default]
+    Failure,
+    /// Every backup.
+    Always,
+}
+
+impl NotifyOn {
+    pub fn wants(self, succeeded: bool) -> bool {
+        self == NotifyOn::Always || !succeeded
+    }
+}
+
+/// A finished backup as the notifications describe it.
+pub struct Outcome<'a> {
+    pub summary: &'a RunSummary,
+    pub bucket: &'a str,
+    /// Fixed start of the backup's keys, e.g. `surrealdb/app/main/`, which sets it apart from
+    /// other backups to the same bucket.
+    pub prefix: Option<&'a str>,
+    /// Redacted error report of a failed backup.
+    pub error: Option<String>,
+    /// Redacted end of the stderr of the external command that failed.
+    pub stderr_tail: Option<String>,
+}
+
+/// Where notification mails go and how they are sent.
+pub struct Mail {
+    /// SMTP server as `host` or `host:port`.
+    pub smtp_host: String,
+    pub from: String,
+    pub to: Vec<String>,
+    /// Login; the connection is then required to use TLS.
+    pub credentials: Option<(String, Secret)>,
+}
+
+/// The Slack message for `outcome`: status, engine, where the backup went, its size, duration and tags.
+pub fn slack_message(outcome: &Outcome) -> Value {
+    let summary = outcome.summary;
+    let (emoji, status) = match summary.success {
+        true => (":white_check_mark:", "succeeded"),
+        false => (":x:", "failed"),
+    };
+    let key = match summary.storage_keys.is_empty() {
+        true => String::from("-"),
+        false => summary.storage_keys.join(", "),
+    };
+    let tags = summary.tags.iter().map(|tag| tag.key.as_str()).collect::<Vec<_>>();
+    let mut lines = vec![
+        format!("{} *{} backup {}*", emoji, summary.command, status),
+        format!("*Bucket:* `{}`  *Key:* `{}`", outcome.bucket, key),
+        format!("*Size:* {}  *Duration:* {:.1}s", human_bytes(summary.bytes), summary.duration_ms as f64 / 1000.0),
+        format!("*Tags:* {}", if tags.is_empty() { String::from("none") } else { tags.join(", ") }),
+    ];
+    if let Some(error) = &outcome.error {
+        lines.push(format!("```{}```", error));
+    }
+    json!({ "text": lines.join("\n") })
+}
+
+/// Posts `message` to a Slack incoming webhook. The URL is a credential, so it goes to curl on
+/// stdin rather than on the command line.
+pub async fn slack(tools: &Tools, webhook: &Secret, message: &Value) -> Result<(), Report> {
+    post_json(tools, webhook.expose(), message).await.wrap_err("Posting to the Slack webhook failed")?;
+    info!(target: "notify", channel = "slack", "Notification sent");
+    Ok(())
+}
+
+/// PagerDuty Events API v2 endpoint.
+const PAGERDUTY_EVENTS_URL: &str = "https://events.pagerduty.com/v2/enqueue";
+
+/// The PagerDuty event for `outcome`: a trigger when the backup failed, otherwise a resolve.
+/// Both share a dedup key per engine, bucket and key prefix, so a success closes the incident its
+/// own earlier failure opened, and repeated failures add to the same incident, while two
+/// databases backed up to one bucket get incidents of their own.
+pub fn pagerduty_event(outcome: &Outcome, routing_key: &Secret) -> Value {
+    let summary = outcome.summary;
+    let dedup_key = match outcome.prefix {
+        Some(prefix) => format!("btagger/{}/{}/{}", summary.command, outcome.bucket, prefix),
+        None => format!("btagger/{}/{}", summary.command, outcome.bucket),
+    };
+    if summary.success {
+        return json!({ "routing_key": routing_key.expose(), "event_action": "resolve", "dedup_key": dedup_key });
+    }
+    json!({
+        "routing_key": routing_key.expose(),
+        "event_action": "trigger",
+        "dedup_key": dedup_key,
+        "payload": {
+            "summary": format!("{} backup failed for {}", summary.command, outcome.bucket),
+            "source": "btagger",
+            "severity": "error",
+            "component": summary.command,
+            "custom_details": {
+                "keys": summary.storage_keys,
+                "error": outcome.error,
+                "stderr_tail": outcome.stderr_tail,
+                "duration_ms": summary.duration_ms,
+            },
+        },
+    })
+}
+
+/// Sends `event` to PagerDuty. The routing key is in the body, which goes to curl on stdin.
+pub async fn pagerduty(tools: &Tools, event: &Value) -> Result<(), Report> {
+    post_json(tools, PAGERDUTY_EVENTS_URL, event).await.wrap_err("Sending the PagerDuty event failed")?;
+    info!(target: "notify", channel = "pagerduty", action = event["event_action"].as_str(), dedup_key = event["dedup_key"].as_str(), "Notification sent");
+    Ok(())
+}
+
+/// POSTs `body` to `url` with curl, handing both over on stdin.
+async fn post_json(tools: &Tools, url: &str, body: &Value) -> Result<(), Report> {
+    let mut command = Command::new(&tools.curl);
+    command
+        .kill_on_drop(true)
+        .arg("--silent")
+        .arg("--show-error")
+        .arg("--fail")
+        .arg("--max-time").arg("30")
+        .arg("--header").arg("Content-Type: application/json")
+        .arg("--config").arg("-");
+    let config = curl_config(&[("url", url), ("data-binary", body.to_string().as_str())]);
+    process::succeeded(tools.runner.run(command, Some(config.into_bytes())).await)?;
+    Ok(())
+}
+
+/// The mail for `outcome`, headers included, with the error and the failing command's stderr
+/// in the body.
+pub fn mail_message(outcome: &Outcome, mail: &Mail, date: DateTime<Utc>) -> String {
+    let summary = outcome.summary;
+    let status = if summary.success { "succeeded" } else { "failed" };
+    let key = match summary.storage_keys.is_empty() {
+        true => String::from("-"),
+        false => summary.storage_keys.join(", "),
+    };
+    let tags = summary.tags.iter().map(|tag| tag.key.as_str()).collect::<Vec<_>>();
+    let mut message = format!(
+        "From: {}\nTo: {}\nSubject: [btagger] {} backup {} for {}\nDate: {}\nContent-Type: text/plain; charset=utf-8\n\n",
+        mail.from,
+        mail.to.join(", "),
+        summary.command,
+        status,
+        outcome.bucket,
+        date.to_rfc2822()
+    );
+    message.push_str(&format!(
+        "Status: {}\nEngine: {}\nBucket: {}\nKey: {}\nSize: {}\nDuration: {:.1}s\nTags: {}\n",
+        status,
+        summary.command,
+        outcome.bucket,
+        key,
+        human_bytes(summary.bytes),
+        summary.duration_ms as f64 / 1000.0,
+        if tags.is_empty() { String::from("none") } else { tags.join(", ") }
+    ));
+    if let Some(error) = &outcome.error {
+        message.push_str(&format!("\nError:\n{}\n", error));
+    }
+    if let Some(stderr_tail) = &outcome.stderr_tail {
+        message.push_str(&format!("\nLast lines of the failing command's stderr:\n{}\n", stderr_tail));
+    }
+    message
+}
+
+/// Sends `message` with curl's SMTP support. Server and login go to curl on stdin, so the
+/// password stays out of process listings.
+pub async fn mail(tools: &Tools, mail: &Mail, message: &str) -> Result<(), Report> {
+    // `run-all --parallel` jobs may be mailing at the same time.
+    static SENT: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!("btagger-{}-{}.eml", std::process::id(), SENT.fetch_add(1, Ordering::Relaxed)));
+    tokio::fs::write(&path, message)
+        .await
+        .wrap_err_with(|| format!("Unable to write {}", path.display()))?;
+    let mut command = Command::new(&tools.curl);
+    command
+        .kill_on_drop(true)
+        .arg("--silent")
+        .arg("--show-error")
+        .arg("--max-time").arg("30")
+        .arg("--mail-from").arg(&mail.from);
+    for to in &mail.to {
+        command.arg("--mail-rcpt").arg(to);
+    }
+    command
+        .arg("--upload-file").arg(&path)
+        .arg("--crlf");
+    let url = format!("smtp://{}", mail.smtp_host);
+    let login = mail.credentials.as_ref().map(|(user, password)| format!("{}:{}", user, password.expose()));
+    let mut options = vec![("url", url.as_str())];
+    if let Some(login) = &login {
+        command.arg("--ssl-reqd");
+        options.push(("user", login));
+    }
+    command.arg("--config").arg("-");
+    let sent = process::succeeded(tools.runner.run(command, Some(curl_config(&options).into_bytes())).await);
+    let _ = tokio::fs::remove_file(&path).await;
+    sent.wrap_err_with(|| format!("Mailing {} through {} failed", mail.to.join(", "), mail.smtp_host))?;
+    info!(target: "notify", channel = "mail", to = mail.to.join(", "), "Notification sent");
+    Ok(())
+}
+
+/// A curl config file setting each option to its value, quoted.
+pub fn curl_config(options: &[(&str, &str)]) -> String {
+    options
+        .iter()
+        .map(|(name, value)| format!("{} = \"{}\"\n", name, value.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect()
+}
+
+/// `bytes` in the largest binary unit that keeps it above one, e.g. `1.5 MiB`.
+fn human_bytes(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < units.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", value, units[unit]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::MockRunner;
+    use crate::tags::Tag;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    fn failed_summary() -> RunSummary {
+        RunSummary {
+            command: String::from("surrealdb"),
+            storage_keys: vec![String::from("surrealdb/app/main/2025-01-01.04-30.zst")],
+            bytes: 3 << 19,
+            raw_bytes: None,
+            duration_ms: 5123,
+            phases_ms: BTreeMap::new(),
+            tags: vec![Tag { key: String::from("standard"), value: String::from("1") }],
+            success: false,
+            error_code: Some("upload"),
+            error_stage: None,
+            error_signal: None,
+            tikv: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn slack_gets_the_outcome_with_the_webhook_kept_off_the_command_line() {
+        let summary = failed_summary();
+        let outcome = Outcome { summary: &summary, bucket: "bk", prefix: None, error: Some(String::from("The upload stage of the pipeline failed")), stderr_tail: None };
+        let message = slack_message(&outcome);
+        let text = message["text"].as_str().unwrap();
+        assert!(text.starts_with(":x: *surrealdb backup failed*"), "{}", text);
+        assert!(text.contains("`surrealdb/app/main/2025-01-01.04-30.zst`") && text.contains("1.5 MiB") && text.contains("5.1s"));
+        assert!(text.contains("*Tags:* standard") && text.contains("upload stage"));
+        assert!(NotifyOn::Failure.wants(false) && !NotifyOn::Failure.wants(true) && NotifyOn::Always.wants(true));
+
+        let runner = Arc::new(MockRunner::new(|_| MockRunner::output(0, "ok", "")));
+        let tools = Tools::mock(runner.clone());
+        let webhook = "https://hooks.slack.com/services/T0/B0/secret";
+        slack(&tools, &Secret::new(webhook), &message).await.unwrap();
+        let calls = runner.calls();
+        assert_eq!(calls[0].program, "curl");
+        assert!(calls[0].has_args(&["--config", "-"]));
+        assert!(!calls[0].args.iter().any(|arg| arg.contains("hooks.slack.com")));
+        let config = String::from_utf8(calls[0].stdin.clone().unwrap()).unwrap();
+        assert!(config.starts_with(&format!("url = \"{}\"\n", webhook)));
+        assert!(config.contains(r#"data-binary = "{\"text\":\":x: *surrealdb backup failed*\\n"#), "{}", config);
+    }
+
+    #[tokio::test]
+    async fn failure_mails_carry_the_stderr_tail_and_keep_the_password_on_stdin() {
+        let summary = failed_summary();
+        let outcome = Outcome {
+            summary: &summary,
+            bucket: "bk",
+            prefix: None,
+            error: Some(String::from("The surreal export stage of the pipeline failed: exited with exit status: 1: connection reset")),
+            stderr_tail: Some(String::from("Connecting to h:8000\nconnection reset")),
+        };
+        let mail = Mail {
+            smtp_host: String::from("mail.internal:587"),
+            from: String::from("btagger@db1"),
+            to: vec![String::from("ops@example.com"), String::from("dba@example.com")],
+            credentials: Some((String::from("btagger"), Secret::new("smtp-pw"))),
+        };
+        let date = chrono::TimeZone::with_ymd_and_hms(&Utc, 2025, 1, 1, 4, 30, 0).unwrap();
+        let message = mail_message(&outcome, &mail, date);
+        assert!(message.starts_with("From: btagger@db1\nTo: ops@example.com, dba@example.com\nSubject: [btagger] surrealdb backup failed for bk\n"), "{}", message);
+        assert!(message.contains("Date: Wed, 1 Jan 2025 04:30:00 +0000\n"));
+        assert!(message.ends_with("stderr:\nConnecting to h:8000\nconnection reset\n"), "{}", message);
+
+        let runner = Arc::new(MockRunner::new(|_| MockRunner::output(0, "", "")));
+        super::mail(&Tools::mock(runner.clone()), &mail, &message).await.unwrap();
+        let calls = runner.calls();
+        assert!(calls[0].has_args(&["--mail-rcpt", "ops@example.com", "--mail-rcpt", "dba@example.com"]));
+        assert!(calls[0].has_args(&["--ssl-reqd", "--config", "-"]));
+        assert!(!calls[0].args.iter().any(|arg| arg.contains("smtp-pw")));
+        let config = String::from_utf8(calls[0].stdin.clone().unwrap()).unwrap();
+        assert_eq!(config, "url = \"smtp://mail.internal:587\"\nuser = \"btagger:smtp-pw\"\n");
+    }
+
+    #[test]
+    fn pagerduty_triggers_on_failure_and_resolves_on_success_under_one_dedup_key() {
+        let mut summary = failed_summary();
+        let routing_key = Secret::new("R0UT1NG");
+        let outcome = Outcome { summary: &summary, bucket: "bk", prefix: Some("surrealdb/app/main/"), error: Some(String::from("upload failed")), stderr_tail: None };
+        let trigger = pagerduty_event(&outcome, &routing_key);
+        assert_eq!(trigger["event_action"], "trigger");
+        assert_eq!(trigger["routing_key"], "R0UT1NG");
+        assert_eq!(trigger["payload"]["summary"], "surrealdb backup failed for bk");
+        assert_eq!(trigger["payload"]["custom_details"]["error"], "upload failed");
+
+        summary.success = true;
+        let resolve = pagerduty_event(&Outcome { summary: &summary, bucket: "bk", prefix: Some("surrealdb/app/main/"), error: None, stderr_tail: None }, &routing_key);
+        assert_eq!(resolve["event_action"], "resolve");
+        assert_eq!(resolve["dedup_key"], trigger["dedup_key"]);
+        assert!(resolve.get("payload").is_none());
+    }
+
+    #[test]
+    fn pagerduty_keeps_the_incidents_of_two_namespaces_in_one_bucket_apart() {
+        let summary = failed_summary();
+        let routing_key = Secret::new("R0UT1NG");
+        let event = |prefix| pagerduty_event(&Outcome { summary: &summary, bucket: "bk", prefix: Some(prefix), error: None, stderr_tail: None }, &routing_key);
+        let (app, billing) = (event("surrealdb/app/main/"), event("surrealdb/billing/main/"));
+        assert_eq!(app["dedup_key"], "btagger/surrealdb/bk/surrealdb/app/main/");
+        assert_eq!(billing["dedup_key"], "btagger/surrealdb/bk/surrealdb/billing/main/");
+    }
+}
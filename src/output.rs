@@ -0,0 +1,100 @@
+use clap::ValueEnum;
+use serde_json::{Map, Value};
+
+/// How a reporting command prints its results.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Aligned columns for a terminal.
+    Table,
+    Json,
+    /// A header line and one line per row, for spreadsheets.
+    Csv,
+    /// A Markdown table, for pasting into tickets.
+    Markdown,
+}
+
+/// Rows of a report under their column names, which every command prints as CSV or Markdown
+/// alike; commands with a table or JSON of their own keep using it.
+#[derive(Debug, Default)]
+pub struct Rows {
+    pub columns: Vec<&'static str>,
+    pub rows: Vec<Vec<String>>,
+}
+
+impl Rows {
+    pub fn new(columns: &[&'static str]) -> Self {
+        Rows { columns: columns.to_vec(), rows: Vec::new() }
+    }
+
+    pub fn push(&mut self, row: Vec<String>) {
+        self.rows.push(row);
+    }
+
+    pub fn render(&self, format: OutputFormat) -> String {
+        match format {
+            OutputFormat::Table => {
+                let widths = self
+                    .columns
+                    .iter()
+                    .enumerate()
+                    .map(|(index, column)| self.rows.iter().map(|row| row[index].chars().count()).chain([column.len()]).max().unwrap_or(0))
+                    .collect::<Vec<_>>();
+                let line = |cells: Vec<String>| {
+                    cells.iter().zip(&widths).map(|(cell, width)| format!("{:<width$}", cell, width = width)).collect::<Vec<_>>().join("  ").trim_end().to_string()
+                };
+                let mut lines = vec![line(self.columns.iter().map(|column| column.to_uppercase()).collect())];
+                lines.extend(self.rows.iter().map(|row| line(row.clone())));
+                lines.join("\n")
+            }
+            // An array of objects keyed by column, for reports without a JSON shape of their own.
+            OutputFormat::Json => {
+                let rows = self
+                    .rows
+                    .iter()
+                    .map(|row| self.columns.iter().zip(row).map(|(column, cell)| (column.to_string(), Value::from(cell.as_str()))).collect::<Map<_, _>>())
+                    .collect::<Vec<_>>();
+                Value::from(rows).to_string()
+            }
+            OutputFormat::Csv => {
+                let line = |cells: &[String]| cells.iter().map(|cell| csv_field(cell)).collect::<Vec<_>>().join(",");
+                let mut lines = vec![self.columns.join(",")];
+                lines.extend(self.rows.iter().map(|row| line(row)));
+                lines.join("\n")
+            }
+            OutputFormat::Markdown => {
+                let line = |cells: Vec<String>| format!("| {} |", cells.join(" | "));
+                let mut lines = vec![
+                    line(self.columns.iter().map(|column| column.to_string()).collect()),
+                    line(self.columns.iter().map(|_| String::from("---")).collect()),
+                ];
+                lines.extend(self.rows.iter().map(|row| line(row.iter().map(|cell| cell.replace('|', "\\|").replace('\n', " ")).collect())));
+                lines.join("\n")
+            }
+        }
+    }
+}
+
+/// `cell` quoted when it holds a comma, quote or line break, as RFC 4180 has it.
+fn csv_field(cell: &str) -> String {
+    match cell.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", cell.replace('"', "\"\"")),
+        false => cell.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_render_as_table_csv_markdown_and_json() {
+        let mut rows = Rows::new(&["tier", "objects", "note"]);
+        rows.push(vec![String::from("monthly"), String::from("12"), String::from("kept, \"locked\"")]);
+        rows.push(vec![String::from("standard"), String::from("3"), String::from("a|b")]);
+        assert_eq!(rows.render(OutputFormat::Table), "TIER      OBJECTS  NOTE\nmonthly   12       kept, \"locked\"\nstandard  3        a|b");
+        assert_eq!(rows.render(OutputFormat::Csv), "tier,objects,note\nmonthly,12,\"kept, \"\"locked\"\"\"\nstandard,3,a|b");
+        assert_eq!(rows.render(OutputFormat::Markdown), "| tier | objects | note |\n| --- | --- | --- |\n| monthly | 12 | kept, \"locked\" |\n| standard | 3 | a\\|b |");
+        let json: Value = serde_json::from_str(&rows.render(OutputFormat::Json)).unwrap();
+        assert_eq!(json[1]["tier"], "standard");
+    }
+}
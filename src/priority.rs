@@ -0,0 +1,63 @@
+use clap::ValueEnum;
+use color_eyre::eyre::{Report, WrapErr};
+use tokio::process::Command;
+use tracing::info;
+
+use crate::process;
+use crate::tools::Tools;
+
+/// The I/O scheduling class of `ionice`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum IoClass {
+    /// Disk time only when no other process wants it.
+    Idle,
+    /// A share of disk time set by --io-level.
+    BestEffort,
+}
+
+/// Lowers the CPU and I/O priority of the calling thread: on Linux, `renice -p` and `ionice -p`
+/// with this pid reach the main thread only. Threads and children take both from the thread
+/// starting them, so this runs before the runtime starts its worker threads, and every export,
+/// compressor and upload of the run is lowered with it. Fails when `renice` or `ionice` does,
+/// rather than running at full priority next to the database.
+pub async fn lower(tools: &Tools, nice: Option<i32>, io_class: Option<IoClass>, io_level: u8) -> Result<(), Report> {
+    let pid = std::process::id().to_string();
+    if let Some(nice) = nice {
+        let mut command = Command::new(&tools.renice);
+        command.arg("-n").arg(nice.to_string()).arg("-p").arg(&pid);
+        process::succeeded(tools.runner.run(command, None).await).wrap_err_with(|| format!("Unable to apply --nice {}", nice))?;
+    }
+    if let Some(io_class) = io_class {
+        let mut command = Command::new(&tools.ionice);
+        match io_class {
+            IoClass::Idle => command.arg("-c").arg("3"),
+            IoClass::BestEffort => command.arg("-c").arg("2").arg("-n").arg(io_level.to_string()),
+        };
+        command.arg("-p").arg(&pid);
+        process::succeeded(tools.runner.run(command, None).await).wrap_err_with(|| format!("Unable to apply --io-class {:?}", io_class))?;
+    }
+    info!(target: "priority", nice, io_class = ?io_class, io_level, "Lowered the priority of the run");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::MockRunner;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn priorities_are_lowered_for_this_process() {
+        let runner = Arc::new(MockRunner::new(|_| MockRunner::output(0, "", "")));
+        let tools = Tools::mock(runner.clone());
+        lower(&tools, Some(10), Some(IoClass::Idle), 7).await.unwrap();
+        let pid = std::process::id().to_string();
+        let calls = runner.calls();
+        assert_eq!(calls.len(), 2);
+        assert!(calls[0].program == "renice" && calls[0].has_args(&["-n", "10", "-p", &pid]));
+        assert!(calls[1].program == "ionice" && calls[1].has_args(&["-c", "3", "-p", &pid]));
+
+        lower(&tools, None, Some(IoClass::BestEffort), 6).await.unwrap();
+        assert!(runner.calls()[2].has_args(&["-c", "2", "-n", "6", "-p", &pid]));
+    }
+}
@@ -0,0 +1,391 @@
+use color_eyre::eyre::{eyre, Report, WrapErr};
+use futures::future::BoxFuture;
+use std::future::Future;
+use std::path::PathBuf;
+use std::process::{Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use tokio::process::{Child, Command};
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+/// Runs external commands to completion. Drivers go through the runner held by
+/// [`Tools`](crate::tools::Tools) rather than calling `Command::output` themselves, so tests can
+/// swap in a [`MockRunner`] and assert on what would have been run.
+pub trait ProcessRunner: std::fmt::Debug + Send + Sync {
+    /// Runs `command` with its stdout and stderr captured, feeding it `stdin` when given.
+    fn run(&self, command: Command, stdin: Option<Vec<u8>>) -> BoxFuture<'_, std::io::Result<Output>>;
+
+    /// Like [`run`](Self::run), but logs each line of output as it arrives, under `process`, for
+    /// commands that run long enough to be watched.
+    fn run_streaming(&self, command: Command, _process: &'static str) -> BoxFuture<'_, std::io::Result<Output>> {
+        self.run(command, None)
+    }
+}
+
+/// Runs commands for real.
+#[derive(Debug, Default)]
+pub struct SystemRunner;
+
+impl ProcessRunner for SystemRunner {
+    fn run(&self, mut command: Command, stdin: Option<Vec<u8>>) -> BoxFuture<'_, std::io::Result<Output>> {
+        Box::pin(async move {
+            let Some(stdin) = stdin else {
+                return command.output().await.map_err(|err| not_found(&command, err));
+            };
+            let mut child = command
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .map_err(|err| not_found(&command, err))?;
+            let mut pipe = child.stdin.take().expect("stdin is piped");
+            // Written alongside the wait, so a child filling its stdout pipe cannot stall the write.
+            let write = async move {
+                let written = pipe.write_all(&stdin).await;
+                drop(pipe);
+                written
+            };
+            let (written, output) = tokio::join!(write, child.wait_with_output());
+            let output = output?;
+            // A child that exits without reading all of its input is judged by its exit status.
+            if output.status.success() {
+                written?;
+            }
+            Ok(output)
+        })
+    }
+
+    fn run_streaming(&self, mut command: Command, process: &'static str) -> BoxFuture<'_, std::io::Result<Output>> {
+        Box::pin(async move {
+            let child = command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().map_err(|err| not_found(&command, err))?;
+            wait_streaming(child, process).await
+        })
+    }
+}
+
+/// Starts `command` outside a runner, for pipelines that wire its stdio up themselves.
+pub fn spawn(command: &mut Command) -> Result<Child, Report> {
+    command.spawn().map_err(|err| not_found(command, err)).wrap_err("failed to execute process")
+}
+
+/// Names the binary when `err` is the OS failing to find it. Binaries are resolved without
+/// checking they exist, so this is where a missing one is reported.
+fn not_found(command: &Command, err: std::io::Error) -> std::io::Error {
+    match err.kind() {
+        std::io::ErrorKind::NotFound => std::io::Error::new(
+            err.kind(),
+            format!("'{}' not found; install it on $PATH or point --bin-path or its own --*-bin flag at it", command.as_std().get_program().to_string_lossy()),
+        ),
+        _ => err,
+    }
+}
+
+/// Waits for `child`, logging every line of its piped stdout and stderr under the `child_output`
+/// target as it is written, tagged with `process` and the stream. The output is also collected,
+/// as `wait_with_output` would.
+pub async fn wait_streaming(mut child: Child, process: &'static str) -> std::io::Result<Output> {
+    let (stdout, stderr) = (child.stdout.take(), child.stderr.take());
+    let (status, stdout, stderr) = tokio::join!(child.wait(), stream_lines(stdout, process, "stdout"), stream_lines(stderr, process, "stderr"));
+    Ok(Output { status: status?, stdout: stdout?, stderr: stderr? })
+}
+
+async fn stream_lines(reader: Option<impl AsyncRead + Unpin>, process: &'static str, stream: &'static str) -> std::io::Result<Vec<u8>> {
+    let Some(reader) = reader else {
+        return Ok(Vec::new());
+    };
+    let (mut reader, mut collected, mut line) = (BufReader::new(reader), Vec::new(), Vec::new());
+    while reader.read_until(b'\n', &mut line).await? > 0 {
+        let text = captured(process, line.strip_suffix(b"\n").unwrap_or(&line));
+        info!(target: "child_output", process, stream, line = text.trim_end());
+        collected.append(&mut line);
+    }
+    Ok(collected)
+}
+
+/// One command seen by a [`MockRunner`].
+#[cfg(test)]
+#[derive(Clone, Debug)]
+pub struct Call {
+    pub program: String,
+    pub args: Vec<String>,
+    pub envs: Vec<(String, String)>,
+    pub stdin: Option<Vec<u8>>,
+}
+
+#[cfg(test)]
+impl Call {
+    pub fn env(&self, name: &str) -> Option<&str> {
+        self.envs.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    /// Whether the arguments contain `needle` as a contiguous run.
+    pub fn has_args(&self, needle: &[&str]) -> bool {
+        self.args.windows(needle.len()).any(|window| window.iter().zip(needle).all(|(arg, want)| arg == want))
+    }
+}
+
+/// Records every command instead of running it, answering with whatever `respond` returns.
+#[cfg(test)]
+pub struct MockRunner {
+    calls: std::sync::Mutex<Vec<Call>>,
+    respond: Box<dyn Fn(&Call) -> Output + Send + Sync>,
+}
+
+#[cfg(test)]
+impl std::fmt::Debug for MockRunner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MockRunner").field("calls", &self.calls).finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+impl MockRunner {
+    pub fn new(respond: impl Fn(&Call) -> Output + Send + Sync + 'static) -> Self {
+        MockRunner { calls: Default::default(), respond: Box::new(respond) }
+    }
+
+    pub fn calls(&self) -> Vec<Call> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// A finished process with the given exit code and output.
+    pub fn output(code: i32, stdout: &str, stderr: &str) -> Output {
+        #[cfg(unix)]
+        let status = std::os::unix::process::ExitStatusExt::from_raw(code << 8);
+        #[cfg(windows)]
+        let status = std::os::windows::process::ExitStatusExt::from_raw(code as u32);
+        Output { status, stdout: stdout.as_bytes().to_vec(), stderr: stderr.as_bytes().to_vec() }
+    }
+}
+
+#[cfg(test)]
+impl ProcessRunner for MockRunner {
+    fn run(&self, command: Command, stdin: Option<Vec<u8>>) -> BoxFuture<'_, std::io::Result<Output>> {
+        let command = command.as_std();
+        let call = Call {
+            program: command.get_program().to_string_lossy().into_owned(),
+            args: command.get_args().map(|arg| arg.to_string_lossy().into_owned()).collect(),
+            envs: command
+                .get_envs()
+                .filter_map(|(key, value)| Some((key.to_string_lossy().into_owned(), value?.to_string_lossy().into_owned())))
+                .collect(),
+            stdin,
+        };
+        let output = (self.respond)(&call);
+        self.calls.lock().unwrap().push(call);
+        Box::pin(async move { Ok(output) })
+    }
+}
+
+/// Runs `future` to completion, or fails once `deadline` passes. Giving up drops the future,
+/// which kills any `kill_on_drop` children it owns.
+pub async fn before_deadline<F: Future>(
+    deadline: Option<Instant>,
+    stage: &str,
+    future: F,
+) -> Result<F::Output, Report> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, future)
+            .await
+            .map_err(|_| eyre!("Timed out during {}", stage)),
+        None => Ok(future.await),
+    }
+}
+
+/// A child that exited unsuccessfully. It reads as its last line of stderr, and keeps a few more
+/// lines for failure reports.
+#[derive(Debug)]
+pub struct CommandFailed {
+    pub status: std::process::ExitStatus,
+    pub stderr_tail: String,
+}
+
+impl std::fmt::Display for CommandFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match signal(self.status) {
+            // Most often the OOM killer, which leaves nothing on stderr.
+            Some(signal @ 9) => write!(f, "was killed by {}, possibly for running out of memory", signal_name(signal))?,
+            Some(signal) => write!(f, "was killed by {}", signal_name(signal))?,
+            None => write!(f, "exited with {}", self.status)?,
+        }
+        match self.stderr_tail.lines().last() {
+            Some(line) => write!(f, ": {}", line),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for CommandFailed {}
+
+/// The signal that killed a child, which then has no exit code.
+pub fn signal(status: std::process::ExitStatus) -> Option<i32> {
+    #[cfg(unix)]
+    return std::os::unix::process::ExitStatusExt::signal(&status);
+    #[cfg(not(unix))]
+    {
+        let _ = status;
+        None
+    }
+}
+
+/// The name of a signal, e.g. `SIGKILL`, or its number for the less common ones.
+pub fn signal_name(signal: i32) -> String {
+    let name = match signal {
+        1 => "SIGHUP",
+        2 => "SIGINT",
+        3 => "SIGQUIT",
+        4 => "SIGILL",
+        6 => "SIGABRT",
+        7 => "SIGBUS",
+        8 => "SIGFPE",
+        9 => "SIGKILL",
+        11 => "SIGSEGV",
+        13 => "SIGPIPE",
+        14 => "SIGALRM",
+        15 => "SIGTERM",
+        24 => "SIGXCPU",
+        25 => "SIGXFSZ",
+        _ => return format!("signal {}", signal),
+    };
+    name.to_string()
+}
+
+/// The last 20 lines of a child's stderr.
+pub fn stderr_tail(stderr: &[u8]) -> String {
+    let stderr = String::from_utf8_lossy(stderr);
+    let lines = stderr.trim().lines().collect::<Vec<_>>();
+    lines[lines.len().saturating_sub(20)..].join("\n")
+}
+
+/// How much of a child's output [`captured`] keeps, and where the whole of a longer one goes.
+struct Capture {
+    limit: u64,
+    spill_dir: Option<PathBuf>,
+}
+
+static CAPTURE: Mutex<Capture> = Mutex::new(Capture { limit: 64 * 1024, spill_dir: None });
+
+/// Sets the bound on output put into log fields and error messages; see `--max-captured-output`.
+pub fn set_capture(limit: u64, spill_dir: Option<PathBuf>) {
+    *CAPTURE.lock().unwrap_or_else(|e| e.into_inner()) = Capture { limit, spill_dir };
+}
+
+/// A child's output for a log field or error message, bounded by `--max-captured-output`. Longer
+/// output keeps its head and tail, where the command line echo and the final error usually are,
+/// around a note of what was left out. With `--captured-output-dir` the whole output is written
+/// there first, named after `label`, and the note gives its path.
+pub fn captured(label: &str, output: &[u8]) -> String {
+    let capture = CAPTURE.lock().unwrap_or_else(|e| e.into_inner());
+    let limit = usize::try_from(capture.limit).unwrap_or(usize::MAX);
+    if output.len() <= limit {
+        return String::from_utf8_lossy(output).into_owned();
+    }
+    let spilled = capture.spill_dir.as_ref().map(|dir| {
+        static SPILLED: AtomicUsize = AtomicUsize::new(0);
+        let label = label.replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "-");
+        let path = dir.join(format!("btagger-{}-{}-{}.log", std::process::id(), SPILLED.fetch_add(1, Ordering::Relaxed), label));
+        match std::fs::write(&path, output) {
+            Ok(()) => format!(", all of it in {}", path.display()),
+            Err(err) => {
+                warn!(target: "captured_output", path = %path.display(), error = %err, "Unable to spill captured output");
+                String::new()
+            }
+        }
+    });
+    truncated(output, limit, &spilled.unwrap_or_default())
+}
+
+fn truncated(output: &[u8], limit: usize, spilled: &str) -> String {
+    let (head, tail) = (&output[..limit / 2], &output[output.len() - (limit - limit / 2)..]);
+    format!(
+        "{}\n[... {} bytes omitted{} ...]\n{}",
+        String::from_utf8_lossy(head),
+        output.len() - limit,
+        spilled,
+        String::from_utf8_lossy(tail)
+    )
+}
+
+/// Checks a finished child, turning a failed exit into a [`CommandFailed`].
+pub fn succeeded(output: std::io::Result<Output>) -> Result<Output, Report> {
+    let output = output.wrap_err("failed to execute process")?;
+    if output.status.success() {
+        return Ok(output);
+    }
+    Err(CommandFailed { status: output.status, stderr_tail: stderr_tail(&output.stderr) }.into())
+}
+
+/// The pipeline stage that broke a streaming backup.
+#[derive(Debug)]
+pub struct StageFailed {
+    pub stage: String,
+}
+
+impl std::fmt::Display for StageFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The {} stage of the pipeline failed", self.stage)
+    }
+}
+
+/// Reduces the outcomes of a streaming pipeline, listed upstream to downstream, to the failure
+/// that broke it. A failing stage closes its pipes and every stage upstream of it then fails with
+/// a broken pipe, so the most downstream failure is the one that came first; the others are logged.
+pub fn first_failure<'a>(stages: impl IntoIterator<Item = (&'a str, Result<(), Report>)>) -> Result<(), Report> {
+    let mut failure: Option<(&str, Report)> = None;
+    for (stage, result) in stages {
+        if let Err(err) = result {
+            if let Some((upstream, upstream_err)) = failure.replace((stage, err)) {
+                warn!(target: "pipeline", stage = upstream, error = %upstream_err, "Stage failed after a downstream failure");
+            }
+        }
+    }
+    match failure {
+        Some((stage, err)) => Err(err.wrap_err(StageFailed { stage: stage.to_string() })),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_output_keeps_its_head_and_tail() {
+        let output = format!("{}{}{}", "a".repeat(10), "b".repeat(100), "c".repeat(10));
+        let kept = truncated(output.as_bytes(), 20, ", all of it in /tmp/x.log");
+        assert_eq!(kept, format!("{}\n[... 100 bytes omitted, all of it in /tmp/x.log ...]\n{}", "a".repeat(10), "c".repeat(10)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn signal_deaths_are_failures_named_by_their_signal() {
+        let killed = std::os::unix::process::ExitStatusExt::from_raw(9);
+        let failed = succeeded(Ok(Output { status: killed, stdout: Vec::new(), stderr: Vec::new() })).unwrap_err();
+        assert_eq!(failed.to_string(), "was killed by SIGKILL, possibly for running out of memory");
+        assert_eq!(signal(killed), Some(9));
+        assert_eq!(signal(MockRunner::output(1, "", "").status), None);
+        assert_eq!(signal_name(42), "signal 42");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn streamed_output_is_still_collected() {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("printf 'one\\ntwo'; echo failed >&2; exit 3");
+        let output = SystemRunner.run_streaming(command, "sh").await.unwrap();
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stdout, b"one\ntwo");
+        assert_eq!(output.stderr, b"failed\n");
+    }
+
+    #[tokio::test]
+    async fn missing_binaries_are_named_when_run() {
+        let err = SystemRunner.run(Command::new("btagger-no-such-tool"), None).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert!(err.to_string().starts_with("'btagger-no-such-tool' not found;"));
+        let err = spawn(&mut Command::new("btagger-no-such-tool")).unwrap_err();
+        assert!(format!("{:#}", err).contains("'btagger-no-such-tool' not found;"));
+    }
+}
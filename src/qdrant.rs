@@ -0,0 +1,246 @@
+use color_eyre::eyre::{eyre, Report, WrapErr};
+use serde_json::Value;
+use tokio::process::Command;
+use tracing::info;
+
+use crate::archive::ArchiveUpload;
+use crate::compression::Compression;
+use crate::failure::Failure;
+use crate::multipart::{ObjectMetadata, Split, Spool};
+use crate::notify::curl_config;
+use crate::process::{self, before_deadline};
+use crate::s3::{self, S3Access};
+use crate::secret::Secret;
+use crate::size::ByteSize;
+use crate::summary::{BackupReport, EmptyBackup, Timings};
+use crate::tools::Tools;
+
+/// A Qdrant instance to snapshot, and where to.
+pub struct QdrantOptions {
+    pub tools: Tools,
+    pub bucket_name: String,
+    pub s3_access: S3Access,
+    pub create_bucket: bool,
+    /// Base URL of the REST API, e.g. `http://localhost:6333`.
+    pub address: String,
+    pub api_key: Option<Secret>,
+    /// Snapshot this collection rather than the whole storage.
+    pub collection: Option<String>,
+    pub tags: String,
+    pub storage_key: String,
+    pub compression: Compression,
+    pub compression_level: Option<u32>,
+    pub part_size: ByteSize,
+    pub part_retries: u32,
+    pub spool: Option<Spool>,
+    pub split: Option<Split>,
+    pub min_expected_bytes: u64,
+    pub allow_empty: bool,
+    pub concurrency: usize,
+    pub deadline: Option<tokio::time::Instant>,
+    pub timings: Timings,
+}
+
+/// Has Qdrant take a snapshot, streams its download through the compressor into the bucket and
+/// tags the object. The snapshot is deleted from the instance afterwards, whatever the outcome, so
+/// scheduled runs do not fill its disk.
+pub async fn backup(options: &QdrantOptions) -> Result<BackupReport, Report> {
+    let metadata = ObjectMetadata::now();
+    let (tools, s3_access, bucket_name, key) = (&options.tools, &options.s3_access, &options.bucket_name, &options.storage_key);
+    let timings = &options.timings;
+    if options.create_bucket {
+        let created = timings.time("bucket_ensure", s3::ensure_bucket(tools, s3_access, bucket_name)).await.wrap_err(Failure::Upload)?;
+        info!(target: "aws_create_bucket_output", bucket = bucket_name, created);
+    }
+
+    // Checked before the snapshot is taken, so there is nothing to clean up.
+    let empty = timings.time("export", before_deadline(options.deadline, "qdrant count", is_empty(options)))
+        .await
+        .and_then(|empty| empty)
+        .wrap_err(Failure::Export { stage: String::from("qdrant count") })?;
+    if empty && !options.allow_empty {
+        return Err(Report::new(EmptyBackup(match &options.collection {
+            Some(collection) => format!("Qdrant collection {}", collection),
+            None => String::from("Qdrant storage"),
+        })));
+    }
+
+    let snapshots = snapshots_path(options.collection.as_deref());
+    let created = timings.time("export", before_deadline(options.deadline, "qdrant snapshot", request(options, "POST", &format!("{}?wait=true", snapshots))))
+        .await
+        .and_then(|created| created)
+        .wrap_err(Failure::Export { stage: String::from("qdrant snapshot") })?;
+    let snapshot = created["result"]["name"].as_str().ok_or_else(|| eyre!("Qdrant answered without a snapshot name: {}", created))?;
+    let raw_bytes = created["result"]["size"].as_u64();
+    info!(target: "qdrant_snapshot_output", snapshot, raw_bytes);
+
+    let snapshot_path = format!("{}/{}", snapshots, snapshot);
+    let uploaded = upload(options, &snapshot_path, metadata).await;
+    let removed = request(options, "DELETE", &snapshot_path).await;
+    info!(target: "qdrant_delete_snapshot_output", snapshot, success = removed.is_ok(), error = removed.err().map(|err| format!("{:#}", err)));
+    let bytes = uploaded?;
+    info!(target: "backup_size", key, raw_bytes, bytes);
+    // The snapshot is only measured once uploaded, so an undersized one is removed again.
+    if bytes < options.min_expected_bytes {
+        let removed = s3::delete_object(tools, s3_access, bucket_name, key).await;
+        info!(target: "aws_remove_partial_backup_output", key, success = removed.is_ok(), error = removed.err().map(|err| format!("{:#}", err)));
+        return Err(eyre!("Snapshot is only {} bytes, below --min-expected-bytes {}", bytes, options.min_expected_bytes)
+            .wrap_err(Failure::Export { stage: String::from("size check") }));
+    }
+
+    timings.time("tagging", s3::put_object_tagging(tools, s3_access, bucket_name, key, &options.tags)).await.wrap_err(Failure::Tagging)?;
+    info!(target: "aws_put_object_tagging_output", key);
+    Ok(BackupReport {
+        storage_key: key.clone(),
+        bytes,
+        raw_bytes,
+        success: true,
+        stderr_tail: None,
+        tikv_stats: None,
+        signal: None,
+    })
+}
+
+async fn upload(options: &QdrantOptions, snapshot_path: &str, metadata: ObjectMetadata) -> Result<u64, Report> {
+    let download = ArchiveUpload {
+        tools: &options.tools,
+        s3_access: &options.s3_access,
+        bucket_name: &options.bucket_name,
+        compression: options.compression,
+        compression_level: options.compression_level,
+        part_size: options.part_size,
+        part_retries: options.part_retries,
+        spool: options.spool.clone(),
+        split: options.split.clone(),
+        concurrency: options.concurrency,
+        deadline: options.deadline,
+        metadata,
+    };
+    let (command, config) = curl(options, "GET", snapshot_path);
+    options.timings.time("upload", download.stream("qdrant download", command, Some(config.into_bytes()), &options.storage_key)).await
+}
+
+/// Whether the collection holds no points, or the storage no collections.
+async fn is_empty(options: &QdrantOptions) -> Result<bool, Report> {
+    match &options.collection {
+        Some(collection) => {
+            let info = request(options, "GET", &format!("collections/{}", collection)).await?;
+            let points = info["result"]["points_count"].as_u64().ok_or_else(|| eyre!("Qdrant answered without a point count: {}", info))?;
+            Ok(points == 0)
+        }
+        None => {
+            let listed = request(options, "GET", "collections").await?;
+            let collections = listed["result"]["collections"].as_array().ok_or_else(|| eyre!("Qdrant answered without a collection list: {}", listed))?;
+            Ok(collections.is_empty())
+        }
+    }
+}
+
+/// The snapshots of the whole storage, or of one collection.
+fn snapshots_path(collection: Option<&str>) -> String {
+    match collection {
+        Some(collection) => format!("collections/{}/snapshots", collection),
+        None => String::from("snapshots"),
+    }
+}
+
+/// A curl command for `method` on `path`, and the config it reads from stdin. The URL and the API
+/// key go in the config, so the key stays out of process listings.
+fn curl(options: &QdrantOptions, method: &str, path: &str) -> (Command, String) {
+    let mut command = Command::new(&options.tools.curl);
+    command
+        .kill_on_drop(true)
+        .arg("--silent")
+        .arg("--show-error")
+        .arg("--fail")
+        .arg("--request").arg(method)
+        .arg("--config").arg("-");
+    let url = format!("{}/{}", options.address.trim_end_matches('/'), path);
+    let header = options.api_key.as_ref().map(|api_key| format!("api-key: {}", api_key.expose()));
+    let mut config = vec![("url", url.as_str())];
+    config.extend(header.as_deref().map(|header| ("header", header)));
+    (command, curl_config(&config))
+}
+
+/// Sends a request to the REST API and returns the JSON it answers with.
+async fn request(options: &QdrantOptions, method: &str, path: &str) -> Result<Value, Report> {
+    let (command, config) = curl(options, method, path);
+    let output = process::succeeded(options.tools.runner.run(command, Some(config.into_bytes())).await)
+        .wrap_err_with(|| format!("{} {} failed", method, path))?;
+    serde_json::from_slice(&output.stdout).wrap_err_with(|| format!("Unable to parse the answer to {} {}", method, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::MockRunner;
+    use std::sync::Arc;
+
+    fn options(runner: Arc<MockRunner>) -> QdrantOptions {
+        QdrantOptions {
+            tools: Tools::mock(runner),
+            bucket_name: String::from("bk"),
+            s3_access: S3Access::default(),
+            create_bucket: false,
+            address: String::from("http://qdrant:6333/"),
+            api_key: Some(Secret::new("qd-key")),
+            collection: Some(String::from("docs")),
+            tags: String::from(r#"{"TagSet":[]}"#),
+            storage_key: String::from("qdrant/2025-01-01.04-30.snapshot.zst"),
+            compression: Compression::Zstd,
+            compression_level: None,
+            part_size: ByteSize(8 * 1024 * 1024),
+            part_retries: 0,
+            spool: None,
+            split: None,
+            min_expected_bytes: 0,
+            allow_empty: false,
+            concurrency: 4,
+            deadline: None,
+            timings: Timings::default(),
+        }
+    }
+
+    #[test]
+    fn api_key_goes_in_the_config() {
+        let (command, config) = curl(&options(Arc::new(MockRunner::new(|_| MockRunner::output(0, "", "")))), "GET", "collections/docs/snapshots/s.snapshot");
+        let args = command.as_std().get_args().map(|arg| arg.to_str().unwrap()).collect::<Vec<_>>();
+        assert_eq!(args, ["--silent", "--show-error", "--fail", "--request", "GET", "--config", "-"]);
+        assert_eq!(config, "url = \"http://qdrant:6333/collections/docs/snapshots/s.snapshot\"\nheader = \"api-key: qd-key\"\n");
+        assert_eq!(snapshots_path(None), "snapshots");
+    }
+
+    #[tokio::test]
+    async fn failed_snapshot_is_an_export_failure() {
+        let runner = Arc::new(MockRunner::new(|call| match call.has_args(&["--request", "GET"]) {
+            true => MockRunner::output(0, r#"{"result":{"status":"green","points_count":5},"status":"ok"}"#, ""),
+            false => MockRunner::output(22, "", "curl: (22) The requested URL returned error: 404"),
+        }));
+        let err = backup(&options(runner.clone())).await.unwrap_err();
+        assert_eq!(Failure::of(&err).stage(), Some("qdrant snapshot"));
+        let calls = runner.calls();
+        assert_eq!(calls.len(), 2);
+        assert!(calls[1].has_args(&["--request", "POST"]));
+        assert!(String::from_utf8(calls[1].stdin.clone().unwrap()).unwrap().contains("/collections/docs/snapshots?wait=true\""));
+    }
+
+    #[tokio::test]
+    async fn a_collection_without_points_is_empty_unless_allowed() {
+        let runner = Arc::new(MockRunner::new(|call| match call.has_args(&["--request", "GET"]) {
+            true => MockRunner::output(0, r#"{"result":{"status":"green","points_count":0},"status":"ok"}"#, ""),
+            false => MockRunner::output(22, "", "curl: (22) The requested URL returned error: 500"),
+        }));
+        let err = backup(&options(runner.clone())).await.unwrap_err();
+        assert!(err.downcast_ref::<EmptyBackup>().is_some(), "{:#}", err);
+        assert_eq!(runner.calls().len(), 1);
+
+        // Allowed, it goes on to take the snapshot.
+        let err = backup(&QdrantOptions { allow_empty: true, ..options(runner.clone()) }).await.unwrap_err();
+        assert_eq!(Failure::of(&err).stage(), Some("qdrant snapshot"));
+
+        let runner = Arc::new(MockRunner::new(|_| MockRunner::output(0, r#"{"result":{"collections":[]},"status":"ok"}"#, "")));
+        let err = backup(&QdrantOptions { collection: None, ..options(runner.clone()) }).await.unwrap_err();
+        assert!(err.downcast_ref::<EmptyBackup>().is_some(), "{:#}", err);
+        assert!(String::from_utf8(runner.calls()[0].stdin.clone().unwrap()).unwrap().contains("6333/collections\""));
+    }
+}
@@ -0,0 +1,73 @@
+use color_eyre::eyre::Report;
+use serde_json::{json, Value};
+use tracing::info;
+
+use crate::s3::{self, S3Access};
+use crate::tags::Tag;
+use crate::tools::Tools;
+
+/// Where and what to replicate; the IAM role is created outside btagger.
+pub struct ReplicationOptions {
+    pub tools: Tools,
+    pub bucket_name: String,
+    pub s3_access: S3Access,
+    /// Name or ARN of the DR bucket.
+    pub destination_bucket: String,
+    /// Account owning the destination; replicas are then owned by it rather than the source account.
+    pub destination_account: Option<String>,
+    pub role_arn: String,
+    pub storage_class: Option<String>,
+    /// Objects carrying any of these tags are replicated.
+    pub tags: Vec<Tag>,
+}
+
+/// Enables versioning on the source bucket, which replication requires, and replaces its
+/// replication configuration with one rule per tag.
+pub async fn run(options: &ReplicationOptions) -> Result<(), Report> {
+    let (tools, s3_access, bucket_name) = (&options.tools, &options.s3_access, options.bucket_name.as_str());
+    let versioning = json!({"Status": "Enabled"}).to_string();
+    s3::put_bucket_configuration(tools, s3_access, bucket_name, "put-bucket-versioning", "--versioning-configuration", &versioning).await?;
+
+    let configuration = configuration(options).to_string();
+    s3::put_bucket_configuration(tools, s3_access, bucket_name, "put-bucket-replication", "--replication-configuration", &configuration)
+        .await?;
+    info!(target: "replication", bucket = bucket_name, destination = options.destination_bucket, configuration);
+    for tag in &options.tags {
+        println!("[REPLICATING] {}={} -> {}", tag.key, tag.value, options.destination_bucket);
+    }
+    Ok(())
+}
+
+/// The `put-bucket-replication` document. A rule filter can only require tags, not offer a
+/// choice of them, so each tag gets its own rule.
+fn configuration(options: &ReplicationOptions) -> Value {
+    let bucket_arn = match options.destination_bucket.starts_with("arn:") {
+        true => options.destination_bucket.clone(),
+        false => format!("arn:aws:s3:::{}", options.destination_bucket),
+    };
+    let mut destination = json!({"Bucket": bucket_arn});
+    if let Some(account) = &options.destination_account {
+        destination["Account"] = json!(account);
+        destination["AccessControlTranslation"] = json!({"Owner": "Destination"});
+    }
+    if let Some(storage_class) = &options.storage_class {
+        destination["StorageClass"] = json!(storage_class);
+    }
+    let rules = options
+        .tags
+        .iter()
+        .enumerate()
+        .map(|(index, tag)| {
+            json!({
+                "ID": format!("btagger-{}", tag.key),
+                "Priority": index + 1,
+                "Status": "Enabled",
+                "Filter": {"Tag": {"Key": tag.key, "Value": tag.value}},
+                // Expiring a backup at the source must not remove the off-site copy.
+                "DeleteMarkerReplication": {"Status": "Disabled"},
+                "Destination": destination,
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({"Role": options.role_arn, "Rules": rules})
+}
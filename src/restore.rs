@@ -0,0 +1,321 @@
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{eyre, Report, WrapErr};
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::process::Command;
+use tracing::{info, warn};
+
+use crate::keys::{KeyTemplate, KeyVars};
+use crate::process;
+use crate::s3::{self, S3Access};
+use crate::secret::Secret;
+use crate::surreal;
+use crate::tags::Tag;
+use crate::tools::Tools;
+use crate::verify;
+
+/// Which SurrealDB backup to restore, and where to.
+pub struct RestoreOptions {
+    pub tools: Tools,
+    pub bucket_name: String,
+    pub s3_access: S3Access,
+    /// Namespace and database the backup was taken from.
+    pub namespace: String,
+    pub database: String,
+    /// Defaults to the newest backup of `namespace` and `database` that fits `before` and `tags`.
+    pub key: Option<String>,
+    /// Only consider backups written before this time.
+    pub before: Option<DateTime<Utc>>,
+    /// Only consider backups carrying all of these tags.
+    pub tags: Vec<Tag>,
+    /// Layout the backups were written with, to find the newest one.
+    pub key_template: KeyTemplate,
+    pub cluster: Option<String>,
+    /// SurrealDB server to import into.
+    pub address: String,
+    pub password: Option<Secret>,
+    /// Where the data lands; the source names when unset.
+    pub target_namespace: Option<String>,
+    pub target_database: Option<String>,
+    /// Without it the restore is only described.
+    pub yes: bool,
+    /// Refuse a target that holds any table, not just those the backup restores.
+    pub require_empty: bool,
+    /// Import even into tables that already exist in the target.
+    pub force: bool,
+    /// Print the selected key and nothing else, for scripts.
+    pub print_only: bool,
+}
+
+/// Downloads and decompresses a backup and imports it with `surreal import`, into the target
+/// namespace and database when they differ from the source. Returns the restored key.
+pub async fn run(options: &RestoreOptions) -> Result<String, Report> {
+    let key = match &options.key {
+        Some(key) => key.clone(),
+        None => {
+            let vars = KeyVars {
+                engine: "surrealdb",
+                cluster: options.cluster.as_deref(),
+                namespace: Some(&options.namespace),
+                database: Some(&options.database),
+            };
+            select(options, &vars).await?
+        }
+    };
+    if options.print_only {
+        println!("{}", key);
+        return Ok(key);
+    }
+    let namespace = options.target_namespace.as_deref().unwrap_or(&options.namespace);
+    let database = options.target_database.as_deref().unwrap_or(&options.database);
+    if !options.yes {
+        println!("[WOULD RESTORE] {} -> {}/{} on {}", key, namespace, database, options.address);
+        println!("Rerun with --yes to import it; existing records with the same IDs are overwritten");
+        println!("Tables the backup restores must not exist in the target yet, unless --force is given");
+        return Ok(key);
+    }
+
+    let endpoint = format!("http://{}", options.address);
+    let target = target_tables(options, &endpoint, namespace, database).await?;
+    if options.require_empty && !target.is_empty() {
+        print_tables(&target);
+        return Err(eyre!("{}/{} on {} is not empty, as --require-empty requires", namespace, database, options.address));
+    }
+
+    println!("[RESTORING] {} -> {}/{} on {}", key, namespace, database, options.address);
+    let export = std::env::temp_dir().join(format!("btagger-{}-restore.surql", std::process::id()));
+    let retargeted = std::env::temp_dir().join(format!("btagger-{}-restore-retargeted.surql", std::process::id()));
+    let result = restore(options, &key, namespace, database, &target, &export, &retargeted).await;
+    let _ = tokio::fs::remove_file(&export).await;
+    let _ = tokio::fs::remove_file(&retargeted).await;
+    result?;
+    info!(target: "audit", action = "restore", bucket = options.bucket_name, key, namespace, database, address = options.address, operator = std::env::var("USER").unwrap_or_default());
+    println!("[RESTORED] {} -> {}/{}", key, namespace, database);
+    Ok(key)
+}
+
+/// The newest backup written before `options.before` that carries all of `options.tags`.
+async fn select(options: &RestoreOptions, vars: &KeyVars<'_>) -> Result<String, Report> {
+    let backups = verify::backups(&options.tools, &options.s3_access, &options.bucket_name, &options.key_template, vars).await?;
+    for object in backups {
+        if let Some(before) = options.before {
+            match DateTime::parse_from_rfc3339(&object.last_modified) {
+                Ok(modified) if modified < before => {}
+                _ => continue,
+            }
+        }
+        if !options.tags.is_empty() {
+            let tags = s3::object_tags(&options.tools, &options.s3_access, &options.bucket_name, &object.key).await?;
+            if !options.tags.iter().all(|tag| tags.contains(tag)) {
+                continue;
+            }
+        }
+        info!(target: "backup_restore", key = object.key, last_modified = object.last_modified, "Backup selected");
+        return Ok(object.key);
+    }
+    let mut constraints = Vec::new();
+    if let Some(before) = options.before {
+        constraints.push(format!("written before {}", before.to_rfc3339()));
+    }
+    if !options.tags.is_empty() {
+        let tags = options.tags.iter().map(|tag| format!("{}={}", tag.key, tag.value)).collect::<Vec<_>>();
+        constraints.push(format!("tagged {}", tags.join(", ")));
+    }
+    Err(eyre!("No backup of {}/{} in {} {}", options.namespace, options.database, options.bucket_name, constraints.join(" and ")))
+}
+
+/// Every table in the target, with its record count.
+async fn target_tables(options: &RestoreOptions, endpoint: &str, namespace: &str, database: &str) -> Result<Vec<(String, u64)>, Report> {
+    let password = options.password.as_ref();
+    let tables = surreal::tables(&options.tools, endpoint, password, namespace, database)
+        .await
+        .wrap_err_with(|| format!("Unable to list the tables of {}/{} on {}", namespace, database, options.address))?;
+    let mut counts = Vec::new();
+    for table in tables {
+        let count = surreal::count(&options.tools, endpoint, password, namespace, database, &table).await?;
+        counts.push((table, count));
+    }
+    counts.sort();
+    Ok(counts)
+}
+
+fn print_tables(target: &[(String, u64)]) {
+    for (table, count) in target {
+        println!("  {} ({} records)", table, count);
+    }
+}
+
+async fn restore(
+    options: &RestoreOptions,
+    key: &str,
+    namespace: &str,
+    database: &str,
+    target: &[(String, u64)],
+    export: &Path,
+    retargeted: &Path,
+) -> Result<(), Report> {
+    let bytes = verify::download(&options.tools, &options.s3_access, &options.bucket_name, key, export).await?;
+    info!(target: "backup_restore", key, bytes, "Backup decompressed");
+
+    let overlap = Overlap::new(&defined_tables(export).await?, target);
+    if !overlap.overwritten.is_empty() {
+        overlap.print();
+        if !options.force {
+            return Err(eyre!(
+                "{}/{} on {} already has {} of the tables the backup restores; rerun with --force to import into them",
+                namespace,
+                database,
+                options.address,
+                overlap.overwritten.len()
+            ));
+        }
+        warn!(target: "backup_restore", key, namespace, database, tables = overlap.overwritten.len(), "Importing into existing tables, as --force allows");
+    }
+
+    // Exports normally leave the namespace and database to the importer, but a `USE` statement
+    // in one would send the data back to the source names.
+    let renamed = (namespace, database) != (options.namespace.as_str(), options.database.as_str());
+    let import_file = match renamed && retarget(export, retargeted, namespace, database).await? > 0 {
+        true => retargeted,
+        false => export,
+    };
+
+    let mut import = Command::new(&options.tools.surreal);
+    import
+        .kill_on_drop(true)
+        .arg("import")
+        .arg("-e").arg(format!("http://{}", options.address));
+    if let Some(password) = &options.password {
+        import.arg("-u").arg("root").arg("-p").arg(password.expose());
+    }
+    import
+        .arg("--namespace").arg(namespace)
+        .arg("--database").arg(database)
+        .arg(import_file);
+    process::succeeded(options.tools.runner.run(import, None).await)
+        .wrap_err_with(|| format!("Importing {} into {}/{} failed", key, namespace, database))?;
+    Ok(())
+}
+
+/// Names of the tables `export` defines.
+async fn defined_tables(export: &Path) -> Result<Vec<String>, Report> {
+    let mut lines = BufReader::new(tokio::fs::File::open(export).await?).lines();
+    let mut tables = Vec::new();
+    while let Some(line) = lines.next_line().await? {
+        tables.extend(table_definition(&line));
+    }
+    Ok(tables)
+}
+
+/// The table a `DEFINE TABLE` statement defines.
+fn table_definition(line: &str) -> Option<String> {
+    let mut words = line.split_whitespace();
+    let define = words.next()?.eq_ignore_ascii_case("DEFINE") && words.next()?.eq_ignore_ascii_case("TABLE");
+    if !define {
+        return None;
+    }
+    // SurrealDB 2.x adds `OVERWRITE` or `IF NOT EXISTS`.
+    let name = words.find(|word| !["OVERWRITE", "IF", "NOT", "EXISTS"].iter().any(|keyword| word.eq_ignore_ascii_case(keyword)))?;
+    let name = name.trim_end_matches(';').trim_matches(|c| matches!(c, '`' | '⟨' | '⟩'));
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// How the tables of a backup meet those already in the target.
+#[derive(Debug, Default, PartialEq)]
+struct Overlap {
+    /// In both; records with the IDs of the backup's are replaced.
+    overwritten: Vec<(String, u64)>,
+    /// Only in the backup.
+    added: Vec<String>,
+    /// Only in the target, and left as they are.
+    kept: Vec<(String, u64)>,
+}
+
+impl Overlap {
+    fn new(backup: &[String], target: &[(String, u64)]) -> Overlap {
+        let mut overlap = Overlap::default();
+        for (table, count) in target {
+            match backup.contains(table) {
+                true => overlap.overwritten.push((table.clone(), *count)),
+                false => overlap.kept.push((table.clone(), *count)),
+            }
+        }
+        overlap.added = backup.iter().filter(|table| !target.iter().any(|(existing, _)| existing == *table)).cloned().collect();
+        overlap
+    }
+
+    fn print(&self) {
+        for (table, count) in &self.overwritten {
+            println!("~ {} ({} records in the target, overwritten where IDs match)", table, count);
+        }
+        for table in &self.added {
+            println!("+ {}", table);
+        }
+        for (table, count) in &self.kept {
+            println!("= {} ({} records, not in the backup)", table, count);
+        }
+    }
+}
+
+/// Copies `export` to `retargeted` with every `USE` statement pointing at the target names.
+/// Returns how many statements were rewritten.
+async fn retarget(export: &Path, retargeted: &Path, namespace: &str, database: &str) -> Result<usize, Report> {
+    let mut lines = BufReader::new(tokio::fs::File::open(export).await?).lines();
+    let mut output = BufWriter::new(
+        tokio::fs::File::create(retargeted)
+            .await
+            .wrap_err_with(|| format!("Unable to create {}", retargeted.display()))?,
+    );
+    let mut rewritten = 0;
+    while let Some(line) = lines.next_line().await? {
+        match use_statement(&line, namespace, database) {
+            Some(statement) => {
+                rewritten += 1;
+                output.write_all(statement.as_bytes()).await?;
+            }
+            None => output.write_all(line.as_bytes()).await?,
+        }
+        output.write_all(b"\n").await?;
+    }
+    output.flush().await?;
+    Ok(rewritten)
+}
+
+/// The replacement for `line` when it is a `USE` statement.
+fn use_statement(line: &str, namespace: &str, database: &str) -> Option<String> {
+    let trimmed = line.trim_start();
+    let is_use = trimmed.get(..4).is_some_and(|start| start.eq_ignore_ascii_case("USE "));
+    is_use.then(|| format!("USE NS `{}` DB `{}`;", namespace, database))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_use_statements_are_rewritten() {
+        assert_eq!(use_statement("USE NS prod DB main;", "staging", "copy").as_deref(), Some("USE NS `staging` DB `copy`;"));
+        assert_eq!(use_statement("  use ns prod;", "staging", "copy").as_deref(), Some("USE NS `staging` DB `copy`;"));
+        assert_eq!(use_statement("UPDATE person:1 CONTENT { used: true };", "staging", "copy"), None);
+        assert_eq!(use_statement("DEFINE TABLE user;", "staging", "copy"), None);
+    }
+
+    #[test]
+    fn existing_tables_the_backup_defines_are_overwritten() {
+        let backup = ["DEFINE TABLE person TYPE ANY SCHEMALESS PERMISSIONS NONE;", "DEFINE TABLE OVERWRITE `order`;", "DEFINE FIELD name ON person;", "INSERT [ { id: person:1 } ];"]
+            .iter()
+            .filter_map(|line| table_definition(line))
+            .collect::<Vec<_>>();
+        assert_eq!(backup, ["person", "order"]);
+        let target = [(String::from("audit"), 4), (String::from("person"), 12)];
+        assert_eq!(
+            Overlap::new(&backup, &target),
+            Overlap {
+                overwritten: vec![(String::from("person"), 12)],
+                added: vec![String::from("order")],
+                kept: vec![(String::from("audit"), 4)],
+            }
+        );
+        assert!(Overlap::new(&backup, &[]).overwritten.is_empty());
+    }
+}
@@ -0,0 +1,113 @@
+use color_eyre::eyre::{eyre, Report, WrapErr};
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+use crate::failure::Failure;
+use crate::multipart::{Journal, MultipartUpload, Spool};
+use crate::s3::{self, S3Access};
+use crate::tools::Tools;
+
+/// Where the interrupted uploads were spooled, and how to finish them.
+pub struct ResumeOptions {
+    pub tools: Tools,
+    pub s3_access: S3Access,
+    pub spool: Spool,
+    pub part_retries: u32,
+    pub concurrency: usize,
+}
+
+/// Finishes every upload journaled in the spool directory: the parts it is missing are uploaded
+/// from the spool file, the object is completed and then tagged as the backup would have been.
+/// The database is not exported again.
+pub async fn run(options: &ResumeOptions) -> Result<(), Report> {
+    let journals = options.spool.journals().await?;
+    if journals.is_empty() {
+        println!("Nothing to resume in {}", options.spool.dir.display());
+        return Ok(());
+    }
+    let mut failed = 0;
+    for path in &journals {
+        match resume(options, path).await {
+            Ok((key, bytes)) => println!("{}: resumed, {} bytes", key, bytes),
+            Err(err) => {
+                warn!(target: "resume", journal = %path.display(), error = format!("{:#}", err), "Unable to resume the upload");
+                println!("{}: {:#}", path.display(), err);
+                failed += 1;
+            }
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        failed => Err(eyre!("{} of {} uploads could not be resumed", failed, journals.len()).wrap_err(Failure::Upload)),
+    }
+}
+
+async fn resume(options: &ResumeOptions, path: &Path) -> Result<(String, u64), Report> {
+    let journal = Journal::read(path).await?;
+    let spool = Spool { tags: journal.tags.clone(), ..options.spool.clone() };
+    let upload = Arc::new(MultipartUpload {
+        tools: options.tools.clone(),
+        s3_access: options.s3_access.clone(),
+        bucket_name: journal.bucket_name.clone(),
+        key: journal.key.clone(),
+        part_size: journal.part_size,
+        part_retries: options.part_retries,
+        concurrency: options.concurrency,
+        deadline: None,
+        spool: Some(spool.clone()),
+        split: None,
+        metadata: None,
+    });
+    info!(target: "resume", key = journal.key, upload_id = journal.upload_id, parts = journal.parts.len(), "Resuming upload");
+    let uploaded = upload.resume(&spool, journal.clone()).await?;
+    let bytes = upload.complete(uploaded, None).await.wrap_err(Failure::Upload)?;
+    s3::put_object_tagging(&options.tools, &options.s3_access, &journal.bucket_name, &journal.key, &journal.tags)
+        .await
+        .wrap_err(Failure::Tagging)?;
+    info!(target: "aws_put_object_tagging_output", key = journal.key);
+    Ok((journal.key, bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::multipart::MIN_PART_SIZE;
+    use crate::process::MockRunner;
+
+    #[tokio::test]
+    async fn missing_parts_are_uploaded_then_the_object_is_completed_and_tagged() {
+        let dir = std::env::temp_dir().join(format!("btagger-resume-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let spool = Spool { dir: dir.clone(), min_free: 0, tags: String::new() };
+        let key = "sqlite/app/2025-01-01.04-30.db.zst";
+        let (file, journal) = spool.paths(key);
+        // Two parts, the first of which made it before the interruption.
+        std::fs::write(&file, vec![7u8; MIN_PART_SIZE as usize + 10]).unwrap();
+        std::fs::write(
+            &journal,
+            format!(
+                r#"{{"bucket_name":"bk","key":"{}","upload_id":"u-1","part_size":{},"tags":"{{\"TagSet\":[]}}","parts":[{{"ETag":"\"e1\"","PartNumber":1}}]}}"#,
+                key, MIN_PART_SIZE
+            ),
+        )
+        .unwrap();
+        let runner = Arc::new(MockRunner::new(|_| MockRunner::output(0, r#"{"ETag":"\"e2\""}"#, "")));
+        let options = ResumeOptions {
+            tools: Tools::mock(runner.clone()),
+            s3_access: S3Access::default(),
+            spool,
+            part_retries: 0,
+            concurrency: 2,
+        };
+        run(&options).await.unwrap();
+
+        let calls = runner.calls();
+        assert_eq!(calls.len(), 3);
+        assert!(calls[0].has_args(&["upload-part", "--bucket", "bk", "--key", key, "--upload-id", "u-1", "--part-number", "2"]));
+        assert!(calls[1].has_args(&["complete-multipart-upload"]));
+        assert!(calls[2].has_args(&["put-object-tagging"]));
+        assert!(!file.exists() && !journal.exists());
+        std::fs::remove_dir(&dir).unwrap();
+    }
+}
@@ -0,0 +1,278 @@
+use chrono::{DateTime, Datelike, Utc};
+use clap::ValueEnum;
+use color_eyre::eyre::{eyre, Report, WrapErr};
+use std::collections::{BTreeMap, BTreeSet};
+use std::str::FromStr;
+use tracing::info;
+
+use crate::multipart;
+use crate::s3::{self, S3Access};
+use crate::simulate::TIERS;
+use crate::tags::{self, Tag, TagSet};
+use crate::tools::Tools;
+use crate::Object;
+
+/// GFS tiers from son to grandfather, each with the tag that keeps a backup under it.
+pub const GFS_TIERS: [(&str, &str); 4] = [("daily", "nightly"), ("weekly", "weekly"), ("monthly", "monthly"), ("yearly", "yearly")];
+
+/// How many backups each tier keeps, e.g. `daily=7,weekly=4,monthly=12,yearly=3`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GfsPolicy {
+    counts: [usize; GFS_TIERS.len()],
+}
+
+impl Default for GfsPolicy {
+    /// Roughly what the README's lifecycle configuration keeps.
+    fn default() -> Self {
+        GfsPolicy { counts: [7, 5, 6, 3] }
+    }
+}
+
+impl FromStr for GfsPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut policy = GfsPolicy::default();
+        for entry in s.split(',').filter(|entry| !entry.trim().is_empty()) {
+            let (tier, count) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("'{}' is not in the form tier=count", entry))?;
+            let index = GFS_TIERS
+                .iter()
+                .position(|(known, _)| *known == tier.trim())
+                .ok_or_else(|| {
+                    format!(
+                        "Unknown tier '{}', expected one of {}",
+                        tier,
+                        GFS_TIERS.iter().map(|(tier, _)| *tier).collect::<Vec<_>>().join(", ")
+                    )
+                })?;
+            policy.counts[index] = count
+                .trim()
+                .parse()
+                .map_err(|_| format!("'{}' is not a number of backups", count))?;
+        }
+        Ok(policy)
+    }
+}
+
+/// How a retention decision is carried out.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Enforcement {
+    /// Set the tier tags on kept backups and remove them from the rest, leaving deletion to lifecycle rules.
+    Tags,
+    /// Delete backups no tier keeps.
+    Delete,
+}
+
+/// Which of `backups` to keep and the tiers keeping each: per tier, the newest backup of each of
+/// the most recent days, ISO weeks, months or years that have one, up to the tier's count.
+pub fn select<'a>(policy: &GfsPolicy, backups: &[(&'a str, DateTime<Utc>)]) -> BTreeMap<&'a str, Vec<&'static str>> {
+    let mut newest_first = backups.to_vec();
+    newest_first.sort_by_key(|(_, time)| std::cmp::Reverse(*time));
+    let mut kept: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for (index, (tier, _)) in GFS_TIERS.iter().enumerate() {
+        let mut periods = BTreeSet::new();
+        for (key, time) in &newest_first {
+            if periods.len() == policy.counts[index] {
+                break;
+            }
+            let period = match index {
+                0 => (time.year(), time.ordinal()),
+                1 => (time.iso_week().year(), time.iso_week().week()),
+                2 => (time.year(), time.month()),
+                _ => (time.year(), 0),
+            };
+            if periods.insert(period) {
+                kept.entry(key).or_default().push(tier);
+            }
+        }
+    }
+    kept
+}
+
+/// Backups by name, each with its modification time and object keys.
+pub type Backups = BTreeMap<String, (DateTime<Utc>, Vec<String>)>;
+
+/// Groups objects into backups, keyed by the path below `prefix` up to and including its next
+/// segment: a SurrealDB backup is one object, or its manifest and numbered objects when it was
+/// split, a TiKV backup every object under its directory. Each backup is as old as its newest
+/// object.
+pub fn group(prefix: &str, objects: Vec<Object>) -> Result<Backups, Report> {
+    let keys = objects.iter().map(|object| object.key.clone()).collect::<BTreeSet<_>>();
+    let mut backups = Backups::new();
+    for object in objects {
+        let key = multipart::split_of(&object.key).filter(|manifest| keys.contains(*manifest)).unwrap_or(&object.key);
+        let Some(rest) = key.strip_prefix(prefix) else { continue };
+        let name = rest.split('/').next().unwrap_or(rest);
+        let modified = DateTime::parse_from_rfc3339(&object.last_modified)
+            .wrap_err_with(|| format!("{} has no valid modification time", object.key))?
+            .with_timezone(&Utc);
+        let backup = backups.entry(format!("{}{}", prefix, name)).or_insert((modified, Vec::new()));
+        backup.0 = backup.0.max(modified);
+        backup.1.push(object.key);
+    }
+    Ok(backups)
+}
+
+/// `current` with its GFS tier tags replaced by those of `tiers`, in whichever style it is tagged.
+/// A `retention=<tier>` tag is set to the longest-lived tier left, counting the tiers it carries
+/// that GFS does not decide, such as `quarterly`.
+fn retag(current: &[Tag], tiers: &[&str]) -> Vec<Tag> {
+    let is_gfs = |tier: &str| GFS_TIERS.iter().any(|(_, tier_tag)| *tier_tag == tier);
+    let kept = GFS_TIERS.iter().filter(|(tier, _)| tiers.contains(tier)).map(|(_, tier_tag)| *tier_tag);
+    if current.iter().any(|tag| tag.key == "retention") {
+        let mut named = tags::tiers(current).into_iter().filter(|tier| !is_gfs(tier)).chain(kept).collect::<Vec<_>>();
+        named.sort_by_key(|tier| TIERS.iter().position(|known| known == tier));
+        let mut wanted = current.iter().filter(|tag| tag.key != "retention").cloned().collect::<Vec<_>>();
+        wanted.insert(0, Tag { key: String::from("retention"), value: named.last().copied().unwrap_or(TIERS[0]).to_string() });
+        return wanted;
+    }
+    let mut wanted = current.iter().filter(|tag| !is_gfs(&tag.key)).cloned().collect::<Vec<_>>();
+    // Lifecycle rules only expire what they can match, and they all match `standard`.
+    if !wanted.iter().any(|tag| tag.key == "standard") {
+        wanted.insert(0, Tag { key: String::from("standard"), value: String::from("1") });
+    }
+    wanted.extend(kept.map(|tier_tag| Tag { key: tier_tag.to_string(), value: String::from("1") }));
+    wanted
+}
+
+/// Where the backups are and how to apply the policy to them.
+pub struct RetainOptions {
+    pub tools: Tools,
+    pub bucket_name: String,
+    pub s3_access: S3Access,
+    /// Directly above the backups; each path segment below it is one backup.
+    pub prefix: String,
+    pub policy: GfsPolicy,
+    pub enforcement: Enforcement,
+    /// Without it the changes are only listed.
+    pub yes: bool,
+}
+
+/// Groups the objects under the prefix into backups, selects the ones to keep and tags or
+/// deletes accordingly. Returns how many backups the policy keeps.
+pub async fn run(options: &RetainOptions) -> Result<usize, Report> {
+    let s3_access = &options.s3_access;
+    let objects = s3::list_objects(&options.tools, s3_access, &options.bucket_name, &options.prefix).await?;
+
+    let backups = group(&options.prefix, objects)?;
+    if backups.is_empty() {
+        return Err(eyre!("No backups under '{}' in {}", options.prefix, options.bucket_name));
+    }
+
+    let times = backups.iter().map(|(backup, (modified, _))| (backup.as_str(), *modified)).collect::<Vec<_>>();
+    let kept = select(&options.policy, &times);
+    let mut changes = 0;
+    for (backup, (_, keys)) in &backups {
+        let tiers = kept.get(backup.as_str()).cloned().unwrap_or_default();
+        match (options.enforcement, tiers.is_empty()) {
+            (Enforcement::Tags, _) => {
+                for key in keys {
+                    let current = s3::object_tags(&options.tools, s3_access, &options.bucket_name, key).await?;
+                    let wanted = retag(&current, &tiers);
+                    if wanted == current {
+                        continue;
+                    }
+                    changes += 1;
+                    let tagging = serde_json::to_string(&TagSet { tag_set: wanted })?;
+                    if !options.yes {
+                        println!("[WOULD TAG] {} {}", key, tagging);
+                        continue;
+                    }
+                    s3::put_object_tagging(&options.tools, s3_access, &options.bucket_name, key, &tagging).await?;
+                    info!(target: "audit", action = "retag", bucket = options.bucket_name, key, tagging, operator = std::env::var("USER").unwrap_or_default());
+                    println!("[TAGGED] {} {}", key, tagging);
+                }
+            }
+            (Enforcement::Delete, false) => {}
+            (Enforcement::Delete, true) => {
+                for key in keys {
+                    changes += 1;
+                    if !options.yes {
+                        println!("[WOULD DELETE] {}", key);
+                        continue;
+                    }
+                    s3::delete_object(&options.tools, s3_access, &options.bucket_name, key).await?;
+                    info!(target: "audit", action = "delete", bucket = options.bucket_name, key, operator = std::env::var("USER").unwrap_or_default());
+                    println!("[DELETED] {}", key);
+                }
+            }
+        }
+        if !tiers.is_empty() {
+            println!("[KEEP] {} ({})", backup, tiers.join(", "));
+        }
+    }
+    if !options.yes && changes > 0 {
+        println!("{} change(s) pending; rerun with --yes to apply them", changes);
+    }
+    Ok(kept.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(year: i32, month: u32, day: u32, hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(year, month, day, hour, 30, 0).unwrap()
+    }
+
+    #[test]
+    fn keeps_the_newest_backup_of_each_recent_period() {
+        let backups = [
+            ("a", at(2024, 12, 30, 4)),
+            ("b", at(2024, 12, 31, 4)),
+            ("c", at(2024, 12, 31, 20)),
+            ("d", at(2025, 1, 1, 4)),
+            ("e", at(2025, 1, 2, 4)),
+        ];
+        let policy = "daily=2,weekly=1,monthly=2,yearly=5".parse().unwrap();
+        let kept = select(&policy, &backups);
+        assert_eq!(kept.get("e"), Some(&vec!["daily", "weekly", "monthly", "yearly"]));
+        assert_eq!(kept.get("d"), Some(&vec!["daily"]));
+        // 2024-12-31 is in the same ISO week as 2025-01-02, so only the month and year keep it.
+        assert_eq!(kept.get("c"), Some(&vec!["monthly", "yearly"]));
+        assert_eq!(kept.get("b"), None);
+        assert_eq!(kept.get("a"), None);
+    }
+
+    #[test]
+    fn zero_counts_keep_nothing() {
+        let policy = "daily=0,weekly=0,monthly=0,yearly=0".parse().unwrap();
+        assert!(select(&policy, &[("a", at(2025, 1, 1, 4))]).is_empty());
+    }
+
+    #[test]
+    fn split_backups_group_under_their_manifest() {
+        let object = |key: &str, modified: &str| Object { key: key.to_string(), size: 1, last_modified: modified.to_string() };
+        let objects = vec![
+            object("surrealdb/ns/db/2025-01-01.04-30.zst", "2025-01-01T04:50:00+00:00"),
+            object("surrealdb/ns/db/2025-01-01.04-30.zst.part0001", "2025-01-01T04:40:00+00:00"),
+            object("surrealdb/ns/db/2025-01-01.04-30.zst.part0002", "2025-01-01T04:45:00+00:00"),
+            object("surrealdb/ns/db/2025-01-02.04-30.zst", "2025-01-02T04:40:00+00:00"),
+            // Not split, whatever its name says: nothing else is stored under its manifest key.
+            object("surrealdb/ns/db/notes.part0001", "2025-01-02T04:40:00+00:00"),
+        ];
+        let backups = group("surrealdb/ns/db/", objects).unwrap();
+        assert_eq!(backups.keys().collect::<Vec<_>>(), ["surrealdb/ns/db/2025-01-01.04-30.zst", "surrealdb/ns/db/2025-01-02.04-30.zst", "surrealdb/ns/db/notes.part0001"]);
+        let (modified, keys) = &backups["surrealdb/ns/db/2025-01-01.04-30.zst"];
+        assert_eq!(*modified, at(2025, 1, 1, 4) + chrono::Duration::minutes(20));
+        assert_eq!(keys.len(), 3);
+    }
+
+    #[test]
+    fn retagging_keeps_the_tag_style() {
+        let tags = |tags: &[&str]| tags.iter().map(|tag| tag.parse().unwrap()).collect::<Vec<Tag>>();
+        assert_eq!(retag(&tags(&["standard", "nightly", "quarterly", "team=data"]), &["weekly"]), tags(&["standard", "quarterly", "team=data", "weekly"]));
+        assert_eq!(retag(&tags(&["team=data"]), &[]), tags(&["standard", "team=data"]));
+        assert_eq!(retag(&tags(&["retention=nightly", "team=data"]), &["daily", "monthly"]), tags(&["retention=monthly", "team=data"]));
+        assert_eq!(retag(&tags(&["retention=quarterly"]), &["weekly"]), tags(&["retention=quarterly"]));
+        assert_eq!(retag(&tags(&["retention=yearly"]), &[]), tags(&["retention=standard"]));
+    }
+
+    #[test]
+    fn rejects_unknown_tiers() {
+        assert!("hourly=3".parse::<GfsPolicy>().is_err());
+    }
+}
@@ -0,0 +1,1042 @@
+use clap::{Args as ClapArgs, ValueEnum};
+use color_eyre::eyre::{eyre, Report, WrapErr};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::process::Command;
+use tracing::info;
+
+use crate::process;
+use crate::secret::Secret;
+use crate::tags::{self, Tag, TagSet};
+use crate::tools::Tools;
+use crate::{ListObjectResult, Object};
+
+/// The S3 endpoint and keys every bucket-touching command takes. Region, path style and the
+/// credential source are global flags.
+#[derive(ClapArgs, Debug)]
+pub struct S3Args {
+    /// S3 service endpoint address. Leave unspecified to use host defaults.
+    #[arg(short = 'e', long)]
+    pub aws_endpoint: Option<String>,
+
+    /// S3 access key ID. Leave unspecified to use host defaults.
+    #[arg(short = 'i', long)]
+    pub aws_id: Option<Secret>,
+
+    /// S3 secret access Key. Leave unspecified to use host defaults.
+    #[arg(short = 'k', long)]
+    pub aws_key: Option<Secret>,
+}
+
+/// Where the credentials for S3 come from.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CredentialSource {
+    /// AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY (and AWS_SESSION_TOKEN) in btagger's own environment.
+    Env,
+    /// Each tool's default chain: an EKS IRSA web identity, the EC2 instance role or the default profile. TiKV nodes use their own.
+    Irsa,
+    /// --aws-id and --aws-key.
+    Static,
+    /// The --aws-profile of the shared config and credentials files.
+    Profile,
+}
+
+/// Credentials handed to `aws` and tikv-br, as chosen by [`CredentialSource`].
+#[derive(Clone, Debug, Default)]
+pub enum Credentials {
+    /// Inherited through the environment unchanged.
+    Env,
+    /// Left to each tool to find.
+    #[default]
+    Irsa,
+    Static(Secret, Secret),
+    Profile(String),
+}
+
+/// Which S3 implementation the bucket lives on, and so which operations btagger attempts.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum S3Compat {
+    /// Amazon S3, where every operation is available.
+    #[default]
+    Aws,
+    /// MinIO, addressed path-style.
+    Minio,
+    /// Ceph RADOS Gateway, addressed path-style. Buckets are created without a LocationConstraint.
+    Ceph,
+    /// SeaweedFS, addressed path-style; its lifecycle rules cannot filter by tag.
+    Seaweedfs,
+    /// Garage, addressed path-style. It has no object tagging, versioning or tag-filtered
+    /// lifecycle, and keys usually may not create buckets.
+    Garage,
+}
+
+impl S3Compat {
+    /// Whether create-bucket is attempted. Otherwise the bucket must exist already.
+    pub fn creates_buckets(self) -> bool {
+        self != S3Compat::Garage
+    }
+
+    /// Whether PutObjectTagging is attempted. Otherwise tags go in a sidecar object, see
+    /// [`sidecar_key`], as they also do when a store answers NotImplemented.
+    pub fn tags_objects(self) -> bool {
+        self != S3Compat::Garage
+    }
+
+    /// Whether an object's tags may be in a sidecar even though GetObjectTagging works: the
+    /// store has no object tagging, or, like SeaweedFS, reads tag sets it cannot write. Elsewhere
+    /// an untagged object is taken as untagged, without looking for a sidecar.
+    pub fn sidecar_tags(self) -> bool {
+        matches!(self, S3Compat::Seaweedfs | S3Compat::Garage)
+    }
+
+    pub fn versioning(self) -> bool {
+        self != S3Compat::Garage
+    }
+
+    /// Whether lifecycle rules can expire objects by tag.
+    pub fn tag_lifecycle(self) -> bool {
+        !matches!(self, S3Compat::Seaweedfs | S3Compat::Garage)
+    }
+
+    /// Whether buckets are addressed as https://endpoint/bucket, as with --s3-force-path-style.
+    pub fn path_style(self) -> bool {
+        self != S3Compat::Aws
+    }
+
+    /// Whether create-bucket names the region as its LocationConstraint. RGW only takes the name
+    /// of one of its zonegroups there and answers InvalidLocationConstraint to AWS region names;
+    /// left out, the bucket goes to the default zonegroup.
+    pub fn location_constraint(self) -> bool {
+        self != S3Compat::Ceph
+    }
+}
+
+/// Where the tags of `key` are kept on stores without object tagging.
+pub fn sidecar_key(key: &str) -> String {
+    format!("{}{}", key, SIDECAR_SUFFIX)
+}
+
+const SIDECAR_SUFFIX: &str = ".tags.json";
+
+/// The endpoint override and credentials every S3 call of a command uses.
+#[derive(Clone, Debug, Default)]
+pub struct S3Access {
+    /// S3-compatible service such as MinIO; AWS when unset.
+    pub endpoint: Option<String>,
+    /// Region of the bucket; each tool's configured default when unset.
+    pub region: Option<String>,
+    pub credentials: Credentials,
+    /// Shared config file requesting path-style addressing, from --s3-force-path-style; `aws`
+    /// only reads the addressing style from config files.
+    pub path_style_config: Option<Arc<ConfigFile>>,
+    pub compat: S3Compat,
+    /// App ID `aws` adds to its user agent, so access logs can attribute requests to btagger.
+    pub user_agent: Option<String>,
+    /// Whether object operations accept the charges of a Requester Pays bucket.
+    pub request_payer: bool,
+}
+
+impl S3Access {
+    /// A MinIO endpoint with static credentials `id` and `key`.
+    #[cfg(test)]
+    pub fn minio() -> Self {
+        S3Access {
+            endpoint: Some(String::from("http://minio:9000")),
+            credentials: Credentials::Static(Secret::new("id"), Secret::new("key")),
+            ..S3Access::default()
+        }
+    }
+
+    /// Arguments every object operation ends with. Bucket operations take no request payer.
+    pub fn object_options(&self) -> &'static [&'static str] {
+        match self.request_payer {
+            true => &["--request-payer", "requester"],
+            false => &[],
+        }
+    }
+
+    /// The keys to hand to a database server that writes to the bucket itself, usually from
+    /// another host, so only explicit keys can be handed over. `None` leaves the server to its own
+    /// credentials, such as an instance role.
+    pub fn server_credentials(&self) -> Result<Option<(Secret, Secret)>, Report> {
+        match &self.credentials {
+            Credentials::Static(id, key) => Ok(Some((id.clone(), key.clone()))),
+            Credentials::Env => {
+                let var = |name| std::env::var(name).map(Secret::new).wrap_err_with(|| format!("{} is not set", name));
+                Ok(Some((var("AWS_ACCESS_KEY_ID")?, var("AWS_SECRET_ACCESS_KEY")?)))
+            }
+            Credentials::Irsa => Ok(None),
+            Credentials::Profile(_) => Err(eyre!("A database server cannot use --aws-profile; pass keys or rely on its own credentials")),
+        }
+    }
+
+    /// The config file section `aws` reads its settings from.
+    fn profile_section(&self) -> String {
+        let profile = match &self.credentials {
+            Credentials::Profile(profile) => Some(profile.clone()),
+            _ => std::env::var("AWS_PROFILE").ok().filter(|profile| !profile.is_empty()),
+        };
+        match profile {
+            Some(profile) if profile != "default" => format!("profile {}", profile),
+            _ => String::from("default"),
+        }
+    }
+
+    /// Has every later `aws` call read a copy of the user's AWS config file with path-style
+    /// addressing for the active profile. The copy is written once per profile and shared by every
+    /// access using it, and removed when the last of them is dropped.
+    pub fn force_path_style(&mut self) -> Result<(), Report> {
+        use std::io::Write;
+
+        static WRITTEN: Mutex<BTreeMap<String, Weak<ConfigFile>>> = Mutex::new(BTreeMap::new());
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let section = self.profile_section();
+        let mut written = WRITTEN.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(config) = written.get(&section).and_then(Weak::upgrade) {
+            self.path_style_config = Some(config);
+            return Ok(());
+        }
+        let original = std::env::var_os("AWS_CONFIG_FILE")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".aws").join("config")));
+        let existing = original.and_then(|path| std::fs::read_to_string(path).ok()).unwrap_or_default();
+        let path = std::env::temp_dir().join(format!("btagger-{}-{}.aws-config", std::process::id(), COUNT.fetch_add(1, Ordering::Relaxed)));
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options
+            .open(&path)
+            .wrap_err_with(|| format!("Unable to create AWS config file {}", path.display()))?;
+        let config = Arc::new(ConfigFile(path));
+        file.write_all(with_path_style(&existing, &section).as_bytes())?;
+        written.insert(section, Arc::downgrade(&config));
+        self.path_style_config = Some(config);
+        Ok(())
+    }
+}
+
+/// A private config file written for `aws`, removed when dropped.
+#[derive(Debug)]
+pub struct ConfigFile(PathBuf);
+
+impl ConfigFile {
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for ConfigFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// `config` with `s3 = addressing_style = path` set in `section`, which is added when missing.
+/// Other settings, including those of other profiles, are kept as they are.
+fn with_path_style(config: &str, section: &str) -> String {
+    const SETTING: &str = "    addressing_style = path";
+    let mut output = Vec::new();
+    let (mut in_section, mut found, mut in_s3) = (false, false, false);
+    let mut needs_s3 = false;
+    for line in config.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            if needs_s3 {
+                // Before the blank lines separating it from the next section.
+                let end = output.iter().rposition(|line: &&str| !line.trim().is_empty()).map_or(0, |index| index + 1);
+                output.splice(end..end, ["s3 =", SETTING]);
+            }
+            in_section = trimmed.trim_start_matches('[').trim_end_matches(']').trim() == section;
+            found |= in_section;
+            needs_s3 = in_section;
+            in_s3 = false;
+            output.push(line);
+            continue;
+        }
+        if in_section && !line.starts_with(char::is_whitespace) {
+            in_s3 = trimmed.split('=').next().map(str::trim) == Some("s3");
+            if in_s3 {
+                output.extend([line, SETTING]);
+                needs_s3 = false;
+                continue;
+            }
+        }
+        // The setting above replaces any addressing style already there.
+        if in_s3 && line.starts_with(char::is_whitespace) && trimmed.starts_with("addressing_style") {
+            continue;
+        }
+        output.push(line);
+    }
+    if needs_s3 {
+        output.extend(["s3 =", SETTING]);
+    }
+    let header = format!("[{}]", section);
+    if !found {
+        output.extend([header.as_str(), "s3 =", SETTING]);
+    }
+    let mut config = output.join("\n");
+    config.push('\n');
+    config
+}
+
+/// Builds `aws` invocations honouring the S3 endpoint override. Every method returns a fresh
+/// `Command`, so no arguments carry over from one invocation into the next.
+#[derive(Clone, Copy)]
+pub struct Aws<'a> {
+    tools: &'a Tools,
+    s3_access: &'a S3Access,
+}
+
+impl<'a> Aws<'a> {
+    pub fn new(tools: &'a Tools, s3_access: &'a S3Access) -> Self {
+        Aws { tools, s3_access }
+    }
+
+    /// Base invocation, for operations without a method of their own.
+    pub fn command(&self) -> Command {
+        let mut command = Command::new(&self.tools.aws);
+        match &self.s3_access.credentials {
+            Credentials::Static(id, key) => {
+                command
+                    .env("AWS_ACCESS_KEY_ID", id.expose())
+                    .env("AWS_SECRET_ACCESS_KEY", key.expose());
+            }
+            Credentials::Profile(profile) => {
+                command.env("AWS_PROFILE", profile);
+            }
+            Credentials::Env | Credentials::Irsa => {}
+        }
+        if let Some(config) = &self.s3_access.path_style_config {
+            command.env("AWS_CONFIG_FILE", config.path());
+        }
+        if let Some(user_agent) = &self.s3_access.user_agent {
+            command.env("AWS_SDK_UA_APP_ID", user_agent);
+            // aws v1 has no app ID, but adds the execution environment to its user agent; one set
+            // by the platform, such as ECS, is left alone.
+            if std::env::var_os("AWS_EXECUTION_ENV").is_none() {
+                command.env("AWS_EXECUTION_ENV", user_agent);
+            }
+        }
+        if let Some(endpoint) = &self.s3_access.endpoint {
+            command.arg("--endpoint-url").arg(endpoint);
+        }
+        if let Some(region) = &self.s3_access.region {
+            command.arg("--region").arg(region);
+        }
+        command
+    }
+
+    pub fn head_bucket(&self, bucket_name: &str) -> Command {
+        let mut command = self.command();
+        command
+            .arg("s3api")
+            .arg("head-bucket")
+            .arg("--bucket").arg(bucket_name);
+        command
+    }
+
+    pub fn create_bucket(&self, bucket_name: &str) -> Command {
+        let mut command = self.command();
+        command
+            .arg("s3api")
+            .arg("create-bucket")
+            .arg("--bucket").arg(bucket_name)
+            .arg("--output").arg("json");
+        // us-east-1 is the one region that rejects being named as a location constraint.
+        if let Some(region) = self.s3_access.region.as_deref().filter(|region| *region != "us-east-1" && self.s3_access.compat.location_constraint()) {
+            command
+                .arg("--create-bucket-configuration")
+                .arg(format!("LocationConstraint={}", region));
+        }
+        command
+    }
+
+    /// One of the `get-bucket-*` calls that read a configuration document, e.g.
+    /// `get-bucket-versioning`.
+    pub fn get_bucket_configuration(&self, bucket_name: &str, operation: &str) -> Command {
+        let mut command = self.command();
+        command
+            .arg("s3api")
+            .arg(operation)
+            .arg("--bucket").arg(bucket_name)
+            .arg("--output").arg("json");
+        command
+    }
+
+    /// One of the `put-bucket-*` calls that set a configuration document, e.g.
+    /// `put-bucket-versioning --versioning-configuration {...}`.
+    pub fn put_bucket_configuration(&self, bucket_name: &str, operation: &str, option: &str, document: &str) -> Command {
+        let mut command = self.command();
+        command
+            .arg("s3api")
+            .arg(operation)
+            .arg("--bucket").arg(bucket_name)
+            .arg(option).arg(document);
+        command
+    }
+
+    pub fn list_objects(&self, bucket_name: &str, prefix: &str) -> Command {
+        let mut command = self.command();
+        command
+            .arg("s3api")
+            .arg("list-objects-v2")
+            .arg("--bucket").arg(bucket_name)
+            .arg("--prefix").arg(prefix)
+            .arg("--output").arg("json")
+            .args(self.s3_access.object_options());
+        command
+    }
+
+    pub fn put_object(&self, bucket_name: &str, key: &str, body: &Path, metadata: Option<&str>) -> Command {
+        let mut command = self.command();
+        command
+            .arg("s3api")
+            .arg("put-object")
+            .arg("--bucket").arg(bucket_name)
+            .arg("--key").arg(key)
+            .arg("--body").arg(body)
+            .args(self.s3_access.object_options());
+        if let Some(metadata) = metadata {
+            command.arg("--metadata").arg(metadata);
+        }
+        command
+    }
+
+    pub fn get_object_tagging(&self, bucket_name: &str, key: &str) -> Command {
+        let mut command = self.command();
+        command
+            .arg("s3api")
+            .arg("get-object-tagging")
+            .arg("--bucket").arg(bucket_name)
+            .arg("--key").arg(key)
+            .arg("--output").arg("json")
+            .args(self.s3_access.object_options());
+        command
+    }
+
+    pub fn head_object(&self, bucket_name: &str, key: &str) -> Command {
+        let mut command = self.command();
+        command
+            .arg("s3api")
+            .arg("head-object")
+            .arg("--bucket").arg(bucket_name)
+            .arg("--key").arg(key)
+            .arg("--output").arg("json")
+            .args(self.s3_access.object_options());
+        command
+    }
+
+    pub fn put_object_tagging(&self, bucket_name: &str, key: &str, tagging: &str) -> Command {
+        let mut command = self.command();
+        command
+            .kill_on_drop(true)
+            .arg("s3api")
+            .arg("put-object-tagging")
+            .arg("--bucket").arg(bucket_name)
+            .arg("--tagging").arg(tagging)
+            .arg("--key").arg(key)
+            .args(self.s3_access.object_options());
+        command
+    }
+
+    pub fn copy_object(&self, from_bucket: &str, to_bucket: &str, key: &str) -> Command {
+        let mut command = self.command();
+        command
+            .arg("s3")
+            .arg("cp")
+            .arg(format!("s3://{}/{}", from_bucket, key))
+            .arg(format!("s3://{}/{}", to_bucket, key))
+            .args(self.s3_access.object_options());
+        command
+    }
+
+    /// Stores what is written to stdin as `key`.
+    pub fn upload(&self, bucket_name: &str, key: &str) -> Command {
+        let mut command = self.command();
+        command
+            .arg("s3")
+            .arg("cp")
+            .arg("-")
+            .arg(format!("s3://{}/{}", bucket_name, key))
+            .args(self.s3_access.object_options());
+        command
+    }
+
+    /// Streams the object to stdout.
+    pub fn download(&self, bucket_name: &str, key: &str) -> Command {
+        let mut command = self.command();
+        command
+            .arg("s3")
+            .arg("cp")
+            .arg(format!("s3://{}/{}", bucket_name, key))
+            .arg("-")
+            .args(self.s3_access.object_options());
+        command
+    }
+
+    pub fn delete_object(&self, bucket_name: &str, key: &str) -> Command {
+        let mut command = self.command();
+        command
+            .arg("s3api")
+            .arg("delete-object")
+            .arg("--bucket").arg(bucket_name)
+            .arg("--key").arg(key)
+            .args(self.s3_access.object_options());
+        command
+    }
+
+    pub fn delete_prefix(&self, bucket_name: &str, prefix: &str) -> Command {
+        let mut command = self.command();
+        command
+            .arg("s3")
+            .arg("rm")
+            .arg(format!("s3://{}/{}", bucket_name, prefix))
+            .arg("--recursive")
+            .args(self.s3_access.object_options());
+        command
+    }
+}
+
+/// Runs `command`, failing with the operation's name and `aws`'s stderr when it exits unsuccessfully.
+async fn run(tools: &Tools, command: Command, operation: &str, subject: &str) -> Result<Output, Report> {
+    let output = tools.runner.run(command, None).await.wrap_err("failed to execute process")?;
+    if !output.status.success() {
+        return Err(eyre!("{} failed for {}: {}", operation, subject, process::captured(operation, &output.stderr).trim()));
+    }
+    Ok(output)
+}
+
+/// Whether create-bucket failed only because the bucket is already there.
+pub fn bucket_already_exists(stderr: &str) -> bool {
+    ["BucketAlreadyOwnedByYou", "BucketAlreadyExists"].iter().any(|code| stderr.contains(code))
+}
+
+/// Creates the bucket, returning whether it was new. A bucket that already exists is fine; any
+/// other failure, such as AccessDenied or an unreachable endpoint, is an error.
+/// Where the store does not let keys create buckets, the bucket is only checked for.
+pub async fn ensure_bucket(tools: &Tools, s3_access: &S3Access, bucket_name: &str) -> Result<bool, Report> {
+    if !s3_access.compat.creates_buckets() {
+        run(tools, Aws::new(tools, s3_access).head_bucket(bucket_name), "head-bucket", bucket_name)
+            .await
+            .wrap_err_with(|| format!("Bucket {} is not there; create it with the store's own tools", bucket_name))?;
+        return Ok(false);
+    }
+    let command = Aws::new(tools, s3_access).create_bucket(bucket_name);
+    let output = tools.runner.run(command, None).await.wrap_err("failed to execute process")?;
+    if output.status.success() {
+        return Ok(true);
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if bucket_already_exists(&stderr) {
+        return Ok(false);
+    }
+    Err(eyre!("create-bucket failed for {}: {}", bucket_name, stderr.trim()))
+        .wrap_err("Unable to create the bucket; pass --no-create-bucket where creating buckets isn't allowed")
+}
+
+/// Sets one bucket configuration document through `operation`, e.g. `put-bucket-policy`.
+pub async fn put_bucket_configuration(
+    tools: &Tools,
+    s3_access: &S3Access,
+    bucket_name: &str,
+    operation: &str,
+    option: &str,
+    document: &str,
+) -> Result<(), Report> {
+    run(tools, Aws::new(tools, s3_access).put_bucket_configuration(bucket_name, operation, option, document), operation, bucket_name)
+        .await
+        .map(|_| ())
+}
+
+/// Every object under `prefix`.
+pub async fn list_objects(
+    tools: &Tools,
+    s3_access: &S3Access,
+    bucket_name: &str,
+    prefix: &str,
+) -> Result<Vec<Object>, Report> {
+    // aws follows the continuation tokens itself, so this is every object, not just the first page.
+    let output = run(tools, Aws::new(tools, s3_access).list_objects(bucket_name, prefix), "list-objects-v2", prefix).await?;
+    // An empty listing comes back as no output at all.
+    if output.stdout.iter().all(u8::is_ascii_whitespace) {
+        return Ok(Vec::new());
+    }
+    let objects = serde_json::from_slice::<ListObjectResult>(&output.stdout)
+        .wrap_err("Unable to parse list-objects-v2 response")?
+        .contents;
+    // Sidecars belong to the object they describe; they are never backups of their own.
+    Ok(objects.into_iter().filter(|object| !object.key.ends_with(SIDECAR_SUFFIX)).collect())
+}
+
+/// The tags currently on `key`. Where the store has no object tagging, or the object carries no
+/// tags, they come from its sidecar when there is one.
+pub async fn object_tags(
+    tools: &Tools,
+    s3_access: &S3Access,
+    bucket_name: &str,
+    key: &str,
+) -> Result<Vec<Tag>, Report> {
+    if !s3_access.compat.tags_objects() {
+        return sidecar_tags(tools, s3_access, bucket_name, key).await;
+    }
+    let output = tools.runner.run(Aws::new(tools, s3_access).get_object_tagging(bucket_name, key), None).await.wrap_err("failed to execute process")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if not_implemented(&stderr) {
+            return sidecar_tags(tools, s3_access, bucket_name, key).await;
+        }
+        return Err(eyre!("get-object-tagging failed for {}: {}", key, stderr.trim()));
+    }
+    let tags = serde_json::from_slice::<TagSet>(&output.stdout)
+        .wrap_err("Unable to parse get-object-tagging response")?
+        .tag_set;
+    // Tagged by the fallback of a store that accepts reading tags but not writing them.
+    if tags.is_empty() && s3_access.compat.sidecar_tags() {
+        return sidecar_tags(tools, s3_access, bucket_name, key).await;
+    }
+    Ok(tags)
+}
+
+/// The Object Lock and encryption headers of an object, as `head-object` reports them.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ObjectHead {
+    pub object_lock_mode: Option<String>,
+    pub object_lock_retain_until_date: Option<String>,
+    pub object_lock_legal_hold_status: Option<String>,
+    pub server_side_encryption: Option<String>,
+}
+
+pub async fn head_object(tools: &Tools, s3_access: &S3Access, bucket_name: &str, key: &str) -> Result<ObjectHead, Report> {
+    let output = run(tools, Aws::new(tools, s3_access).head_object(bucket_name, key), "head-object", key).await?;
+    serde_json::from_slice(&output.stdout).wrap_err("Unable to parse head-object response")
+}
+
+/// Whether a store answered that it lacks the operation altogether.
+pub fn not_implemented(stderr: &str) -> bool {
+    stderr.contains("NotImplemented")
+}
+
+/// Whether a store answered that the object does not exist.
+fn not_found(stderr: &str) -> bool {
+    stderr.contains("(404)") || stderr.contains("NoSuchKey")
+}
+
+/// The tags kept in the sidecar of `key`, none when it has no sidecar.
+async fn sidecar_tags(tools: &Tools, s3_access: &S3Access, bucket_name: &str, key: &str) -> Result<Vec<Tag>, Report> {
+    let sidecar = sidecar_key(key);
+    let output = tools.runner.run(Aws::new(tools, s3_access).download(bucket_name, &sidecar), None).await.wrap_err("failed to execute process")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        // An object without a sidecar has no tags, as an untagged object would.
+        if not_found(&stderr) {
+            return Ok(Vec::new());
+        }
+        return Err(eyre!("download failed for {}: {}", sidecar, stderr.trim()));
+    }
+    Ok(serde_json::from_slice::<TagSet>(&output.stdout)
+        .wrap_err_with(|| format!("Unable to parse {}", sidecar))?
+        .tag_set)
+}
+
+async fn put_sidecar(tools: &Tools, s3_access: &S3Access, bucket_name: &str, key: &str, tagging: &str) -> Result<(), Report> {
+    let sidecar = sidecar_key(key);
+    let output = tools.runner.run(Aws::new(tools, s3_access).upload(bucket_name, &sidecar), Some(tagging.as_bytes().to_vec())).await;
+    crate::process::succeeded(output).map(|_| ()).wrap_err_with(|| format!("Unable to store the tags of {} in {}", key, sidecar))
+}
+
+/// Stores the file at `body` as `key`, with user `metadata` (`name=value,...`) when given.
+pub async fn put_object(tools: &Tools, s3_access: &S3Access, bucket_name: &str, key: &str, body: &Path, metadata: Option<&str>) -> Result<(), Report> {
+    run(tools, Aws::new(tools, s3_access).put_object(bucket_name, key, body, metadata), "put-object", key)
+        .await
+        .map(|_| ())
+}
+
+/// Stores `contents` as `key`, for small objects, with user `metadata` (`name=value,...`) when
+/// given.
+pub async fn write_object(tools: &Tools, s3_access: &S3Access, bucket_name: &str, key: &str, contents: Vec<u8>, metadata: Option<&str>) -> Result<(), Report> {
+    let mut command = Aws::new(tools, s3_access).upload(bucket_name, key);
+    if let Some(metadata) = metadata {
+        command.arg("--metadata").arg(metadata);
+    }
+    let output = tools.runner.run(command, Some(contents)).await;
+    crate::process::succeeded(output).map(|_| ()).wrap_err_with(|| format!("upload failed for {}", key))
+}
+
+/// The contents of `key`, for small objects.
+pub async fn read_object(tools: &Tools, s3_access: &S3Access, bucket_name: &str, key: &str) -> Result<Vec<u8>, Report> {
+    Ok(run(tools, Aws::new(tools, s3_access).download(bucket_name, key), "download", key).await?.stdout)
+}
+
+/// Replaces the tags on `key` with `tagging`, a JSON `{"TagSet": [...]}` document. Stores without
+/// object tagging, by `--s3-compat-mode` or by answering NotImplemented, get the document as the
+/// object's sidecar instead.
+pub async fn put_object_tagging(
+    tools: &Tools,
+    s3_access: &S3Access,
+    bucket_name: &str,
+    key: &str,
+    tagging: &str,
+) -> Result<(), Report> {
+    if !s3_access.compat.tags_objects() {
+        return put_sidecar(tools, s3_access, bucket_name, key, tagging).await;
+    }
+    let tag_set: TagSet = serde_json::from_str(tagging).wrap_err_with(|| format!("The tags for {} are not a TagSet document", key))?;
+    tags::validate(&tag_set.tag_set).wrap_err_with(|| format!("Unable to tag {}", key))?;
+    let output = tools.runner.run(Aws::new(tools, s3_access).put_object_tagging(bucket_name, key, tagging), None).await.wrap_err("failed to execute process")?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if not_implemented(&stderr) {
+        tracing::warn!(target: "s3", key, sidecar = sidecar_key(key), "The store does not implement object tagging; storing the tags in a sidecar");
+        return put_sidecar(tools, s3_access, bucket_name, key, tagging).await;
+    }
+    Err(eyre!("put-object-tagging failed for {}: {}", key, stderr.trim()))
+}
+
+/// Tags every one of `keys` with `tagging`, `concurrency` at a time.
+pub async fn tag_objects(
+    tools: &Tools,
+    s3_access: &S3Access,
+    bucket_name: &str,
+    keys: &[&str],
+    tagging: &str,
+    concurrency: usize,
+) -> Result<(), Report> {
+    stream::iter(keys)
+        .map(|key| async move {
+            put_object_tagging(tools, s3_access, bucket_name, key, tagging).await?;
+            info!(target: "aws_put_object_tagging_output", key);
+            Ok::<_, Report>(())
+        })
+        .buffer_unordered(concurrency.max(1))
+        .try_collect::<Vec<_>>()
+        .await
+        .map(|_| ())
+}
+
+/// Copies `key` from one bucket to the same key in another, server side.
+pub async fn copy_object(
+    tools: &Tools,
+    s3_access: &S3Access,
+    from_bucket: &str,
+    to_bucket: &str,
+    key: &str,
+) -> Result<(), Report> {
+    run(tools, Aws::new(tools, s3_access).copy_object(from_bucket, to_bucket, key), "copy", key)
+        .await
+        .map(|_| ())
+}
+
+/// Deletes `key`, and its sidecar on stores whose tags may be kept in one, see
+/// [`S3Compat::sidecar_tags`]. A sidecar that was never written is no error.
+pub async fn delete_object(
+    tools: &Tools,
+    s3_access: &S3Access,
+    bucket_name: &str,
+    key: &str,
+) -> Result<(), Report> {
+    run(tools, Aws::new(tools, s3_access).delete_object(bucket_name, key), "delete-object", key).await?;
+    if !s3_access.compat.sidecar_tags() {
+        return Ok(());
+    }
+    let sidecar = sidecar_key(key);
+    let output = tools.runner.run(Aws::new(tools, s3_access).delete_object(bucket_name, &sidecar), None).await.wrap_err("failed to execute process")?;
+    if !output.status.success() && !not_found(&String::from_utf8_lossy(&output.stderr)) {
+        return Err(eyre!("delete-object failed for {}: {}", sidecar, process::captured("delete-object", &output.stderr).trim()));
+    }
+    Ok(())
+}
+
+/// Deletes every object under `prefix`.
+pub async fn delete_prefix(
+    tools: &Tools,
+    s3_access: &S3Access,
+    bucket_name: &str,
+    prefix: &str,
+) -> Result<(), Report> {
+    run(tools, Aws::new(tools, s3_access).delete_prefix(bucket_name, prefix), "recursive delete", prefix)
+        .await
+        .map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+    use std::path::PathBuf;
+
+    fn tools() -> Tools {
+        Tools { aws: PathBuf::from("/opt/bin/aws"), ..Tools::mock(std::sync::Arc::new(crate::process::SystemRunner)) }
+    }
+
+    #[test]
+    fn path_style_is_set_in_the_active_profile_only() {
+        let config = "[default]\nregion = eu-west-1\n\n[profile backups]\nregion = us-east-1\ns3 =\n    addressing_style = virtual\n    max_concurrent_requests = 4\n";
+        assert_eq!(
+            with_path_style(config, "profile backups"),
+            "[default]\nregion = eu-west-1\n\n[profile backups]\nregion = us-east-1\ns3 =\n    addressing_style = path\n    max_concurrent_requests = 4\n"
+        );
+        assert_eq!(
+            with_path_style(config, "default"),
+            "[default]\nregion = eu-west-1\ns3 =\n    addressing_style = path\n\n[profile backups]\nregion = us-east-1\ns3 =\n    addressing_style = virtual\n    max_concurrent_requests = 4\n"
+        );
+        assert_eq!(with_path_style("", "default"), "[default]\ns3 =\n    addressing_style = path\n");
+    }
+
+    #[test]
+    fn region_applies_to_every_call_and_bucket_creation() {
+        let tools = tools();
+        let access = S3Access { region: Some(String::from("eu-central-1")), ..S3Access::default() };
+        let aws = Aws::new(&tools, &access);
+        assert_eq!(
+            argv(&aws.create_bucket("bk")),
+            [
+                "--region", "eu-central-1", "s3api", "create-bucket", "--bucket", "bk", "--output", "json",
+                "--create-bucket-configuration", "LocationConstraint=eu-central-1",
+            ]
+        );
+        let access = S3Access { region: Some(String::from("us-east-1")), ..S3Access::default() };
+        assert!(!argv(&Aws::new(&tools, &access).create_bucket("bk")).contains(&String::from("--create-bucket-configuration")));
+        // RGW rejects AWS region names as location constraints.
+        let access = S3Access { region: Some(String::from("eu-central-1")), compat: S3Compat::Ceph, ..S3Access::default() };
+        assert!(!argv(&Aws::new(&tools, &access).create_bucket("bk")).contains(&String::from("--create-bucket-configuration")));
+    }
+
+    #[test]
+    fn path_style_config_is_passed_to_aws() {
+        let tools = tools();
+        let access = S3Access { path_style_config: Some(Arc::new(ConfigFile(PathBuf::from("/tmp/aws-config")))), ..S3Access::default() };
+        let command = Aws::new(&tools, &access).head_bucket("bk");
+        let env = command.as_std().get_envs().find(|(key, _)| *key == "AWS_CONFIG_FILE").and_then(|(_, value)| value);
+        assert_eq!(env, Some(OsStr::new("/tmp/aws-config")));
+    }
+
+    #[test]
+    fn path_style_config_is_written_once_and_removed_with_its_last_access() {
+        let mut first = S3Access { credentials: Credentials::Profile(String::from("path-style-test")), ..S3Access::default() };
+        first.force_path_style().unwrap();
+        let mut second = first.clone();
+        second.path_style_config = None;
+        second.force_path_style().unwrap();
+        let path = first.path_style_config.as_ref().unwrap().path().to_path_buf();
+        assert_eq!(second.path_style_config.as_ref().unwrap().path(), path);
+        assert!(std::fs::read_to_string(&path).unwrap().contains("[profile path-style-test]"));
+        drop(first);
+        assert!(path.exists());
+        drop(second);
+        assert!(!path.exists());
+    }
+
+    fn argv(command: &Command) -> Vec<String> {
+        command.as_std().get_args().map(|arg| arg.to_string_lossy().into_owned()).collect()
+    }
+
+    fn env(command: &Command, name: &str) -> Option<String> {
+        command
+            .as_std()
+            .get_envs()
+            .find(|(key, _)| *key == OsStr::new(name))
+            .and_then(|(_, value)| value.map(|value| value.to_string_lossy().into_owned()))
+    }
+
+    #[test]
+    fn user_agent_and_request_payer_reach_aws() {
+        let tools = tools();
+        let access = S3Access { user_agent: Some(String::from("btagger-1.2.3")), request_payer: true, ..S3Access::default() };
+        let aws = Aws::new(&tools, &access);
+        assert_eq!(env(&aws.head_bucket("bk"), "AWS_SDK_UA_APP_ID").as_deref(), Some("btagger-1.2.3"));
+        assert_eq!(argv(&aws.download("bk", "k")), ["s3", "cp", "s3://bk/k", "-", "--request-payer", "requester"]);
+        // Bucket operations reject the option.
+        assert_eq!(argv(&aws.head_bucket("bk")), ["s3api", "head-bucket", "--bucket", "bk"]);
+    }
+
+    #[test]
+    fn commands_run_the_resolved_binary() {
+        let tools = tools();
+        assert_eq!(Aws::new(&tools, &S3Access::default()).create_bucket("bk").as_std().get_program(), "/opt/bin/aws");
+    }
+
+    #[test]
+    fn endpoint_override_adds_the_url_and_credentials() {
+        let (tools, endpoint) = (tools(), S3Access::minio());
+        let command = Aws::new(&tools, &endpoint).create_bucket("bk");
+        assert_eq!(
+            argv(&command),
+            ["--endpoint-url", "http://minio:9000", "s3api", "create-bucket", "--bucket", "bk", "--output", "json"]
+        );
+        assert_eq!(env(&command, "AWS_ACCESS_KEY_ID").as_deref(), Some("id"));
+        assert_eq!(env(&command, "AWS_SECRET_ACCESS_KEY").as_deref(), Some("key"));
+    }
+
+    #[test]
+    fn host_defaults_leave_credentials_alone() {
+        let tools = tools();
+        let command = Aws::new(&tools, &S3Access::default()).create_bucket("bk");
+        assert_eq!(argv(&command), ["s3api", "create-bucket", "--bucket", "bk", "--output", "json"]);
+        assert_eq!(env(&command, "AWS_ACCESS_KEY_ID"), None);
+    }
+
+    #[test]
+    fn aws_profile_is_exported() {
+        let tools = tools();
+        let access = S3Access { endpoint: None, credentials: Credentials::Profile(String::from("backups")), ..S3Access::default() };
+        let command = Aws::new(&tools, &access).head_bucket("bk");
+        assert_eq!(argv(&command), ["s3api", "head-bucket", "--bucket", "bk"]);
+        assert_eq!(env(&command, "AWS_PROFILE").as_deref(), Some("backups"));
+        assert_eq!(env(&command, "AWS_ACCESS_KEY_ID"), None);
+    }
+
+    #[test]
+    fn endpoint_works_with_inherited_credentials() {
+        let tools = tools();
+        let access = S3Access { endpoint: Some(String::from("http://minio:9000")), credentials: Credentials::Env, ..S3Access::default() };
+        let command = Aws::new(&tools, &access).head_bucket("bk");
+        assert_eq!(argv(&command), ["--endpoint-url", "http://minio:9000", "s3api", "head-bucket", "--bucket", "bk"]);
+        assert_eq!(env(&command, "AWS_ACCESS_KEY_ID"), None);
+    }
+
+    #[test]
+    fn repeated_invocations_do_not_accumulate_arguments() {
+        let (tools, endpoint) = (tools(), S3Access::minio());
+        let aws = Aws::new(&tools, &endpoint);
+        let _ = aws.create_bucket("bk");
+        let _ = aws.list_objects("bk", "tikv/");
+        for key in ["tikv/a", "tikv/b"] {
+            assert_eq!(
+                argv(&aws.put_object_tagging("bk", key, r#"{"TagSet":[]}"#)),
+                ["--endpoint-url", "http://minio:9000", "s3api", "put-object-tagging", "--bucket", "bk", "--tagging", r#"{"TagSet":[]}"#, "--key", key]
+            );
+        }
+    }
+
+    #[test]
+    fn object_operations_argv() {
+        let tools = tools();
+        let access = S3Access::default();
+        let aws = Aws::new(&tools, &access);
+        assert_eq!(
+            argv(&aws.list_objects("bk", "surrealdb/ns/")),
+            ["s3api", "list-objects-v2", "--bucket", "bk", "--prefix", "surrealdb/ns/", "--output", "json"]
+        );
+        assert_eq!(
+            argv(&aws.get_object_tagging("bk", "k")),
+            ["s3api", "get-object-tagging", "--bucket", "bk", "--key", "k", "--output", "json"]
+        );
+        assert_eq!(argv(&aws.copy_object("from", "to", "k")), ["s3", "cp", "s3://from/k", "s3://to/k"]);
+        assert_eq!(argv(&aws.download("bk", "k")), ["s3", "cp", "s3://bk/k", "-"]);
+        assert_eq!(argv(&aws.delete_object("bk", "k")), ["s3api", "delete-object", "--bucket", "bk", "--key", "k"]);
+        assert_eq!(argv(&aws.delete_prefix("bk", "tikv/x")), ["s3", "rm", "s3://bk/tikv/x", "--recursive"]);
+        assert_eq!(argv(&aws.head_bucket("bk")), ["s3api", "head-bucket", "--bucket", "bk"]);
+        assert_eq!(
+            argv(&aws.put_bucket_configuration("bk", "put-bucket-versioning", "--versioning-configuration", r#"{"Status":"Enabled"}"#)),
+            ["s3api", "put-bucket-versioning", "--bucket", "bk", "--versioning-configuration", r#"{"Status":"Enabled"}"#]
+        );
+        assert_eq!(
+            argv(&aws.put_object("bk", "k", Path::new("/tmp/body"), None)),
+            ["s3api", "put-object", "--bucket", "bk", "--key", "k", "--body", "/tmp/body"]
+        );
+        assert_eq!(
+            argv(&aws.put_object("bk", "k", Path::new("/tmp/body"), Some("a=1"))),
+            ["s3api", "put-object", "--bucket", "bk", "--key", "k", "--body", "/tmp/body", "--metadata", "a=1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn garage_keeps_tags_in_sidecars_and_never_creates_buckets() {
+        let runner = std::sync::Arc::new(crate::process::MockRunner::new(|call| {
+            if call.has_args(&["list-objects-v2"]) {
+                crate::process::MockRunner::output(0, r#"{"Contents":[{"Key":"k.zst","Size":10},{"Key":"k.zst.tags.json","Size":2}]}"#, "")
+            } else if call.has_args(&["s3://bk/missing.tags.json"]) {
+                crate::process::MockRunner::output(1, "", "fatal error: An error occurred (404) when calling the HeadObject operation")
+            } else if call.has_args(&["s3://bk/k.zst.tags.json", "-"]) {
+                crate::process::MockRunner::output(0, r#"{"TagSet":[{"Key":"daily","Value":"1"}]}"#, "")
+            } else {
+                crate::process::MockRunner::output(0, "", "")
+            }
+        }));
+        let tools = Tools { runner: runner.clone(), ..tools() };
+        let access = S3Access { compat: S3Compat::Garage, ..S3Access::default() };
+        let tagging = r#"{"TagSet":[{"Key":"daily","Value":"1"}]}"#;
+
+        assert!(!ensure_bucket(&tools, &access, "bk").await.unwrap());
+        put_object_tagging(&tools, &access, "bk", "k.zst", tagging).await.unwrap();
+        let keys = list_objects(&tools, &access, "bk", "").await.unwrap().into_iter().map(|object| object.key).collect::<Vec<_>>();
+        assert_eq!(keys, ["k.zst"]);
+        assert_eq!(object_tags(&tools, &access, "bk", "k.zst").await.unwrap()[0].key, "daily");
+        assert!(object_tags(&tools, &access, "bk", "missing").await.unwrap().is_empty());
+        delete_object(&tools, &access, "bk", "k.zst").await.unwrap();
+
+        let calls = runner.calls();
+        assert!(calls[0].has_args(&["head-bucket"]));
+        assert_eq!(calls[1].args, ["s3", "cp", "-", "s3://bk/k.zst.tags.json"]);
+        assert_eq!(calls[1].stdin.as_deref(), Some(tagging.as_bytes()));
+        assert!(calls.iter().all(|call| !call.has_args(&["put-object-tagging"]) && !call.has_args(&["create-bucket"])));
+        assert!(calls[calls.len() - 1].has_args(&["--key", "k.zst.tags.json"]));
+    }
+
+    #[tokio::test]
+    async fn tagging_falls_back_to_a_sidecar_when_not_implemented() {
+        let runner = std::sync::Arc::new(crate::process::MockRunner::new(|call| {
+            if call.has_args(&["put-object-tagging"]) || call.has_args(&["get-object-tagging"]) {
+                crate::process::MockRunner::output(254, "", "An error occurred (NotImplemented) when calling the PutObjectTagging operation")
+            } else if call.has_args(&["s3://bk/k.zst.tags.json", "-"]) {
+                crate::process::MockRunner::output(0, r#"{"TagSet":[{"Key":"weekly","Value":"1"}]}"#, "")
+            } else {
+                crate::process::MockRunner::output(0, "", "")
+            }
+        }));
+        let tools = Tools { runner: runner.clone(), ..tools() };
+        let access = S3Access::default();
+
+        put_object_tagging(&tools, &access, "bk", "k.zst", r#"{"TagSet":[]}"#).await.unwrap();
+        assert_eq!(object_tags(&tools, &access, "bk", "k.zst").await.unwrap()[0].key, "weekly");
+        let calls = runner.calls();
+        assert!(calls[0].has_args(&["put-object-tagging"]));
+        assert_eq!(calls[1].args, ["s3", "cp", "-", "s3://bk/k.zst.tags.json"]);
+        assert!(calls[2].has_args(&["get-object-tagging"]));
+
+        let runner = std::sync::Arc::new(crate::process::MockRunner::new(|_| crate::process::MockRunner::output(1, "", "An error occurred (AccessDenied)")));
+        let denied = Tools { runner: runner.clone(), ..tools };
+        assert!(put_object_tagging(&denied, &access, "bk", "k.zst", r#"{"TagSet":[]}"#).await.is_err());
+        assert_eq!(runner.calls().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn sidecars_are_only_looked_for_where_tags_may_be_in_them() {
+        let runner = std::sync::Arc::new(crate::process::MockRunner::new(|call| {
+            if call.has_args(&["get-object-tagging"]) {
+                crate::process::MockRunner::output(0, r#"{"TagSet":[]}"#, "")
+            } else if call.has_args(&["s3://bk/k.zst.tags.json", "-"]) {
+                crate::process::MockRunner::output(1, "", "fatal error: An error occurred (404) when calling the HeadObject operation")
+            } else if call.has_args(&["--key", "k.zst.tags.json"]) {
+                crate::process::MockRunner::output(254, "", "An error occurred (NoSuchKey) when calling the DeleteObject operation")
+            } else {
+                crate::process::MockRunner::output(0, "", "")
+            }
+        }));
+        let tools = Tools { runner: runner.clone(), ..tools() };
+
+        let aws = S3Access::default();
+        assert!(object_tags(&tools, &aws, "bk", "k.zst").await.unwrap().is_empty());
+        delete_object(&tools, &aws, "bk", "k.zst").await.unwrap();
+        let calls = runner.calls();
+        assert_eq!(calls.len(), 2);
+        assert!(calls.iter().all(|call| !call.args.iter().any(|arg| arg.contains(SIDECAR_SUFFIX))));
+
+        // SeaweedFS reads tags it cannot write, so the sidecar is checked, and a missing one is fine.
+        let seaweedfs = S3Access { compat: S3Compat::Seaweedfs, ..S3Access::default() };
+        assert!(object_tags(&tools, &seaweedfs, "bk", "k.zst").await.unwrap().is_empty());
+        delete_object(&tools, &seaweedfs, "bk", "k.zst").await.unwrap();
+        let calls = &runner.calls()[2..];
+        assert_eq!(calls.len(), 4);
+        assert!(calls[1].has_args(&["s3://bk/k.zst.tags.json", "-"]));
+        assert!(calls[3].has_args(&["--key", "k.zst.tags.json"]));
+    }
+}
@@ -0,0 +1,155 @@
+use std::convert::Infallible;
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+const MASK: &str = "******";
+
+/// Secrets shorter than this are only masked where they stand alone, so a one- or two-letter
+/// value doesn't starve every log line of that letter.
+const MIN_INLINE_LEN: usize = 8;
+
+/// Every secret value seen by the process; consulted by [`redact`].
+static REGISTRY: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// A credential value that never prints its contents.
+///
+/// Constructing a `Secret` registers its value so that [`redact`] (and therefore every log
+/// line and error report) masks it, even when it leaks through external command output.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        let value = value.into();
+        if !value.trim().is_empty() {
+            let mut registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+            if !registry.contains(&value) {
+                registry.push(value.clone());
+            }
+        }
+        Secret(value)
+    }
+
+    /// The raw value, for handing to external commands only.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for Secret {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Secret::new(s))
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(MASK)
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(MASK)
+    }
+}
+
+/// Masks every registered secret value in `text`.
+pub fn redact(text: &str) -> String {
+    let registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+    registry.iter().fold(text.to_string(), |text, secret| match secret.len() < MIN_INLINE_LEN {
+        true => mask_tokens(&text, secret),
+        false => text.replace(secret.as_str(), MASK),
+    })
+}
+
+/// Masks the occurrences of `secret` not directly next to a letter, digit or underscore.
+fn mask_tokens(text: &str, secret: &str) -> String {
+    let is_word = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+    let mut masked = String::with_capacity(text.len());
+    let mut rest = 0;
+    for (start, _) in text.match_indices(secret) {
+        let end = start + secret.len();
+        if start < rest || is_word(text[..start].chars().next_back()) || is_word(text[end..].chars().next()) {
+            continue;
+        }
+        masked.push_str(&text[rest..start]);
+        masked.push_str(MASK);
+        rest = end;
+    }
+    masked.push_str(&text[rest..]);
+    masked
+}
+
+/// Writer adapter that redacts everything passing through it; used for the tracing output.
+pub struct RedactingWriter<W>(pub W);
+
+impl<W: Write> Write for RedactingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        self.0.write_all(redact(&text).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The registry is process-wide, so every test registers values of its own.
+
+    #[test]
+    fn redact_masks_registered_values() {
+        let secret = Secret::new("hunter2-redact-test");
+        assert_eq!(redact("password=hunter2-redact-test; again hunter2-redact-test"), "password=******; again ******");
+        assert_eq!(redact("nothing to hide"), "nothing to hide");
+        assert_eq!(secret.expose(), "hunter2-redact-test");
+    }
+
+    #[test]
+    fn short_secrets_are_masked_only_as_whole_tokens() {
+        Secret::new("q7z");
+        assert_eq!(redact("at q7z, then=q7z, \"q7z\""), "at ******, then=******, \"******\"");
+        assert_eq!(redact("aq7z q7zb q7z_x"), "aq7z q7zb q7z_x");
+    }
+
+    #[test]
+    fn blank_values_are_not_registered() {
+        Secret::new("  ");
+        assert_eq!(redact("a  b"), "a  b");
+    }
+
+    #[test]
+    fn debug_and_display_never_show_the_value() {
+        let secret = Secret::new("debug-display-test-value");
+        assert_eq!(format!("{:?}", secret), MASK);
+        assert_eq!(format!("{}", secret), MASK);
+        assert_eq!(format!("{:?}", Some(secret)), "Some(******)");
+    }
+
+    #[test]
+    fn registering_a_value_twice_keeps_one_entry() {
+        Secret::new("dedup-test-value");
+        "dedup-test-value".parse::<Secret>().unwrap();
+        let registry = REGISTRY.lock().unwrap();
+        assert_eq!(registry.iter().filter(|value| *value == "dedup-test-value").count(), 1);
+    }
+
+    #[test]
+    fn redacting_writer_masks_what_passes_through() {
+        Secret::new("writer-test-value");
+        let mut writer = RedactingWriter(Vec::new());
+        let line = b"token writer-test-value\n";
+        assert_eq!(writer.write(line).unwrap(), line.len());
+        writer.flush().unwrap();
+        assert_eq!(writer.0, b"token ******\n");
+    }
+}
@@ -0,0 +1,149 @@
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use color_eyre::eyre::Report;
+use std::str::FromStr;
+
+use crate::output::{OutputFormat, Rows};
+use crate::size::ByteSize;
+use crate::tags::{self, Schedule, Tag};
+
+/// Retention tiers from shortest- to longest-lived; an object is kept as long as its highest tier says.
+pub const TIERS: [&str; 6] = ["standard", "nightly", "weekly", "monthly", "quarterly", "yearly"];
+
+/// Days each tier is retained before expiring, e.g. `standard=3,nightly=7,weekly=35`.
+#[derive(Clone, Debug)]
+pub struct RetentionPolicy {
+    days: [i64; TIERS.len()],
+}
+
+impl Default for RetentionPolicy {
+    /// The expirations from the README's lifecycle configuration.
+    fn default() -> Self {
+        RetentionPolicy { days: [3, 7, 35, 190, 370, 1096] }
+    }
+}
+
+impl FromStr for RetentionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut policy = RetentionPolicy::default();
+        for entry in s.split(',').filter(|entry| !entry.trim().is_empty()) {
+            let (tier, days) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("'{}' is not in the form tier=days", entry))?;
+            let index = TIERS
+                .iter()
+                .position(|known| *known == tier.trim())
+                .ok_or_else(|| format!("Unknown tier '{}', expected one of {}", tier, TIERS.join(", ")))?;
+            policy.days[index] = days
+                .trim()
+                .parse()
+                .map_err(|_| format!("'{}' is not a number of days", days))?;
+        }
+        Ok(policy)
+    }
+}
+
+impl RetentionPolicy {
+    /// Days objects of the tier named `tier` are kept, if it is one of [`TIERS`].
+    pub fn days(&self, tier: &str) -> Option<i64> {
+        TIERS.iter().position(|known| *known == tier).map(|index| self.days[index])
+    }
+}
+
+/// Index into [`TIERS`] of the longest-lived tier in `tags`.
+pub fn tier_of(tags: &[Tag]) -> usize {
+    tags.iter()
+        .filter_map(|tag| TIERS.iter().position(|tier| *tier == tag.key))
+        .max()
+        .unwrap_or(0)
+}
+
+struct SimulatedBackup {
+    created: DateTime<Utc>,
+    expires: DateTime<Utc>,
+    tier: usize,
+}
+
+/// Generates the scheduled backups between `from` and `to`, tags them, and prints how many
+/// objects of each tier would exist at every `step_days` along with the estimated storage.
+pub fn run(
+    schedule: &Schedule,
+    policy: &RetentionPolicy,
+    from: NaiveDate,
+    to: NaiveDate,
+    step_days: i64,
+    backup_size: ByteSize,
+    format: OutputFormat,
+) -> Result<(), Report> {
+    let mut backups = Vec::new();
+    let mut day = from;
+    while day <= to {
+        let midnight = day.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc();
+        // Same trigger times as a "{minutes} {day_offset}/{every_n} * * *" cron schedule.
+        let mut hour = schedule.day_offset_in_hours;
+        while hour < 24 {
+            let created = midnight + Duration::hours(hour) + Duration::minutes(schedule.minutes_offset_from_hour);
+            let tier = tier_of(&tags::matched(&tags::evaluate(schedule, created)?));
+            backups.push(SimulatedBackup {
+                created,
+                expires: created + Duration::days(policy.days[tier]),
+                tier,
+            });
+            hour += schedule.every_n_hours.max(1);
+        }
+        day = day.succ_opt().unwrap_or(NaiveDate::MAX);
+    }
+
+    let mut days = Vec::new();
+    let mut peak = (0, from);
+    let mut day = from;
+    while day <= to {
+        let at = (day + Duration::days(1)).and_hms_opt(0, 0, 0).unwrap_or_default().and_utc();
+        let mut counts = [0u64; TIERS.len()];
+        for backup in backups.iter().filter(|backup| backup.created < at && at <= backup.expires) {
+            counts[backup.tier] += 1;
+        }
+        let objects: u64 = counts.iter().sum();
+        if objects > peak.0 {
+            peak = (objects, day);
+        }
+        days.push((day, counts, objects));
+        day += Duration::days(step_days.max(1));
+    }
+
+    if format != OutputFormat::Table {
+        let columns = ["date"].into_iter().chain(TIERS).chain(["objects", "bytes"]).collect::<Vec<_>>();
+        let mut rows = Rows::new(&columns);
+        for (day, counts, objects) in &days {
+            let row = [day.to_string()].into_iter().chain(counts.iter().map(u64::to_string)).chain([objects.to_string(), (objects * backup_size.0).to_string()]);
+            rows.push(row.collect());
+        }
+        println!("{}", rows.render(format));
+        return Ok(());
+    }
+    println!(
+        "{:<12}{}{:>9}{:>12}",
+        "date",
+        TIERS.iter().map(|tier| format!("{:>11}", tier)).collect::<String>(),
+        "objects",
+        "storage"
+    );
+    for (day, counts, objects) in &days {
+        println!(
+            "{:<12}{}{:>9}{:>12}",
+            day.to_string(),
+            counts.iter().map(|count| format!("{:>11}", count)).collect::<String>(),
+            objects,
+            ByteSize(objects * backup_size.0).to_string()
+        );
+    }
+    println!(
+        "peak: {} objects ({}) on {}; {} backups taken in total",
+        peak.0,
+        ByteSize(peak.0 * backup_size.0),
+        peak.1,
+        backups.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,48 @@
+use std::fmt;
+use std::str::FromStr;
+
+const UNITS: [(&str, u64); 7] = [
+    ("TiB", 1 << 40),
+    ("GiB", 1 << 30),
+    ("MiB", 1 << 20),
+    ("KiB", 1 << 10),
+    ("T", 1_000_000_000_000),
+    ("G", 1_000_000_000),
+    ("M", 1_000_000),
+];
+
+/// Byte count parsed from human-friendly strings such as `64MiB`, `5GiB`, `10M` or `1024`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (number, multiplier) = UNITS
+            .iter()
+            .chain([("K", 1_000), ("B", 1)].iter())
+            .find_map(|(suffix, multiplier)| s.strip_suffix(suffix).map(|number| (number, *multiplier)))
+            .unwrap_or((s, 1));
+        let number: u64 = number
+            .trim()
+            .parse()
+            .map_err(|_| format!("'{}' is not a size like 64MiB, 5GiB or 1048576", s))?;
+        number
+            .checked_mul(multiplier)
+            .map(ByteSize)
+            .ok_or_else(|| format!("'{}' is too large", s))
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (suffix, multiplier) in UNITS.iter().take(4) {
+            if self.0 >= *multiplier && self.0.is_multiple_of(*multiplier) {
+                return write!(f, "{}{}", self.0 / multiplier, suffix);
+            }
+        }
+        write!(f, "{}B", self.0)
+    }
+}
@@ -0,0 +1,201 @@
+use color_eyre::eyre::{eyre, Report, WrapErr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::process::Command;
+use tracing::info;
+
+use crate::archive::ArchiveUpload;
+use crate::compression::Compression;
+use crate::failure::Failure;
+use crate::multipart::{ObjectMetadata, Split, Spool};
+use crate::process::{self, before_deadline};
+use crate::s3::{self, S3Access};
+use crate::size::ByteSize;
+use crate::summary::{BackupReport, EmptyBackup, Timings};
+use crate::tools::Tools;
+
+/// An SQLite database file to back up, and where to.
+pub struct SqliteOptions {
+    pub tools: Tools,
+    pub bucket_name: String,
+    pub s3_access: S3Access,
+    pub create_bucket: bool,
+    pub path: PathBuf,
+    pub tags: String,
+    pub storage_key: String,
+    pub compression: Compression,
+    pub compression_level: Option<u32>,
+    pub part_size: ByteSize,
+    pub part_retries: u32,
+    pub spool: Option<Spool>,
+    pub split: Option<Split>,
+    pub min_expected_bytes: u64,
+    pub allow_empty: bool,
+    pub concurrency: usize,
+    pub deadline: Option<tokio::time::Instant>,
+    pub timings: Timings,
+}
+
+/// Takes a consistent copy with sqlite3's `.backup`, which works while the owning service keeps
+/// writing, then compresses, uploads and tags the copy. The copy is removed whatever the outcome.
+pub async fn backup(options: &SqliteOptions) -> Result<BackupReport, Report> {
+    // The counter keeps `run-all --parallel` jobs apart.
+    static COPIED: AtomicUsize = AtomicUsize::new(0);
+    let copy = std::env::temp_dir().join(format!("btagger-sqlite-{}-{}.db", std::process::id(), COPIED.fetch_add(1, Ordering::Relaxed)));
+    let result = backup_through(options, &copy).await;
+    if let Err(err) = tokio::fs::remove_file(&copy).await {
+        if err.kind() != std::io::ErrorKind::NotFound {
+            tracing::warn!(target: "sqlite", copy = %copy.display(), error = %err, "Unable to remove the copy");
+        }
+    }
+    result
+}
+
+async fn backup_through(options: &SqliteOptions, copy: &Path) -> Result<BackupReport, Report> {
+    let metadata = ObjectMetadata::now();
+    let (tools, s3_access, bucket_name, key) = (&options.tools, &options.s3_access, &options.bucket_name, &options.storage_key);
+    let timings = &options.timings;
+    if options.create_bucket {
+        let created = timings.time("bucket_ensure", s3::ensure_bucket(tools, s3_access, bucket_name)).await.wrap_err(Failure::Upload)?;
+        info!(target: "aws_create_bucket_output", bucket = bucket_name, created);
+    }
+
+    // Read-only, so a mistyped path fails instead of backing up a new, empty database.
+    let mut command = Command::new(&tools.sqlite3);
+    command.kill_on_drop(true).arg("-bail").arg("-readonly").arg(&options.path);
+    let script = backup_script(copy);
+    timings.time("export", before_deadline(options.deadline, "sqlite3 .backup", tools.runner.run(command, Some(script.into_bytes()))))
+        .await
+        .and_then(process::succeeded)
+        .wrap_err(Failure::Export { stage: String::from("sqlite3 .backup") })?;
+    let raw_bytes = tokio::fs::metadata(copy)
+        .await
+        .wrap_err_with(|| format!("sqlite3 left no copy at {}", copy.display()))?
+        .len();
+    // A database without a single table, index or view is empty, e.g. a path the service has
+    // not written to yet.
+    let mut command = Command::new(&tools.sqlite3);
+    command.kill_on_drop(true).arg("-bail").arg("-readonly").arg(copy).arg("SELECT count(*) FROM sqlite_master;");
+    let output = process::succeeded(tools.runner.run(command, None).await).wrap_err(Failure::Export { stage: String::from("sqlite3 schema check") })?;
+    let objects = String::from_utf8_lossy(&output.stdout).trim().parse::<u64>().wrap_err("sqlite3 did not count the schema's objects")?;
+    if objects == 0 && !options.allow_empty {
+        return Err(Report::new(EmptyBackup(format!("SQLite database {}", options.path.display()))));
+    }
+    // Checked before uploading, so nothing needs removing.
+    if raw_bytes < options.min_expected_bytes {
+        return Err(eyre!("{} is only {} bytes, below --min-expected-bytes {}", options.path.display(), raw_bytes, options.min_expected_bytes)
+            .wrap_err(Failure::Export { stage: String::from("size check") }));
+    }
+
+    let upload = ArchiveUpload {
+        tools,
+        s3_access,
+        bucket_name,
+        compression: options.compression,
+        compression_level: options.compression_level,
+        part_size: options.part_size,
+        part_retries: options.part_retries,
+        spool: options.spool.clone(),
+        split: options.split.clone(),
+        concurrency: options.concurrency,
+        deadline: options.deadline,
+        metadata,
+    };
+    let bytes = timings.time("upload", upload.upload_file(copy, key)).await?;
+    info!(target: "backup_size", key, raw_bytes, bytes);
+
+    timings.time("tagging", s3::put_object_tagging(tools, s3_access, bucket_name, key, &options.tags)).await.wrap_err(Failure::Tagging)?;
+    info!(target: "aws_put_object_tagging_output", key);
+    Ok(BackupReport {
+        storage_key: key.clone(),
+        bytes,
+        raw_bytes: Some(raw_bytes),
+        success: true,
+        stderr_tail: None,
+        tikv_stats: None,
+        signal: None,
+    })
+}
+
+/// Waits for the service's locks rather than failing at once, then copies the database.
+fn backup_script(copy: &Path) -> String {
+    format!(".timeout 10000\n.backup '{}'\n", copy.display())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::MockRunner;
+    use std::sync::Arc;
+
+    /// Stands in for sqlite3, writing the copy the script asks for and counting `tables` schema
+    /// objects in it, and for a store that takes every upload.
+    fn runner(tables: &'static str) -> Arc<MockRunner> {
+        Arc::new(MockRunner::new(move |call| {
+            if call.program == "sqlite3" && call.has_args(&["SELECT count(*) FROM sqlite_master;"]) {
+                MockRunner::output(0, tables, "")
+            } else if call.program == "sqlite3" {
+                let script = String::from_utf8(call.stdin.clone().unwrap()).unwrap();
+                let copy = script.split('\'').nth(1).unwrap();
+                std::fs::write(copy, b"SQLite format 3\0").unwrap();
+                MockRunner::output(0, "", "")
+            } else if call.has_args(&["create-multipart-upload"]) {
+                MockRunner::output(0, r#"{"UploadId":"u-1"}"#, "")
+            } else if call.has_args(&["upload-part"]) {
+                MockRunner::output(0, r#"{"ETag":"\"e-1\""}"#, "")
+            } else {
+                MockRunner::output(0, "{}", "")
+            }
+        }))
+    }
+
+    fn options(runner: Arc<MockRunner>) -> SqliteOptions {
+        SqliteOptions {
+            tools: Tools::mock(runner),
+            bucket_name: String::from("bk"),
+            s3_access: S3Access::default(),
+            create_bucket: false,
+            path: PathBuf::from("/srv/app/app.db"),
+            tags: String::from(r#"{"TagSet":[]}"#),
+            storage_key: String::from("sqlite/app/2025-01-01.04-30.db"),
+            compression: Compression::None,
+            compression_level: None,
+            part_size: ByteSize(8 * 1024 * 1024),
+            part_retries: 0,
+            spool: None,
+            split: None,
+            min_expected_bytes: 0,
+            allow_empty: false,
+            concurrency: 4,
+            deadline: None,
+            timings: Timings::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn copy_is_uploaded_tagged_and_removed() {
+        let runner = runner("3\n");
+        let report = backup(&options(runner.clone())).await.unwrap();
+        assert_eq!((report.bytes, report.raw_bytes), (16, Some(16)));
+
+        let calls = runner.calls();
+        assert!(calls[0].has_args(&["-bail", "-readonly", "/srv/app/app.db"]));
+        let script = String::from_utf8(calls[0].stdin.clone().unwrap()).unwrap();
+        assert!(script.starts_with(".timeout 10000\n.backup '"), "{}", script);
+        assert!(!Path::new(script.split('\'').nth(1).unwrap()).exists());
+        assert!(calls.iter().any(|call| call.has_args(&["complete-multipart-upload"])));
+        assert!(calls.last().unwrap().has_args(&["put-object-tagging"]));
+    }
+
+    #[tokio::test]
+    async fn a_database_without_tables_is_empty_unless_allowed() {
+        let runner = runner("0\n");
+        let err = backup(&options(runner.clone())).await.unwrap_err();
+        assert!(err.downcast_ref::<EmptyBackup>().is_some(), "{:#}", err);
+        assert!(!runner.calls().iter().any(|call| call.has_args(&["create-multipart-upload"])));
+
+        let runner = self::runner("0\n");
+        backup(&SqliteOptions { allow_empty: true, ..options(runner.clone()) }).await.unwrap();
+        assert!(runner.calls().last().unwrap().has_args(&["put-object-tagging"]));
+    }
+}
@@ -0,0 +1,156 @@
+use clap::Subcommand;
+use color_eyre::eyre::{eyre, Report, WrapErr};
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+use tracing::info;
+
+use crate::history::RunRecord;
+use crate::process;
+use crate::tools::Tools;
+
+/// Tables of the local catalog: a row per run, indexed by start time.
+const SCHEMA: &str = "\
+CREATE TABLE IF NOT EXISTS runs (
+    started TEXT NOT NULL,
+    finished TEXT NOT NULL,
+    command TEXT NOT NULL,
+    success INTEGER NOT NULL,
+    error_code TEXT,
+    keys TEXT NOT NULL,
+    bytes INTEGER NOT NULL,
+    raw_bytes INTEGER,
+    version TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS runs_started ON runs (started);
+";
+
+/// Moving the catalog between hosts.
+#[derive(Subcommand, Debug)]
+pub enum StateAction {
+    /// Write the catalog as SQL, to stdout or --output.
+    Export {
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Load SQL written by `state export` into a new --state-db.
+    Import {
+        input: PathBuf,
+    },
+}
+
+/// The SQLite catalog at `--state-db`, kept with the sqlite3 CLI.
+pub struct StateDb<'a> {
+    pub tools: &'a Tools,
+    pub path: &'a Path,
+}
+
+impl StateDb<'_> {
+    /// Runs `sql` against the catalog, creating the tables first.
+    async fn execute(&self, sql: &str) -> Result<Vec<u8>, Report> {
+        let mut command = Command::new(&self.tools.sqlite3);
+        command.kill_on_drop(true).arg("-bail").arg(self.path);
+        let script = format!("{}{}", SCHEMA, sql);
+        let output = process::succeeded(self.tools.runner.run(command, Some(script.into_bytes())).await)
+            .wrap_err_with(|| format!("Unable to use the state database {}", self.path.display()))?;
+        Ok(output.stdout)
+    }
+
+    pub async fn record_run(&self, record: &RunRecord) -> Result<(), Report> {
+        let sql = format!(
+            "INSERT INTO runs (started, finished, command, success, error_code, keys, bytes, raw_bytes, version) VALUES ({}, {}, {}, {}, {}, {}, {}, {}, {});\n",
+            quote(&record.started.to_rfc3339()),
+            quote(&record.finished.to_rfc3339()),
+            quote(&record.command),
+            u8::from(record.success),
+            record.error_code.as_deref().map_or_else(|| String::from("NULL"), quote),
+            quote(&record.keys.join(" ")),
+            record.bytes,
+            record.raw_bytes.map_or_else(|| String::from("NULL"), |raw_bytes| raw_bytes.to_string()),
+            quote(&record.version)
+        );
+        self.execute(&sql).await?;
+        info!(target: "state_db", path = %self.path.display(), command = record.command, "Run recorded");
+        Ok(())
+    }
+
+    /// The whole catalog as SQL statements.
+    pub async fn export(&self) -> Result<Vec<u8>, Report> {
+        self.execute(".dump\n").await
+    }
+
+    /// Loads an export into the catalog, which must not exist yet; the export creates the tables.
+    pub async fn import(&self, sql: &[u8]) -> Result<(), Report> {
+        if tokio::fs::try_exists(self.path).await? {
+            return Err(eyre!("{} already exists; import into a new --state-db", self.path.display()));
+        }
+        let mut command = Command::new(&self.tools.sqlite3);
+        command.kill_on_drop(true).arg("-bail").arg(self.path);
+        process::succeeded(self.tools.runner.run(command, Some(sql.to_vec())).await)
+            .wrap_err_with(|| format!("Unable to import into {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+/// `value` as an SQL string literal.
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Runs a `state` subcommand against the catalog at `path`.
+pub async fn run(tools: &Tools, path: &Path, action: StateAction) -> Result<(), Report> {
+    let state = StateDb { tools, path };
+    match action {
+        StateAction::Export { output: Some(output) } => {
+            let sql = state.export().await?;
+            tokio::fs::write(&output, sql)
+                .await
+                .wrap_err_with(|| format!("Unable to write {}", output.display()))?;
+        }
+        StateAction::Export { output: None } => {
+            let sql = state.export().await?;
+            print!("{}", String::from_utf8_lossy(&sql));
+        }
+        StateAction::Import { input } => {
+            let sql = tokio::fs::read(&input)
+                .await
+                .wrap_err_with(|| format!("Unable to read {}", input.display()))?;
+            state.import(&sql).await?;
+            info!(target: "state_db", path = %path.display(), from = %input.display(), "State imported");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::MockRunner;
+    use chrono::{TimeZone, Utc};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn runs_are_inserted_after_the_schema_with_quoted_values() {
+        let runner = Arc::new(MockRunner::new(|_| MockRunner::output(0, "", "")));
+        let tools = Tools::mock(runner.clone());
+        let started = Utc.with_ymd_and_hms(2025, 1, 1, 4, 30, 0).unwrap();
+        let record = RunRecord {
+            started,
+            finished: started,
+            command: String::from("surrealdb"),
+            success: true,
+            error_code: None,
+            keys: vec![String::from("surrealdb/o'brien/main/2025-01-01.04-30.zst")],
+            bytes: 66,
+            raw_bytes: Some(512),
+            version: String::from("0.1.0"),
+        };
+        StateDb { tools: &tools, path: Path::new("/var/lib/btagger/state.db") }.record_run(&record).await.unwrap();
+
+        let calls = runner.calls();
+        assert_eq!(calls[0].program, "sqlite3");
+        assert!(calls[0].has_args(&["-bail", "/var/lib/btagger/state.db"]));
+        let script = String::from_utf8(calls[0].stdin.clone().unwrap()).unwrap();
+        assert!(script.starts_with(SCHEMA));
+        assert!(script.ends_with("'surrealdb', 1, NULL, 'surrealdb/o''brien/main/2025-01-01.04-30.zst', 66, 512, '0.1.0');\n"), "{}", script);
+    }
+}
@@ -0,0 +1,68 @@
+use chrono::{DateTime, Duration, Utc};
+use color_eyre::eyre::Report;
+use tracing::info;
+
+use crate::output::{OutputFormat, Rows};
+use crate::s3::{self, S3Access};
+use crate::tools::Tools;
+
+/// Inputs for the freshness check.
+pub struct StatusOptions {
+    pub tools: Tools,
+    pub bucket_name: String,
+    pub s3_access: S3Access,
+    pub prefixes: Vec<String>,
+    /// Oldest the newest backup under each prefix may be.
+    pub max_age: Duration,
+    /// The table is one `[OK]`, `[STALE]` or `[MISSING]` line per prefix.
+    pub format: OutputFormat,
+}
+
+/// Prints one line per prefix and reports whether every prefix has a fresh enough backup.
+pub async fn run(options: &StatusOptions, now: DateTime<Utc>) -> Result<bool, Report> {
+    let mut fresh = true;
+    let mut rows = Rows::new(&["prefix", "status", "newest", "modified", "age", "limit"]);
+    let table = options.format == OutputFormat::Table;
+    for prefix in &options.prefixes {
+        let listing = s3::list_objects(&options.tools, &options.s3_access, &options.bucket_name, prefix).await?;
+        let newest = listing
+            .iter()
+            .filter_map(|object| {
+                DateTime::parse_from_rfc3339(&object.last_modified)
+                    .ok()
+                    .map(|modified| (modified.with_timezone(&Utc), object.key.as_str()))
+            })
+            .max();
+
+        match newest {
+            Some((modified, key)) => {
+                let age = now - modified;
+                let ok = age <= options.max_age;
+                fresh &= ok;
+                info!(target: "backup_status", prefix, key, modified = modified.to_rfc3339(), age_minutes = age.num_minutes(), ok);
+                let status = if ok { "OK" } else { "STALE" };
+                if table {
+                    println!("[{}] {}: newest {} is {} old (limit {})", status, prefix, key, human(age), human(options.max_age));
+                }
+                rows.push(vec![prefix.clone(), status.to_lowercase(), key.to_string(), modified.to_rfc3339(), human(age), human(options.max_age)]);
+            }
+            None => {
+                fresh = false;
+                info!(target: "backup_status", prefix, ok = false, "No backups found");
+                if table {
+                    println!("[MISSING] {}: no backups found", prefix);
+                }
+                rows.push(vec![prefix.clone(), String::from("missing"), String::new(), String::new(), String::new(), human(options.max_age)]);
+            }
+        }
+    }
+    if !table {
+        println!("{}", rows.render(options.format));
+    }
+    Ok(fresh)
+}
+
+/// `duration` as hours and minutes, e.g. `4h20m`.
+pub fn human(duration: Duration) -> String {
+    format!("{}h{:02}m", duration.num_hours(), duration.num_minutes() % 60)
+}
@@ -0,0 +1,233 @@
+use color_eyre::eyre::Report;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, info_span, Instrument};
+
+use crate::process::{self, CommandFailed};
+use crate::Tag;
+
+/// Wall-clock milliseconds spent per phase of a run. Clones share the same record, so concurrent
+/// pipeline stages can each time themselves.
+#[derive(Clone, Debug, Default)]
+pub struct Timings(Arc<Mutex<BTreeMap<&'static str, u64>>>);
+
+impl Timings {
+    /// Runs `future` inside a `phase` span and adds its duration to the phase.
+    pub async fn time<F: Future>(&self, phase: &'static str, future: F) -> F::Output {
+        let started = Instant::now();
+        let output = future.instrument(info_span!("phase", phase)).await;
+        self.record(phase, started.elapsed());
+        output
+    }
+
+    /// Adds `elapsed` to the phase.
+    pub fn record(&self, phase: &'static str, elapsed: Duration) {
+        let elapsed_ms = elapsed.as_millis() as u64;
+        info!(target: "phase_timing", phase, elapsed_ms);
+        if let Ok(mut phases) = self.0.lock() {
+            *phases.entry(phase).or_default() += elapsed_ms;
+        }
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<&'static str, u64> {
+        self.0.lock().map(|phases| phases.clone()).unwrap_or_default()
+    }
+}
+
+/// What a backup driver hands back for the run summary.
+#[derive(Debug)]
+pub struct BackupReport {
+    pub storage_key: String,
+    pub bytes: u64,
+    /// Size of the export before compression, where the driver sees it.
+    pub raw_bytes: Option<u64>,
+    pub success: bool,
+    /// End of the stderr of the tool that made an unsuccessful backup fail.
+    pub stderr_tail: Option<String>,
+    /// What tikv-br reported backing up.
+    pub tikv_stats: Option<TikvStats>,
+    /// Signal that killed the tool behind an unsuccessful backup.
+    pub signal: Option<i32>,
+}
+
+/// The figures of a tikv-br backup success summary, from its plain or `--log-format=json` output.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct TikvStats {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_kv: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_ranges: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ranges_failed: Option<u64>,
+    /// Size of the key-value data backed up, before tikv-br compressed it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data_size: Option<u64>,
+    /// Checksum tikv-br computed over the backed up data, as printed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+}
+
+impl TikvStats {
+    /// The stats of the last success summary in `output`; `None` when there is none.
+    pub fn parse(output: &str) -> Option<Self> {
+        let line = output.lines().rev().find(|line| line.contains("backup success summary"))?;
+        let fields = match serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(line.trim()) {
+            Ok(object) => object
+                .into_iter()
+                .map(|(name, value)| (name, value.as_str().map(String::from).unwrap_or_else(|| value.to_string())))
+                .collect::<Vec<_>>(),
+            // `[name=value]` fields, after the bracketed timestamp, level and message.
+            Err(_) => line
+                .split('[')
+                .filter_map(|field| field.trim_end().strip_suffix(']')?.split_once('='))
+                .map(|(name, value)| (name.to_string(), value.trim_matches('"').to_string()))
+                .collect(),
+        };
+        let field = |names: &[&str]| fields.iter().find(|(name, _)| names.contains(&name.as_str())).map(|(_, value)| value.as_str());
+        let count = |name: &str| field(&[name]).and_then(|value| value.parse().ok());
+        Some(TikvStats {
+            total_kv: count("total-kv"),
+            total_ranges: count("total-ranges"),
+            ranges_failed: count("ranges-failed"),
+            data_size: field(&["data-size", "total-kv-size"]).and_then(human_size),
+            checksum: field(&["checksum", "total-crc64xor", "crc64xor"]).map(String::from),
+        })
+    }
+
+    /// `total-kv`, or failing that `total-ranges`; zero means nothing was backed up.
+    pub fn backed_up(&self) -> Option<u64> {
+        self.total_kv.or(self.total_ranges)
+    }
+}
+
+/// Parses a size as tikv-br prints it, in decimal units, e.g. `2.136MB` or `0B`.
+fn human_size(size: &str) -> Option<u64> {
+    let split = size.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let multiplier = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1e0,
+        "KB" | "KIB" => 1e3,
+        "MB" | "MIB" => 1e6,
+        "GB" | "GIB" => 1e9,
+        "TB" | "TIB" => 1e12,
+        "PB" | "PIB" => 1e15,
+        _ => return None,
+    };
+    Some((number.parse::<f64>().ok()? * multiplier).round() as u64)
+}
+
+/// A backup that holds no data, e.g. an export of an empty database or a tikv-br run that
+/// backed up no ranges. It is never tagged, and the run fails with [`Failure::Empty`](crate::failure::Failure::Empty).
+#[derive(Debug)]
+pub struct EmptyBackup(pub String);
+
+impl std::fmt::Display for EmptyBackup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is empty; pass --allow-empty to keep it anyway", self.0)
+    }
+}
+
+impl std::error::Error for EmptyBackup {}
+
+/// Final machine-readable result of a run, printed as a single JSON line on stdout.
+#[derive(Serialize)]
+pub struct RunSummary {
+    pub command: String,
+    pub storage_keys: Vec<String>,
+    pub bytes: u64,
+    /// Uncompressed export size; absent for TiKV, whose backups are stored as tikv-br writes them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw_bytes: Option<u64>,
+    pub duration_ms: u64,
+    /// Where the time went, e.g. `export` versus `upload`.
+    pub phases_ms: BTreeMap<&'static str, u64>,
+    pub tags: Vec<Tag>,
+    pub success: bool,
+    /// [`Failure::code`](crate::failure::Failure::code) of a failed run, e.g. `upload`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<&'static str>,
+    /// Pipeline stage an export failed in, e.g. `surreal export`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_stage: Option<String>,
+    /// Signal that killed the external command a failed run failed in, e.g. `SIGKILL`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_signal: Option<String>,
+    /// What tikv-br reported backing up.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tikv: Option<TikvStats>,
+}
+
+impl RunSummary {
+    pub fn new(command: &str, tags: Vec<Tag>, started: Instant, timings: &Timings, result: &Result<BackupReport, Report>) -> Self {
+        let mut summary = RunSummary {
+            command: command.to_string(),
+            storage_keys: Vec::new(),
+            bytes: 0,
+            raw_bytes: None,
+            duration_ms: started.elapsed().as_millis() as u64,
+            phases_ms: timings.snapshot(),
+            tags,
+            success: false,
+            error_code: None,
+            error_stage: None,
+            error_signal: None,
+            tikv: None,
+        };
+        if let Ok(report) = result {
+            summary.storage_keys.push(report.storage_key.clone());
+            summary.bytes = report.bytes;
+            summary.raw_bytes = report.raw_bytes;
+            summary.success = report.success;
+            summary.tikv = report.tikv_stats.clone();
+        }
+        let signal = match result {
+            Ok(report) => report.signal,
+            Err(err) => err.chain().find_map(|err| err.downcast_ref::<CommandFailed>()).and_then(|failed| process::signal(failed.status)),
+        };
+        summary.error_signal = signal.map(process::signal_name);
+        summary
+    }
+
+    /// Writes the summary to stdout; logs stay on stderr.
+    pub fn print(&self) -> Result<(), serde_json::Error> {
+        println!("{}", serde_json::to_string(self)?);
+        Ok(())
+    }
+}
+
+/// A job's summary in the combined `run-all` summary.
+#[derive(Serialize)]
+pub struct JobSummary {
+    pub name: String,
+    #[serde(flatten)]
+    pub summary: RunSummary,
+}
+
+/// Combined result of `run-all`, printed as a single JSON line on stdout.
+#[derive(Serialize)]
+pub struct RunAllSummary {
+    pub command: String,
+    pub jobs: Vec<JobSummary>,
+    pub duration_ms: u64,
+    pub success: bool,
+}
+
+impl RunAllSummary {
+    pub fn new(jobs: Vec<JobSummary>, started: Instant) -> Self {
+        RunAllSummary {
+            command: String::from("run-all"),
+            success: jobs.iter().all(|job| job.summary.success),
+            duration_ms: started.elapsed().as_millis() as u64,
+            jobs,
+        }
+    }
+
+    /// Writes the summary to stdout; logs stay on stderr.
+    pub fn print(&self) -> Result<(), serde_json::Error> {
+        println!("{}", serde_json::to_string(self)?);
+        Ok(())
+    }
+}
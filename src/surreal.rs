@@ -0,0 +1,237 @@
+use color_eyre::eyre::{ContextCompat, Report, WrapErr};
+use serde_json::Value;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::process::Command;
+
+use crate::process;
+use crate::secret::Secret;
+use crate::tools::Tools;
+
+/// Which part of a database `surreal export` writes. The default is everything.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExportFilter {
+    /// Export just these tables.
+    pub only_tables: Vec<String>,
+    /// Export every table but these.
+    pub exclude_tables: Vec<String>,
+    /// Definitions without records.
+    pub schema_only: bool,
+}
+
+impl ExportFilter {
+    /// Path segment keeping filtered backups apart from full ones and from each other, e.g.
+    /// `schema` or `only-person-order`; `None` for a full export.
+    pub fn label(&self) -> Option<String> {
+        let mut parts = Vec::new();
+        if self.schema_only {
+            parts.push(String::from("schema"));
+        }
+        if !self.only_tables.is_empty() {
+            parts.push(format!("only-{}", self.only_tables.join("-")));
+        }
+        if !self.exclude_tables.is_empty() {
+            parts.push(format!("without-{}", self.exclude_tables.join("-")));
+        }
+        if parts.is_empty() {
+            return None;
+        }
+        let label = parts.join(".");
+        Some(label.chars().map(|c| if c.is_ascii_alphanumeric() || "-_.".contains(c) { c } else { '_' }).collect())
+    }
+
+    /// Whether `key` has a path segment written by [`ExportFilter::label`], i.e. is not a full export.
+    pub fn is_filtered_key(key: &str) -> bool {
+        key.split('/').rev().skip(1).any(|segment| {
+            segment.split('.').all(|part| part == "schema" || part.starts_with("only-") || part.starts_with("without-"))
+        })
+    }
+
+    /// `surreal export` arguments for the filter, given the tables to keep when it excludes some.
+    pub fn args(&self, tables: &[String]) -> Vec<String> {
+        let mut args = Vec::new();
+        if !tables.is_empty() {
+            args.extend([String::from("--tables"), tables.join(",")]);
+        }
+        if self.schema_only {
+            args.extend([String::from("--records"), String::from("false")]);
+        }
+        args
+    }
+
+    /// The tables to pass as `--tables`: the chosen ones, or all of `existing` but the excluded.
+    pub fn tables(&self, existing: &[String]) -> Vec<String> {
+        match self.only_tables.is_empty() {
+            false => self.only_tables.clone(),
+            true if self.exclude_tables.is_empty() => Vec::new(),
+            true => existing.iter().filter(|table| !self.exclude_tables.contains(table)).cloned().collect(),
+        }
+    }
+}
+
+/// Counts the record statements of a `surreal export` as it streams past, so an export of an
+/// empty database can be told apart from one that merely has a schema.
+#[derive(Debug, Default)]
+pub struct RecordCounter {
+    /// Start of the current line, up to the longest statement keyword.
+    line_start: Vec<u8>,
+    /// Whether the rest of the current line has already been judged.
+    judged: bool,
+    pub records: u64,
+}
+
+impl RecordCounter {
+    /// Statements that write records; 1.x exports use `UPDATE`, 2.x `INSERT`.
+    const KEYWORDS: [&'static [u8]; 4] = [b"INSERT ", b"UPDATE ", b"CREATE ", b"RELATE "];
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if byte == b'\n' {
+                self.line_start.clear();
+                self.judged = false;
+            } else if !self.judged {
+                self.line_start.push(byte);
+                if Self::KEYWORDS.contains(&self.line_start.as_slice()) {
+                    self.records += 1;
+                    self.judged = true;
+                } else if !Self::KEYWORDS.iter().any(|keyword| keyword.starts_with(&self.line_start)) {
+                    self.judged = true;
+                }
+            }
+        }
+    }
+}
+
+/// Copies an export from `reader` to `writer`, returning its size and how many record
+/// statements it held.
+pub async fn relay<R, W>(mut reader: R, mut writer: W) -> std::io::Result<(u64, u64)>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut counter = RecordCounter::default();
+    let mut buffer = vec![0; 64 * 1024];
+    let mut bytes = 0;
+    loop {
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        counter.feed(&buffer[..read]);
+        writer.write_all(&buffer[..read]).await?;
+        bytes += read as u64;
+    }
+    writer.shutdown().await?;
+    Ok((bytes, counter.records))
+}
+
+/// Runs `sql` with `surreal sql` against `endpoint` and returns the first JSON document it prints.
+pub async fn query(
+    tools: &Tools,
+    endpoint: &str,
+    password: Option<&Secret>,
+    namespace: &str,
+    database: &str,
+    sql: &str,
+) -> Result<Value, Report> {
+    let mut command = Command::new(&tools.surreal);
+    command
+        .kill_on_drop(true)
+        .arg("sql")
+        .arg("-e").arg(endpoint);
+    if let Some(password) = password {
+        command.arg("-u").arg("root").arg("-p").arg(password.expose());
+    }
+    command
+        .arg("--namespace").arg(namespace)
+        .arg("--database").arg(database)
+        .arg("--json")
+        .arg("--hide-welcome");
+    let output = process::succeeded(tools.runner.run(command, Some(sql.as_bytes().to_vec())).await)?;
+    // Only the first JSON document matters; the shell may print more after it.
+    serde_json::Deserializer::from_slice(&output.stdout)
+        .into_iter::<Value>()
+        .next()
+        .wrap_err_with(|| format!("No result for: {}", sql))?
+        .wrap_err_with(|| format!("Unable to parse the result of: {}", sql))
+}
+
+/// Names of the tables in the database, from `INFO FOR DB`.
+pub async fn tables(tools: &Tools, endpoint: &str, password: Option<&Secret>, namespace: &str, database: &str) -> Result<Vec<String>, Report> {
+    let info = query(tools, endpoint, password, namespace, database, "INFO FOR DB;").await?;
+    // SurrealDB 1.x calls the table list `tb`, 2.x `tables`.
+    Ok(first_object(&info)
+        .and_then(|info| info.get("tables").or_else(|| info.get("tb")))
+        .and_then(Value::as_object)
+        .map(|tables| tables.keys().cloned().collect())
+        .unwrap_or_default())
+}
+
+/// Number of records in `table`.
+pub async fn count(tools: &Tools, endpoint: &str, password: Option<&Secret>, namespace: &str, database: &str, table: &str) -> Result<u64, Report> {
+    let result = query(tools, endpoint, password, namespace, database, &format!("SELECT count() FROM `{}` GROUP ALL;", table)).await?;
+    Ok(first_object(&result)
+        .and_then(|row| row.get("count"))
+        .and_then(Value::as_u64)
+        .unwrap_or(0))
+}
+
+/// The first object in a possibly nested array of statement results.
+pub fn first_object(value: &Value) -> Option<&serde_json::Map<String, Value>> {
+    match value {
+        Value::Object(object) => Some(object),
+        Value::Array(values) => values.iter().find_map(first_object),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::MockRunner;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn query_sends_the_statement_on_stdin() {
+        let runner = Arc::new(MockRunner::new(|_| MockRunner::output(0, r#"[[{"count":3}]]"#, "")));
+        let tools = Tools::mock(runner.clone());
+        let result = query(&tools, "http://127.0.0.1:8000", Some(&Secret::new("pw")), "ns", "db", "SELECT 1;").await.unwrap();
+        assert_eq!(first_object(&result).and_then(|row| row.get("count")), Some(&Value::from(3)));
+
+        let calls = runner.calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].program, "surreal");
+        assert!(calls[0].has_args(&["--namespace", "ns", "--database", "db", "--json"]));
+        assert_eq!(calls[0].stdin.as_deref(), Some(&b"SELECT 1;"[..]));
+    }
+
+    #[test]
+    fn record_statements_are_counted_across_chunks() {
+        let export = "-- TABLE: person\nDEFINE TABLE person SCHEMALESS;\nINSERT [ { id: person:1 } ];\nUPDATE person:2 CONTENT {};\nCOMMIT TRANSACTION;\n";
+        for chunk in [1, 3, export.len()] {
+            let mut counter = RecordCounter::default();
+            export.as_bytes().chunks(chunk).for_each(|bytes| counter.feed(bytes));
+            assert_eq!(counter.records, 2, "chunks of {}", chunk);
+        }
+        let mut counter = RecordCounter::default();
+        counter.feed(b"DEFINE TABLE person;\nOPTION IMPORT;\n");
+        assert_eq!(counter.records, 0);
+    }
+
+    #[test]
+    fn filters_become_export_arguments_and_a_label() {
+        let tables = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        assert_eq!(ExportFilter::default().label(), None);
+        assert!(ExportFilter::default().args(&[]).is_empty());
+
+        let filter = ExportFilter { exclude_tables: tables(&["audit"]), schema_only: true, ..ExportFilter::default() };
+        let kept = filter.tables(&tables(&["person", "audit", "order"]));
+        assert_eq!(kept, tables(&["person", "order"]));
+        assert_eq!(filter.args(&kept), tables(&["--tables", "person,order", "--records", "false"]));
+        assert_eq!(filter.label().as_deref(), Some("schema.without-audit"));
+        assert!(ExportFilter::is_filtered_key("surrealdb/app/schema.without-audit/2025-01-01.04-30.zst"));
+        assert!(!ExportFilter::is_filtered_key("surrealdb/app/2025-01-01.04-30.zst"));
+
+        let filter = ExportFilter { only_tables: tables(&["person", "a:b"]), ..ExportFilter::default() };
+        assert_eq!(filter.label().as_deref(), Some("only-person-a_b"));
+    }
+}
@@ -0,0 +1,55 @@
+use color_eyre::eyre::{eyre, Report, WrapErr};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use tracing::info;
+
+use crate::s3::{self, S3Access};
+use crate::tags::{Tag, TagSet};
+use crate::tools::Tools;
+
+/// Which existing objects to tag; `keys` and `prefix` are mutually exclusive.
+pub struct TagObjectOptions {
+    pub tools: Tools,
+    pub bucket_name: String,
+    pub s3_access: S3Access,
+    pub keys: Vec<String>,
+    /// Tag every object under this prefix instead of fixed keys.
+    pub prefix: Option<String>,
+    /// Replaces whatever tags the objects had.
+    pub tags: Vec<Tag>,
+    pub concurrency: usize,
+}
+
+/// Applies the tag set to each selected object, as a backup would have, so backups taken outside
+/// btagger fall under the same lifecycle rules. Returns how many objects were tagged.
+pub async fn run(options: &TagObjectOptions) -> Result<usize, Report> {
+    let s3_access = &options.s3_access;
+    let keys = match &options.prefix {
+        Some(prefix) => {
+            let objects = s3::list_objects(&options.tools, s3_access, &options.bucket_name, prefix).await?;
+            if objects.is_empty() {
+                return Err(eyre!("No objects under '{}' in {}", prefix, options.bucket_name));
+            }
+            objects.into_iter().map(|object| object.key).collect()
+        }
+        None if options.keys.is_empty() => return Err(eyre!("Pass --key or --prefix")),
+        None => options.keys.clone(),
+    };
+
+    let tagging = serde_json::to_string(&TagSet { tag_set: options.tags.clone() })?;
+    let tagged = stream::iter(&keys)
+        .map(|key| {
+            let tagging = tagging.as_str();
+            async move {
+                s3::put_object_tagging(&options.tools, s3_access, &options.bucket_name, key, tagging)
+                    .await
+                    .wrap_err_with(|| format!("Tagging {} failed", key))?;
+                info!(target: "object_tagging", bucket = options.bucket_name, key, tagging);
+                println!("[TAGGED] {}", key);
+                Ok::<_, Report>(())
+            }
+        })
+        .buffer_unordered(options.concurrency.max(1))
+        .try_collect::<Vec<_>>()
+        .await?;
+    Ok(tagged.len())
+}
@@ -0,0 +1,652 @@
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use clap::ValueEnum;
+use color_eyre::eyre::{eyre, ContextCompat, Report};
+use color_eyre::Section;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use valuable::Valuable;
+
+use crate::cron::Cron;
+use crate::simulate::{tier_of, TIERS};
+
+#[derive(Serialize, Deserialize, Valuable)]
+#[serde(rename_all = "PascalCase")]
+pub struct TagSet {
+    pub tag_set: Vec<Tag>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Valuable)]
+#[serde(rename_all = "PascalCase")]
+pub struct Tag {
+    pub key: String,
+    pub value: String,
+}
+
+impl FromStr for Tag {
+    type Err = String;
+
+    /// Parses `key=value`, or a bare `key` meaning `key=1` as set on matched periods.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, value) = s.split_once('=').unwrap_or((s, "1"));
+        if key.trim().is_empty() {
+            return Err(format!("'{}' has no tag key", s));
+        }
+        Ok(Tag {
+            key: key.trim().to_string(),
+            value: value.trim().to_string(),
+        })
+    }
+}
+
+/// Most tags S3 allows on an object.
+pub const MAX_TAGS: usize = 10;
+const MAX_KEY_CHARS: usize = 128;
+const MAX_VALUE_CHARS: usize = 256;
+/// Characters S3 allows in tags besides letters, numbers and spaces.
+const TAG_PUNCTUATION: &str = "+-=._:/@";
+
+/// Checks `tags` against the S3 tagging limits, naming the offending tag, so a bad tag set fails
+/// with what to change rather than with the aws CLI's InvalidTag.
+pub fn validate(tags: &[Tag]) -> Result<(), Report> {
+    if tags.len() > MAX_TAGS {
+        let keys = tags.iter().map(|tag| tag.key.as_str()).collect::<Vec<_>>().join(", ");
+        return Err(eyre!("{} tags ({}) are more than the {} S3 allows on an object", tags.len(), keys, MAX_TAGS))
+            .suggestion("Use --tag-merge highest or --tag-style single to write one tier tag, or drop calendar and [[rules]] tags");
+    }
+    for (index, tag) in tags.iter().enumerate() {
+        let name = format!("{}={}", tag.key, tag.value);
+        if tag.key.is_empty() || tag.key.chars().count() > MAX_KEY_CHARS {
+            return Err(eyre!("Tag '{}' has a key of {} characters; S3 allows 1 to {}", name, tag.key.chars().count(), MAX_KEY_CHARS));
+        }
+        if tag.value.chars().count() > MAX_VALUE_CHARS {
+            return Err(eyre!("Tag '{}' has a value of {} characters; S3 allows up to {}", name, tag.value.chars().count(), MAX_VALUE_CHARS));
+        }
+        if tag.key.starts_with("aws:") {
+            return Err(eyre!("Tag '{}' uses the reserved 'aws:' prefix", name));
+        }
+        if let Some(invalid) = tag.key.chars().chain(tag.value.chars()).find(|c| !(c.is_alphanumeric() || c.is_whitespace() || TAG_PUNCTUATION.contains(*c))) {
+            return Err(eyre!("Tag '{}' contains '{}', which S3 does not allow in tags", name, invalid))
+                .suggestion(format!("Use only letters, numbers, spaces and {}", TAG_PUNCTUATION.chars().map(String::from).collect::<Vec<_>>().join(" ")));
+        }
+        if tags[..index].iter().any(|earlier| earlier.key == tag.key) {
+            return Err(eyre!("Tag key '{}' is set twice; S3 allows each key once per object", tag.key));
+        }
+    }
+    Ok(())
+}
+
+/// How `btagger tags` prints the tag set.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TagFormat {
+    /// The S3 TagSet JSON taken by `put-object-tagging --tagging`.
+    Json,
+    /// `Key=Value&Key=Value`, the query-string form taken by `put-object --tagging`.
+    AwsCli,
+    /// `export` lines for `eval` in a shell script.
+    Shell,
+    /// The TagSet as YAML.
+    Yaml,
+}
+
+impl TagFormat {
+    pub fn render(self, tags: &[Tag]) -> Result<String, Report> {
+        Ok(match self {
+            TagFormat::Json => serde_json::to_string(&TagSet { tag_set: tags.to_vec() })?,
+            TagFormat::AwsCli => tags
+                .iter()
+                .map(|tag| format!("{}={}", percent_encode(&tag.key), percent_encode(&tag.value)))
+                .collect::<Vec<_>>()
+                .join("&"),
+            // BTAGGER_TAGS matches the variable hooks receive; each tag also gets its own variable.
+            TagFormat::Shell => {
+                let mut lines = vec![format!("export BTAGGER_TAGS={}", shell_quote(&TagFormat::Json.render(tags)?))];
+                lines.extend(tags.iter().map(|tag| format!("export BTAGGER_TAG_{}={}", env_name(&tag.key), shell_quote(&tag.value))));
+                lines.join("\n") + "\n"
+            }
+            TagFormat::Yaml => {
+                let mut yaml = String::from("TagSet:\n");
+                for tag in tags {
+                    yaml.push_str(&format!("- Key: {}\n  Value: {}\n", yaml_quote(&tag.key), yaml_quote(&tag.value)));
+                }
+                yaml
+            }
+        })
+    }
+}
+
+/// Percent-encodes everything but the characters RFC 3986 leaves unreserved.
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+fn yaml_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "''"))
+}
+
+/// Upper-cases a tag key and replaces anything not valid in a variable name with `_`.
+fn env_name(key: &str) -> String {
+    key.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' }).collect()
+}
+
+/// Backup cadence the period tags are matched against.
+#[derive(Clone, Copy, Debug)]
+pub struct Schedule {
+    pub every_n_hours: i64,
+    pub minutes_offset_from_hour: i64,
+    pub day_offset_in_hours: i64,
+    pub lag_window_in_minutes: i64,
+    pub window_strategy: WindowStrategy,
+    /// How far before now [`WindowStrategy::PreShift`] starts looking for the trigger; a quarter
+    /// of the lag window when unset.
+    pub window_pre_shift: Option<Duration>,
+    /// Which backup of each period the monthly, quarterly and yearly tags go on.
+    pub period_anchor: PeriodAnchor,
+}
+
+/// Which backup of a period its tag goes on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum PeriodAnchor {
+    /// The first backup of the period, e.g. on the 1st of the month.
+    Start,
+    /// The last backup of the period, e.g. on the last day of the month.
+    #[default]
+    End,
+}
+
+impl Schedule {
+    fn lag_window(&self) -> Duration {
+        Duration::minutes(self.lag_window_in_minutes)
+    }
+
+    /// Whether a trigger `diff` from now, i.e. `when - now`, matches the run, and why. Every
+    /// match, whether for tagging, logging or `tags --explain`, is decided here.
+    pub fn decide(&self, diff: Duration) -> Decision {
+        let matched = match self.window_strategy {
+            WindowStrategy::PreShift => diff.abs() < self.lag_window(),
+            WindowStrategy::Symmetric => diff.abs() <= self.lag_window() / 2,
+        };
+        let within = if matched { "within" } else { "outside" };
+        Decision { matched, reason: format!("scheduled {}, {} {}", offset(diff), within, self.window()) }
+    }
+
+    /// The window a trigger must fall in, e.g. `the 20m lag window either side`.
+    pub fn window(&self) -> String {
+        match self.window_strategy {
+            WindowStrategy::PreShift => format!("the {} lag window either side", human(self.lag_window())),
+            WindowStrategy::Symmetric => format!("{} either side, edges included", human(self.lag_window() / 2)),
+        }
+    }
+}
+
+/// `diff` from now, e.g. `4m ago` or `in 17h 30m`.
+fn offset(diff: Duration) -> String {
+    match diff < Duration::zero() {
+        true => format!("{} ago", human(-diff)),
+        false => format!("in {}", human(diff)),
+    }
+}
+
+/// `duration` in whole seconds, e.g. `4m 30s`.
+fn human(duration: Duration) -> String {
+    humantime::format_duration(std::time::Duration::from_secs(duration.num_seconds().unsigned_abs())).to_string()
+}
+
+/// The outcome of [`Schedule::decide`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Decision {
+    pub matched: bool,
+    /// Where the trigger fell relative to the window, e.g. `scheduled 4m ago, within the 20m
+    /// lag window either side`.
+    pub reason: String,
+}
+
+/// How a run is matched to the scheduled trigger of a period.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum WindowStrategy {
+    /// The next trigger after `--window-pre-shift` before now, if it is within the lag window
+    /// either side of now. A run started later than the pre-shift misses its trigger.
+    #[default]
+    PreShift,
+    /// Any trigger within half the lag window either side of now, edges included.
+    Symmetric,
+}
+
+/// Outcome of checking one period rule against the current time.
+pub struct Evaluation {
+    pub tag: Tag,
+    /// When the rule's backups are triggered.
+    pub cron: String,
+    /// For a rule tagging the first or last backup of a period, when its periods start.
+    pub period_start_cron: Option<String>,
+    /// When the search for the rule's next trigger started.
+    pub searched_from: DateTime<Utc>,
+    /// For a rule tagging the first or last backup of a period, the start of the period the
+    /// trigger begins, or of the one after the period it ends.
+    pub period_start: Option<DateTime<Utc>>,
+    /// Next matching run time: the first trigger from, or the last before, `period_start` for
+    /// period rules.
+    pub when: DateTime<Utc>,
+    /// `when - now`.
+    pub diff: Duration,
+    pub matched: bool,
+    /// Why it did or did not match.
+    pub reason: String,
+}
+
+/// Checks every period rule against `now`.
+pub fn evaluate(schedule: &Schedule, now: DateTime<Utc>) -> Result<Vec<Evaluation>, Report> {
+    let rules = periods(
+        schedule.day_offset_in_hours,
+        schedule.minutes_offset_from_hour,
+        schedule.every_n_hours,
+    );
+    evaluate_rules(schedule, rules, now)
+}
+
+/// Checks `rules`, e.g. the `[[rules]]` of the config file, against `now`.
+pub fn evaluate_rules(schedule: &Schedule, rules: Vec<Rule>, now: DateTime<Utc>) -> Result<Vec<Evaluation>, Report> {
+    let lag_window = schedule.lag_window();
+    let now_comparison_value = match schedule.window_strategy {
+        // Need to subtract a few minutes to catch the current trigger.
+        // 1/4 of the lag window feels right.
+        WindowStrategy::PreShift => now.checked_sub_signed(schedule.window_pre_shift.unwrap_or(lag_window / 4)),
+        // The trigger is searched for after this, so a second earlier keeps the edge itself in.
+        WindowStrategy::Symmetric => now.checked_sub_signed(lag_window / 2 + Duration::seconds(1)),
+    }
+    .wrap_err("Unable to apply jitter to current UTC timestamp")
+    .suggestion("Check the system clock")?;
+
+    let mut evaluations = Vec::new();
+    for rule in rules {
+        let Ok((period_start, next_when)) = next_trigger(&rule, schedule.period_anchor, now_comparison_value) else {
+            continue;
+        };
+        let diff = next_when - now;
+        let Decision { matched, reason } = schedule.decide(diff);
+        evaluations.push(Evaluation {
+            tag: rule.tag,
+            cron: rule.cron.to_string(),
+            period_start_cron: rule.period_start.map(|period_start| period_start.to_string()),
+            searched_from: now_comparison_value,
+            period_start,
+            when: next_when,
+            diff,
+            matched,
+            reason,
+        });
+    }
+    Ok(evaluations)
+}
+
+/// The first trigger of `rule` after `from`, with the start of the period it begins or ends for
+/// period rules.
+fn next_trigger(rule: &Rule, anchor: PeriodAnchor, from: DateTime<Utc>) -> Result<(Option<DateTime<Utc>>, DateTime<Utc>), Report> {
+    let Some(period_start) = &rule.period_start else {
+        return Ok((None, next(&rule.cron, from)?));
+    };
+    let mut boundary = match anchor {
+        // The period `from` is in may not have had its first trigger yet.
+        PeriodAnchor::Start => previous(period_start, from + Duration::minutes(1))?,
+        PeriodAnchor::End => next(period_start, from)?,
+    };
+    // The trigger of the period the boundary begins or closes, unless that one is already past,
+    // in which case the period after it.
+    loop {
+        let trigger = match anchor {
+            PeriodAnchor::Start => next(&rule.cron, boundary - Duration::minutes(1))?,
+            PeriodAnchor::End => previous(&rule.cron, boundary)?,
+        };
+        if trigger > from {
+            return Ok((Some(boundary), trigger));
+        }
+        boundary = next(period_start, boundary)?;
+    }
+}
+
+/// The first occurrence of `cron` after the minute of `after`.
+pub fn next(cron: &Cron, after: DateTime<Utc>) -> Result<DateTime<Utc>, Report> {
+    cron.next_after(after).ok_or_else(|| eyre!("'{}' has no occurrence in the five years after {}", cron, after))
+}
+
+/// The last occurrence of `cron` before `before`.
+pub fn previous(cron: &Cron, before: DateTime<Utc>) -> Result<DateTime<Utc>, Report> {
+    cron.previous_before(before).ok_or_else(|| eyre!("'{}' has no occurrence in the five years before {}", cron, before))
+}
+
+/// A report of how each period rule was matched against `now`, for `tags --explain`.
+pub fn explain(schedule: &Schedule, now: DateTime<Utc>, evaluations: &[Evaluation]) -> String {
+    let time = |at: DateTime<Utc>| at.to_rfc3339_opts(SecondsFormat::Secs, true);
+    let strategy = schedule.window_strategy.to_possible_value().map(|value| value.get_name().to_string()).unwrap_or_default();
+    let mut report = format!("now: {}, window strategy: {}\n", time(now), strategy);
+    for evaluation in evaluations {
+        report.push_str(&format!("{}:\n", evaluation.tag.key));
+        match (&evaluation.period_start_cron, schedule.period_anchor) {
+            (Some(period_start_cron), PeriodAnchor::Start) => report.push_str(&format!("  cron:         {}, the first from each {}\n", evaluation.cron, period_start_cron)),
+            (Some(period_start_cron), PeriodAnchor::End) => report.push_str(&format!("  cron:         {}, the last before each {}\n", evaluation.cron, period_start_cron)),
+            (None, _) => report.push_str(&format!("  cron:         {}\n", evaluation.cron)),
+        }
+        report.push_str(&format!("  searched:     from {} ({})\n", time(evaluation.searched_from), offset(evaluation.searched_from - now)));
+        if let Some(period_start) = evaluation.period_start {
+            report.push_str(&format!("  period start: {}\n", time(period_start)));
+        }
+        report.push_str(&format!("  trigger:      {}\n", time(evaluation.when)));
+        report.push_str(&format!("  delta:        {}\n", offset(evaluation.diff)));
+        report.push_str(&format!("  window:       {}\n", schedule.window()));
+        report.push_str(&format!("  matched:      {}\n", if evaluation.matched { "yes" } else { "no" }));
+    }
+    report
+}
+
+/// The tags to apply: `standard` on every backup, plus each matched period.
+pub fn matched(evaluations: &[Evaluation]) -> Vec<Tag> {
+    // Add default tag every time
+    let mut tags: Vec<Tag> = Vec::new();
+    tags.push(Tag {
+        key: String::from("standard"),
+        value: String::from("1"),
+    });
+    tags.extend(
+        evaluations
+            .iter()
+            .filter(|evaluation| evaluation.matched)
+            .map(|evaluation| evaluation.tag.clone()),
+    );
+    tags
+}
+
+/// How the tier tags of a backup matching several periods are written, for stores that limit
+/// the number of tags on an object.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum TagMerge {
+    /// Every matched tier, e.g. `standard=1`, `nightly=1` and `monthly=1`.
+    #[default]
+    All,
+    /// Only the longest-lived tier, e.g. `monthly=1`.
+    Highest,
+    /// One `retention` tag holding the longest-lived tier, e.g. `retention=monthly`.
+    Retention,
+}
+
+/// Whether a backup gets a tag per matched tier or a single one, for `--tag-style`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum TagStyle {
+    /// A tag per matched tier, merged as `--tag-merge` says.
+    #[default]
+    Multi,
+    /// One `retention=<tier>` tag, the same as `--tag-merge retention`.
+    Single,
+}
+
+impl TagStyle {
+    /// The merge policy the style comes down to.
+    pub fn merge(self, merge: TagMerge) -> TagMerge {
+        match self {
+            TagStyle::Multi => merge,
+            TagStyle::Single => TagMerge::Retention,
+        }
+    }
+}
+
+/// The tiers `tags` name, shortest-lived first, whether they carry a tag per tier or the one
+/// `retention=<tier>` of `--tag-merge retention`. A tier tag set to `0` names no tier.
+pub fn tiers(tags: &[Tag]) -> Vec<&'static str> {
+    TIERS
+        .iter()
+        .filter(|tier| tags.iter().any(|tag| (tag.key == **tier && tag.value != "0") || (tag.key == "retention" && tag.value == **tier)))
+        .copied()
+        .collect()
+}
+
+/// Applies `merge` to the tier tags among `tags`; calendar and configured tags are kept as they are.
+pub fn merge(tags: Vec<Tag>, merge: TagMerge) -> Vec<Tag> {
+    if merge == TagMerge::All {
+        return tags;
+    }
+    let highest = TIERS[tier_of(&tags)];
+    let (tiers, mut others): (Vec<Tag>, Vec<Tag>) = tags.into_iter().partition(|tag| TIERS.contains(&tag.key.as_str()));
+    let merged = match merge {
+        TagMerge::Retention => Tag { key: String::from("retention"), value: String::from(highest) },
+        _ => tiers.into_iter().find(|tag| tag.key == highest).unwrap_or_else(|| Tag { key: String::from(highest), value: String::from("1") }),
+    };
+    others.insert(0, merged);
+    others
+}
+
+/// A period tag and the backups it goes on.
+#[derive(Clone)]
+pub struct Rule {
+    pub tag: Tag,
+    /// When the backups the tag may go on are triggered.
+    pub cron: Cron,
+    /// For a tag on the first or last backup of each period, when the periods start; the tag goes
+    /// on the first trigger of `cron` from each start, or the last before it.
+    pub period_start: Option<Cron>,
+}
+
+pub fn periods(
+    day_offset_in_hours: i64,
+    minutes_offset_from_hour: i64,
+    every_n_hours: i64,
+) -> Vec<Rule> {
+    // always tag as standard, so manual runs get tagged for lifecycle rules
+    // let standard = (
+    //     format!("{} {}/{} * * *", minutes_offset_from_hour, day_offset_in_hours, every_n_hours),
+    //     Tag {
+    //         key: String::from("standard"),
+    //         value: String::from("1"),
+    //     },
+    //     false,
+    // );
+
+    let nightly = format!(
+        "{} {} * * *",
+        minutes_offset_from_hour,
+        every_n_hours + day_offset_in_hours
+    );
+    let weekly = format!(
+        "{} {} * * 6",
+        minutes_offset_from_hour,
+        every_n_hours + day_offset_in_hours
+    );
+    // Offsets past the end of the day never trigger, so there is nothing to tag.
+    let (Ok(nightly), Ok(weekly)) = (nightly.parse::<Cron>(), weekly.parse::<Cron>()) else {
+        return Vec::new();
+    };
+    let tag = |key: &str| Tag {
+        key: String::from(key),
+        value: String::from("1"),
+    };
+    let period_start = |cron: &str| cron.parse::<Cron>().ok();
+    vec![
+        Rule {
+            tag: tag("nightly"),
+            cron: nightly.clone(),
+            period_start: None,
+        },
+        Rule {
+            tag: tag("weekly"),
+            cron: weekly,
+            period_start: None,
+        },
+        Rule {
+            tag: tag("monthly"),
+            cron: nightly.clone(),
+            period_start: period_start("0 0 1 * *"),
+        },
+        Rule {
+            tag: tag("quarterly"),
+            cron: nightly.clone(),
+            period_start: period_start("0 0 1 */3 *"),
+        },
+        Rule {
+            tag: tag("yearly"),
+            cron: nightly,
+            period_start: period_start("0 0 1 1 *"),
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags() -> Vec<Tag> {
+        vec!["standard".parse().unwrap(), "team=data & ops".parse().unwrap(), "owner=o'brien".parse().unwrap()]
+    }
+
+    #[test]
+    fn aws_cli_output_is_a_query_string() {
+        assert_eq!(TagFormat::AwsCli.render(&tags()).unwrap(), "standard=1&team=data%20%26%20ops&owner=o%27brien");
+    }
+
+    #[test]
+    fn shell_output_quotes_values() {
+        assert_eq!(
+            TagFormat::Shell.render(&tags()[..1]).unwrap(),
+            "export BTAGGER_TAGS='{\"TagSet\":[{\"Key\":\"standard\",\"Value\":\"1\"}]}'\nexport BTAGGER_TAG_STANDARD='1'\n"
+        );
+        assert!(TagFormat::Shell.render(&tags()).unwrap().ends_with("export BTAGGER_TAG_OWNER='o'\\''brien'\n"));
+    }
+
+    #[test]
+    fn yaml_output_lists_the_tag_set() {
+        assert_eq!(
+            TagFormat::Yaml.render(&tags()[1..]).unwrap(),
+            "TagSet:\n- Key: 'team'\n  Value: 'data & ops'\n- Key: 'owner'\n  Value: 'o''brien'\n"
+        );
+    }
+
+    fn matched_at(window_strategy: WindowStrategy, window_pre_shift: Option<Duration>, at: &str) -> Vec<String> {
+        // Nightly at 04:30, with the default 20 minute lag window.
+        let schedule = Schedule { every_n_hours: 4, minutes_offset_from_hour: 30, day_offset_in_hours: 0, lag_window_in_minutes: 20, window_strategy, window_pre_shift, period_anchor: PeriodAnchor::End };
+        let evaluations = evaluate(&schedule, at.parse().unwrap()).unwrap();
+        matched(&evaluations).into_iter().map(|tag| tag.key).collect()
+    }
+
+    #[test]
+    fn pre_shift_misses_runs_started_later_than_the_shift() {
+        assert_eq!(matched_at(WindowStrategy::PreShift, None, "2025-01-15T04:34:00Z"), ["standard", "nightly"]);
+        assert_eq!(matched_at(WindowStrategy::PreShift, None, "2025-01-15T04:36:00Z"), ["standard"]);
+        assert_eq!(matched_at(WindowStrategy::PreShift, Some(Duration::minutes(10)), "2025-01-15T04:36:00Z"), ["standard", "nightly"]);
+        assert_eq!(matched_at(WindowStrategy::PreShift, None, "2025-01-15T04:11:00Z"), ["standard", "nightly"]);
+    }
+
+    #[test]
+    fn decisions_compare_the_offset_with_the_window_in_the_same_units() {
+        let schedule = |window_strategy| Schedule { every_n_hours: 4, minutes_offset_from_hour: 30, day_offset_in_hours: 0, lag_window_in_minutes: 20, window_strategy, window_pre_shift: None, period_anchor: PeriodAnchor::End };
+        // 25 seconds is well within 20 minutes, whatever 25 minutes would be.
+        assert!(schedule(WindowStrategy::PreShift).decide(Duration::seconds(25)).matched);
+        assert!(!schedule(WindowStrategy::PreShift).decide(Duration::minutes(-20)).matched);
+        assert_eq!(
+            schedule(WindowStrategy::PreShift).decide(Duration::seconds(-270)),
+            Decision { matched: true, reason: String::from("scheduled 4m 30s ago, within the 20m lag window either side") }
+        );
+        assert_eq!(
+            schedule(WindowStrategy::Symmetric).decide(Duration::minutes(11)),
+            Decision { matched: false, reason: String::from("scheduled in 11m, outside 10m either side, edges included") }
+        );
+    }
+
+    #[test]
+    fn explain_shows_how_each_rule_was_matched() {
+        let schedule = Schedule { every_n_hours: 4, minutes_offset_from_hour: 30, day_offset_in_hours: 0, lag_window_in_minutes: 20, window_strategy: WindowStrategy::PreShift, window_pre_shift: None, period_anchor: PeriodAnchor::End };
+        let now = "2025-01-31T04:34:00Z".parse().unwrap();
+        let report = explain(&schedule, now, &evaluate(&schedule, now).unwrap());
+        assert!(report.starts_with("now: 2025-01-31T04:34:00Z, window strategy: pre-shift\nnightly:\n  cron:         30 4 * * *\n  searched:     from 2025-01-31T04:29:00Z (5m ago)\n"), "{}", report);
+        assert!(report.contains(
+            "monthly:\n  cron:         30 4 * * *, the last before each 0 0 1 * *\n  searched:     from 2025-01-31T04:29:00Z (5m ago)\n  period start: 2025-02-01T00:00:00Z\n  trigger:      2025-01-31T04:30:00Z\n  delta:        4m ago\n  window:       the 20m lag window either side\n  matched:      yes\n"
+        ), "{}", report);
+    }
+
+    #[test]
+    fn previous_occurrences_are_found_however_far_back() {
+        let at = |time: &str| time.parse::<DateTime<Utc>>().unwrap();
+        let cron = |expression: &str| expression.parse::<Cron>().unwrap();
+        assert_eq!(previous(&cron("*/5 * * * *"), at("2025-03-01T00:02:00Z")).unwrap(), at("2025-03-01T00:00:00Z"));
+        assert_eq!(previous(&cron("30 4 * * *"), at("2025-03-01T00:00:00Z")).unwrap(), at("2025-02-28T04:30:00Z"));
+        assert_eq!(previous(&cron("30 4 * * *"), at("2024-03-01T00:00:00Z")).unwrap(), at("2024-02-29T04:30:00Z"));
+        // Strictly before.
+        assert_eq!(previous(&cron("0 0 1 1 *"), at("2025-01-01T00:00:00Z")).unwrap(), at("2024-01-01T00:00:00Z"));
+    }
+
+    #[test]
+    fn period_end_rules_tag_the_last_trigger_before_the_next_period() {
+        // Backups at 23:50, so the last of each period is ten minutes before the next one starts.
+        let schedule = Schedule { every_n_hours: 23, minutes_offset_from_hour: 50, day_offset_in_hours: 0, lag_window_in_minutes: 20, window_strategy: WindowStrategy::Symmetric, window_pre_shift: None, period_anchor: PeriodAnchor::End };
+        let tags = |at: &str| matched(&evaluate(&schedule, at.parse().unwrap()).unwrap()).into_iter().map(|tag| tag.key).collect::<Vec<_>>();
+        assert_eq!(tags("2025-03-31T23:55:00Z"), ["standard", "nightly", "monthly", "quarterly"]);
+        assert_eq!(tags("2025-04-01T00:01:00Z"), ["standard"]);
+        assert_eq!(tags("2024-12-31T23:45:00Z"), ["standard", "nightly", "monthly", "quarterly", "yearly"]);
+        assert_eq!(tags("2025-03-30T23:50:00Z"), ["standard", "nightly"]);
+    }
+
+    #[test]
+    fn start_anchored_tags_go_on_the_first_backup_of_the_period() {
+        let schedule = Schedule { every_n_hours: 4, minutes_offset_from_hour: 30, day_offset_in_hours: 0, lag_window_in_minutes: 20, window_strategy: WindowStrategy::PreShift, window_pre_shift: None, period_anchor: PeriodAnchor::Start };
+        let tags = |at: &str| matched(&evaluate(&schedule, at.parse().unwrap()).unwrap()).into_iter().map(|tag| tag.key).collect::<Vec<_>>();
+        assert_eq!(tags("2025-01-01T04:31:00Z"), ["standard", "nightly", "monthly", "quarterly", "yearly"]);
+        assert_eq!(tags("2025-04-01T04:25:00Z"), ["standard", "nightly", "monthly", "quarterly"]);
+        assert_eq!(tags("2025-03-31T04:30:00Z"), ["standard", "nightly"]);
+        assert_eq!(tags("2025-02-02T04:30:00Z"), ["standard", "nightly"]);
+    }
+
+    #[test]
+    fn configured_rules_take_full_cron_syntax() {
+        let config: crate::config::Config = toml::from_str("[[rules]]\ntag = \"month-end-friday\"\ncron = \"30 4 * * 5L\"\n").unwrap();
+        let schedule = Schedule { every_n_hours: 4, minutes_offset_from_hour: 30, day_offset_in_hours: 0, lag_window_in_minutes: 20, window_strategy: WindowStrategy::PreShift, window_pre_shift: None, period_anchor: PeriodAnchor::End };
+        let tags = |at: &str| matched(&evaluate_rules(&schedule, config.rules().unwrap(), at.parse().unwrap()).unwrap()).into_iter().map(|tag| tag.key).collect::<Vec<_>>();
+        assert_eq!(tags("2025-01-31T04:32:00Z"), ["standard", "month-end-friday"]);
+        assert_eq!(tags("2025-01-24T04:32:00Z"), ["standard"]);
+        // Invalid expressions are refused when the config file is read.
+        let err = toml::from_str::<crate::config::Config>("[[rules]]\ntag = \"x\"\ncron = \"30 4 * * 5#9\"\n").unwrap_err();
+        assert!(err.to_string().contains("'30 4 * * 5#9': day of week field"), "{}", err);
+    }
+
+    #[test]
+    fn merged_tier_tags_keep_the_longest_lived_tier() {
+        let tags = ["standard", "nightly", "monthly", "quarterly", "fy-close=2025"].iter().map(|tag| tag.parse().unwrap()).collect::<Vec<Tag>>();
+        let keys = |tags: Vec<Tag>| tags.into_iter().map(|tag| format!("{}={}", tag.key, tag.value)).collect::<Vec<_>>();
+        assert_eq!(keys(merge(tags.clone(), TagMerge::All)), ["standard=1", "nightly=1", "monthly=1", "quarterly=1", "fy-close=2025"]);
+        assert_eq!(keys(merge(tags.clone(), TagMerge::Highest)), ["quarterly=1", "fy-close=2025"]);
+        assert_eq!(keys(merge(tags, TagMerge::Retention)), ["retention=quarterly", "fy-close=2025"]);
+        assert_eq!(keys(merge(vec!["standard".parse().unwrap()], TagMerge::Retention)), ["retention=standard"]);
+    }
+
+    #[test]
+    fn tiers_are_read_from_either_tag_style() {
+        let tags = |tags: &[&str]| tags.iter().map(|tag| tag.parse().unwrap()).collect::<Vec<Tag>>();
+        assert_eq!(tiers(&tags(&["standard", "nightly", "monthly", "fy-close=2025"])), ["standard", "nightly", "monthly"]);
+        assert_eq!(tiers(&tags(&["retention=monthly", "fy-close=2025"])), ["monthly"]);
+        assert_eq!(tiers(&tags(&["standard", "yearly=0", "retention=unknown"])), ["standard"]);
+    }
+
+    #[test]
+    fn tag_sets_beyond_the_s3_limits_name_the_offending_tag() {
+        let tag = |tag: &str| tag.parse::<Tag>().unwrap();
+        assert!(validate(&[tag("standard"), tag("fy-close=2025"), tag("owner=ops@example.com")]).is_ok());
+        assert_eq!(validate(&tags()).unwrap_err().to_string(), "Tag 'team=data & ops' contains '&', which S3 does not allow in tags");
+        assert_eq!(validate(&[tag("aws:backup")]).unwrap_err().to_string(), "Tag 'aws:backup=1' uses the reserved 'aws:' prefix");
+        assert_eq!(validate(&[tag(&format!("note={}", "x".repeat(257)))]).unwrap_err().to_string(), format!("Tag 'note={}' has a value of 257 characters; S3 allows up to 256", "x".repeat(257)));
+        let many = (0..11).map(|index| tag(&format!("t{}", index))).collect::<Vec<_>>();
+        assert!(validate(&many).unwrap_err().to_string().starts_with("11 tags (t0, t1,"));
+    }
+
+    #[test]
+    fn symmetric_window_includes_both_edges() {
+        assert_eq!(matched_at(WindowStrategy::Symmetric, None, "2025-01-15T04:20:00Z"), ["standard", "nightly"]);
+        assert_eq!(matched_at(WindowStrategy::Symmetric, None, "2025-01-15T04:40:00Z"), ["standard", "nightly"]);
+        assert_eq!(matched_at(WindowStrategy::Symmetric, None, "2025-01-15T04:19:59Z"), ["standard"]);
+        assert_eq!(matched_at(WindowStrategy::Symmetric, None, "2025-01-15T04:40:01Z"), ["standard"]);
+        // Period-end rules match the last day of the period, here a run started late on January 31st.
+        assert_eq!(matched_at(WindowStrategy::Symmetric, None, "2025-01-31T04:38:00Z"), ["standard", "nightly", "monthly"]);
+    }
+}
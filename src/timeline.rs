@@ -0,0 +1,121 @@
+use chrono::{DateTime, Duration, Utc};
+use color_eyre::eyre::{eyre, Report};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use serde_json::json;
+use tracing::info;
+
+use crate::output::{OutputFormat, Rows};
+use crate::retention;
+use crate::s3::{self, S3Access};
+use crate::simulate::TIERS;
+use crate::status;
+use crate::tags;
+use crate::tools::Tools;
+
+/// Which backups to chart and what spacing between them is expected.
+pub struct TimelineOptions {
+    pub tools: Tools,
+    pub bucket_name: String,
+    pub s3_access: S3Access,
+    /// Directly above the backups; each path segment below it is one backup.
+    pub prefix: String,
+    /// Longest expected time between two backups; anything longer is reported as a gap.
+    pub max_gap: Duration,
+    /// The ASCII chart as a table; JSON is one document with the backups and gaps.
+    pub format: OutputFormat,
+    pub concurrency: usize,
+}
+
+/// Lists the backups under the prefix oldest first with their tiers, marking every gap longer
+/// than `max_gap`, including the one since the newest backup. Returns how many gaps were found.
+pub async fn run(options: &TimelineOptions, now: DateTime<Utc>) -> Result<usize, Report> {
+    let objects = s3::list_objects(&options.tools, &options.s3_access, &options.bucket_name, &options.prefix).await?;
+    let mut backups = retention::group(&options.prefix, objects)?.into_iter().collect::<Vec<_>>();
+    if backups.is_empty() {
+        return Err(eyre!("No backups under '{}' in {}", options.prefix, options.bucket_name));
+    }
+    backups.sort_by_key(|(_, (modified, _))| *modified);
+
+    // Every object of a backup is tagged alike, so the first one speaks for it.
+    let tiers = stream::iter(&backups)
+        .map(|(_, (_, keys))| async move {
+            let tags = s3::object_tags(&options.tools, &options.s3_access, &options.bucket_name, &keys[0]).await?;
+            Ok::<_, Report>(tags::tiers(&tags))
+        })
+        .buffered(options.concurrency.max(1))
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    let times = backups.iter().map(|(_, (modified, _))| *modified).collect::<Vec<_>>();
+    let gaps = gaps(&times, now, options.max_gap);
+    info!(target: "backup_timeline", prefix = options.prefix, backups = backups.len(), gaps = gaps.len());
+
+    if options.format == OutputFormat::Json {
+        let entries = backups
+            .iter()
+            .zip(&tiers)
+            .map(|((backup, (modified, _)), tiers)| json!({"backup": backup, "time": modified.to_rfc3339(), "tiers": tiers}))
+            .collect::<Vec<_>>();
+        let gap_entries = gaps
+            .iter()
+            .map(|(from, to)| json!({"from": from.to_rfc3339(), "to": to.to_rfc3339(), "hours": (*to - *from).num_hours()}))
+            .collect::<Vec<_>>();
+        println!("{}", json!({"prefix": options.prefix, "backups": entries, "gaps": gap_entries}));
+        return Ok(gaps.len());
+    }
+
+    if options.format != OutputFormat::Table {
+        let mut rows = Rows::new(&["time", "backup", "tiers", "gap_after"]);
+        for ((backup, (modified, _)), tiers) in backups.iter().zip(&tiers) {
+            let gap = gaps.iter().find(|(from, _)| from == modified).map(|(from, to)| status::human(*to - *from));
+            rows.push(vec![modified.to_rfc3339(), backup.clone(), tiers.join(" "), gap.unwrap_or_default()]);
+        }
+        println!("{}", rows.render(options.format));
+        return Ok(gaps.len());
+    }
+
+    let header = TIERS.iter().map(|tier| &tier[..1]).collect::<Vec<_>>().join(" ").to_uppercase();
+    println!("{:<16}  {}  BACKUP", "TIME (UTC)", header);
+    for (index, ((backup, (modified, _)), tiers)) in backups.iter().zip(&tiers).enumerate() {
+        if let Some((from, to)) = gaps.iter().find(|(_, to)| to == modified) {
+            println!("{}", gap_line(*from, *to, options.max_gap));
+        }
+        let marks = TIERS.iter().map(|tier| if tiers.contains(tier) { "x" } else { "." }).collect::<Vec<_>>().join(" ");
+        println!("{}  {}  {}", modified.format("%Y-%m-%d %H:%M"), marks, backup);
+        if index + 1 == backups.len() {
+            if let Some((from, to)) = gaps.iter().find(|(from, _)| from == modified) {
+                println!("{}", gap_line(*from, *to, options.max_gap));
+            }
+        }
+    }
+    Ok(gaps.len())
+}
+
+/// Spans between consecutive `times`, and from the last of them to `now`, longer than `max_gap`.
+fn gaps(times: &[DateTime<Utc>], now: DateTime<Utc>, max_gap: Duration) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+    times
+        .iter()
+        .copied()
+        .zip(times.iter().skip(1).copied().chain([now]))
+        .filter(|(from, to)| *to - *from > max_gap)
+        .collect()
+}
+
+fn gap_line(from: DateTime<Utc>, to: DateTime<Utc>, max_gap: Duration) -> String {
+    format!("  !! GAP of {} (expected at most {})", status::human(to - from), status::human(max_gap))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn gaps_include_the_time_since_the_newest_backup() {
+        let at = |day, hour| Utc.with_ymd_and_hms(2025, 1, day, hour, 30, 0).unwrap();
+        let times = [at(1, 0), at(1, 4), at(2, 8), at(2, 12)];
+        let max_gap = Duration::hours(4) + Duration::minutes(20);
+        assert_eq!(gaps(&times, at(2, 14), max_gap), vec![(at(1, 4), at(2, 8))]);
+        assert_eq!(gaps(&times, at(3, 0), max_gap), vec![(at(1, 4), at(2, 8)), (at(2, 12), at(3, 0))]);
+    }
+}
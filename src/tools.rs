@@ -0,0 +1,219 @@
+use clap::Args as ClapArgs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{debug, info};
+
+use crate::process::{ProcessRunner, SystemRunner};
+
+/// Where to find the external binaries.
+#[derive(ClapArgs, Debug)]
+pub struct ToolArgs {
+    /// Path whose 'bin/' holds every external binary under its usual name, e.g. 'bin/aws' and 'bin/clickhouse-client'; the --*-bin flags override it per binary. Binaries are looked up on $PATH when unset.
+    #[arg(short, long)]
+    pub bin_path: Option<String>,
+
+    /// Explicit path to the aws CLI; overrides --bin-path.
+    #[arg(long)]
+    pub aws_bin: Option<PathBuf>,
+
+    /// Explicit path to zstd; overrides --bin-path.
+    #[arg(long)]
+    pub zstd_bin: Option<PathBuf>,
+
+    /// Explicit path to surreal; overrides --bin-path.
+    #[arg(long)]
+    pub surreal_bin: Option<PathBuf>,
+
+    /// Explicit path to tikv-br; overrides --bin-path.
+    #[arg(long)]
+    pub tikv_br_bin: Option<PathBuf>,
+
+    /// Explicit path to gzip; overrides --bin-path.
+    #[arg(long)]
+    pub gzip_bin: Option<PathBuf>,
+
+    /// Explicit path to lz4; overrides --bin-path.
+    #[arg(long)]
+    pub lz4_bin: Option<PathBuf>,
+
+    /// Explicit path to xz; overrides --bin-path.
+    #[arg(long)]
+    pub xz_bin: Option<PathBuf>,
+
+    /// Explicit path to curl, used for notifications; overrides --bin-path.
+    #[arg(long)]
+    pub curl_bin: Option<PathBuf>,
+
+    /// Explicit path to sqlite3, used for --state-db; overrides --bin-path.
+    #[arg(long)]
+    pub sqlite3_bin: Option<PathBuf>,
+
+    /// Explicit path to clickhouse-client; overrides --bin-path.
+    #[arg(long)]
+    pub clickhouse_client_bin: Option<PathBuf>,
+
+    /// Explicit path to nodetool; overrides --bin-path.
+    #[arg(long)]
+    pub nodetool_bin: Option<PathBuf>,
+
+    /// Explicit path to tar, used to archive snapshot directories; overrides --bin-path.
+    #[arg(long)]
+    pub tar_bin: Option<PathBuf>,
+
+    /// Explicit path to influxd, used for InfluxDB 1.x; overrides --bin-path.
+    #[arg(long)]
+    pub influxd_bin: Option<PathBuf>,
+
+    /// Explicit path to influx, used for InfluxDB 2.x; overrides --bin-path.
+    #[arg(long)]
+    pub influx_bin: Option<PathBuf>,
+
+    /// Explicit path to neo4j-admin; overrides --bin-path.
+    #[arg(long)]
+    pub neo4j_admin_bin: Option<PathBuf>,
+
+    /// Explicit path to cockroach; overrides --bin-path.
+    #[arg(long)]
+    pub cockroach_bin: Option<PathBuf>,
+
+    /// Explicit path to nats; overrides --bin-path.
+    #[arg(long)]
+    pub nats_bin: Option<PathBuf>,
+
+    /// Explicit path to df, used to check the space left in --spool-dir; overrides --bin-path.
+    #[arg(long)]
+    pub df_bin: Option<PathBuf>,
+
+    /// Explicit path to renice, used for --nice; overrides --bin-path.
+    #[arg(long)]
+    pub renice_bin: Option<PathBuf>,
+
+    /// Explicit path to ionice, used for --io-class; overrides --bin-path.
+    #[arg(long)]
+    pub ionice_bin: Option<PathBuf>,
+}
+
+/// Resolved locations of every external binary the backups shell out to.
+#[derive(Debug, Clone)]
+pub struct Tools {
+    pub aws: PathBuf,
+    pub zstd: PathBuf,
+    pub surreal: PathBuf,
+    pub tikv_br: PathBuf,
+    pub gzip: PathBuf,
+    pub lz4: PathBuf,
+    pub xz: PathBuf,
+    pub curl: PathBuf,
+    pub sqlite3: PathBuf,
+    pub clickhouse_client: PathBuf,
+    pub nodetool: PathBuf,
+    pub tar: PathBuf,
+    pub influxd: PathBuf,
+    pub influx: PathBuf,
+    pub neo4j_admin: PathBuf,
+    pub cockroach: PathBuf,
+    pub nats: PathBuf,
+    pub df: PathBuf,
+    pub renice: PathBuf,
+    pub ionice: PathBuf,
+    /// Runs the commands built for these binaries.
+    pub runner: Arc<dyn ProcessRunner>,
+}
+
+impl Tools {
+    /// Per-tool overrides win, then `{bin_path}/bin/{name}`, then a `$PATH` lookup.
+    pub fn resolve(args: &ToolArgs) -> Self {
+        let bin_path = args.bin_path.as_deref().map(Path::new);
+        let tools = Tools {
+            aws: resolve_one(args.aws_bin.as_deref(), bin_path, "aws"),
+            zstd: resolve_one(args.zstd_bin.as_deref(), bin_path, "zstd"),
+            surreal: resolve_one(args.surreal_bin.as_deref(), bin_path, "surreal"),
+            tikv_br: resolve_one(args.tikv_br_bin.as_deref(), bin_path, "tikv-br"),
+            gzip: resolve_one(args.gzip_bin.as_deref(), bin_path, "gzip"),
+            lz4: resolve_one(args.lz4_bin.as_deref(), bin_path, "lz4"),
+            xz: resolve_one(args.xz_bin.as_deref(), bin_path, "xz"),
+            curl: resolve_one(args.curl_bin.as_deref(), bin_path, "curl"),
+            sqlite3: resolve_one(args.sqlite3_bin.as_deref(), bin_path, "sqlite3"),
+            clickhouse_client: resolve_one(args.clickhouse_client_bin.as_deref(), bin_path, "clickhouse-client"),
+            nodetool: resolve_one(args.nodetool_bin.as_deref(), bin_path, "nodetool"),
+            tar: resolve_one(args.tar_bin.as_deref(), bin_path, "tar"),
+            influxd: resolve_one(args.influxd_bin.as_deref(), bin_path, "influxd"),
+            influx: resolve_one(args.influx_bin.as_deref(), bin_path, "influx"),
+            neo4j_admin: resolve_one(args.neo4j_admin_bin.as_deref(), bin_path, "neo4j-admin"),
+            cockroach: resolve_one(args.cockroach_bin.as_deref(), bin_path, "cockroach"),
+            nats: resolve_one(args.nats_bin.as_deref(), bin_path, "nats"),
+            df: resolve_one(args.df_bin.as_deref(), bin_path, "df"),
+            renice: resolve_one(args.renice_bin.as_deref(), bin_path, "renice"),
+            ionice: resolve_one(args.ionice_bin.as_deref(), bin_path, "ionice"),
+            runner: Arc::new(SystemRunner),
+        };
+        info!(
+            target: "resolved_tools",
+            aws = %tools.aws.display(),
+            zstd = %tools.zstd.display(),
+            surreal = %tools.surreal.display(),
+            tikv_br = %tools.tikv_br.display(),
+            gzip = %tools.gzip.display(),
+            lz4 = %tools.lz4.display(),
+            xz = %tools.xz.display(),
+            curl = %tools.curl.display(),
+            sqlite3 = %tools.sqlite3.display(),
+            clickhouse_client = %tools.clickhouse_client.display(),
+            nodetool = %tools.nodetool.display(),
+            tar = %tools.tar.display(),
+            influxd = %tools.influxd.display(),
+            influx = %tools.influx.display(),
+            neo4j_admin = %tools.neo4j_admin.display(),
+            cockroach = %tools.cockroach.display(),
+            nats = %tools.nats.display(),
+            df = %tools.df.display(),
+            renice = %tools.renice.display(),
+            ionice = %tools.ionice.display(),
+        );
+        tools
+    }
+}
+
+#[cfg(test)]
+impl Tools {
+    /// Every binary by its bare name, run by `runner`.
+    pub fn mock(runner: Arc<dyn ProcessRunner>) -> Self {
+        Tools {
+            aws: PathBuf::from("aws"),
+            zstd: PathBuf::from("zstd"),
+            surreal: PathBuf::from("surreal"),
+            tikv_br: PathBuf::from("tikv-br"),
+            gzip: PathBuf::from("gzip"),
+            lz4: PathBuf::from("lz4"),
+            xz: PathBuf::from("xz"),
+            curl: PathBuf::from("curl"),
+            sqlite3: PathBuf::from("sqlite3"),
+            clickhouse_client: PathBuf::from("clickhouse-client"),
+            nodetool: PathBuf::from("nodetool"),
+            tar: PathBuf::from("tar"),
+            influxd: PathBuf::from("influxd"),
+            influx: PathBuf::from("influx"),
+            neo4j_admin: PathBuf::from("neo4j-admin"),
+            cockroach: PathBuf::from("cockroach"),
+            nats: PathBuf::from("nats"),
+            df: PathBuf::from("df"),
+            renice: PathBuf::from("renice"),
+            ionice: PathBuf::from("ionice"),
+            runner,
+        }
+    }
+}
+
+fn resolve_one(explicit: Option<&Path>, bin_path: Option<&Path>, name: &str) -> PathBuf {
+    if let Some(explicit) = explicit {
+        return explicit.to_path_buf();
+    }
+    if let Some(bin_path) = bin_path {
+        return bin_path.join("bin").join(name);
+    }
+    which::which(name).unwrap_or_else(|err| {
+        // Most runs use a few of these, so a missing one is only reported when it is run.
+        debug!(target: "resolved_tools", "Unable to find '{}' on $PATH: {}", name, err);
+        PathBuf::from(name)
+    })
+}
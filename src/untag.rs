@@ -0,0 +1,111 @@
+use chrono::{DateTime, Duration, Utc};
+use color_eyre::eyre::{eyre, Report};
+use std::collections::HashMap;
+use tracing::info;
+
+use crate::multipart;
+use crate::s3::{self, S3Access};
+use crate::simulate::{RetentionPolicy, TIERS};
+use crate::tags::{self, Tag, TagSet};
+use crate::tools::Tools;
+
+/// Which objects to check and how long each tier tag holds on to them.
+pub struct UntagOptions {
+    pub tools: Tools,
+    pub bucket_name: String,
+    pub s3_access: S3Access,
+    pub prefix: String,
+    pub retention: RetentionPolicy,
+    /// Without it the changes are only listed.
+    pub yes: bool,
+}
+
+/// Removes every tier tag but `standard` from objects older than that tier's retention, so a
+/// single catch-all lifecycle rule can expire them on stores without tag-filtered rules. A
+/// `retention=<tier>` tag is set to `standard` instead. Returns how many objects lost tags.
+pub async fn run(options: &UntagOptions, now: DateTime<Utc>) -> Result<usize, Report> {
+    let s3_access = &options.s3_access;
+    let objects = s3::list_objects(&options.tools, s3_access, &options.bucket_name, &options.prefix).await?;
+    if objects.is_empty() {
+        return Err(eyre!("No objects under '{}' in {}", options.prefix, options.bucket_name));
+    }
+
+    // The numbered objects of a split backup go with its manifest, which is written last, so
+    // none of them loses a tag before the manifest does.
+    let modified = objects.iter().map(|object| (object.key.clone(), object.last_modified.clone())).collect::<HashMap<_, _>>();
+    let mut changed = 0;
+    for object in &objects {
+        let last_modified = multipart::split_of(&object.key).and_then(|manifest| modified.get(manifest)).unwrap_or(&object.last_modified);
+        // Objects without a readable age are left alone rather than guessed at.
+        let Ok(modified) = DateTime::parse_from_rfc3339(last_modified) else { continue };
+        let age = now - modified.with_timezone(&Utc);
+        let tags = s3::object_tags(&options.tools, s3_access, &options.bucket_name, &object.key).await?;
+        let (expired, mut kept): (Vec<_>, Vec<_>) = tags.into_iter().partition(|tag| {
+            tags::tiers(std::slice::from_ref(tag))
+                .first()
+                .is_some_and(|tier| *tier != TIERS[0] && options.retention.days(tier).is_some_and(|days| age > Duration::days(days)))
+        });
+        if expired.is_empty() {
+            continue;
+        }
+        // An object tagged `retention=<tier>` falls back to `standard` rather than to no tier.
+        if expired.iter().any(|tag| tag.key == "retention") {
+            kept.insert(0, Tag { key: String::from("retention"), value: String::from(TIERS[0]) });
+        }
+        changed += 1;
+        let removed = tags::tiers(&expired).join(", ");
+        if !options.yes {
+            println!("[WOULD UNTAG] {} ({}; modified {})", object.key, removed, last_modified);
+            continue;
+        }
+        let tagging = serde_json::to_string(&TagSet { tag_set: kept })?;
+        s3::put_object_tagging(&options.tools, s3_access, &options.bucket_name, &object.key, &tagging).await?;
+        info!(target: "audit", action = "untag", bucket = options.bucket_name, key = object.key, removed, operator = std::env::var("USER").unwrap_or_default());
+        println!("[UNTAGGED] {} ({})", object.key, removed);
+    }
+    if !options.yes && changed > 0 {
+        println!("{} object(s) have expired tags; rerun with --yes to remove them", changed);
+    }
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::MockRunner;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn expired_tiers_are_removed_in_either_tag_style() {
+        let listing = r#"{"Contents":[
+            {"Key":"surrealdb/a.zst","Size":10,"LastModified":"2025-01-01T04:40:00+00:00"},
+            {"Key":"surrealdb/b.zst","Size":10,"LastModified":"2025-01-01T04:40:00+00:00"},
+            {"Key":"surrealdb/c.zst","Size":10,"LastModified":"2025-03-01T04:40:00+00:00"}
+        ]}"#;
+        let runner = Arc::new(MockRunner::new(move |call| {
+            if call.has_args(&["list-objects-v2"]) {
+                MockRunner::output(0, listing, "")
+            } else if call.has_args(&["get-object-tagging"]) && call.has_args(&["--key", "surrealdb/a.zst"]) {
+                MockRunner::output(0, r#"{"TagSet":[{"Key":"standard","Value":"1"},{"Key":"weekly","Value":"1"},{"Key":"yearly","Value":"1"}]}"#, "")
+            } else if call.has_args(&["get-object-tagging"]) {
+                MockRunner::output(0, r#"{"TagSet":[{"Key":"retention","Value":"weekly"},{"Key":"team","Value":"data"}]}"#, "")
+            } else {
+                MockRunner::output(0, "", "")
+            }
+        }));
+        let options = UntagOptions {
+            tools: Tools::mock(runner.clone()),
+            bucket_name: String::from("bk"),
+            s3_access: S3Access::default(),
+            prefix: String::from("surrealdb/"),
+            retention: "weekly=28".parse().unwrap(),
+            yes: true,
+        };
+        let now = "2025-03-02T00:00:00Z".parse().unwrap();
+        // c is within the weekly retention.
+        assert_eq!(run(&options, now).await.unwrap(), 2);
+        let tagged = runner.calls().into_iter().filter(|call| call.has_args(&["put-object-tagging"])).collect::<Vec<_>>();
+        assert!(tagged[0].has_args(&["--key", "surrealdb/a.zst"]) && tagged[0].has_args(&[r#"{"TagSet":[{"Key":"standard","Value":"1"},{"Key":"yearly","Value":"1"}]}"#]));
+        assert!(tagged[1].has_args(&["--key", "surrealdb/b.zst"]) && tagged[1].has_args(&[r#"{"TagSet":[{"Key":"retention","Value":"standard"},{"Key":"team","Value":"data"}]}"#]));
+    }
+}
@@ -0,0 +1,273 @@
+use color_eyre::eyre::{eyre, ContextCompat, Report, WrapErr};
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::process::Command;
+use tracing::info;
+
+use crate::cas;
+use crate::compression::Compression;
+use crate::dictionary;
+use crate::keys::{KeyTemplate, KeyVars};
+use crate::multipart;
+use crate::process;
+use crate::s3::{self, Aws, S3Access};
+use crate::secret::Secret;
+use crate::surreal::{self, ExportFilter};
+use crate::tools::Tools;
+use crate::Object;
+
+/// Which SurrealDB backup to check and how thoroughly.
+pub struct VerifyOptions {
+    pub tools: Tools,
+    pub bucket_name: String,
+    pub s3_access: S3Access,
+    pub namespace: String,
+    pub database: String,
+    /// Defaults to the newest backup of `namespace`.
+    pub key: Option<String>,
+    /// Layout the backups were written with, to find the newest one.
+    pub key_template: KeyTemplate,
+    pub cluster: Option<String>,
+    /// Import into a scratch in-memory SurrealDB and count rows, rather than only decompressing.
+    pub deep: bool,
+}
+
+/// Downloads and decompresses a backup, and with `deep` restores it into a temporary in-memory
+/// SurrealDB instance and counts the rows of every table. Returns the verified key.
+pub async fn run(options: &VerifyOptions) -> Result<String, Report> {
+    let s3_access = &options.s3_access;
+    let key = match &options.key {
+        Some(key) => key.clone(),
+        None => {
+            let vars = KeyVars {
+                engine: "surrealdb",
+                cluster: options.cluster.as_deref(),
+                namespace: Some(&options.namespace),
+                database: Some(&options.database),
+            };
+            newest_backup(&options.tools, s3_access, &options.bucket_name, &options.key_template, &vars).await?
+        }
+    };
+
+    let export = std::env::temp_dir().join(format!("btagger-{}-verify.surql", std::process::id()));
+    let result = verify(options, &key, &export).await;
+    let _ = tokio::fs::remove_file(&export).await;
+    result?;
+    Ok(key)
+}
+
+/// The most recently written full backup that `key_template` could have produced for `vars`.
+pub async fn newest_backup(tools: &Tools, s3_access: &S3Access, bucket_name: &str, key_template: &KeyTemplate, vars: &KeyVars<'_>) -> Result<String, Report> {
+    let prefix = key_template.prefix(vars)?;
+    backups(tools, s3_access, bucket_name, key_template, vars)
+        .await?
+        .into_iter()
+        .next()
+        .map(|object| object.key)
+        .wrap_err_with(|| format!("No backups found under {}", prefix))
+}
+
+/// Every full backup `key_template` could have produced for `vars`, newest first.
+pub async fn backups(tools: &Tools, s3_access: &S3Access, bucket_name: &str, key_template: &KeyTemplate, vars: &KeyVars<'_>) -> Result<Vec<Object>, Report> {
+    let prefix = key_template.prefix(vars)?;
+    // A split backup is found by its manifest; its numbered objects are not backups of their own.
+    let mut objects = multipart::without_split_parts(s3::list_objects(tools, s3_access, bucket_name, &prefix).await?)
+        .into_iter()
+        // Other namespaces or clusters may share the prefix when the template starts with the
+        // date; table or schema-only exports are not full backups either.
+        .filter(|object| !ExportFilter::is_filtered_key(&object.key))
+        .filter(|object| {
+            let compression = Compression::from_key(&object.key);
+            let key = object.key.strip_suffix(cas::INDEX_EXTENSION).or_else(|| object.key.strip_suffix(compression.extension())).unwrap_or(&object.key);
+            key_template.matches(key, vars) || key_template.matches(&object.key, vars)
+        })
+        .collect::<Vec<_>>();
+    // RFC 3339 timestamps from S3 share a format, so they sort as strings.
+    objects.sort_by(|a, b| b.last_modified.cmp(&a.last_modified));
+    Ok(objects)
+}
+
+async fn verify(options: &VerifyOptions, key: &str, export: &Path) -> Result<(), Report> {
+    let bytes = download(&options.tools, &options.s3_access, &options.bucket_name, key, export).await?;
+    info!(target: "backup_verify", key, bytes, "Backup decompressed");
+    println!("[OK] {}: decompressed {} bytes", key, bytes);
+    if !options.deep {
+        return Ok(());
+    }
+
+    let counts = restore_to_scratch(&options.tools, &options.namespace, &options.database, export).await?;
+    for (table, count) in &counts {
+        info!(target: "backup_verify", key, table, count);
+        println!("[OK] {}: table {} has {} rows", key, table, count);
+    }
+    if counts.is_empty() {
+        return Err(eyre!("{} restored, but contains no tables", key));
+    }
+    Ok(())
+}
+
+/// Streams the object through the matching decompressor into `export`, returning its size. The
+/// numbered objects of a split backup are downloaded one after the other into the same stream,
+/// and a backup stored as chunks is reassembled from its index.
+pub async fn download(tools: &Tools, s3_access: &S3Access, bucket_name: &str, key: &str, export: &Path) -> Result<u64, Report> {
+    if cas::is_index(key) {
+        return cas::download(tools, s3_access, bucket_name, key, export).await;
+    }
+    let keys = multipart::data_keys(tools, s3_access, bucket_name, key).await?;
+    let (mut fetched, downloaded) = tokio::io::duplex(64 * 1024);
+    let fetch = async move {
+        for key in &keys {
+            let mut download = process::spawn(
+                Aws::new(tools, s3_access)
+                    .download(bucket_name, key)
+                    .kill_on_drop(true)
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped()),
+            )?;
+            let mut stdout = download.stdout.take().wrap_err("failed to pipe")?;
+            tokio::io::copy(&mut stdout, &mut fetched).await.wrap_err_with(|| format!("Unable to relay the download of {}", key))?;
+            process::succeeded(download.wait_with_output().await).wrap_err_with(|| format!("Unable to download {}", key))?;
+        }
+        // Dropping the pipe ends the stream for the decompressor.
+        drop(fetched);
+        Ok::<_, Report>(())
+    };
+    let (download_result, decompressed) = tokio::join!(fetch, decompress(tools, s3_access, bucket_name, key, downloaded, export));
+    let (decompress_result, copy_result) = decompressed?;
+    process::first_failure([
+        ("download", download_result),
+        ("decompression", decompress_result),
+        ("write", copy_result.as_ref().map(|_| ()).map_err(|err| eyre!("{}", err))),
+    ])?;
+    Ok(copy_result?)
+}
+
+/// Decompresses `downloaded` into `export`, returning how the decompressor fared and what was
+/// written. A zstd frame names the dictionary it was compressed with in its header; that
+/// dictionary is fetched from the bucket first.
+async fn decompress(
+    tools: &Tools,
+    s3_access: &S3Access,
+    bucket_name: &str,
+    key: &str,
+    mut downloaded: DuplexStream,
+    export: &Path,
+) -> Result<(Result<(), Report>, std::io::Result<u64>), Report> {
+    let compression = Compression::from_key(key);
+    let mut header = Vec::new();
+    if compression == Compression::Zstd {
+        (&mut downloaded).take(18).read_to_end(&mut header).await.wrap_err("Unable to read the download")?;
+    }
+    let dictionary = match dictionary::frame_dictionary_id(&header) {
+        Some(id) => {
+            let path = dictionary::scratch_path();
+            let dictionary_key = dictionary::fetch_by_id(tools, s3_access, bucket_name, id, &path).await?;
+            info!(target: "dictionary", key, dictionary = dictionary_key, "Decompressing with the dictionary");
+            Some(path)
+        }
+        None => None,
+    };
+    // The header read ahead goes back in front of the rest of the download.
+    let downloaded = std::io::Cursor::new(header).chain(downloaded);
+    let result = decompress_into(tools, compression, dictionary.as_deref(), downloaded, export).await;
+    if let Some(path) = &dictionary {
+        let _ = tokio::fs::remove_file(path).await;
+    }
+    result
+}
+
+async fn decompress_into(
+    tools: &Tools,
+    compression: Compression,
+    dictionary: Option<&Path>,
+    mut downloaded: impl AsyncRead + Unpin,
+    export: &Path,
+) -> Result<(Result<(), Report>, std::io::Result<u64>), Report> {
+    let mut file = tokio::fs::File::create(export)
+        .await
+        .wrap_err_with(|| format!("Unable to create {}", export.display()))?;
+    let (decompress_result, copy_result) = match compression.decompress_command(tools) {
+        Some(mut decompressor) => {
+            let mut child = process::spawn(
+                decompressor
+                    .args(dictionary.map(|path| [Path::new("-D"), path]).into_iter().flatten())
+                    .kill_on_drop(true)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped()),
+            )?;
+            let mut stdin = child.stdin.take().wrap_err("failed to pipe")?;
+            let mut stdout = child.stdout.take().wrap_err("failed to pipe")?;
+            let relay = async move {
+                let relayed = tokio::io::copy(&mut downloaded, &mut stdin).await;
+                drop(stdin);
+                relayed
+            };
+            let (relayed, copied, decompressed) =
+                tokio::join!(relay, tokio::io::copy(&mut stdout, &mut file), child.wait_with_output());
+            let decompress_result = relayed
+                .wrap_err("Unable to relay the download to the decompressor")
+                .and_then(|_| process::succeeded(decompressed).map(|_| ()));
+            (decompress_result, copied)
+        }
+        None => (Ok(()), tokio::io::copy(&mut downloaded, &mut file).await),
+    };
+    file.flush().await?;
+    Ok((decompress_result, copy_result))
+}
+
+/// Starts `surreal start memory` on a free local port, imports `export` and counts every table.
+async fn restore_to_scratch(tools: &Tools, namespace: &str, database: &str, export: &Path) -> Result<Vec<(String, u64)>, Report> {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+    let endpoint = format!("http://127.0.0.1:{}", port);
+    let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_nanos();
+    let password = Secret::new(format!("{:x}{:x}", nanos, std::process::id()));
+
+    let _server = process::spawn(
+        Command::new(&tools.surreal)
+            .kill_on_drop(true)
+            .arg("start")
+            .arg("--bind").arg(format!("127.0.0.1:{}", port))
+            .arg("--user").arg("root")
+            .arg("--pass").arg(password.expose())
+            .arg("memory")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null()),
+    )?;
+    let mut ready = false;
+    for _ in 0..60 {
+        let mut isready = Command::new(&tools.surreal);
+        isready.arg("isready").arg("-e").arg(&endpoint);
+        let status = tools.runner.run(isready, None).await?;
+        if status.status.success() {
+            ready = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    if !ready {
+        return Err(eyre!("Scratch SurrealDB on {} did not become ready", endpoint));
+    }
+
+    let mut import = Command::new(&tools.surreal);
+    import
+        .arg("import")
+        .arg("-e").arg(&endpoint)
+        .arg("-u").arg("root")
+        .arg("-p").arg(password.expose())
+        .arg("--namespace").arg(namespace)
+        .arg("--database").arg(database)
+        .arg(export);
+    process::succeeded(tools.runner.run(import, None).await)
+        .wrap_err("Importing the backup into the scratch instance failed")?;
+
+    let tables = surreal::tables(tools, &endpoint, Some(&password), namespace, database).await?;
+    let mut counts = Vec::new();
+    for table in tables {
+        let count = surreal::count(tools, &endpoint, Some(&password), namespace, database, &table).await?;
+        counts.push((table, count));
+    }
+    Ok(counts)
+}
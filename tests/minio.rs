@@ -0,0 +1,288 @@
+//! End-to-end backups into a throwaway MinIO container. They need Docker and the `aws` CLI on
+//! `$PATH`, so they only run on request: `cargo test --test minio -- --ignored`. SurrealDB,
+//! tikv-br and the compressor are replaced by shell stubs; everything else is the real binary.
+//! Setting `BTAGGER_IT_EXPORT_BYTES` makes the SurrealDB stub export that many zero bytes
+//! instead, for uploads of more than one part.
+#![cfg(unix)]
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+
+use serde_json::Value;
+use testcontainers_modules::minio::MinIO;
+use testcontainers_modules::testcontainers::runners::AsyncRunner;
+use testcontainers_modules::testcontainers::ContainerAsync;
+use tokio::process::Command;
+
+const EXPORT: &str = "DEFINE TABLE person;\nCREATE person:1 SET name = \"Ada\";\n";
+
+struct Harness {
+    // Kept alive for the length of the test; dropping it removes the container.
+    _minio: ContainerAsync<MinIO>,
+    endpoint: String,
+    aws: PathBuf,
+    stubs: PathBuf,
+}
+
+impl Harness {
+    async fn start(name: &str) -> Harness {
+        let minio = MinIO::default().start().await.expect("MinIO container should start (is Docker running?)");
+        let port = minio.get_host_port_ipv4(9000).await.unwrap();
+        let aws = which::which("aws").expect("the aws CLI must be on $PATH");
+        let stubs = std::env::temp_dir().join(format!("btagger-it-{}-{}", std::process::id(), name));
+        write_stubs(&stubs, &aws);
+        Harness { _minio: minio, endpoint: format!("http://127.0.0.1:{}", port), aws, stubs }
+    }
+
+    /// btagger with the stubs, pointed at MinIO, running `command`. Its temporary files go in
+    /// the stub directory, where tests can look for leftovers.
+    fn command(&self, command: &str) -> Command {
+        let tmp = self.stubs.join("tmp");
+        std::fs::create_dir_all(&tmp).unwrap();
+        let mut btagger = Command::new(env!("CARGO_BIN_EXE_btagger"));
+        btagger
+            .env("AWS_DEFAULT_REGION", "us-east-1")
+            .env("TMPDIR", tmp)
+            .arg("--bin-path").arg(&self.stubs)
+            .arg("--aws-bin").arg(&self.aws)
+            .arg(command)
+            .arg("-e").arg(&self.endpoint)
+            .arg("-i").arg("minioadmin")
+            .arg("-k").arg("minioadmin");
+        btagger
+    }
+
+    /// Runs btagger against the `backups` bucket, returning its run summary.
+    async fn btagger(&self, command: &str, args: &[&str]) -> Value {
+        self.btagger_with(command, args, &[]).await
+    }
+
+    async fn btagger_with(&self, command: &str, args: &[&str], envs: &[(&str, &str)]) -> Value {
+        let output = self.command(command).arg("-B").arg("backups").args(args).envs(envs.iter().copied()).output().await.unwrap();
+        assert!(output.status.success(), "btagger failed: {}", String::from_utf8_lossy(&output.stderr));
+        serde_json::from_slice(&output.stdout).expect("btagger prints one JSON summary")
+    }
+
+    async fn aws(&self, args: &[&str]) -> Output {
+        let output = Command::new(&self.aws)
+            .env("AWS_ACCESS_KEY_ID", "minioadmin")
+            .env("AWS_SECRET_ACCESS_KEY", "minioadmin")
+            .env("AWS_DEFAULT_REGION", "us-east-1")
+            .arg("--endpoint-url").arg(&self.endpoint)
+            .args(args)
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success(), "aws {:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+        output
+    }
+
+    async fn aws_json(&self, args: &[&str]) -> Value {
+        let output = self.aws(&[args, &["--output", "json"]].concat()).await;
+        serde_json::from_slice(&output.stdout).unwrap()
+    }
+
+    /// Keys under `prefix` in the `backups` bucket.
+    async fn keys(&self, prefix: &str) -> Vec<String> {
+        let listing = self.aws_json(&["s3api", "list-objects-v2", "--bucket", "backups", "--prefix", prefix]).await;
+        listing["Contents"]
+            .as_array()
+            .map(|objects| objects.iter().map(|object| object["Key"].as_str().unwrap().to_string()).collect())
+            .unwrap_or_default()
+    }
+
+    async fn tags(&self, key: &str) -> Vec<(String, String)> {
+        let output = self.aws(&["s3api", "get-object-tagging", "--bucket", "backups", "--key", key, "--output", "json"]).await;
+        let tagging: Value = serde_json::from_slice(&output.stdout).unwrap();
+        tagging["TagSet"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|tag| (tag["Key"].as_str().unwrap().to_string(), tag["Value"].as_str().unwrap().to_string()))
+            .collect()
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.stubs);
+    }
+}
+
+fn write_stubs(dir: &Path, aws: &Path) {
+    let bin = dir.join("bin");
+    std::fs::create_dir_all(&bin).unwrap();
+    let stubs = [
+        (
+            "surreal",
+            format!(
+                "#!/bin/sh\ncase \"$1\" in\n  export) if [ -n \"$BTAGGER_IT_EXPORT_BYTES\" ]; then head -c \"$BTAGGER_IT_EXPORT_BYTES\" /dev/zero; else printf '{}'; fi ;;\nesac\n",
+                EXPORT.replace('\n', "\\n")
+            ),
+        ),
+        // Stands in for zstd both ways; the pipeline only cares that bytes flow through.
+        ("zstd", String::from("#!/bin/sh\nexec cat\n")),
+        // Writes two objects under --storage, as tikv-br writes SST files and a backupmeta.
+        (
+            "tikv-br",
+            format!(
+                "#!/bin/sh\nfor arg in \"$@\"; do\n  case \"$arg\" in\n    --storage=*) storage=\"${{arg#--storage=}}\" ;;\n    --s3.endpoint=*) endpoint=\"${{arg#--s3.endpoint=}}\" ;;\n  esac\ndone\nfor name in backupmeta 1.sst; do\n  echo \"$name\" | '{}' --endpoint-url \"$endpoint\" s3 cp - \"$storage/$name\" || exit 1\ndone\n",
+                aws.display()
+            ),
+        ),
+    ];
+    for (name, script) in stubs {
+        let path = bin.join(name);
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+}
+
+fn standard(tags: &[(String, String)]) -> bool {
+    tags.contains(&(String::from("standard"), String::from("1")))
+}
+
+fn storage_key(summary: &Value) -> String {
+    assert_eq!(summary["success"], Value::Bool(true), "{}", summary);
+    summary["storage_keys"][0].as_str().unwrap().to_string()
+}
+
+#[tokio::test]
+#[ignore = "needs Docker and the aws CLI"]
+async fn surrealdb_backup_lands_tagged_in_minio() {
+    let harness = Harness::start("surrealdb").await;
+    let summary = harness
+        .btagger("surrealdb", &["-N", "app", "-d", "main", "-a", "127.0.0.1:8000", "-p", "root"])
+        .await;
+    let key = storage_key(&summary);
+    assert!(key.starts_with("surrealdb/app/") && key.ends_with(".zst"), "{}", key);
+
+    let object = harness.aws(&["s3", "cp", &format!("s3://backups/{}", key), "-"]).await;
+    assert_eq!(String::from_utf8_lossy(&object.stdout), EXPORT);
+    assert!(standard(&harness.tags(&key).await));
+
+    let head = harness.aws(&["s3api", "head-object", "--bucket", "backups", "--key", &key, "--output", "json"]).await;
+    let head: Value = serde_json::from_slice(&head.stdout).unwrap();
+    assert_eq!(head["Metadata"]["uncompressed-bytes"], Value::from(EXPORT.len().to_string()));
+}
+
+#[tokio::test]
+#[ignore = "needs Docker and the aws CLI"]
+async fn tikv_backup_tags_every_object_in_minio() {
+    let harness = Harness::start("tikv").await;
+    let summary = harness.btagger("tikv", &["-p", "127.0.0.1:2379"]).await;
+    let key = storage_key(&summary);
+
+    let keys = harness.keys(&key).await;
+    assert_eq!(keys.len(), 2, "{:?}", keys);
+    for key in keys {
+        assert!(standard(&harness.tags(&key).await), "{} is untagged", key);
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs Docker and the aws CLI"]
+async fn split_backup_becomes_tagged_numbered_objects_and_a_manifest() {
+    let harness = Harness::start("split").await;
+    let bytes = 12 * 1024 * 1024;
+    let summary = harness
+        .btagger_with(
+            "surrealdb",
+            &["-N", "app", "-d", "main", "-a", "127.0.0.1:8000", "-p", "root", "--split-size", "5MiB", "--part-size", "5MiB"],
+            &[("BTAGGER_IT_EXPORT_BYTES", &bytes.to_string())],
+        )
+        .await;
+    let key = storage_key(&summary);
+    let parts = (1..=3).map(|number| format!("{}.part{:04}", key, number)).collect::<Vec<_>>();
+    assert_eq!(harness.keys(&key).await, [vec![key.clone()], parts.clone()].concat());
+
+    let manifest = harness.aws(&["s3", "cp", &format!("s3://backups/{}", key), "-"]).await;
+    let manifest: Value = serde_json::from_slice(&manifest.stdout).unwrap();
+    assert_eq!(manifest["bytes"], Value::from(bytes));
+    let listed = manifest["objects"].as_array().unwrap().iter().map(|object| object["key"].as_str().unwrap().to_string()).collect::<Vec<_>>();
+    assert_eq!(listed, parts);
+    for key in [vec![key.clone()], parts].concat() {
+        assert!(standard(&harness.tags(&key).await), "{} is untagged", key);
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs Docker and the aws CLI"]
+async fn cas_backups_share_chunks_and_leave_no_pending_marker() {
+    let harness = Harness::start("cas").await;
+    let args = ["-N", "app", "-d", "main", "-a", "127.0.0.1:8000", "-p", "root", "--cas"];
+    let first = harness.btagger("surrealdb", &args).await;
+    let key = storage_key(&first);
+    assert!(key.ends_with(".cas.json"), "{}", key);
+    assert!(standard(&harness.tags(&key).await));
+    let chunks = harness.keys("chunks/").await;
+    assert!(!chunks.is_empty());
+    assert!(harness.keys("_cas-pending/").await.is_empty());
+
+    // The same export again only adds an index.
+    let second = harness.btagger("surrealdb", &args).await;
+    assert_eq!(harness.keys("chunks/").await, chunks);
+    assert!(second["bytes"].as_u64().unwrap() < first["bytes"].as_u64().unwrap(), "{} vs {}", second, first);
+    assert!(harness.keys("_cas-pending/").await.is_empty());
+}
+
+#[tokio::test]
+#[ignore = "needs Docker and the aws CLI"]
+async fn resume_finishes_an_interrupted_spooled_upload() {
+    let harness = Harness::start("resume").await;
+    let spool = harness.stubs.join("spool");
+    std::fs::create_dir_all(&spool).unwrap();
+    harness.aws(&["s3api", "create-bucket", "--bucket", "backups"]).await;
+
+    // A spooled upload of two parts that was interrupted after the first, as the backup leaves it.
+    let key = "surrealdb/app/2025-01-01.04-30.zst";
+    let part_size = 5 * 1024 * 1024;
+    let file = spool.join("btagger-spool-surrealdb_app_2025-01-01.04-30.zst");
+    std::fs::write(&file, vec![7u8; part_size + 1024]).unwrap();
+    let first = spool.join("first-part");
+    std::fs::write(&first, vec![7u8; part_size]).unwrap();
+    let created = harness.aws_json(&["s3api", "create-multipart-upload", "--bucket", "backups", "--key", key]).await;
+    let upload_id = created["UploadId"].as_str().unwrap();
+    let part = harness
+        .aws_json(&["s3api", "upload-part", "--bucket", "backups", "--key", key, "--upload-id", upload_id, "--part-number", "1", "--body", first.to_str().unwrap()])
+        .await;
+    std::fs::remove_file(&first).unwrap();
+    let journal = serde_json::json!({
+        "bucket_name": "backups",
+        "key": key,
+        "upload_id": upload_id,
+        "part_size": part_size,
+        "tags": r#"{"TagSet":[{"Key":"nightly","Value":"1"}]}"#,
+        "parts": [{"ETag": part["ETag"], "PartNumber": 1}],
+    });
+    let journal_path = spool.join("btagger-spool-surrealdb_app_2025-01-01.04-30.zst.upload.json");
+    std::fs::write(&journal_path, journal.to_string()).unwrap();
+
+    let output = harness.command("resume").arg("--spool-dir").arg(&spool).output().await.unwrap();
+    assert!(output.status.success(), "btagger failed: {}", String::from_utf8_lossy(&output.stderr));
+    let head = harness.aws_json(&["s3api", "head-object", "--bucket", "backups", "--key", key]).await;
+    assert_eq!(head["ContentLength"], Value::from(part_size + 1024));
+    assert_eq!(harness.tags(key).await, [(String::from("nightly"), String::from("1"))]);
+    assert!(!file.exists() && !journal_path.exists());
+}
+
+#[tokio::test]
+#[ignore = "needs Docker and the aws CLI"]
+async fn path_style_backup_lands_in_minio_and_removes_its_aws_config() {
+    let harness = Harness::start("path-style").await;
+    let summary = harness
+        .btagger("surrealdb", &["-N", "app", "-d", "main", "-a", "127.0.0.1:8000", "-p", "root", "--s3-force-path-style"])
+        .await;
+    let key = storage_key(&summary);
+    let object = harness.aws(&["s3", "cp", &format!("s3://backups/{}", key), "-"]).await;
+    assert_eq!(String::from_utf8_lossy(&object.stdout), EXPORT);
+    assert!(standard(&harness.tags(&key).await));
+
+    let leftovers = std::fs::read_dir(harness.stubs.join("tmp"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|name| name.ends_with(".aws-config"))
+        .collect::<Vec<_>>();
+    assert!(leftovers.is_empty(), "{:?}", leftovers);
+}